@@ -0,0 +1,45 @@
+//! Generates the gRPC service of `proto/chatgpt.proto` when the `grpc`
+//! feature is on. The message types are written by hand in
+//! `src/api/grpc.rs`, so building needs no `protoc`.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "grpc")]
+    grpc::generate();
+}
+
+#[cfg(feature = "grpc")]
+mod grpc {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    /// `(method, route, request, response, server streaming)`
+    #[rustfmt::skip]
+    const METHODS: &[(&str, &str, &str, &str, bool)] = &[
+        ("create_thread", "CreateThread", "CreateThreadRequest", "Thread", false),
+        ("get_thread", "GetThread", "GetThreadRequest", "Thread", false),
+        ("list_threads", "ListThreads", "ListThreadsRequest", "ListThreadsResponse", false),
+        ("delete_thread", "DeleteThread", "DeleteThreadRequest", "DeleteThreadResponse", false),
+        ("add_message", "AddMessage", "AddMessageRequest", "Message", false),
+        ("list_messages", "ListMessages", "ListMessagesRequest", "ListMessagesResponse", false),
+        ("generate", "Generate", "GenerateRequest", "GenerateEvent", true),
+        ("cancel_response", "CancelResponse", "CancelResponseRequest", "CancelResponseResponse", false),
+    ];
+
+    pub fn generate() {
+        let mut service = Service::builder().name("ChatGpt").package("chatgpt.v1");
+        for &(name, route, input, output, streaming) in METHODS {
+            let mut method = Method::builder()
+                .name(name)
+                .route_name(route)
+                .input_type(format!("super::{}", input))
+                .output_type(format!("super::{}", output))
+                .codec_path("tonic_prost::ProstCodec");
+            if streaming {
+                method = method.server_streaming();
+            }
+            service = service.method(method.build());
+        }
+        Builder::new().compile(&[service.build()]);
+    }
+}
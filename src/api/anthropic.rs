@@ -0,0 +1,474 @@
+//! Anthropic Messages API compatibility: `POST /v1/messages`.
+//!
+//! Each request carries the whole conversation, so it runs as a
+//! [`OneShot`] turn rather than on a thread, with the system prompt and
+//! earlier turns folded into the message.
+
+use std::convert::Infallible;
+
+use axum::Json;
+use axum::extract::State;
+use axum::extract::rejection::JsonRejection;
+use axum::http::{HeaderValue, StatusCode, header::RETRY_AFTER};
+use axum::response::{IntoResponse, Response, Sse, sse::Event};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{Instrument, info};
+
+use super::error::ApiError;
+use super::handlers::resolve_model;
+use super::oneshot::{OneShot, fold_prompt};
+use super::state::AppState;
+use crate::backend::ChatBackend;
+
+/// Route of the Messages API
+pub const PATH: &str = "/v1/messages";
+
+#[derive(Debug, Deserialize)]
+pub struct CreateMessageRequest {
+    pub model: String,
+    /// Required by the Messages API, though upstream decides the answer length
+    pub max_tokens: u32,
+    #[serde(default)]
+    pub system: Option<SystemPrompt>,
+    pub messages: Vec<InputMessage>,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+/// `system` as a plain string or as text blocks
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum SystemPrompt {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InputMessage {
+    pub role: Role,
+    pub content: MessageContent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+/// Message content as a plain string or as content blocks
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        source: ImageSource,
+    },
+    /// Tool use, documents and anything newer, which upstream cannot take
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ImageSource {
+    Base64 {
+        media_type: String,
+        data: String,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Serialize)]
+struct MessageObject {
+    id: String,
+    #[serde(rename = "type")]
+    object: &'static str,
+    role: &'static str,
+    model: String,
+    content: Vec<TextBlock>,
+    stop_reason: Option<&'static str>,
+    stop_sequence: Option<String>,
+    usage: MessageUsage,
+}
+
+#[derive(Debug, Serialize)]
+struct TextBlock {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    text: String,
+}
+
+impl TextBlock {
+    fn new(text: String) -> Self {
+        Self { kind: "text", text }
+    }
+}
+
+/// Token counts are not reported by upstream, so these stay at zero
+#[derive(Debug, Default, Serialize)]
+struct MessageUsage {
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+/// Server-sent events of a streamed message, in the order they are sent
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: MessageObject,
+    },
+    ContentBlockStart {
+        index: u32,
+        content_block: TextBlock,
+    },
+    ContentBlockDelta {
+        index: u32,
+        delta: TextDelta,
+    },
+    ContentBlockStop {
+        index: u32,
+    },
+    MessageDelta {
+        delta: StopDelta,
+        usage: OutputUsage,
+    },
+    MessageStop,
+}
+
+#[derive(Debug, Serialize)]
+struct TextDelta {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    text: String,
+}
+
+#[derive(Debug, Serialize)]
+struct StopDelta {
+    stop_reason: &'static str,
+    stop_sequence: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct OutputUsage {
+    output_tokens: u32,
+}
+
+impl StreamEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::MessageStart { .. } => "message_start",
+            Self::ContentBlockStart { .. } => "content_block_start",
+            Self::ContentBlockDelta { .. } => "content_block_delta",
+            Self::ContentBlockStop { .. } => "content_block_stop",
+            Self::MessageDelta { .. } => "message_delta",
+            Self::MessageStop => "message_stop",
+        }
+    }
+
+    fn into_event(self) -> Event {
+        Event::default()
+            .event(self.name())
+            .json_data(&self)
+            .unwrap()
+    }
+}
+
+/// An [`ApiError`] rendered as a Messages API `{"type": "error", ...}` object
+#[derive(Debug)]
+pub struct MessagesError(pub ApiError);
+
+impl MessagesError {
+    /// Messages API error type for the status, e.g. `overloaded_error` for 503
+    fn error_type(&self) -> &'static str {
+        match self.0.status {
+            StatusCode::PAYLOAD_TOO_LARGE => "request_too_large",
+            StatusCode::SERVICE_UNAVAILABLE => "overloaded_error",
+            _ => match self.0.error_type() {
+                "upstream_error" => "api_error",
+                other => other,
+            },
+        }
+    }
+
+    fn body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "error",
+            "error": {
+                "type": self.error_type(),
+                "message": self.0.message,
+            }
+        })
+    }
+
+    /// SSE event reporting a failure after the stream has started
+    fn into_event(self) -> Event {
+        Event::default()
+            .event("error")
+            .json_data(self.body())
+            .unwrap()
+    }
+}
+
+impl From<ApiError> for MessagesError {
+    fn from(err: ApiError) -> Self {
+        Self(err)
+    }
+}
+
+impl From<JsonRejection> for MessagesError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self(ApiError::from(rejection))
+    }
+}
+
+impl IntoResponse for MessagesError {
+    fn into_response(self) -> Response {
+        let mut response = (self.0.status, Json(self.body())).into_response();
+        if let Some(seconds) = self.0.retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+}
+
+/// What gets sent upstream for a Messages API request
+#[derive(Debug, PartialEq)]
+struct Prompt {
+    message: String,
+    /// Base64 data of the image in the last user message
+    image: Option<String>,
+}
+
+impl Prompt {
+    /// Fold the system prompt and earlier turns into a single message
+    fn build(system: Option<SystemPrompt>, messages: Vec<InputMessage>) -> Result<Self, ApiError> {
+        let Some((last, history)) = messages.split_last() else {
+            return Err(
+                ApiError::bad_request("`messages` must not be empty").with_param("messages")
+            );
+        };
+        if last.role != Role::User {
+            return Err(
+                ApiError::bad_request("The last message must have the `user` role")
+                    .with_param(format!("messages.{}.role", history.len())),
+            );
+        }
+
+        let system = match system {
+            Some(SystemPrompt::Text(text)) => text,
+            Some(SystemPrompt::Blocks(blocks)) => text_of(&blocks, "system")?.0,
+            None => String::new(),
+        };
+
+        let mut transcript = Vec::new();
+        for (index, message) in history.iter().enumerate() {
+            let param = format!("messages.{}.content", index);
+            let (text, image) = message.content.text(&param)?;
+            if image.is_some() {
+                return Err(ApiError::bad_request(
+                    "Images are only supported in the last user message",
+                )
+                .with_param(param));
+            }
+            let speaker = match message.role {
+                Role::User => "User",
+                Role::Assistant => "Assistant",
+            };
+            transcript.push((speaker, text));
+        }
+
+        let param = format!("messages.{}.content", history.len());
+        let (text, image) = last.content.text(&param)?;
+        if text.trim().is_empty() && image.is_none() {
+            return Err(
+                ApiError::bad_request("Last user message content is empty").with_param(param)
+            );
+        }
+
+        Ok(Self {
+            message: fold_prompt(&system, &transcript, &text),
+            image,
+        })
+    }
+}
+
+impl MessageContent {
+    /// The text of the content and the data of its image, if it has one
+    fn text(&self, param: &str) -> Result<(String, Option<String>), ApiError> {
+        match self {
+            Self::Text(text) => Ok((text.clone(), None)),
+            Self::Blocks(blocks) => text_of(blocks, param),
+        }
+    }
+}
+
+/// Join the text blocks of `blocks` and pick out their single image
+fn text_of(blocks: &[ContentBlock], param: &str) -> Result<(String, Option<String>), ApiError> {
+    let mut texts = Vec::new();
+    let mut image = None;
+
+    for (index, block) in blocks.iter().enumerate() {
+        let param = || format!("{}.{}", param, index);
+        match block {
+            ContentBlock::Text { text } => texts.push(text.as_str()),
+            ContentBlock::Image { source } => {
+                let ImageSource::Base64 { media_type, data } = source else {
+                    return Err(
+                        ApiError::bad_request("Only base64 image sources are supported")
+                            .with_param(format!("{}.source", param())),
+                    );
+                };
+                if !media_type.starts_with("image/") {
+                    return Err(ApiError::bad_request(format!(
+                        "Unsupported image media type `{}`",
+                        media_type
+                    ))
+                    .with_param(format!("{}.source.media_type", param())));
+                }
+                if image.replace(data.clone()).is_some() {
+                    return Err(
+                        ApiError::bad_request("Only one image per request is supported")
+                            .with_param(param()),
+                    );
+                }
+            }
+            ContentBlock::Unsupported => {
+                return Err(ApiError::bad_request(
+                    "Only `text` and `image` content blocks are supported",
+                )
+                .with_param(param()));
+            }
+        }
+    }
+
+    Ok((texts.join("\n\n"), image))
+}
+
+/// Create a message (Messages API)
+pub async fn create_message<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    payload: Result<Json<CreateMessageRequest>, JsonRejection>,
+) -> Result<Response, MessagesError> {
+    state.check_accepting()?;
+    let Json(payload) = payload?;
+
+    info!(
+        "Creating message from {} messages, stream: {}",
+        payload.messages.len(),
+        payload.stream
+    );
+
+    if payload.max_tokens == 0 {
+        return Err(ApiError::bad_request("`max_tokens` must be at least 1")
+            .with_param("max_tokens")
+            .into());
+    }
+    let model = resolve_model(&state, Some(payload.model))?;
+    let prompt = Prompt::build(payload.system, payload.messages)?;
+    state.check_message(&prompt.message)?;
+
+    let message = MessageObject {
+        id: format!("msg_{}", uuid::Uuid::new_v4().simple()),
+        object: "message",
+        role: "assistant",
+        model,
+        content: Vec::new(),
+        stop_reason: None,
+        stop_sequence: None,
+        usage: MessageUsage::default(),
+    };
+    let turn = OneShot::begin(&state, message.id.clone(), prompt.message, prompt.image).await;
+
+    if payload.stream {
+        Ok(stream_message(state, turn, message))
+    } else {
+        let answer = turn.complete().await?;
+        Ok(Json(MessageObject {
+            content: vec![TextBlock::new(answer)],
+            stop_reason: Some("end_turn"),
+            ..message
+        })
+        .into_response())
+    }
+}
+
+fn stream_message<B: ChatBackend>(
+    state: AppState<B>,
+    turn: OneShot<B>,
+    message: MessageObject,
+) -> Response {
+    let (tx, rx) = mpsc::channel(100);
+
+    // Spawn a task that relays deltas from upstream as they arrive
+    tokio::spawn(
+        async move {
+            let mut turn = turn.stream();
+
+            let opening = [
+                StreamEvent::MessageStart { message },
+                StreamEvent::ContentBlockStart {
+                    index: 0,
+                    content_block: TextBlock::new(String::new()),
+                },
+            ];
+            for event in opening {
+                let _ = tx.send(Ok::<_, Infallible>(event.into_event())).await;
+            }
+
+            while let Some(delta) = turn.deltas.recv().await {
+                let bytes = delta.len();
+                let event = StreamEvent::ContentBlockDelta {
+                    index: 0,
+                    delta: TextDelta {
+                        kind: "text_delta",
+                        text: delta,
+                    },
+                };
+                if tx.send(Ok(event.into_event())).await.is_err() {
+                    // The client went away, so stop the upstream request as well
+                    turn.cancel();
+                    break;
+                }
+                state.metrics().add_streamed_bytes(bytes);
+            }
+
+            let closing = match turn.finish().await {
+                Ok(_) => vec![
+                    StreamEvent::ContentBlockStop { index: 0 }.into_event(),
+                    StreamEvent::MessageDelta {
+                        delta: StopDelta {
+                            stop_reason: "end_turn",
+                            stop_sequence: None,
+                        },
+                        usage: OutputUsage { output_tokens: 0 },
+                    }
+                    .into_event(),
+                    StreamEvent::MessageStop.into_event(),
+                ],
+                Err(err) => vec![MessagesError(err).into_event()],
+            };
+            for event in closing {
+                let _ = tx.send(Ok(event)).await;
+            }
+        }
+        .in_current_span(),
+    );
+
+    Sse::new(ReceiverStream::new(rx)).into_response()
+}
@@ -0,0 +1,604 @@
+//! Import of ChatGPT's own data export.
+//!
+//! The `conversations.json` in a ChatGPT data export holds every conversation
+//! as a tree of message nodes: editing a prompt or regenerating an answer
+//! starts a new branch, and `current_node` is the leaf that was last shown.
+//! [`convert`] walks that tree into [`Branch`]es, one per branch taken,
+//! which `POST /v1/threads/import/chatgpt` and `chatgpt import` turn into
+//! threads. Each thread remembers the leaf node it ends at, so importing a
+//! newer export skips branches already there and extends threads whose
+//! conversation went on.
+
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use axum::{
+    Json,
+    extract::{Query, State},
+    response::{IntoResponse, Response as AxumResponse},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tracing::info;
+
+use super::error::{ApiError, ApiJson};
+use super::export::{EXPORT_VERSION, ThreadExport};
+use super::handlers::thread_object;
+use super::state::AppState;
+use super::storage::{StoredThread, ThreadStore};
+use super::types::{Attachment, ListThreadsResponse, ThreadMessage};
+use crate::backend::ChatBackend;
+use crate::utils::Result;
+
+/// `metadata.source` of imported threads
+pub const SOURCE: &str = "chatgpt";
+
+/// One conversation of `conversations.json`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Conversation {
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub conversation_id: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub create_time: Option<f64>,
+    #[serde(default)]
+    pub update_time: Option<f64>,
+    /// Leaf of the branch that was shown last
+    #[serde(default)]
+    pub current_node: Option<String>,
+    #[serde(default)]
+    pub mapping: HashMap<String, Node>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Node {
+    #[serde(default)]
+    pub message: Option<Message>,
+    #[serde(default)]
+    pub parent: Option<String>,
+    #[serde(default)]
+    pub children: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Message {
+    pub author: Author,
+    #[serde(default)]
+    pub create_time: Option<f64>,
+    #[serde(default)]
+    pub content: Option<Content>,
+    /// Who an assistant message is addressed to; "all" unless it calls a tool
+    #[serde(default)]
+    pub recipient: Option<String>,
+    #[serde(default)]
+    pub metadata: MessageMetadata,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Author {
+    pub role: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Content {
+    #[serde(default)]
+    pub content_type: String,
+    /// Text, or objects such as image pointers in `multimodal_text`
+    #[serde(default)]
+    pub parts: Vec<Value>,
+    /// Body of `code` content
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MessageMetadata {
+    #[serde(default)]
+    pub attachments: Vec<ExportedAttachment>,
+    #[serde(default)]
+    pub is_visually_hidden_from_conversation: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExportedAttachment {
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default, rename = "mimeType", alias = "mime_type")]
+    pub mime_type: Option<String>,
+}
+
+/// Which branches of each conversation to import
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Branches {
+    /// Only the branch ending at `current_node`
+    #[default]
+    Active,
+    /// Every branch, one thread each
+    All,
+}
+
+impl FromStr for Branches {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "active" => Ok(Self::Active),
+            "all" => Ok(Self::All),
+            _ => Err(format!("Unknown branches {:?}; use active or all", s)),
+        }
+    }
+}
+
+impl Conversation {
+    fn conversation_id(&self) -> Option<&str> {
+        self.conversation_id.as_deref().or(self.id.as_deref())
+    }
+
+    /// Node IDs from the root to each leaf taken, in display order
+    fn branches(&self, which: Branches) -> Vec<Vec<&str>> {
+        let leaves = match which {
+            Branches::Active => self.active_leaf().into_iter().collect(),
+            Branches::All => self.leaves(),
+        };
+        leaves.into_iter().map(|leaf| self.path_to(leaf)).collect()
+    }
+
+    /// `current_node`, or the newest leaf if it is missing
+    fn active_leaf(&self) -> Option<&str> {
+        if let Some(current) = self.current_node.as_deref()
+            && self.mapping.contains_key(current)
+        {
+            return Some(current);
+        }
+        self.leaves().into_iter().max_by(|a, b| {
+            let time = |id: &str| {
+                self.mapping[id]
+                    .message
+                    .as_ref()
+                    .and_then(|m| m.create_time)
+            };
+            time(a)
+                .partial_cmp(&time(b))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+    }
+
+    /// Leaves in depth-first order, so branch numbers follow the tree
+    fn leaves(&self) -> Vec<&str> {
+        let mut roots: Vec<&str> = self
+            .mapping
+            .iter()
+            .filter(|(_, node)| {
+                node.parent
+                    .as_deref()
+                    .is_none_or(|parent| !self.mapping.contains_key(parent))
+            })
+            .map(|(id, _)| id.as_str())
+            .collect();
+        roots.sort_unstable();
+
+        let mut leaves = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stack: Vec<&str> = roots.into_iter().rev().collect();
+        while let Some(id) = stack.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let children: Vec<&str> = self.mapping[id]
+                .children
+                .iter()
+                .map(String::as_str)
+                .filter(|child| self.mapping.contains_key(*child))
+                .collect();
+            if children.is_empty() {
+                leaves.push(id);
+            }
+            stack.extend(children.into_iter().rev());
+        }
+        leaves
+    }
+
+    fn path_to<'a>(&'a self, leaf: &'a str) -> Vec<&'a str> {
+        let mut path = vec![leaf];
+        let mut seen = HashSet::from([leaf]);
+        let mut id = leaf;
+        while let Some(parent) = self.mapping[id].parent.as_deref() {
+            if !self.mapping.contains_key(parent) || !seen.insert(parent) {
+                break;
+            }
+            path.push(parent);
+            id = parent;
+        }
+        path.reverse();
+        path
+    }
+
+    /// The visible user and assistant turns along `path`, each with the
+    /// index in `path` of the node it starts at
+    fn messages(&self, path: &[&str]) -> (Vec<ThreadMessage>, Vec<usize>) {
+        let mut messages: Vec<ThreadMessage> = Vec::new();
+        let mut starts = Vec::new();
+        for (index, message) in path
+            .iter()
+            .enumerate()
+            .filter_map(|(index, id)| Some((index, self.mapping[*id].message.as_ref()?)))
+        {
+            let Some(converted) = convert_message(message) else {
+                continue;
+            };
+            // Answers split around tool calls read as one turn
+            match messages.last_mut() {
+                Some(last) if last.role == converted.role => {
+                    if !converted.content.is_empty() {
+                        if !last.content.is_empty() {
+                            last.content.push_str("\n\n");
+                        }
+                        last.content.push_str(&converted.content);
+                    }
+                    last.attachments.extend(converted.attachments);
+                }
+                _ => {
+                    messages.push(converted);
+                    starts.push(index);
+                }
+            }
+        }
+        (messages, starts)
+    }
+}
+
+fn convert_message(message: &Message) -> Option<ThreadMessage> {
+    let role = message.author.role.as_str();
+    if !matches!(role, "user" | "assistant")
+        || message.metadata.is_visually_hidden_from_conversation
+        || message.recipient.as_deref().is_some_and(|r| r != "all")
+    {
+        return None;
+    }
+
+    let mut attachments: Vec<Attachment> = message
+        .metadata
+        .attachments
+        .iter()
+        .map(|attachment| Attachment {
+            id: attachment.id.clone(),
+            name: attachment.name.clone(),
+            mime_type: attachment.mime_type.clone(),
+            url: None,
+        })
+        .collect();
+    let mut content = String::new();
+    if let Some(body) = &message.content {
+        match body.content_type.as_str() {
+            "text" | "multimodal_text" => {
+                let mut texts = Vec::new();
+                for part in &body.parts {
+                    match part {
+                        Value::String(text) if !text.is_empty() => texts.push(text.as_str()),
+                        Value::Object(object) => {
+                            if let Some(id) = object.get("asset_pointer").and_then(Value::as_str) {
+                                let id = id.rsplit("://").next().unwrap_or(id);
+                                if !attachments.iter().any(|a| a.id.as_deref() == Some(id)) {
+                                    attachments.push(Attachment {
+                                        id: Some(id.to_string()),
+                                        name: None,
+                                        mime_type: None,
+                                        url: None,
+                                    });
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                content = texts.join("\n");
+            }
+            "code" => {
+                if let Some(text) = body.text.as_deref().filter(|text| !text.is_empty()) {
+                    let language = body.language.as_deref().filter(|l| *l != "unknown");
+                    content = format!("```{}\n{}\n```", language.unwrap_or(""), text);
+                }
+            }
+            // Browsing results, reasoning summaries and the like are not turns
+            _ => {}
+        }
+    }
+
+    if content.trim().is_empty() && attachments.is_empty() {
+        return None;
+    }
+    Some(ThreadMessage {
+        role: role.to_string(),
+        content,
+        created_at: message.create_time.map(seconds),
+        status: None,
+        attachments,
+    })
+}
+
+fn seconds(time: f64) -> u64 {
+    if time.is_finite() && time > 0.0 {
+        time as u64
+    } else {
+        0
+    }
+}
+
+/// One branch of a conversation, converted to a thread
+#[derive(Debug, Clone)]
+pub struct Branch {
+    pub thread: ThreadExport,
+    /// Node IDs from the root to the leaf
+    path: Vec<String>,
+    /// Index in `path` of the node each message of `thread` starts at
+    starts: Vec<usize>,
+}
+
+/// Threads for `conversations`, skipping conversations with nothing to show
+pub fn convert(conversations: &[Conversation], which: Branches) -> Vec<Branch> {
+    let mut exports = Vec::new();
+    for conversation in conversations {
+        let branches: Vec<(Vec<&str>, Vec<ThreadMessage>, Vec<usize>)> = conversation
+            .branches(which)
+            .into_iter()
+            .map(|path| {
+                let (messages, starts) = conversation.messages(&path);
+                (path, messages, starts)
+            })
+            .filter(|(_, messages, _)| !messages.is_empty())
+            .collect();
+        let count = branches.len();
+
+        for (index, (path, messages, starts)) in branches.into_iter().enumerate() {
+            let mut metadata = json!({ "source": SOURCE });
+            if let Some(title) = &conversation.title {
+                metadata["title"] = json!(title);
+            }
+            if let Some(id) = conversation.conversation_id() {
+                metadata["chatgpt_conversation_id"] = json!(id);
+            }
+            metadata[LEAF_ID] = json!(path.last());
+            if which == Branches::All {
+                metadata["branch"] = json!(index + 1);
+                metadata["branches"] = json!(count);
+            }
+            exports.push(Branch {
+                thread: ThreadExport {
+                    object: super::export::EXPORT_OBJECT.to_string(),
+                    version: EXPORT_VERSION,
+                    id: conversation.conversation_id().map(str::to_string),
+                    created_at: conversation
+                        .create_time
+                        .map(seconds)
+                        .or_else(|| messages[0].created_at),
+                    exported_at: None,
+                    metadata: Some(metadata),
+                    messages,
+                },
+                path: path.into_iter().map(str::to_string).collect(),
+                starts,
+            });
+        }
+    }
+    exports
+}
+
+/// Parse the contents of `conversations.json`
+pub fn parse(text: &str) -> serde_json::Result<Vec<Conversation>> {
+    serde_json::from_str(text)
+}
+
+/// `metadata` key of the node an imported thread ends at
+const LEAF_ID: &str = "chatgpt_leaf_id";
+
+/// Messages to add to a thread imported earlier whose conversation went on
+#[derive(Debug, Clone)]
+pub struct Extension<K> {
+    pub thread: K,
+    pub messages: Vec<ThreadMessage>,
+    leaf_id: String,
+}
+
+impl<K> Extension<K> {
+    /// Add the new messages to the thread and move it to the new leaf
+    pub fn apply(self, metadata: &mut Option<Value>, messages: &mut Vec<ThreadMessage>) {
+        if let Some(metadata) = metadata.as_mut().filter(|m| m.is_object()) {
+            metadata[LEAF_ID] = json!(self.leaf_id);
+        }
+        messages.extend(self.messages);
+    }
+}
+
+/// How importing branches changes the threads already there
+#[derive(Debug, Clone)]
+pub struct ImportPlan<K> {
+    /// Branches not imported before
+    pub new: Vec<ThreadExport>,
+    /// Threads whose leaf a branch has since grown past
+    pub extend: Vec<Extension<K>>,
+    /// Branches imported before and unchanged since
+    pub skipped: usize,
+}
+
+/// Match `branches` against the `existing` threads. A branch ending at the
+/// leaf of an imported thread is skipped, one passing through it extends that
+/// thread, and any other one becomes a new thread.
+pub fn plan<'a, K: Clone>(
+    existing: impl IntoIterator<Item = (K, Option<&'a Value>)>,
+    branches: Vec<Branch>,
+) -> ImportPlan<K> {
+    // Threads by (conversation, leaf); `None` for threads of this import
+    let mut leaves: HashMap<(String, String), Option<K>> = existing
+        .into_iter()
+        .filter_map(|(thread, metadata)| Some((import_key(metadata)?, Some(thread))))
+        .collect();
+    let mut plan = ImportPlan {
+        new: Vec::new(),
+        extend: Vec::new(),
+        skipped: 0,
+    };
+
+    for branch in branches {
+        let Some((conversation, leaf)) = import_key(branch.thread.metadata.as_ref()) else {
+            plan.new.push(branch.thread);
+            continue;
+        };
+        if leaves.contains_key(&(conversation.clone(), leaf.clone())) {
+            plan.skipped += 1;
+            continue;
+        }
+
+        // The deepest earlier leaf along the way that a thread ends at
+        let grown = branch
+            .path
+            .iter()
+            .enumerate()
+            .rev()
+            .skip(1)
+            .find_map(|(index, node)| {
+                let key = (conversation.clone(), node.clone());
+                match leaves.get(&key) {
+                    Some(Some(thread)) => Some((index, key, thread.clone())),
+                    _ => None,
+                }
+            });
+        match grown {
+            Some((index, key, thread)) => {
+                leaves.remove(&key);
+                leaves.insert((conversation, leaf.clone()), Some(thread.clone()));
+                let messages = branch
+                    .thread
+                    .messages
+                    .into_iter()
+                    .zip(branch.starts)
+                    .filter(|(_, start)| *start > index)
+                    .map(|(message, _)| message)
+                    .collect();
+                plan.extend.push(Extension {
+                    thread,
+                    messages,
+                    leaf_id: leaf,
+                });
+            }
+            None => {
+                leaves.insert((conversation, leaf), None);
+                plan.new.push(branch.thread);
+            }
+        }
+    }
+    plan
+}
+
+/// What [`import_to_store`] did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreImport {
+    pub imported: usize,
+    /// Threads of an earlier import that got the newer messages
+    pub updated: usize,
+    /// Branches already in the store from an earlier import
+    pub skipped: usize,
+}
+
+/// Add `branches` to the thread file of a stopped server. Branches imported
+/// before are skipped and threads whose conversation went on get the new
+/// messages, so the same export can be imported again after downloading a
+/// newer one.
+pub fn import_to_store(store: &ThreadStore, branches: Vec<Branch>) -> Result<StoreImport> {
+    let mut threads = store.load()?;
+    let plan = plan(
+        threads
+            .iter()
+            .enumerate()
+            .map(|(index, thread)| (index, thread.metadata.as_ref())),
+        branches,
+    );
+    let summary = StoreImport {
+        imported: plan.new.len(),
+        updated: plan.extend.len(),
+        skipped: plan.skipped,
+    };
+
+    for extension in plan.extend {
+        let thread = &mut threads[extension.thread];
+        extension.apply(&mut thread.metadata, &mut thread.messages);
+    }
+    for export in plan.new {
+        threads.push(StoredThread {
+            id: uuid::Uuid::new_v4().to_string(),
+            created_at: export.created_at.unwrap_or_else(super::export::now),
+            metadata: export.metadata,
+            proxy: None,
+            conversation: None,
+            messages: export.messages,
+        });
+    }
+
+    if summary.imported + summary.updated > 0 {
+        store.save(threads)?;
+    }
+    Ok(summary)
+}
+
+fn import_key(metadata: Option<&Value>) -> Option<(String, String)> {
+    let metadata = metadata?;
+    if metadata["source"] != SOURCE {
+        return None;
+    }
+    let id = metadata["chatgpt_conversation_id"].as_str()?;
+    let leaf = metadata[LEAF_ID].as_str()?;
+    Some((id.to_string(), leaf.to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    pub branches: Option<String>,
+}
+
+/// Create threads from the `conversations.json` of a ChatGPT data export.
+/// Branches imported before are skipped and threads whose conversation went
+/// on are extended; nothing is imported if any message is too long or the
+/// threads would not fit.
+pub async fn import_chatgpt<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    Query(query): Query<ImportQuery>,
+    ApiJson(conversations): ApiJson<Vec<Conversation>>,
+) -> std::result::Result<AxumResponse, ApiError> {
+    let which = match query.branches.as_deref() {
+        None => Branches::Active,
+        Some(branches) => branches
+            .parse::<Branches>()
+            .map_err(|err| ApiError::bad_request(err).with_param("branches"))?,
+    };
+
+    let import = state
+        .import_branches(convert(&conversations, which))
+        .await?;
+    info!(
+        "Imported {} threads from {} ChatGPT conversations, updated {}, skipped {} unchanged",
+        import.created.len(),
+        conversations.len(),
+        import.updated.len(),
+        import.skipped
+    );
+
+    let data = import
+        .created
+        .into_iter()
+        .chain(import.updated)
+        .map(|(thread_id, thread)| thread_object(thread_id, &thread))
+        .collect();
+    Ok(Json(ListThreadsResponse {
+        object: "list".to_string(),
+        data,
+        has_more: false,
+    })
+    .into_response())
+}
@@ -0,0 +1,563 @@
+//! `api_server` settings, read from a TOML file and overridden from the
+//! command line.
+//!
+//! Precedence, highest first: command-line flags, environment variables,
+//! the config file, built-in defaults. The binary maps flags and environment
+//! variables onto [`ConfigOverrides`]; everything else only comes from the
+//! file.
+
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::sessions::{DEFAULT_POOLED_SESSIONS, DEFAULT_SESSION_IDLE};
+use crate::client::{ClientConfig, RetryPolicy};
+use crate::utils::redact::{REDACTED, scrub};
+use crate::utils::{ChatGptError, LogFormat, Result, Secret, Utils};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub server: ListenConfig,
+    pub upstream: UpstreamConfig,
+    pub auth: AuthConfig,
+    pub storage: StorageConfig,
+    pub limits: LimitsConfig,
+    pub logging: LoggingConfig,
+    pub webhooks: WebhookConfig,
+    /// Served by `/v1/models`; responses may only ask for these. The first
+    /// one is used when a request names no model.
+    pub models: Vec<ModelConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            server: ListenConfig::default(),
+            upstream: UpstreamConfig::default(),
+            auth: AuthConfig::default(),
+            storage: StorageConfig::default(),
+            limits: LimitsConfig::default(),
+            logging: LoggingConfig::default(),
+            webhooks: WebhookConfig::default(),
+            models: default_models(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    /// IP address to bind
+    pub host: String,
+    pub port: u16,
+    pub backend: BackendKind,
+    /// How long a shutdown waits for in-flight responses before cancelling
+    /// them; 0 cancels right away
+    pub drain_timeout_secs: u64,
+    /// Whether the gRPC service shares the port with the HTTP API
+    pub grpc: GrpcMode,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 6969,
+            backend: BackendKind::default(),
+            drain_timeout_secs: 30,
+            grpc: GrpcMode::default(),
+        }
+    }
+}
+
+/// Where the gRPC service (`proto/chatgpt.proto`) is served. Anything but
+/// `off` needs a build with the `grpc` feature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum GrpcMode {
+    #[default]
+    Off,
+    /// gRPC and the HTTP API on the same port
+    Alongside,
+    /// gRPC only, without the HTTP API
+    Only,
+}
+
+/// What threads talk to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Chatgpt,
+    /// Offline echo backend, see [`EchoBackend`](crate::backend::EchoBackend)
+    Echo,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Default proxy for new threads; a thread may still bring its own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    pub base_url: String,
+    pub connect_timeout_secs: u64,
+    pub read_timeout_secs: u64,
+    pub request_timeout_secs: u64,
+    /// Tries per upstream call, including the first
+    pub max_attempts: u32,
+    /// Idle sessions kept for turns that do not belong to a thread (the
+    /// Anthropic and Ollama APIs); 0 opens a new session for every turn
+    pub pooled_sessions: usize,
+    /// How long an idle session is kept for reuse
+    pub session_idle_secs: u64,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        let client = ClientConfig::default();
+        let secs = |timeout: Option<Duration>| timeout.map_or(0, |t| t.as_secs());
+        Self {
+            proxy: None,
+            base_url: client.base_url,
+            connect_timeout_secs: secs(client.connect_timeout),
+            read_timeout_secs: secs(client.read_timeout),
+            request_timeout_secs: secs(client.request_timeout),
+            max_attempts: client.retry.max_attempts,
+            pooled_sessions: DEFAULT_POOLED_SESSIONS,
+            session_idle_secs: DEFAULT_SESSION_IDLE.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Keys accepted as `Authorization: Bearer <key>` or `x-api-key`. When
+    /// empty the API is open to anyone who can reach it.
+    pub api_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub kind: StorageKind,
+    /// Thread file, required for `kind = "file"`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// How often changed threads are written out
+    pub flush_interval_secs: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            kind: StorageKind::default(),
+            path: None,
+            flush_interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// Threads live only as long as the process
+    #[default]
+    Memory,
+    /// Threads are saved to a JSON file and restored on startup
+    File,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Largest request body accepted
+    pub max_body_bytes: usize,
+    /// Threads kept at once; unlimited when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_threads: Option<usize>,
+    /// Longest message accepted, in characters; unlimited when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_message_chars: Option<usize>,
+    /// How long an `Idempotency-Key` keeps mapping to its response
+    pub idempotency_window_secs: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 2 * 1024 * 1024,
+            max_threads: None,
+            max_message_chars: None,
+            idempotency_window_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `tracing` filter, e.g. `info` or `chatgpt_rs=debug,tower_http=warn`
+    pub level: String,
+    pub format: LogFormat,
+    /// Levels for single modules on top of `level`, e.g.
+    /// `"chatgpt_rs::client" = "debug"`
+    pub modules: BTreeMap<String, String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::default(),
+            modules: BTreeMap::new(),
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// `level` and the per-module levels as one `tracing` filter
+    pub fn filter(&self) -> String {
+        let mut filter = self.level.clone();
+        for (module, level) in &self.modules {
+            filter.push_str(&format!(",{}={}", module, level));
+        }
+        filter
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Called when any response finishes, unless its request names its own
+    /// `webhook_url`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Key of the `webhook-signature` HMAC; webhooks are off without one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<Secret<String>>,
+    /// URL prefixes a request's own `webhook_url` may start with. When
+    /// empty, requests may only name `url`.
+    pub allowed_urls: Vec<String>,
+    /// Tries per event, including the first
+    pub max_attempts: u32,
+    /// Delay before the first retry; it doubles after every failed attempt
+    pub initial_backoff_ms: u64,
+    /// Upper bound for any single delay
+    pub max_backoff_ms: u64,
+    /// How long one attempt may take
+    pub timeout_secs: u64,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            secret: None,
+            allowed_urls: Vec::new(),
+            max_attempts: 5,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 60_000,
+            timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    pub id: String,
+    #[serde(default = "default_owner")]
+    pub owned_by: String,
+    #[serde(default)]
+    pub created: u64,
+}
+
+fn default_owner() -> String {
+    "openai".to_string()
+}
+
+/// The catalog served when the config file lists no `[[models]]`
+pub fn default_models() -> Vec<ModelConfig> {
+    [("gpt-4", 1677610602), ("gpt-4o", 1715367049)]
+        .into_iter()
+        .map(|(id, created)| ModelConfig {
+            id: id.to_string(),
+            owned_by: default_owner(),
+            created,
+        })
+        .collect()
+}
+
+/// Settings given on the command line or through the environment
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub proxy: Option<String>,
+    /// Drop any default proxy, whatever else sets one
+    pub no_proxy: bool,
+    pub backend: Option<BackendKind>,
+    pub drain_timeout_secs: Option<u64>,
+    pub grpc: Option<GrpcMode>,
+    /// Replace the configured keys when not empty
+    pub api_keys: Vec<String>,
+    pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<String>,
+}
+
+impl ServerConfig {
+    /// Read a config file. Sections and keys left out keep their defaults;
+    /// unknown keys are an error so typos do not go unnoticed.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            ChatGptError::configuration(format!("Failed to read {}: {}", path.display(), err))
+        })?;
+        Self::from_toml(&text)
+            .map_err(|err| ChatGptError::configuration(format!("{}: {}", path.display(), err)))
+    }
+
+    pub fn from_toml(text: &str) -> std::result::Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Layer command-line and environment settings over the file
+    pub fn apply(&mut self, overrides: ConfigOverrides) {
+        if let Some(host) = overrides.host {
+            self.server.host = host;
+        }
+        if let Some(port) = overrides.port {
+            self.server.port = port;
+        }
+        if let Some(backend) = overrides.backend {
+            self.server.backend = backend;
+        }
+        if let Some(secs) = overrides.drain_timeout_secs {
+            self.server.drain_timeout_secs = secs;
+        }
+        if let Some(grpc) = overrides.grpc {
+            self.server.grpc = grpc;
+        }
+        if let Some(proxy) = overrides.proxy {
+            self.upstream.proxy = Some(proxy);
+        }
+        if overrides.no_proxy {
+            self.upstream.proxy = None;
+        }
+        if !overrides.api_keys.is_empty() {
+            self.auth.api_keys = overrides.api_keys;
+        }
+        if let Some(level) = overrides.log_level {
+            self.logging.level = level;
+        }
+        if let Some(format) = overrides.log_format {
+            self.logging.format = format;
+        }
+        if let Some(url) = overrides.webhook_url {
+            self.webhooks.url = Some(url);
+        }
+        if let Some(secret) = overrides.webhook_secret {
+            self.webhooks.secret = Some(Secret::new(secret));
+        }
+    }
+
+    /// Check every setting, reporting all problems at once
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        if self.listen_addr().is_err() {
+            problems.push(format!(
+                "server.host: {:?} is not an IP address",
+                self.server.host
+            ));
+        }
+
+        if self.server.grpc != GrpcMode::Off && !cfg!(feature = "grpc") {
+            problems.push(
+                "server.grpc: this build has no gRPC support (build with `--features grpc`)"
+                    .to_string(),
+            );
+        }
+
+        if let Some(proxy) = &self.upstream.proxy
+            && let Err(err) = Utils::format_proxy(proxy)
+        {
+            problems.push(format!("upstream.proxy: {}", err));
+        }
+        match url::Url::parse(&self.upstream.base_url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => problems.push(format!(
+                "upstream.base_url: {:?} is not an http(s) URL",
+                self.upstream.base_url
+            )),
+        }
+        for (name, value) in [
+            ("connect_timeout_secs", self.upstream.connect_timeout_secs),
+            ("read_timeout_secs", self.upstream.read_timeout_secs),
+            ("request_timeout_secs", self.upstream.request_timeout_secs),
+            ("session_idle_secs", self.upstream.session_idle_secs),
+        ] {
+            if value == 0 {
+                problems.push(format!("upstream.{}: must be at least 1", name));
+            }
+        }
+        if self.upstream.max_attempts == 0 {
+            problems.push("upstream.max_attempts: must be at least 1".to_string());
+        }
+
+        let mut keys = HashSet::new();
+        for key in &self.auth.api_keys {
+            if key.trim().is_empty() {
+                problems.push("auth.api_keys: keys must not be empty".to_string());
+            } else if !keys.insert(key) {
+                problems.push("auth.api_keys: a key is listed twice".to_string());
+            }
+        }
+
+        if self.storage.kind == StorageKind::File && self.storage.path.is_none() {
+            problems.push("storage.path: required when storage.kind is \"file\"".to_string());
+        }
+        if self.storage.flush_interval_secs == 0 {
+            problems.push("storage.flush_interval_secs: must be at least 1".to_string());
+        }
+
+        if self.limits.max_body_bytes == 0 {
+            problems.push("limits.max_body_bytes: must be at least 1".to_string());
+        }
+        if self.limits.max_threads == Some(0) {
+            problems.push("limits.max_threads: must be at least 1".to_string());
+        }
+        if self.limits.max_message_chars == Some(0) {
+            problems.push("limits.max_message_chars: must be at least 1".to_string());
+        }
+        if self.limits.idempotency_window_secs == 0 {
+            problems.push("limits.idempotency_window_secs: must be at least 1".to_string());
+        }
+
+        if let Err(err) = tracing_subscriber::EnvFilter::try_new(&self.logging.level) {
+            problems.push(format!(
+                "logging.level: {:?} is not a valid filter: {}",
+                self.logging.level, err
+            ));
+        }
+        for (module, level) in &self.logging.modules {
+            if module.trim().is_empty() || module.contains([',', '=']) {
+                problems.push(format!(
+                    "logging.modules: {:?} is not a module path",
+                    module
+                ));
+            }
+            if level
+                .parse::<tracing::level_filters::LevelFilter>()
+                .is_err()
+            {
+                problems.push(format!(
+                    "logging.modules.{}: {:?} is not a level (trace, debug, info, warn, error, off)",
+                    module, level
+                ));
+            }
+        }
+
+        if let Some(url) = &self.webhooks.url {
+            match url::Url::parse(url) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+                _ => problems.push(format!(
+                    "webhooks.url: {:?} is not an http(s) URL",
+                    scrub(url)
+                )),
+            }
+            if self.webhooks.secret.is_none() {
+                problems.push("webhooks.secret: required when webhooks.url is set".to_string());
+            }
+        }
+        for prefix in &self.webhooks.allowed_urls {
+            match url::Url::parse(prefix) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => {}
+                _ => problems.push(format!(
+                    "webhooks.allowed_urls: {:?} is not an http(s) URL",
+                    scrub(prefix)
+                )),
+            }
+        }
+        if self
+            .webhooks
+            .secret
+            .as_ref()
+            .is_some_and(|secret| secret.expose().is_empty())
+        {
+            problems.push("webhooks.secret: must not be empty".to_string());
+        }
+        for (name, value) in [
+            ("max_attempts", self.webhooks.max_attempts as u64),
+            ("timeout_secs", self.webhooks.timeout_secs),
+        ] {
+            if value == 0 {
+                problems.push(format!("webhooks.{}: must be at least 1", name));
+            }
+        }
+
+        if self.models.is_empty() {
+            problems.push("models: at least one model is required".to_string());
+        }
+        let mut ids = HashSet::new();
+        for model in &self.models {
+            if model.id.trim().is_empty() {
+                problems.push("models: ids must not be empty".to_string());
+            } else if !ids.insert(&model.id) {
+                problems.push(format!("models: {:?} is listed twice", model.id));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ChatGptError::configuration(format!(
+                "invalid configuration:\n  - {}",
+                problems.join("\n  - ")
+            )))
+        }
+    }
+
+    pub fn listen_addr(&self) -> Result<SocketAddr> {
+        format!("{}:{}", self.server.host, self.server.port)
+            .parse()
+            .map_err(|err| ChatGptError::configuration(format!("invalid address: {}", err)))
+    }
+
+    /// Settings for the ChatGPT sessions threads open
+    pub fn client_config(&self) -> ClientConfig {
+        let secs = |secs: u64| Some(Duration::from_secs(secs));
+        ClientConfig {
+            base_url: self.upstream.base_url.trim_end_matches('/').to_string(),
+            connect_timeout: secs(self.upstream.connect_timeout_secs),
+            read_timeout: secs(self.upstream.read_timeout_secs),
+            request_timeout: secs(self.upstream.request_timeout_secs),
+            retry: RetryPolicy {
+                max_attempts: self.upstream.max_attempts,
+                ..RetryPolicy::default()
+            },
+            ..ClientConfig::default()
+        }
+    }
+
+    /// The effective config as TOML, with API keys and proxy credentials masked
+    pub fn to_toml(&self) -> String {
+        let mut shown = self.clone();
+        for key in &mut shown.auth.api_keys {
+            *key = REDACTED.to_string();
+        }
+        if let Some(proxy) = &mut shown.upstream.proxy {
+            *proxy = scrub(proxy).into_owned();
+        }
+        if let Some(secret) = &mut shown.webhooks.secret {
+            *secret = Secret::new(REDACTED.to_string());
+        }
+        toml::to_string_pretty(&shown).expect("config serializes to TOML")
+    }
+}
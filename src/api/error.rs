@@ -1,66 +1,203 @@
-use axum::http::StatusCode;
+use axum::extract::FromRequest;
+use axum::extract::rejection::JsonRejection;
+use axum::http::{StatusCode, header::RETRY_AFTER};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 
 use crate::utils::ChatGptError;
-use super::types::ErrorResponse;
+use super::types::{ErrorBody, ErrorResponse};
 
+/// Non-standard 499 "Client Closed Request", for turns the caller cancelled
+pub fn client_closed_request() -> StatusCode {
+    StatusCode::from_u16(499).expect("499 is a valid status code")
+}
+
+/// API error rendered as an OpenAI-style `{"error": {...}}` object
 #[derive(Debug)]
 pub struct ApiError {
     pub status: StatusCode,
+    /// Stable machine-readable code, e.g. `thread_busy` or `upstream_unavailable`
+    pub code: &'static str,
     pub message: String,
+    /// Request field the error refers to, if any
+    pub param: Option<String>,
+    /// Seconds the caller should wait before retrying, sent as `Retry-After`
+    pub retry_after: Option<u64>,
 }
 
 impl ApiError {
-    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
         Self {
             status,
+            code,
             message: message.into(),
+            param: None,
+            retry_after: None,
         }
     }
 
     pub fn bad_request(message: impl Into<String>) -> Self {
-        Self::new(StatusCode::BAD_REQUEST, message)
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request", message)
     }
 
     pub fn not_found(message: impl Into<String>) -> Self {
-        Self::new(StatusCode::NOT_FOUND, message)
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "invalid_api_key", message)
+    }
+
+    pub fn thread_busy(thread_id: &str) -> Self {
+        Self::new(
+            StatusCode::CONFLICT,
+            "thread_busy",
+            format!("Thread {} already has a response in progress", thread_id),
+        )
+        .with_param("thread_id")
     }
 
     pub fn internal_error(message: impl Into<String>) -> Self {
-        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", message)
+    }
+
+    pub fn with_param(mut self, param: impl Into<String>) -> Self {
+        self.param = Some(param.into());
+        self
+    }
+
+    /// OpenAI-style error type derived from the status code
+    pub fn error_type(&self) -> &'static str {
+        match self.status {
+            StatusCode::UNAUTHORIZED => "authentication_error",
+            StatusCode::FORBIDDEN => "permission_error",
+            StatusCode::NOT_FOUND => "not_found_error",
+            StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => {
+                "upstream_error"
+            }
+            status if status.is_client_error() => "invalid_request_error",
+            _ => "api_error",
+        }
+    }
+
+    /// The JSON body sent to clients (also used for SSE error events)
+    pub fn body(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: ErrorBody {
+                message: self.message.clone(),
+                error_type: self.error_type(),
+                code: self.code,
+                param: self.param.clone(),
+            },
+        }
     }
 }
 
 impl From<ChatGptError> for ApiError {
     fn from(err: ChatGptError) -> Self {
-        let status = match err {
-            ChatGptError::InvalidProxy(_) => StatusCode::BAD_REQUEST,
-            ChatGptError::Authentication(_) => StatusCode::UNAUTHORIZED,
-            ChatGptError::IpFlagged => StatusCode::FORBIDDEN,
-            ChatGptError::Network(_) => StatusCode::BAD_GATEWAY,
-            ChatGptError::Configuration(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            ChatGptError::ChallengeSolve(_)
-            | ChatGptError::VmExecution(_)
-            | ChatGptError::InvalidResponse(_)
-            | ChatGptError::Unknown(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            ChatGptError::Json(_)
+        let status = match &err {
+            ChatGptError::InvalidProxy(_)
+            | ChatGptError::InvalidRequest(_)
             | ChatGptError::Base64Decode(_)
-            | ChatGptError::Io(_)
-            | ChatGptError::Image(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            | ChatGptError::Image(_) => StatusCode::BAD_REQUEST,
+            ChatGptError::Network(err) if err.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            ChatGptError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ChatGptError::Upstream { status: 429, .. } => StatusCode::TOO_MANY_REQUESTS,
+            ChatGptError::Upstream { status: 503, .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ChatGptError::Upstream { status: 408 | 504, .. } => StatusCode::GATEWAY_TIMEOUT,
+            ChatGptError::Network(_)
+            | ChatGptError::Json(_)
+            | ChatGptError::InvalidResponse(_)
+            | ChatGptError::ChallengeSolve(_)
+            | ChatGptError::VmExecution(_)
+            | ChatGptError::IpFlagged
+            | ChatGptError::Authentication(_)
+            | ChatGptError::Upstream { .. } => StatusCode::BAD_GATEWAY,
+            // Asked for by the caller, so not an outage
+            ChatGptError::Cancelled => client_closed_request(),
+            ChatGptError::Io(_) | ChatGptError::Configuration(_) | ChatGptError::Unknown(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
         };
 
-        Self::new(status, err.to_string())
+        let param = match &err {
+            ChatGptError::InvalidProxy(_) => Some("proxy".to_string()),
+            _ => None,
+        };
+
+        let retry_after = match &err {
+            ChatGptError::Upstream { retry_after, .. } => *retry_after,
+            _ => None,
+        };
+
+        Self {
+            status,
+            code: err.code(),
+            message: err.to_string(),
+            param,
+            retry_after,
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::new(rejection.status(), "invalid_request", rejection.body_text())
     }
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
-        let payload = Json(ErrorResponse {
-            status: "error",
-            detail: self.message,
-        });
+        let payload = Json(self.body());
+
+        match self.retry_after {
+            Some(seconds) => (self.status, [(RETRY_AFTER, seconds.to_string())], payload).into_response(),
+            None => (self.status, payload).into_response(),
+        }
+    }
+}
+
+/// `Json` extractor whose rejections use the API error format
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(status: u16) -> ChatGptError {
+        ChatGptError::Upstream {
+            status,
+            endpoint: "conversation".to_string(),
+            body_excerpt: String::new(),
+            retry_after: None,
+        }
+    }
+
+    #[test]
+    fn maps_errors_to_statuses() {
+        let cases = [
+            (upstream(429), 429, "rate_limited"),
+            (upstream(503), 503, "upstream_unavailable"),
+            (upstream(504), 504, "upstream_unavailable"),
+            (upstream(403), 502, "upstream_rejected"),
+            (ChatGptError::timeout("read"), 504, "upstream_timeout"),
+            (ChatGptError::invalid_proxy("nope"), 400, "invalid_proxy"),
+            (ChatGptError::configuration("bad"), 500, "configuration_error"),
+        ];
+        for (err, status, code) in cases {
+            let api = ApiError::from(err);
+            assert_eq!((api.status.as_u16(), api.code), (status, code));
+        }
+    }
 
-        (self.status, payload).into_response()
+    #[test]
+    fn cancelled_is_not_an_outage() {
+        let err = ApiError::from(ChatGptError::Cancelled);
+        assert_eq!(err.status.as_u16(), 499);
+        assert_eq!(err.code, "cancelled");
+        assert_ne!(err.error_type(), "upstream_error");
     }
 }
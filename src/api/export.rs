@@ -0,0 +1,377 @@
+//! Thread export and import.
+//!
+//! `GET /v1/threads/{id}/export` renders a thread as JSON, Markdown or HTML.
+//! The JSON form is a [`ThreadExport`], which `POST /v1/threads/import` takes
+//! back, so a thread can be archived and restored with its roles,
+//! timestamps, metadata and attachment references.
+
+use std::fmt::Write as _;
+use std::str::FromStr;
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    response::{IntoResponse, Response as AxumResponse},
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::error::{ApiError, ApiJson};
+use super::handlers::thread_object;
+use super::state::{AppState, ThreadState};
+use super::types::{Attachment, ThreadMessage};
+use crate::backend::ChatBackend;
+
+/// `object` of an exported thread
+pub const EXPORT_OBJECT: &str = "thread.export";
+
+/// Newest export format this server reads and writes
+pub const EXPORT_VERSION: u32 = 1;
+
+/// A thread as exported, and as accepted by the import endpoint
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThreadExport {
+    #[serde(default = "export_object")]
+    pub object: String,
+    #[serde(default = "export_version")]
+    pub version: u32,
+    /// ID the thread had on the exporting server; imports get a new one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// When the thread was created; the time of import if left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exported_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    pub messages: Vec<ThreadMessage>,
+}
+
+fn export_object() -> String {
+    EXPORT_OBJECT.to_string()
+}
+
+fn export_version() -> u32 {
+    EXPORT_VERSION
+}
+
+impl ThreadExport {
+    pub fn from_thread<B>(thread_id: &str, thread: &ThreadState<B>) -> Self {
+        Self {
+            object: export_object(),
+            version: EXPORT_VERSION,
+            id: Some(thread_id.to_string()),
+            created_at: Some(thread.created_at),
+            exported_at: Some(now()),
+            metadata: thread.metadata.clone(),
+            messages: thread.messages.clone(),
+        }
+    }
+
+    /// `metadata.title` if it is a string, else a name made from the ID
+    pub fn title(&self) -> String {
+        match self.metadata.as_ref().and_then(|m| m["title"].as_str()) {
+            Some(title) if !title.trim().is_empty() => title.to_string(),
+            _ => format!("Thread {}", self.id.as_deref().unwrap_or("export")),
+        }
+    }
+}
+
+/// What `GET /v1/threads/{id}/export` renders
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Markdown,
+    Html,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "markdown" | "md" => Ok(Self::Markdown),
+            "html" => Ok(Self::Html),
+            _ => Err(format!(
+                "Unknown export format {:?}; use json, markdown or html",
+                s
+            )),
+        }
+    }
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Markdown => "text/markdown; charset=utf-8",
+            Self::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Markdown => "md",
+            Self::Html => "html",
+        }
+    }
+
+    pub fn render(self, export: &ThreadExport) -> String {
+        match self {
+            Self::Json => serde_json::to_string_pretty(export).expect("export serializes"),
+            Self::Markdown => to_markdown(export),
+            Self::Html => to_html(export),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+/// Export a thread as a download
+pub async fn export_thread<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    Path(thread_id): Path<String>,
+    Query(query): Query<ExportQuery>,
+) -> Result<AxumResponse, ApiError> {
+    let format = match query.format.as_deref() {
+        None => ExportFormat::Json,
+        Some(format) => format
+            .parse::<ExportFormat>()
+            .map_err(|err| ApiError::bad_request(err).with_param("format"))?,
+    };
+    let thread = state.get_thread(&thread_id).await?;
+    let export = ThreadExport::from_thread(&thread_id, &thread);
+
+    let disposition = format!(
+        "attachment; filename=\"thread-{}.{}\"",
+        thread_id,
+        format.extension()
+    );
+    Ok((
+        [
+            (CONTENT_TYPE, format.content_type().to_string()),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        format.render(&export),
+    )
+        .into_response())
+}
+
+/// Create a thread from an exported one
+pub async fn import_thread<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    ApiJson(export): ApiJson<ThreadExport>,
+) -> Result<AxumResponse, ApiError> {
+    if export.object != EXPORT_OBJECT {
+        return Err(ApiError::bad_request(format!(
+            "Expected an object of type {:?}, got {:?}",
+            EXPORT_OBJECT, export.object
+        ))
+        .with_param("object"));
+    }
+    if export.version > EXPORT_VERSION {
+        return Err(ApiError::bad_request(format!(
+            "Export version {} is newer than this server reads ({})",
+            export.version, EXPORT_VERSION
+        ))
+        .with_param("version"));
+    }
+
+    let created_at = export.created_at.unwrap_or_else(now);
+    let (thread_id, thread) = state
+        .import_thread(created_at, export.metadata, export.messages)
+        .await?;
+    if let Some(original) = &export.id {
+        info!("Thread {} imported as {}", original, thread_id);
+    }
+
+    Ok(Json(thread_object(thread_id, &thread)).into_response())
+}
+
+/// The thread as a Markdown document
+pub fn to_markdown(export: &ThreadExport) -> String {
+    let mut out = format!("# {}\n\n", export.title());
+    if let Some(created_at) = export.created_at {
+        let _ = writeln!(out, "- Created: {}", timestamp(created_at));
+    }
+    if let Some(id) = &export.id {
+        let _ = writeln!(out, "- Thread: `{}`", id);
+    }
+    if let Some(metadata) = &export.metadata {
+        let _ = writeln!(out, "- Metadata: `{}`", metadata);
+    }
+
+    for message in &export.messages {
+        let _ = write!(out, "\n---\n\n### {}", role_name(&message.role));
+        if let Some(created_at) = message.created_at {
+            let _ = write!(out, " · {}", timestamp(created_at));
+        }
+        if let Some(status) = &message.status {
+            let _ = write!(out, " ({})", status);
+        }
+        let _ = write!(out, "\n\n{}\n", message.content.trim_end());
+
+        if !message.attachments.is_empty() {
+            out.push_str("\nAttachments:\n\n");
+            for attachment in &message.attachments {
+                let name = attachment_name(attachment);
+                match &attachment.url {
+                    Some(url) => {
+                        let _ = write!(out, "- [{}]({})", name, url);
+                    }
+                    None => {
+                        let _ = write!(out, "- {}", name);
+                    }
+                }
+                if let Some(mime_type) = &attachment.mime_type {
+                    let _ = write!(out, " ({})", mime_type);
+                }
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+/// The thread as a standalone HTML page
+pub fn to_html(export: &ThreadExport) -> String {
+    let title = escape(&export.title());
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>\n{STYLE}</style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+    );
+
+    out.push_str("<p class=\"meta\">");
+    if let Some(created_at) = export.created_at {
+        let _ = write!(out, "Created {}", timestamp(created_at));
+    }
+    if let Some(id) = &export.id {
+        let _ = write!(out, " · <code>{}</code>", escape(id));
+    }
+    out.push_str("</p>\n");
+    if let Some(metadata) = &export.metadata {
+        let pretty = serde_json::to_string_pretty(metadata).unwrap_or_default();
+        let _ = writeln!(out, "<pre class=\"metadata\">{}</pre>", escape(&pretty));
+    }
+
+    for message in &export.messages {
+        let _ = write!(
+            out,
+            "<article class=\"message {}\">\n<header>{}",
+            escape(&message.role),
+            escape(&role_name(&message.role))
+        );
+        if let Some(created_at) = message.created_at {
+            let _ = write!(out, " · {}", timestamp(created_at));
+        }
+        if let Some(status) = &message.status {
+            let _ = write!(out, " ({})", escape(status));
+        }
+        let _ = writeln!(
+            out,
+            "</header>\n<div class=\"content\">{}</div>",
+            escape(message.content.trim_end())
+        );
+
+        if !message.attachments.is_empty() {
+            out.push_str("<ul class=\"attachments\">\n");
+            for attachment in &message.attachments {
+                let name = escape(&attachment_name(attachment));
+                out.push_str("<li>");
+                match &attachment.url {
+                    Some(url) if linkable(url) => {
+                        let _ = write!(out, "<a href=\"{}\">{}</a>", escape(url), name);
+                    }
+                    Some(url) => {
+                        let _ = write!(out, "{} <code>{}</code>", name, escape(url));
+                    }
+                    None => out.push_str(&name),
+                }
+                if let Some(mime_type) = &attachment.mime_type {
+                    let _ = write!(out, " ({})", escape(mime_type));
+                }
+                out.push_str("</li>\n");
+            }
+            out.push_str("</ul>\n");
+        }
+        out.push_str("</article>\n");
+    }
+
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+const STYLE: &str = "\
+body { font-family: system-ui, sans-serif; max-width: 48rem; margin: 2rem auto; padding: 0 1rem; color: #222; }
+.meta { color: #666; }
+.metadata { background: #f6f6f6; padding: 0.5rem; overflow-x: auto; }
+.message { border-top: 1px solid #ddd; padding: 0.75rem 0; }
+.message header { font-weight: 600; color: #555; margin-bottom: 0.5rem; }
+.message.user header { color: #1a5fb4; }
+.message.assistant header { color: #26a269; }
+.content { white-space: pre-wrap; }
+";
+
+fn role_name(role: &str) -> String {
+    let mut chars = role.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => "Unknown".to_string(),
+    }
+}
+
+fn attachment_name(attachment: &Attachment) -> String {
+    attachment
+        .name
+        .clone()
+        .or_else(|| attachment.id.clone())
+        .unwrap_or_else(|| "attachment".to_string())
+}
+
+fn timestamp(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .format("%Y-%m-%d %H:%M:%S UTC")
+        .to_string()
+}
+
+/// Whether `url` is safe to use as a link target; imported archives can carry
+/// anything, including `javascript:` URLs
+fn linkable(url: &str) -> bool {
+    let url = url.trim_start_matches(|c: char| c.is_whitespace() || c.is_control());
+    match url.split_once(':') {
+        Some((scheme, _)) => ["http", "https", "data"]
+            .iter()
+            .any(|allowed| scheme.eq_ignore_ascii_case(allowed)),
+        None => false,
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub(super) fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,474 @@
+//! gRPC service of `proto/chatgpt.proto`, built with the `grpc` feature.
+//!
+//! The service shares the port and the [`AppState`] of the HTTP API (see
+//! [`GrpcMode`](super::config::GrpcMode)), so threads are the same on both
+//! and `Generate` takes the same generation path as `/v1/responses`.
+
+use axum::Router;
+use axum::http::StatusCode;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Request, Response, Status};
+use tracing::{Instrument, info};
+
+use super::error::{ApiError, client_closed_request};
+use super::handlers::{message_object, message_objects, prepare_generation, thread_object};
+use super::idempotency::CompletedResponse;
+use super::state::AppState;
+use super::types::{self, ThreadMessage};
+use crate::backend::ChatBackend;
+use proto::chat_gpt_server::{ChatGpt, ChatGptServer};
+use proto::generate_event::Event;
+
+/// Messages and service stubs of the `chatgpt.v1` package.
+///
+/// The messages mirror `proto/chatgpt.proto` field for field; the client and
+/// server modules are generated by `build.rs`.
+pub mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Thread {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(uint64, tag = "2")]
+        pub created_at: u64,
+        /// Thread metadata as JSON text, empty when there is none
+        #[prost(string, tag = "3")]
+        pub metadata_json: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Message {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(string, tag = "2")]
+        pub thread_id: String,
+        #[prost(string, tag = "3")]
+        pub role: String,
+        #[prost(string, tag = "4")]
+        pub content: String,
+        #[prost(uint64, tag = "5")]
+        pub created_at: u64,
+        /// `incomplete` when generation stopped early, otherwise empty
+        #[prost(string, tag = "6")]
+        pub status: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct NewMessage {
+        #[prost(string, tag = "1")]
+        pub role: String,
+        #[prost(string, tag = "2")]
+        pub content: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CreateThreadRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub messages: Vec<NewMessage>,
+        #[prost(string, tag = "2")]
+        pub metadata_json: String,
+        #[prost(string, optional, tag = "3")]
+        pub proxy: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetThreadRequest {
+        #[prost(string, tag = "1")]
+        pub thread_id: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListThreadsRequest {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListThreadsResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub threads: Vec<Thread>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DeleteThreadRequest {
+        #[prost(string, tag = "1")]
+        pub thread_id: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DeleteThreadResponse {
+        #[prost(string, tag = "1")]
+        pub thread_id: String,
+        #[prost(bool, tag = "2")]
+        pub deleted: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AddMessageRequest {
+        #[prost(string, tag = "1")]
+        pub thread_id: String,
+        #[prost(string, tag = "2")]
+        pub role: String,
+        #[prost(string, tag = "3")]
+        pub content: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListMessagesRequest {
+        #[prost(string, tag = "1")]
+        pub thread_id: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ListMessagesResponse {
+        #[prost(message, repeated, tag = "1")]
+        pub messages: Vec<Message>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GenerateRequest {
+        #[prost(string, tag = "1")]
+        pub thread_id: String,
+        /// User message to add before generating
+        #[prost(string, optional, tag = "2")]
+        pub content: Option<String>,
+        #[prost(string, optional, tag = "3")]
+        pub model: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Response {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(string, tag = "2")]
+        pub thread_id: String,
+        #[prost(uint64, tag = "3")]
+        pub created_at: u64,
+        #[prost(string, tag = "4")]
+        pub model: String,
+        /// `in_progress`, `completed` or `cancelled`
+        #[prost(string, tag = "5")]
+        pub status: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GenerateEvent {
+        #[prost(oneof = "generate_event::Event", tags = "1, 2, 3")]
+        pub event: Option<generate_event::Event>,
+    }
+
+    pub mod generate_event {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Event {
+            #[prost(message, tag = "1")]
+            Created(super::Response),
+            #[prost(string, tag = "2")]
+            Delta(String),
+            #[prost(message, tag = "3")]
+            Completed(super::Response),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CancelResponseRequest {
+        #[prost(string, tag = "1")]
+        pub response_id: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CancelResponseResponse {
+        #[prost(string, tag = "1")]
+        pub response_id: String,
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/chatgpt.v1.ChatGpt.rs"));
+}
+
+/// Path prefix of the service's RPCs
+pub const PREFIX: &str = "/chatgpt.v1.ChatGpt/";
+
+/// The gRPC service as routes to merge into the HTTP router
+pub fn routes<B: ChatBackend, S: Clone + Send + Sync + 'static>(state: AppState<B>) -> Router<S> {
+    let path = format!("{}{{*method}}", PREFIX);
+    Router::new().route_service(&path, ChatGptServer::new(Service { state }))
+}
+
+/// Status codes by HTTP status. The API's own error code is passed along in
+/// the `error-code` metadata entry.
+impl From<ApiError> for Status {
+    fn from(err: ApiError) -> Self {
+        let code = match err.status {
+            StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE => Code::InvalidArgument,
+            StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+            StatusCode::FORBIDDEN => Code::PermissionDenied,
+            StatusCode::NOT_FOUND => Code::NotFound,
+            StatusCode::CONFLICT => Code::Aborted,
+            StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+            StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+            status if status == client_closed_request() => Code::Cancelled,
+            _ => Code::Internal,
+        };
+        let mut status = Status::new(code, err.message);
+        status
+            .metadata_mut()
+            .insert("error-code", MetadataValue::from_static(err.code));
+        status
+    }
+}
+
+fn thread(thread: types::Thread) -> proto::Thread {
+    proto::Thread {
+        id: thread.id,
+        created_at: thread.created_at,
+        metadata_json: thread
+            .metadata
+            .map(|metadata| metadata.to_string())
+            .unwrap_or_default(),
+    }
+}
+
+fn message(message: types::Message) -> proto::Message {
+    proto::Message {
+        id: message.id,
+        thread_id: message.thread_id,
+        role: message.role,
+        content: message
+            .content
+            .into_iter()
+            .map(|part| part.text.value)
+            .collect(),
+        created_at: message.created_at,
+        status: message.status.unwrap_or_default(),
+    }
+}
+
+fn response(completed: &CompletedResponse) -> proto::Response {
+    proto::Response {
+        id: completed.id.clone(),
+        thread_id: completed.thread_id.clone(),
+        created_at: completed.created_at,
+        model: completed.model.clone(),
+        status: completed.status.clone(),
+    }
+}
+
+fn event(event: Event) -> proto::GenerateEvent {
+    proto::GenerateEvent { event: Some(event) }
+}
+
+struct Service<B: ChatBackend> {
+    state: AppState<B>,
+}
+
+#[tonic::async_trait]
+impl<B: ChatBackend> ChatGpt for Service<B> {
+    async fn create_thread(
+        &self,
+        request: Request<proto::CreateThreadRequest>,
+    ) -> Result<Response<proto::Thread>, Status> {
+        let request = request.into_inner();
+        let metadata = match request.metadata_json.trim() {
+            "" => None,
+            json => Some(serde_json::from_str(json).map_err(|err| {
+                ApiError::bad_request(format!("metadata_json is not valid JSON: {}", err))
+                    .with_param("metadata_json")
+            })?),
+        };
+        let messages = request
+            .messages
+            .into_iter()
+            .map(|message| ThreadMessage {
+                role: message.role,
+                content: message.content,
+                created_at: None,
+                status: None,
+                attachments: Vec::new(),
+            })
+            .collect();
+
+        let (thread_id, state) = self
+            .state
+            .create_thread(messages, metadata, request.proxy.as_deref())
+            .await?;
+        Ok(Response::new(thread(thread_object(thread_id, &state))))
+    }
+
+    async fn get_thread(
+        &self,
+        request: Request<proto::GetThreadRequest>,
+    ) -> Result<Response<proto::Thread>, Status> {
+        let thread_id = request.into_inner().thread_id;
+        let state = self.state.get_thread(&thread_id).await?;
+        Ok(Response::new(thread(thread_object(thread_id, &state))))
+    }
+
+    async fn list_threads(
+        &self,
+        _request: Request<proto::ListThreadsRequest>,
+    ) -> Result<Response<proto::ListThreadsResponse>, Status> {
+        let threads = self
+            .state
+            .list_threads()
+            .await
+            .into_iter()
+            .map(|(id, state)| thread(thread_object(id, &state)))
+            .collect();
+        Ok(Response::new(proto::ListThreadsResponse { threads }))
+    }
+
+    async fn delete_thread(
+        &self,
+        request: Request<proto::DeleteThreadRequest>,
+    ) -> Result<Response<proto::DeleteThreadResponse>, Status> {
+        let thread_id = request.into_inner().thread_id;
+        self.state.delete_thread(&thread_id).await?;
+        Ok(Response::new(proto::DeleteThreadResponse {
+            thread_id,
+            deleted: true,
+        }))
+    }
+
+    async fn add_message(
+        &self,
+        request: Request<proto::AddMessageRequest>,
+    ) -> Result<Response<proto::Message>, Status> {
+        let request = request.into_inner();
+        if request.content.trim().is_empty() {
+            return Err(ApiError::bad_request("Message content cannot be empty")
+                .with_param("content")
+                .into());
+        }
+
+        let added = ThreadMessage {
+            role: request.role,
+            content: request.content,
+            created_at: None,
+            status: None,
+            attachments: Vec::new(),
+        };
+        let index = self
+            .state
+            .add_message_to_thread(
+                &request.thread_id,
+                added.role.clone(),
+                added.content.clone(),
+            )
+            .await?;
+        let created_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Ok(Response::new(message(message_object(
+            &request.thread_id,
+            index,
+            &added,
+            created_at,
+        ))))
+    }
+
+    async fn list_messages(
+        &self,
+        request: Request<proto::ListMessagesRequest>,
+    ) -> Result<Response<proto::ListMessagesResponse>, Status> {
+        let thread_id = request.into_inner().thread_id;
+        let state = self.state.get_thread(&thread_id).await?;
+        let messages = message_objects(&thread_id, &state)
+            .into_iter()
+            .map(message)
+            .collect();
+        Ok(Response::new(proto::ListMessagesResponse { messages }))
+    }
+
+    type GenerateStream = ReceiverStream<Result<proto::GenerateEvent, Status>>;
+
+    async fn generate(
+        &self,
+        request: Request<proto::GenerateRequest>,
+    ) -> Result<Response<Self::GenerateStream>, Status> {
+        let request = request.into_inner();
+        let state = self.state.clone();
+        state.check_accepting()?;
+        let thread_id = request.thread_id;
+
+        // Claim the thread before adding the message, so a busy thread is left as it was
+        let run_guard = state
+            .get_thread(&thread_id)
+            .await?
+            .try_begin_run(&thread_id)?;
+        if let Some(content) = request.content {
+            if content.trim().is_empty() {
+                return Err(ApiError::bad_request("Message content cannot be empty")
+                    .with_param("content")
+                    .into());
+            }
+            state
+                .add_message_to_thread(&thread_id, "user".to_string(), content)
+                .await?;
+        }
+        let generation = prepare_generation(
+            state.clone(),
+            thread_id,
+            run_guard,
+            request.model,
+            None,
+        )
+        .await?;
+
+        info!("Generating response {} over gRPC", generation.response_id);
+        let created = proto::Response {
+            id: generation.response_id.clone(),
+            thread_id: generation.thread_id.clone(),
+            created_at: generation.created_at,
+            model: generation.model.clone(),
+            status: "in_progress".to_string(),
+        };
+        let cancel = generation.cancel.clone();
+
+        let (tx, rx) = mpsc::channel(100);
+        let _ = tx.send(Ok(event(Event::Created(created)))).await;
+
+        // Spawn a task that relays deltas from upstream as they arrive
+        tokio::spawn(
+            async move {
+                let (delta_tx, mut delta_rx) = mpsc::unbounded_channel();
+                let task = state.spawn_generation(generation.run(Some(delta_tx)));
+
+                loop {
+                    let delta = tokio::select! {
+                        delta = delta_rx.recv() => delta,
+                        // The client closed the stream, so stop the upstream request as well
+                        _ = tx.closed() => {
+                            cancel.cancel();
+                            break;
+                        }
+                    };
+                    let Some(delta) = delta else { break };
+                    let bytes = delta.len();
+                    if tx.send(Ok(event(Event::Delta(delta)))).await.is_err() {
+                        cancel.cancel();
+                        break;
+                    }
+                    state.metrics().add_streamed_bytes(bytes);
+                }
+
+                let last = match task.await {
+                    Ok(Ok(completed)) => Ok(event(Event::Completed(response(&completed)))),
+                    Ok(Err(err)) => Err(Status::from(err)),
+                    Err(err) => Err(Status::internal(format!("Response task failed: {}", err))),
+                };
+                let _ = tx.send(last).await;
+            }
+            .in_current_span(),
+        );
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn cancel_response(
+        &self,
+        request: Request<proto::CancelResponseRequest>,
+    ) -> Result<Response<proto::CancelResponseResponse>, Status> {
+        let response_id = request.into_inner().response_id;
+        self.state.cancel_response(&response_id).await?;
+        Ok(Response::new(proto::CancelResponseResponse { response_id }))
+    }
+}
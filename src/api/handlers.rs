@@ -1,20 +1,30 @@
 use axum::{
     extract::State,
+    http::{HeaderMap, HeaderName, StatusCode},
     response::{IntoResponse, Response as AxumResponse, Sse, sse::Event},
     Json,
 };
 use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{OwnedMutexGuard, RwLock, mpsc};
 use tokio_stream::wrappers::ReceiverStream;
-use tracing::{error, info};
-
-use super::error::ApiError;
-use super::state::AppState;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info};
+
+use crate::backend::{ChatBackend, DeltaSender};
+use crate::client::ConversationPosition;
+use crate::utils::ChatGptError;
+use super::error::{ApiError, ApiJson};
+use super::idempotency::{Claim, CompletedResponse, IdempotencyGuard};
+use super::state::{AppState, ThreadState};
 use super::types::*;
+use super::webhooks;
 
 /// Create a new thread
-pub async fn create_thread(
-    State(state): State<AppState>,
-    Json(payload): Json<CreateThreadRequest>,
+pub async fn create_thread<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    ApiJson(payload): ApiJson<CreateThreadRequest>,
 ) -> std::result::Result<AxumResponse, ApiError> {
     info!("Creating new thread with {} initial messages", payload.messages.len());
 
@@ -26,47 +36,28 @@ pub async fn create_thread(
         )
         .await?;
 
-    let response = Thread {
-        id: thread_id,
-        object: "thread".to_string(),
-        created_at: thread_state.created_at,
-        metadata: thread_state.metadata,
-    };
-
-    Ok(Json(response).into_response())
+    Ok(Json(thread_object(thread_id, &thread_state)).into_response())
 }
 
 /// Get a thread by ID
-pub async fn get_thread(
-    State(state): State<AppState>,
+pub async fn get_thread<B: ChatBackend>(
+    State(state): State<AppState<B>>,
     axum::extract::Path(thread_id): axum::extract::Path<String>,
 ) -> std::result::Result<AxumResponse, ApiError> {
     let thread_state = state.get_thread(&thread_id).await?;
 
-    let response = Thread {
-        id: thread_id,
-        object: "thread".to_string(),
-        created_at: thread_state.created_at,
-        metadata: thread_state.metadata,
-    };
-
-    Ok(Json(response).into_response())
+    Ok(Json(thread_object(thread_id, &thread_state)).into_response())
 }
 
 /// List all threads
-pub async fn list_threads(
-    State(state): State<AppState>,
+pub async fn list_threads<B: ChatBackend>(
+    State(state): State<AppState<B>>,
 ) -> std::result::Result<AxumResponse, ApiError> {
     let threads = state.list_threads().await;
 
     let data: Vec<Thread> = threads
         .into_iter()
-        .map(|(id, state)| Thread {
-            id,
-            object: "thread".to_string(),
-            created_at: state.created_at,
-            metadata: state.metadata,
-        })
+        .map(|(id, state)| thread_object(id, &state))
         .collect();
 
     let response = ListThreadsResponse {
@@ -79,8 +70,8 @@ pub async fn list_threads(
 }
 
 /// Delete a thread
-pub async fn delete_thread(
-    State(state): State<AppState>,
+pub async fn delete_thread<B: ChatBackend>(
+    State(state): State<AppState<B>>,
     axum::extract::Path(thread_id): axum::extract::Path<String>,
 ) -> std::result::Result<AxumResponse, ApiError> {
     state.delete_thread(&thread_id).await?;
@@ -94,10 +85,10 @@ pub async fn delete_thread(
 }
 
 /// Add a message to a thread
-pub async fn add_message(
-    State(state): State<AppState>,
+pub async fn add_message<B: ChatBackend>(
+    State(state): State<AppState<B>>,
     axum::extract::Path(thread_id): axum::extract::Path<String>,
-    Json(payload): Json<AddMessageRequest>,
+    ApiJson(payload): ApiJson<AddMessageRequest>,
 ) -> std::result::Result<AxumResponse, ApiError> {
     if payload.content.trim().is_empty() {
         return Err(ApiError::bad_request("Message content cannot be empty"));
@@ -126,41 +117,22 @@ pub async fn add_message(
                 annotations: vec![],
             },
         }],
+        status: None,
     };
 
     Ok(Json(response).into_response())
 }
 
 /// List messages in a thread
-pub async fn list_messages(
-    State(state): State<AppState>,
+pub async fn list_messages<B: ChatBackend>(
+    State(state): State<AppState<B>>,
     axum::extract::Path(thread_id): axum::extract::Path<String>,
 ) -> std::result::Result<AxumResponse, ApiError> {
     let thread_state = state.get_thread(&thread_id).await?;
 
-    let data: Vec<Message> = thread_state
-        .get_messages()
-        .iter()
-        .enumerate()
-        .map(|(idx, msg)| Message {
-            id: format!("msg_{}_{}", thread_id, idx),
-            object: "thread.message".to_string(),
-            created_at: msg.created_at.unwrap_or(thread_state.created_at),
-            thread_id: thread_id.clone(),
-            role: msg.role.clone(),
-            content: vec![ContentPart {
-                content_type: "text".to_string(),
-                text: TextContent {
-                    value: msg.content.clone(),
-                    annotations: vec![],
-                },
-            }],
-        })
-        .collect();
-
     let response = ListMessagesResponse {
         object: "list".to_string(),
-        data,
+        data: message_objects(&thread_id, &thread_state),
         has_more: false,
     };
 
@@ -168,14 +140,74 @@ pub async fn list_messages(
 }
 
 /// Create a response (run the assistant)
-pub async fn create_response(
-    State(state): State<AppState>,
-    Json(payload): Json<CreateResponseRequest>,
+pub async fn create_response<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    headers: HeaderMap,
+    ApiJson(payload): ApiJson<CreateResponseRequest>,
 ) -> std::result::Result<AxumResponse, ApiError> {
+    state.check_accepting()?;
     let thread_id = payload.thread_id.clone();
     
     info!("Creating response for thread: {}, stream: {}", thread_id, payload.stream);
+    let webhook = state.webhooks().resolve(payload.webhook_url)?;
+
+    // Retries carrying the same Idempotency-Key get the original response
+    let mut idempotency = None;
+    if let Some(key) = headers.get("idempotency-key").and_then(|v| v.to_str().ok()) {
+        // Everything but `stream`, which only changes how the answer is sent
+        let request = serde_json::json!({
+            "thread_id": payload.thread_id,
+            "model": payload.model,
+            "instructions": payload.instructions,
+            "proxy": payload.proxy,
+            "webhook_url": webhook,
+        })
+        .to_string();
+        loop {
+            match state.idempotency().claim(key, &request)? {
+                Claim::New(guard) => {
+                    idempotency = Some(guard);
+                    break;
+                }
+                Claim::Existing(mut result) => {
+                    // If the original request fails the key is released and claimed again
+                    if let Ok(Some(completed)) = result.wait_for(Option::is_some).await.map(|r| r.clone()) {
+                        info!("Replaying response {} for Idempotency-Key {}", completed.id, key);
+                        return Ok(replay_response(completed, payload.stream));
+                    }
+                }
+            }
+        }
+    }
+
+    // Claim the thread first, then read its messages so the snapshot is current
+    let run_guard = state.get_thread(&thread_id).await?.try_begin_run(&thread_id)?;
+    let generation = prepare_generation(
+        state,
+        thread_id,
+        run_guard,
+        payload.model,
+        idempotency,
+    )
+    .await?
+    .with_webhook(webhook);
+
+    if payload.stream {
+        handle_stream_response(generation).await
+    } else {
+        handle_non_stream_response(generation).await
+    }
+}
 
+/// Set up a turn on a thread claimed with `run_guard`, answering its last
+/// user message
+pub(super) async fn prepare_generation<B: ChatBackend>(
+    state: AppState<B>,
+    thread_id: String,
+    run_guard: OwnedMutexGuard<()>,
+    model: Option<String>,
+    idempotency: Option<IdempotencyGuard>,
+) -> std::result::Result<Generation<B>, ApiError> {
     let thread_state = state.get_thread(&thread_id).await?;
 
     // Get the last user message
@@ -191,127 +223,324 @@ pub async fn create_response(
         return Err(ApiError::bad_request("Last user message content is empty"));
     }
 
+    let model = resolve_model(&state, model)?;
     let is_new = thread_state.is_new();
     let client_arc = thread_state.client.clone();
+    let proxy = thread_state.proxy.clone();
+    let conversation = thread_state.conversation.clone();
+    let position = thread_state.get_messages().len();
 
-    if payload.stream {
-        handle_stream_response(state, thread_state, client_arc, &message_content, is_new, thread_id).await
-    } else {
-        handle_non_stream_response(state, thread_state, client_arc, &message_content, is_new, thread_id).await
+    let response_id = uuid::Uuid::new_v4().to_string();
+    let cancel = state.begin_response(&response_id).await;
+    let webhook = state.webhooks().resolve(None)?;
+    let created_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    Ok(Generation {
+        state,
+        client_arc,
+        message: message_content,
+        model,
+        is_new,
+        proxy,
+        conversation,
+        thread_id,
+        position,
+        response_id,
+        created_at,
+        cancel,
+        idempotency,
+        webhook,
+        _run_guard: run_guard,
+    })
+}
+
+/// The requested model, or the first configured one, if the server serves it
+pub(super) fn resolve_model<B: ChatBackend>(
+    state: &AppState<B>,
+    requested: Option<String>,
+) -> std::result::Result<String, ApiError> {
+    let models = state.models();
+    match requested {
+        None => Ok(models
+            .first()
+            .map(|model| model.id.clone())
+            .unwrap_or_default()),
+        Some(model) if models.iter().any(|m| m.id == model) => Ok(model),
+        Some(model) => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "model_not_found",
+            format!("The model `{}` does not exist", model),
+        )
+        .with_param("model")),
     }
 }
 
-async fn handle_non_stream_response(
-    state: AppState,
-    mut thread_state: super::state::ThreadState,
-    client_arc: std::sync::Arc<tokio::sync::RwLock<crate::client::ChatGptClient>>,
-    message: &str,
-    is_new: bool,
-    thread_id: String,
+/// Delivery log of a response's webhook
+pub async fn get_webhook<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    axum::extract::Path(response_id): axum::extract::Path<String>,
 ) -> std::result::Result<AxumResponse, ApiError> {
-    let mut client = client_arc.write().await;
+    let log = state.webhooks().log(&response_id).ok_or_else(|| {
+        ApiError::not_found(format!("Response {} has no webhook deliveries", response_id))
+    })?;
 
-    let answer = if is_new {
-        client.start_conversation(message).await.map_err(|err| {
-            error!("ChatGPT start_conversation failed: {:?}", err);
-            ApiError::from(err)
-        })?
-    } else {
-        client.hold_conversation(message, false).await.map_err(|err| {
-            error!("ChatGPT hold_conversation failed: {:?}", err);
-            ApiError::from(err)
-        })?
-    };
-
-    drop(client);
+    Ok(Json(log).into_response())
+}
 
-    // Add assistant's response to thread
-    thread_state.add_message("assistant".to_string(), answer.clone());
-    state.update_thread(&thread_id, thread_state).await?;
+/// Send a response's webhook event again
+pub async fn redeliver_webhook<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    axum::extract::Path(response_id): axum::extract::Path<String>,
+) -> std::result::Result<AxumResponse, ApiError> {
+    let log = state.webhooks().redeliver(&response_id)?;
+    info!("Redelivering webhook for response {}", response_id);
 
-    let response_id = uuid::Uuid::new_v4().to_string();
-    let created_at = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
+    Ok((StatusCode::ACCEPTED, Json(log)).into_response())
+}
 
-    let response = Response {
-        id: response_id,
-        object: "thread.response".to_string(),
-        created_at,
-        thread_id,
-        status: "completed".to_string(),
-        model: "gpt-4".to_string(),
-        usage: Some(Usage {
-            prompt_tokens: 0,
-            completion_tokens: 0,
-            total_tokens: 0,
-        }),
-    };
+/// Cancel a response that is still generating
+pub async fn cancel_response<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    axum::extract::Path(response_id): axum::extract::Path<String>,
+) -> std::result::Result<AxumResponse, ApiError> {
+    state.cancel_response(&response_id).await?;
 
-    Ok(Json(response).into_response())
+    Ok(Json(serde_json::json!({
+        "id": response_id,
+        "object": "thread.response",
+        "status": "cancelling"
+    }))
+    .into_response())
 }
 
-async fn handle_stream_response(
-    state: AppState,
-    mut thread_state: super::state::ThreadState,
-    client_arc: std::sync::Arc<tokio::sync::RwLock<crate::client::ChatGptClient>>,
-    message: &str,
+/// One assistant turn on a thread
+pub(super) struct Generation<B: ChatBackend> {
+    state: AppState<B>,
+    client_arc: Arc<RwLock<Option<B>>>,
+    message: String,
+    pub(super) model: String,
     is_new: bool,
-    thread_id: String,
-) -> std::result::Result<AxumResponse, ApiError> {
-    let mut client = client_arc.write().await;
+    /// Used to open the session of a thread restored from storage
+    proxy: Option<String>,
+    conversation: Option<ConversationPosition>,
+    pub(super) thread_id: String,
+    /// Number of thread messages the response was generated from
+    position: usize,
+    pub(super) response_id: String,
+    pub(super) created_at: u64,
+    pub(super) cancel: CancellationToken,
+    /// Set when the request carried an Idempotency-Key. Such turns keep running
+    /// when the caller disconnects so that a retry can pick up the result.
+    idempotency: Option<IdempotencyGuard>,
+    /// Told how the turn ended. Such turns also keep running without their
+    /// caller, since the webhook reports the result.
+    webhook: Option<String>,
+    /// Keeps the thread claimed until the turn is over
+    _run_guard: OwnedMutexGuard<()>,
+}
 
-    let answer = if is_new {
-        client.start_conversation(message).await.map_err(|err| {
-            error!("ChatGPT start_conversation failed: {:?}", err);
-            ApiError::from(err)
-        })?
-    } else {
-        client.hold_conversation(message, false).await.map_err(|err| {
-            error!("ChatGPT hold_conversation failed: {:?}", err);
-            ApiError::from(err)
-        })?
-    };
+impl<B: ChatBackend> Generation<B> {
+    /// Report the end of the turn to `webhook` instead of the configured one
+    pub(super) fn with_webhook(mut self, webhook: Option<String>) -> Self {
+        self.webhook = webhook;
+        self
+    }
 
-    drop(client);
+    /// Whether the turn should survive its caller going away
+    pub(super) fn is_detached(&self) -> bool {
+        self.idempotency.is_some() || self.webhook.is_some()
+    }
 
-    // Add assistant's response to thread
-    let full_answer = answer.clone();
-    thread_state.add_message("assistant".to_string(), full_answer);
-    state.update_thread(&thread_id, thread_state).await?;
+    /// Run the turn against the thread's client and record the answer.
+    ///
+    /// Deltas are forwarded to `deltas` as they arrive. If the turn is cancelled
+    /// or fails midway, the text received so far is kept on the thread as an
+    /// incomplete assistant message.
+    #[tracing::instrument(
+        name = "response",
+        skip_all,
+        fields(id = %self.response_id, thread_id = %self.thread_id)
+    )]
+    pub(super) async fn run(mut self, deltas: Option<DeltaSender>) -> std::result::Result<CompletedResponse, ApiError> {
+        let Some(url) = self.webhook.take() else {
+            return self.turn(deltas).await;
+        };
 
-    // Split response into chunks for streaming
-    let chunks: Vec<String> = answer
-        .chars()
-        .collect::<Vec<char>>()
-        .chunks(10)
-        .map(|chunk| chunk.iter().collect::<String>())
-        .collect();
+        let state = self.state.clone();
+        let response_id = self.response_id.clone();
+        let failed = Response {
+            id: self.response_id.clone(),
+            object: "thread.response".to_string(),
+            created_at: self.created_at,
+            thread_id: self.thread_id.clone(),
+            status: "failed".to_string(),
+            model: self.model.clone(),
+            usage: None,
+        };
+        let result = self.turn(deltas).await;
+        let event = match &result {
+            Ok(completed) => webhooks::response_event(response_object(completed), Some(&completed.answer), None),
+            Err(err) => webhooks::response_event(failed, None, Some(err)),
+        };
+        state.webhooks().deliver(&response_id, url, event);
+        result
+    }
 
-    let created_at = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
+    async fn turn(self, deltas: Option<DeltaSender>) -> std::result::Result<CompletedResponse, ApiError> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (client_arc, message, cancel, mut is_new) =
+            (&self.client_arc, &self.message, &self.cancel, self.is_new);
+        let (state, proxy, conversation) = (&self.state, &self.proxy, &self.conversation);
+        let started = Instant::now();
+
+        let upstream = async move {
+            let mut session = client_arc.write().await;
+            if session.is_none() {
+                let mut client = state.connect(proxy.as_deref()).await?;
+                match conversation {
+                    Some(position) => client.resume(position.clone()),
+                    // Nothing upstream to continue, so the turn starts over
+                    None => is_new = true,
+                }
+                *session = Some(client);
+            }
+            let client = session.as_mut().expect("session was just opened");
 
-    let response_id = uuid::Uuid::new_v4().to_string();
+            let result = if is_new {
+                client.start_conversation(message, Some(&tx), cancel).await
+            } else {
+                client.continue_conversation(message, Some(&tx), cancel).await
+            };
+            Ok::<_, ApiError>((result, client.position()))
+        };
+
+        let forward = async {
+            let mut partial = String::new();
+            let mut first = true;
+            while let Some(delta) = rx.recv().await {
+                if std::mem::take(&mut first) {
+                    state.metrics().observe_first_token(started.elapsed());
+                }
+                partial.push_str(&delta);
+                if let Some(deltas) = &deltas {
+                    let _ = deltas.send(delta);
+                }
+            }
+            partial
+        };
+
+        let (opened, partial) = tokio::join!(upstream, forward);
+        self.state.finish_response(&self.response_id).await;
+        let (result, conversation) = opened?;
+
+        let (answer, complete) = match &result {
+            Ok(answer) => (answer.clone(), true),
+            Err(_) => (partial, false),
+        };
+
+        // Add assistant's response to thread
+        if complete || !answer.is_empty() {
+            let status = (!complete).then(|| "incomplete".to_string());
+            self.state
+                .record_answer(&self.thread_id, self.position, answer.clone(), status, conversation)
+                .await?;
+        }
+
+        let metrics = self.state.metrics();
+        let status = match result {
+            Ok(_) => "completed",
+            Err(ChatGptError::Cancelled) => {
+                info!("Response {} cancelled", self.response_id);
+                "cancelled"
+            }
+            Err(err) => {
+                error!(code = err.code(), "Conversation failed: {}", err);
+                metrics.observe_generation("failed", started.elapsed());
+                metrics.upstream_error(err.code());
+                return Err(ApiError::from(err));
+            }
+        };
+        metrics.observe_generation(status, started.elapsed());
+
+        let completed = CompletedResponse {
+            id: self.response_id,
+            thread_id: self.thread_id,
+            created_at: self.created_at,
+            model: self.model,
+            status: status.to_string(),
+            answer,
+        };
+
+        if let Some(idempotency) = self.idempotency {
+            idempotency.complete(completed.clone());
+        }
+
+        Ok(completed)
+    }
+}
+
+async fn handle_non_stream_response<B: ChatBackend>(
+    generation: Generation<B>,
+) -> std::result::Result<AxumResponse, ApiError> {
+    // If the caller disconnects this handler is dropped, which cancels the turn
+    let guard = (!generation.is_detached()).then(|| generation.cancel.clone().drop_guard());
+    let state = generation.state.clone();
+    let completed = state.spawn_generation(generation.run(None))
+        .await
+        .map_err(|err| ApiError::internal_error(format!("Response task failed: {}", err)))??;
+    if let Some(guard) = guard {
+        guard.disarm();
+    }
+
+    Ok(Json(response_object(&completed)).into_response())
+}
+
+async fn handle_stream_response<B: ChatBackend>(
+    generation: Generation<B>,
+) -> std::result::Result<AxumResponse, ApiError> {
+    let created_at = generation.created_at;
+    let response_id = generation.response_id.clone();
+    let thread_id = generation.thread_id.clone();
+    let cancel = generation.cancel.clone();
+    let detached = generation.is_detached();
+    let state = generation.state.clone();
 
     let (tx, rx) = tokio::sync::mpsc::channel(100);
 
-    // Spawn a task to send chunks
+    // Spawn a task that relays deltas from upstream as they arrive
     tokio::spawn(async move {
-        // Send content chunks
-        for (i, chunk) in chunks.into_iter().enumerate() {
+        let (delta_tx, mut delta_rx) = mpsc::unbounded_channel();
+        let task = state.spawn_generation(generation.run(Some(delta_tx)));
+
+        let mut first = true;
+        loop {
+            let delta = tokio::select! {
+                delta = delta_rx.recv() => delta,
+                // The client went away before the next delta, even the first one
+                _ = tx.closed() => {
+                    if !detached {
+                        cancel.cancel();
+                    }
+                    break;
+                }
+            };
+            let Some(delta) = delta else { break };
+            let bytes = delta.len();
             let chunk_data = ResponseChunk {
                 id: response_id.clone(),
                 object: "thread.response.chunk".to_string(),
                 created_at,
                 thread_id: thread_id.clone(),
                 delta: Delta {
-                    role: if i == 0 { Some("assistant".to_string()) } else { None },
-                    content: Some(chunk),
+                    role: if first { Some("assistant".to_string()) } else { None },
+                    content: Some(delta),
                 },
+                status: None,
             };
+            first = false;
 
             if tx
                 .send(Ok::<_, Infallible>(
@@ -320,27 +549,125 @@ async fn handle_stream_response(
                 .await
                 .is_err()
             {
+                // The client went away, so stop the upstream request as well
+                if !detached {
+                    cancel.cancel();
+                }
                 break;
             }
+            state.metrics().add_streamed_bytes(bytes);
         }
 
-        // Send final chunk
-        let final_chunk = ResponseChunk {
-            id: response_id.clone(),
+        let final_event = match task.await {
+            Ok(Ok(completed)) => Event::default().json_data(final_chunk(&completed)).unwrap(),
+            Ok(Err(err)) => error_event(err),
+            Err(err) => error_event(ApiError::internal_error(format!("Response task failed: {}", err))),
+        };
+
+        let _ = tx.send(Ok(final_event)).await;
+    }.in_current_span());
+
+    let stream = ReceiverStream::new(rx);
+    Ok(Sse::new(stream).into_response())
+}
+
+/// Answer a retried request from the stored response, in the format it asked for
+fn replay_response(completed: CompletedResponse, stream: bool) -> AxumResponse {
+    let replayed = [(HeaderName::from_static("idempotent-replayed"), "true")];
+
+    if !stream {
+        return (replayed, Json(response_object(&completed))).into_response();
+    }
+
+    let mut events = Vec::new();
+    if !completed.answer.is_empty() {
+        let chunk = ResponseChunk {
+            id: completed.id.clone(),
             object: "thread.response.chunk".to_string(),
-            created_at,
-            thread_id: thread_id.clone(),
+            created_at: completed.created_at,
+            thread_id: completed.thread_id.clone(),
             delta: Delta {
-                role: None,
-                content: None,
+                role: Some("assistant".to_string()),
+                content: Some(completed.answer.clone()),
             },
+            status: None,
         };
+        events.push(Ok::<_, Infallible>(Event::default().json_data(chunk).unwrap()));
+    }
+    events.push(Ok(Event::default().json_data(final_chunk(&completed)).unwrap()));
 
-        let _ = tx
-            .send(Ok(Event::default().json_data(final_chunk).unwrap()))
-            .await;
-    });
+    (replayed, Sse::new(tokio_stream::iter(events))).into_response()
+}
 
-    let stream = ReceiverStream::new(rx);
-    Ok(Sse::new(stream).into_response())
+pub(super) fn response_object(completed: &CompletedResponse) -> Response {
+    Response {
+        id: completed.id.clone(),
+        object: "thread.response".to_string(),
+        created_at: completed.created_at,
+        thread_id: completed.thread_id.clone(),
+        status: completed.status.clone(),
+        model: completed.model.clone(),
+        usage: Some(Usage {
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            total_tokens: 0,
+        }),
+    }
+}
+
+pub(super) fn thread_object<B>(thread_id: String, thread: &ThreadState<B>) -> Thread {
+    Thread {
+        id: thread_id,
+        object: "thread".to_string(),
+        created_at: thread.created_at,
+        metadata: thread.metadata.clone(),
+    }
+}
+
+/// A thread message as listed by the API. Message IDs are positions in the thread.
+pub(super) fn message_object(thread_id: &str, index: usize, msg: &ThreadMessage, fallback_created_at: u64) -> Message {
+    Message {
+        id: format!("msg_{}_{}", thread_id, index),
+        object: "thread.message".to_string(),
+        created_at: msg.created_at.unwrap_or(fallback_created_at),
+        thread_id: thread_id.to_string(),
+        role: msg.role.clone(),
+        content: vec![ContentPart {
+            content_type: "text".to_string(),
+            text: TextContent {
+                value: msg.content.clone(),
+                annotations: vec![],
+            },
+        }],
+        status: msg.status.clone(),
+    }
+}
+
+pub(super) fn message_objects<B>(thread_id: &str, thread: &ThreadState<B>) -> Vec<Message> {
+    thread
+        .get_messages()
+        .iter()
+        .enumerate()
+        .map(|(idx, msg)| message_object(thread_id, idx, msg, thread.created_at))
+        .collect()
+}
+
+/// Last chunk of a stream, carrying the final status
+fn final_chunk(completed: &CompletedResponse) -> ResponseChunk {
+    ResponseChunk {
+        id: completed.id.clone(),
+        object: "thread.response.chunk".to_string(),
+        created_at: completed.created_at,
+        thread_id: completed.thread_id.clone(),
+        delta: Delta {
+            role: None,
+            content: None,
+        },
+        status: Some(completed.status.clone()),
+    }
+}
+
+/// SSE event reporting a failure after the stream has started
+fn error_event(err: ApiError) -> Event {
+    Event::default().event("error").json_data(err.body()).unwrap()
 }
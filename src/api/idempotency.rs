@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::http::StatusCode;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+
+use super::error::ApiError;
+
+/// How long a key maps to its response unless configured otherwise
+pub const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A finished response, kept so retries with the same key get the same result
+#[derive(Debug, Clone)]
+pub struct CompletedResponse {
+    pub id: String,
+    pub thread_id: String,
+    pub created_at: u64,
+    pub model: String,
+    pub status: String,
+    pub answer: String,
+}
+
+struct Entry {
+    /// SHA-256 of the request the key was first used with
+    fingerprint: [u8; 32],
+    created: Instant,
+    result: watch::Receiver<Option<CompletedResponse>>,
+}
+
+/// Outcome of claiming an `Idempotency-Key`
+pub enum Claim {
+    /// First use of the key; the caller runs the response and completes the guard
+    New(IdempotencyGuard),
+    /// The key is known; wait on this for the original response
+    Existing(watch::Receiver<Option<CompletedResponse>>),
+}
+
+/// Maps `Idempotency-Key` headers to responses for a limited window
+#[derive(Clone)]
+pub struct IdempotencyStore {
+    window: Duration,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl IdempotencyStore {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Claim a key for `request`, a canonical form of the request body. A
+    /// key reused with a different request is rejected rather than answered
+    /// with the response to the first one.
+    pub fn claim(&self, key: &str, request: &str) -> Result<Claim, ApiError> {
+        let fingerprint: [u8; 32] = Sha256::digest(request.as_bytes()).into();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.created.elapsed() < self.window);
+
+        if let Some(entry) = entries.get(key) {
+            if entry.fingerprint != fingerprint {
+                return Err(ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "idempotency_key_reused",
+                    "Idempotency-Key was already used for a different request",
+                ));
+            }
+            return Ok(Claim::Existing(entry.result.clone()));
+        }
+
+        let (sender, result) = watch::channel(None);
+        entries.insert(
+            key.to_string(),
+            Entry {
+                fingerprint,
+                created: Instant::now(),
+                result,
+            },
+        );
+
+        Ok(Claim::New(IdempotencyGuard {
+            key: key.to_string(),
+            entries: self.entries.clone(),
+            sender,
+            completed: false,
+        }))
+    }
+}
+
+impl Default for IdempotencyStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_IDEMPOTENCY_WINDOW)
+    }
+}
+
+/// Ownership of a freshly claimed key.
+///
+/// Dropping the guard without completing it releases the key, so a failed
+/// request can be retried with the same key and runs again.
+pub struct IdempotencyGuard {
+    key: String,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    sender: watch::Sender<Option<CompletedResponse>>,
+    completed: bool,
+}
+
+impl IdempotencyGuard {
+    /// Store the response and wake up any retries waiting on it
+    pub fn complete(mut self, response: CompletedResponse) {
+        self.sender.send_replace(Some(response));
+        self.completed = true;
+    }
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.entries.lock().unwrap().remove(&self.key);
+        }
+    }
+}
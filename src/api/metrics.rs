@@ -0,0 +1,198 @@
+//! Prometheus metrics served on `/metrics`.
+//!
+//! Every [`AppState`](super::AppState) owns its own registry, so several
+//! servers in one process (as in tests) do not share counters.
+
+use std::time::Duration;
+
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{Histogram, exponential_buckets};
+use prometheus_client::registry::{Registry, Unit};
+
+/// Content type of [`Metrics::render`]'s output
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    method: String,
+    /// Route template such as `/v1/threads/{thread_id}`, never a raw path
+    route: String,
+    status: u16,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct StatusLabels {
+    status: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct KindLabels {
+    kind: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ReasonLabels {
+    reason: &'static str,
+}
+
+type HistogramFamily<L> = Family<L, Histogram, fn() -> Histogram>;
+
+/// HTTP handling: 5ms up to ~40s
+fn request_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.005, 2.0, 14))
+}
+
+/// Upstream work: 50ms up to ~7min
+fn upstream_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.05, 2.0, 14))
+}
+
+/// Counters and timings of one API server
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    http_requests: Family<RequestLabels, Counter>,
+    http_request_duration: HistogramFamily<RequestLabels>,
+    time_to_first_token: Histogram,
+    generation_duration: HistogramFamily<StatusLabels>,
+    upstream_errors: Family<KindLabels, Counter>,
+    client_init_duration: Histogram,
+    threads_active: Gauge,
+    threads_removed: Family<ReasonLabels, Counter>,
+    streamed_bytes: Counter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("chatgpt"),
+            http_requests: Family::default(),
+            http_request_duration: Family::new_with_constructor(request_histogram),
+            time_to_first_token: upstream_histogram(),
+            generation_duration: Family::new_with_constructor(upstream_histogram),
+            upstream_errors: Family::default(),
+            client_init_duration: upstream_histogram(),
+            threads_active: Gauge::default(),
+            threads_removed: Family::default(),
+            streamed_bytes: Counter::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register(
+            "http_requests",
+            "HTTP requests by method, route and status",
+            metrics.http_requests.clone(),
+        );
+        registry.register_with_unit(
+            "http_request_duration",
+            "Time until response headers were sent; streams keep going after",
+            Unit::Seconds,
+            metrics.http_request_duration.clone(),
+        );
+        registry.register_with_unit(
+            "time_to_first_token",
+            "Time from the start of a turn to its first streamed delta",
+            Unit::Seconds,
+            metrics.time_to_first_token.clone(),
+        );
+        registry.register_with_unit(
+            "generation_duration",
+            "Time a turn took, by final status",
+            Unit::Seconds,
+            metrics.generation_duration.clone(),
+        );
+        registry.register(
+            "upstream_errors",
+            "Failed upstream turns and session setups, by error code",
+            metrics.upstream_errors.clone(),
+        );
+        registry.register_with_unit(
+            "client_init_duration",
+            "Time to open a backend session for a thread",
+            Unit::Seconds,
+            metrics.client_init_duration.clone(),
+        );
+        registry.register(
+            "threads_active",
+            "Threads currently held by the server",
+            metrics.threads_active.clone(),
+        );
+        registry.register(
+            "threads_removed",
+            "Threads dropped from the server, by reason",
+            metrics.threads_removed.clone(),
+        );
+        registry.register_with_unit(
+            "streamed",
+            "Answer text sent to clients over streaming responses",
+            Unit::Bytes,
+            metrics.streamed_bytes.clone(),
+        );
+
+        metrics
+    }
+
+    pub fn observe_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        let labels = RequestLabels {
+            method: method.to_string(),
+            route: route.to_string(),
+            status,
+        };
+        self.http_requests.get_or_create(&labels).inc();
+        self.http_request_duration
+            .get_or_create(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_first_token(&self, elapsed: Duration) {
+        self.time_to_first_token.observe(elapsed.as_secs_f64());
+    }
+
+    /// Record a finished turn; `status` is `completed`, `cancelled` or `failed`
+    pub fn observe_generation(&self, status: &'static str, elapsed: Duration) {
+        self.generation_duration
+            .get_or_create(&StatusLabels { status })
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Count an upstream failure by its stable error code
+    pub fn upstream_error(&self, kind: &'static str) {
+        self.upstream_errors
+            .get_or_create(&KindLabels { kind })
+            .inc();
+    }
+
+    pub fn observe_client_init(&self, elapsed: Duration) {
+        self.client_init_duration.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn thread_removed(&self, reason: &'static str) {
+        self.threads_removed
+            .get_or_create(&ReasonLabels { reason })
+            .inc();
+    }
+
+    pub fn add_streamed_bytes(&self, bytes: usize) {
+        self.streamed_bytes.inc_by(bytes as u64);
+    }
+
+    /// Metrics in the OpenMetrics text format, with the active thread gauge
+    /// set to `active_threads`
+    pub fn render(&self, active_threads: usize) -> String {
+        self.threads_active.set(active_threads as i64);
+
+        let mut text = String::new();
+        prometheus_client::encoding::text::encode(&mut text, &self.registry)
+            .expect("writing to a String cannot fail");
+        text
+    }
+}
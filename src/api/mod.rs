@@ -1,9 +1,25 @@
+mod anthropic;
 mod error;
 mod handlers;
+mod idempotency;
+mod ollama;
+mod oneshot;
+mod sessions;
 mod state;
 mod types;
+mod ws;
 
+pub mod chatgpt_import;
+pub mod config;
+pub mod export;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod metrics;
 pub mod server;
+pub mod storage;
+pub mod webhooks;
 
+pub use config::ServerConfig;
 pub use server::run;
 pub use state::AppState;
+pub use types::{Attachment, ThreadMessage};
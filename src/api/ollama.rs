@@ -0,0 +1,628 @@
+//! Ollama API compatibility: `/api/chat`, `/api/generate`, `/api/tags` and
+//! `/api/version`, for local UIs and editor plugins that speak Ollama.
+//!
+//! Requests carry the whole conversation. Chats are mapped onto threads by
+//! their history, so a conversation keeps one upstream conversation; single
+//! prompts run as [`OneShot`] turns. Streams are newline-delimited JSON, which
+//! is also the default, as in Ollama.
+
+use std::convert::Infallible;
+use std::time::Instant;
+
+use axum::Json;
+use axum::body::Body;
+use axum::extract::State;
+use axum::extract::rejection::JsonRejection;
+use axum::http::HeaderValue;
+use axum::http::header::{CONTENT_TYPE, RETRY_AFTER};
+use axum::response::{IntoResponse, Response};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{Instrument, info};
+
+use super::error::ApiError;
+use super::handlers::{Generation, prepare_generation, resolve_model};
+use super::oneshot::{OneShot, OneShotStream, fold_prompt};
+use super::state::AppState;
+use super::types::ThreadMessage;
+use crate::backend::ChatBackend;
+
+/// Prefix of the Ollama routes
+pub const PREFIX: &str = "/api/";
+
+const NDJSON: &str = "application/x-ndjson";
+
+/// Metadata `source` of the threads chats run on
+const SOURCE: &str = "ollama";
+
+fn default_stream() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatRequest {
+    pub model: String,
+    /// An empty list only loads the model, as in Ollama
+    #[serde(default)]
+    pub messages: Vec<ChatMessage>,
+    #[serde(default = "default_stream")]
+    pub stream: bool,
+    /// Continue this thread instead of looking one up by the history; only
+    /// the last message is sent
+    #[serde(default)]
+    pub thread_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: String,
+    /// Base64 image data
+    #[serde(default)]
+    pub images: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GenerateRequest {
+    pub model: String,
+    /// An empty prompt only loads the model, as in Ollama
+    #[serde(default)]
+    pub prompt: String,
+    #[serde(default)]
+    pub system: Option<String>,
+    /// Base64 image data
+    #[serde(default)]
+    pub images: Vec<String>,
+    #[serde(default = "default_stream")]
+    pub stream: bool,
+    /// Send the prompt as the next turn of this thread
+    #[serde(default)]
+    pub thread_id: Option<String>,
+}
+
+/// One line of a streamed reply, or the whole reply when not streaming
+#[derive(Debug, Serialize)]
+struct Chunk {
+    model: String,
+    created_at: String,
+    #[serde(flatten)]
+    content: Content,
+    done: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    stats: Option<Stats>,
+    /// Thread the turn ran on, given with the last chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Content {
+    Chat { message: AssistantMessage },
+    Generate { response: String },
+}
+
+#[derive(Debug, Serialize)]
+struct AssistantMessage {
+    role: &'static str,
+    content: String,
+}
+
+/// Sent with the last chunk. Token counts are not reported by upstream, so
+/// only timings are given.
+#[derive(Debug, Serialize)]
+struct Stats {
+    /// `stop`, or `load` when nothing was generated
+    done_reason: &'static str,
+    /// Nanoseconds spent on the request
+    total_duration: u128,
+}
+
+/// Which endpoint a reply is for, which decides the shape of its chunks
+#[derive(Debug, Clone, Copy)]
+enum Endpoint {
+    Chat,
+    Generate,
+}
+
+/// Builds the chunks of one reply
+#[derive(Debug, Clone)]
+struct Reply {
+    endpoint: Endpoint,
+    model: String,
+    started: Instant,
+    thread_id: Option<String>,
+}
+
+impl Reply {
+    fn chunk(&self, text: String, done_reason: Option<&'static str>) -> Chunk {
+        let content = match self.endpoint {
+            Endpoint::Chat => Content::Chat {
+                message: AssistantMessage {
+                    role: "assistant",
+                    content: text,
+                },
+            },
+            Endpoint::Generate => Content::Generate { response: text },
+        };
+        Chunk {
+            model: self.model.clone(),
+            created_at: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            content,
+            done: done_reason.is_some(),
+            stats: done_reason.map(|done_reason| Stats {
+                done_reason,
+                total_duration: self.started.elapsed().as_nanos(),
+            }),
+            thread_id: done_reason.and(self.thread_id.clone()),
+        }
+    }
+
+    /// A chunk as one line of newline-delimited JSON
+    fn line(&self, text: String, done_reason: Option<&'static str>) -> String {
+        ndjson_line(&self.chunk(text, done_reason))
+    }
+}
+
+fn ndjson_line(value: &impl Serialize) -> String {
+    let mut line = serde_json::to_string(value).unwrap();
+    line.push('\n');
+    line
+}
+
+/// An [`ApiError`] rendered as Ollama's `{"error": "..."}` object
+#[derive(Debug)]
+pub struct OllamaError(pub ApiError);
+
+impl OllamaError {
+    fn body(&self) -> serde_json::Value {
+        serde_json::json!({ "error": self.0.message })
+    }
+}
+
+impl From<ApiError> for OllamaError {
+    fn from(err: ApiError) -> Self {
+        Self(err)
+    }
+}
+
+impl From<JsonRejection> for OllamaError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self(ApiError::from(rejection))
+    }
+}
+
+impl IntoResponse for OllamaError {
+    fn into_response(self) -> Response {
+        let mut response = (self.0.status, Json(self.body())).into_response();
+        if let Some(seconds) = self.0.retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+}
+
+/// The catalog model named by `model`. Ollama clients may add the default
+/// `:latest` tag to names they were given without one.
+fn resolve<B: ChatBackend>(state: &AppState<B>, model: String) -> Result<String, ApiError> {
+    match model.strip_suffix(":latest") {
+        Some(name) if state.models().iter().any(|m| m.id == name) => Ok(name.to_string()),
+        _ => resolve_model(state, Some(model)),
+    }
+}
+
+/// The single image of a message, if it has one
+fn single_image(images: &[String], param: &str) -> Result<Option<String>, ApiError> {
+    match images {
+        [] => Ok(None),
+        [image] => Ok(Some(image.clone())),
+        _ => {
+            Err(ApiError::bad_request("Only one image per request is supported").with_param(param))
+        }
+    }
+}
+
+/// Chat with a model (Ollama API)
+///
+/// Each conversation runs on a thread. A request whose history picks up where
+/// a thread left off continues that thread's upstream conversation; any other
+/// history starts a thread of its own, folded into its first message.
+pub async fn chat<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    payload: Result<Json<ChatRequest>, JsonRejection>,
+) -> Result<Response, OllamaError> {
+    state.check_accepting()?;
+    let Json(payload) = payload?;
+
+    info!(
+        "Ollama chat with {} messages, stream: {}",
+        payload.messages.len(),
+        payload.stream
+    );
+
+    let reply = Reply {
+        endpoint: Endpoint::Chat,
+        model: resolve(&state, payload.model)?,
+        started: Instant::now(),
+        thread_id: None,
+    };
+
+    let mut system = Vec::new();
+    let mut turns = Vec::new();
+    for (index, message) in payload.messages.iter().enumerate() {
+        match message.role.as_str() {
+            "system" => system.push(message.content.as_str()),
+            "user" | "assistant" => turns.push((index, message)),
+            other => {
+                return Err(
+                    ApiError::bad_request(format!("Unsupported role `{}`", other))
+                        .with_param(format!("messages.{}.role", index))
+                        .into(),
+                );
+            }
+        }
+    }
+
+    let Some(&(last_index, last)) = turns.last() else {
+        return Ok(loaded(reply, payload.stream));
+    };
+    if last.role != "user" {
+        return Err(
+            ApiError::bad_request("The last message must have the `user` role")
+                .with_param(format!("messages.{}.role", last_index))
+                .into(),
+        );
+    }
+
+    let mut history = Vec::new();
+    for &(index, message) in &turns[..turns.len() - 1] {
+        if !message.images.is_empty() {
+            return Err(ApiError::bad_request(
+                "Images are only supported in the last user message",
+            )
+            .with_param(format!("messages.{}.images", index))
+            .into());
+        }
+        let speaker = if message.role == "user" {
+            "User"
+        } else {
+            "Assistant"
+        };
+        history.push((speaker, message.content.clone()));
+    }
+
+    let images_param = format!("messages.{}.images", last_index);
+    let image = single_image(&last.images, &images_param)?;
+    if last.content.trim().is_empty() && image.is_none() {
+        return Err(ApiError::bad_request("Last user message content is empty")
+            .with_param(format!("messages.{}.content", last_index))
+            .into());
+    }
+
+    let prompt = fold_prompt(&system.join("\n\n"), &history, &last.content);
+    let turn = match (payload.thread_id, image) {
+        (Some(_), Some(_)) => return Err(image_on_thread(&images_param).into()),
+        // Upstream takes images only on the first message, so these run on their own
+        (None, Some(image)) => one_shot(&state, prompt, Some(image)).await?,
+        (Some(thread_id), None) => {
+            next_turn(&state, thread_id, last.content.clone(), &reply.model).await?
+        }
+        (None, None) => {
+            let continued = match find_thread(&state, &payload.messages).await {
+                Some(thread_id) => {
+                    match next_turn(&state, thread_id, last.content.clone(), &reply.model).await {
+                        // Another request with the same history has it, so
+                        // this one gets a thread of its own
+                        Err(err) if err.code == "thread_busy" => None,
+                        other => Some(other?),
+                    }
+                }
+                None => None,
+            };
+            match continued {
+                Some(turn) => turn,
+                None => new_thread(&state, &payload.messages, prompt, &reply.model).await?,
+            }
+        }
+    };
+    run(state, reply, turn, payload.stream).await
+}
+
+/// Complete a prompt (Ollama API)
+///
+/// Prompts run on their own unless the request names a `thread_id` to
+/// continue.
+pub async fn generate<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    payload: Result<Json<GenerateRequest>, JsonRejection>,
+) -> Result<Response, OllamaError> {
+    state.check_accepting()?;
+    let Json(payload) = payload?;
+
+    info!("Ollama generate, stream: {}", payload.stream);
+
+    let reply = Reply {
+        endpoint: Endpoint::Generate,
+        model: resolve(&state, payload.model)?,
+        started: Instant::now(),
+        thread_id: None,
+    };
+
+    let image = single_image(&payload.images, "images")?;
+    if payload.prompt.trim().is_empty() && image.is_none() {
+        return Ok(loaded(reply, payload.stream));
+    }
+
+    let system = payload.system.unwrap_or_default();
+    let message = fold_prompt(&system, &[], &payload.prompt);
+    let turn = match (payload.thread_id, image) {
+        (Some(_), Some(_)) => return Err(image_on_thread("images").into()),
+        (Some(thread_id), None) => next_turn(&state, thread_id, message, &reply.model).await?,
+        (None, image) => one_shot(&state, message, image).await?,
+    };
+    run(state, reply, turn, payload.stream).await
+}
+
+/// Answer a request that only asks for the model to be loaded
+fn loaded(reply: Reply, stream: bool) -> Response {
+    let chunk = reply.chunk(String::new(), Some("load"));
+    if stream {
+        ([(CONTENT_TYPE, NDJSON)], ndjson_line(&chunk)).into_response()
+    } else {
+        Json(chunk).into_response()
+    }
+}
+
+fn image_on_thread(param: &str) -> ApiError {
+    ApiError::bad_request("Images can only be sent with the first message of a conversation")
+        .with_param(param)
+}
+
+/// Fingerprint of a chat history, by role and content
+fn prefix_hash(messages: &[ChatMessage]) -> String {
+    let transcript: Vec<_> = messages
+        .iter()
+        .map(|message| (&message.role, &message.content))
+        .collect();
+    let transcript = serde_json::to_string(&transcript).unwrap();
+    format!("{:x}", Sha256::digest(transcript.as_bytes()))
+}
+
+/// The thread whose conversation `messages` continues: the history the
+/// thread was opened with, then the thread's later turns, then one new user
+/// message
+async fn find_thread<B: ChatBackend>(
+    state: &AppState<B>,
+    messages: &[ChatMessage],
+) -> Option<String> {
+    let (_, history) = messages.split_last()?;
+    for (thread_id, thread) in state.list_threads().await {
+        let Some(metadata) = &thread.metadata else {
+            continue;
+        };
+        let Some(opening) = metadata["prefix_messages"].as_u64() else {
+            continue;
+        };
+        let opening = opening as usize;
+        if metadata["source"] != SOURCE || opening >= history.len() {
+            continue;
+        }
+
+        let (prefix, later) = history.split_at(opening);
+        let turns = thread.get_messages().get(1..).unwrap_or_default();
+        if metadata["prefix_sha256"] == prefix_hash(prefix).as_str()
+            && later.len() == turns.len()
+            && later
+                .iter()
+                .zip(turns)
+                .all(|(sent, kept)| sent.role == kept.role && sent.content == kept.content)
+        {
+            return Some(thread_id);
+        }
+    }
+    None
+}
+
+/// Open a thread for a chat history no thread continues, with the history
+/// folded into `prompt`
+async fn new_thread<B: ChatBackend>(
+    state: &AppState<B>,
+    messages: &[ChatMessage],
+    prompt: String,
+    model: &str,
+) -> Result<Turn<B>, ApiError> {
+    let metadata = serde_json::json!({
+        "source": SOURCE,
+        "prefix_sha256": prefix_hash(messages),
+        "prefix_messages": messages.len(),
+    });
+    let first = ThreadMessage {
+        role: "user".to_string(),
+        content: prompt,
+        created_at: None,
+        status: None,
+        attachments: Vec::new(),
+    };
+    let (thread_id, thread) = state
+        .create_thread(vec![first], Some(metadata), None)
+        .await?;
+    let run_guard = thread.try_begin_run(&thread_id)?;
+    let generation = prepare_generation(
+        state.clone(),
+        thread_id,
+        run_guard,
+        Some(model.to_string()),
+        None,
+    )
+    .await?;
+    Ok(Turn::Thread(generation))
+}
+
+/// Add `content` to `thread_id` and set up the turn answering it
+async fn next_turn<B: ChatBackend>(
+    state: &AppState<B>,
+    thread_id: String,
+    content: String,
+    model: &str,
+) -> Result<Turn<B>, ApiError> {
+    // Claim the thread before adding the message, so a busy thread is left as it was
+    let run_guard = state
+        .get_thread(&thread_id)
+        .await?
+        .try_begin_run(&thread_id)?;
+    state
+        .add_message_to_thread(&thread_id, "user".to_string(), content)
+        .await?;
+    let generation = prepare_generation(
+        state.clone(),
+        thread_id,
+        run_guard,
+        Some(model.to_string()),
+        None,
+    )
+    .await?;
+    Ok(Turn::Thread(generation))
+}
+
+async fn one_shot<B: ChatBackend>(
+    state: &AppState<B>,
+    message: String,
+    image: Option<String>,
+) -> Result<Turn<B>, ApiError> {
+    state.check_message(&message)?;
+    let id = format!("ollama_{}", uuid::Uuid::new_v4().simple());
+    Ok(Turn::OneShot(
+        OneShot::begin(state, id, message, image).await,
+    ))
+}
+
+/// The turn behind a request. Only one is alive per request, so the
+/// variants are not boxed.
+#[allow(clippy::large_enum_variant)]
+enum Turn<B: ChatBackend> {
+    /// A new upstream conversation outside any thread
+    OneShot(OneShot<B>),
+    /// The next turn of a thread
+    Thread(Generation<B>),
+}
+
+impl<B: ChatBackend> Turn<B> {
+    /// Run the turn and return the answer. If the caller disconnects the
+    /// future is dropped, which cancels the turn.
+    async fn complete(self, state: &AppState<B>) -> Result<String, ApiError> {
+        let generation = match self {
+            Self::OneShot(turn) => return turn.complete().await,
+            Self::Thread(generation) => generation,
+        };
+        let guard = (!generation.is_detached()).then(|| generation.cancel.clone().drop_guard());
+        let completed = state
+            .spawn_generation(generation.run(None))
+            .await
+            .map_err(|err| ApiError::internal_error(format!("Response task failed: {}", err)))??;
+        if let Some(guard) = guard {
+            guard.disarm();
+        }
+        Ok(completed.answer)
+    }
+
+    fn stream(self, state: &AppState<B>) -> OneShotStream {
+        match self {
+            Self::OneShot(turn) => turn.stream(),
+            Self::Thread(generation) => OneShotStream::from_generation(state, generation),
+        }
+    }
+}
+
+async fn run<B: ChatBackend>(
+    state: AppState<B>,
+    mut reply: Reply,
+    turn: Turn<B>,
+    stream: bool,
+) -> Result<Response, OllamaError> {
+    if let Turn::Thread(generation) = &turn {
+        reply.thread_id = Some(generation.thread_id.clone());
+    }
+
+    if !stream {
+        let answer = turn.complete(&state).await?;
+        return Ok(Json(reply.chunk(answer, Some("stop"))).into_response());
+    }
+
+    let (tx, rx) = mpsc::channel(100);
+
+    // Spawn a task that relays deltas from upstream as they arrive
+    tokio::spawn(
+        async move {
+            let mut turn = turn.stream(&state);
+
+            while let Some(delta) = turn.deltas.recv().await {
+                let bytes = delta.len();
+                if tx
+                    .send(Ok::<_, Infallible>(reply.line(delta, None)))
+                    .await
+                    .is_err()
+                {
+                    // The client went away, so stop the upstream request as well
+                    turn.cancel();
+                    break;
+                }
+                state.metrics().add_streamed_bytes(bytes);
+            }
+
+            let last = match turn.finish().await {
+                Ok(_) => reply.line(String::new(), Some("stop")),
+                Err(err) => ndjson_line(&OllamaError(err).body()),
+            };
+            let _ = tx.send(Ok(last)).await;
+        }
+        .in_current_span(),
+    );
+
+    Ok((
+        [(CONTENT_TYPE, NDJSON)],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response())
+}
+
+/// List models (Ollama API)
+pub async fn tags<B: ChatBackend>(State(state): State<AppState<B>>) -> impl IntoResponse {
+    let models: Vec<_> = state
+        .models()
+        .iter()
+        .map(|model| {
+            let modified_at = Utc
+                .timestamp_opt(model.created as i64, 0)
+                .single()
+                .unwrap_or_default()
+                .to_rfc3339_opts(SecondsFormat::Secs, true);
+            serde_json::json!({
+                "name": model.id,
+                "model": model.id,
+                "modified_at": modified_at,
+                "size": 0,
+                "digest": "",
+                "details": {
+                    "format": "",
+                    "family": model.owned_by,
+                    "families": null,
+                    "parameter_size": "",
+                    "quantization_level": ""
+                }
+            })
+        })
+        .collect();
+
+    Json(serde_json::json!({ "models": models }))
+}
+
+/// Server version (Ollama API); clients check it when connecting
+pub async fn version() -> impl IntoResponse {
+    Json(serde_json::json!({ "version": env!("CARGO_PKG_VERSION") }))
+}
@@ -0,0 +1,196 @@
+//! Turns run as a new upstream conversation, for the compatibility APIs
+//! (Anthropic Messages, Ollama) whose requests carry the whole conversation
+//! instead of naming a thread.
+
+use std::time::Instant;
+
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use super::error::ApiError;
+use super::handlers::Generation;
+use super::state::AppState;
+use crate::backend::{ChatBackend, DeltaSender};
+use crate::utils::ChatGptError;
+
+/// Fold a system prompt and earlier turns into the single message the web
+/// backend sees, which has no system prompt of its own.
+///
+/// `history` holds `(speaker, text)` pairs such as `("User", "Hi")`.
+pub fn fold_prompt(system: &str, history: &[(&str, String)], last: &str) -> String {
+    let mut sections = Vec::new();
+    if !system.trim().is_empty() {
+        sections.push(system.to_string());
+    }
+
+    if !history.is_empty() {
+        let transcript: Vec<_> = history
+            .iter()
+            .map(|(speaker, text)| format!("{}: {}", speaker, text))
+            .collect();
+        sections.push(format!(
+            "Conversation so far:\n\n{}",
+            transcript.join("\n\n")
+        ));
+    }
+
+    sections.push(last.to_string());
+    sections.join("\n\n")
+}
+
+/// One turn in a new upstream conversation, on a pooled backend session
+pub struct OneShot<B: ChatBackend> {
+    state: AppState<B>,
+    id: String,
+    message: String,
+    /// Base64 data of an image sent with the message
+    image: Option<String>,
+    cancel: CancellationToken,
+}
+
+impl<B: ChatBackend> OneShot<B> {
+    /// Register the turn under `id`, so it can be cancelled like a response
+    pub async fn begin(
+        state: &AppState<B>,
+        id: String,
+        message: String,
+        image: Option<String>,
+    ) -> Self {
+        let cancel = state.begin_response(&id).await;
+        Self {
+            state: state.clone(),
+            id,
+            message,
+            image,
+            cancel,
+        }
+    }
+
+    /// Run the turn and return the answer. If the caller disconnects the
+    /// future is dropped, which cancels the turn.
+    pub async fn complete(self) -> Result<String, ApiError> {
+        let guard = self.cancel.clone().drop_guard();
+        let state = self.state.clone();
+        let answer = state
+            .spawn_generation(self.run(None))
+            .await
+            .map_err(|err| ApiError::internal_error(format!("Response task failed: {}", err)))??;
+        guard.disarm();
+        Ok(answer)
+    }
+
+    /// Start the turn in the background and hand out its deltas
+    pub fn stream(self) -> OneShotStream {
+        let (tx, deltas) = mpsc::unbounded_channel();
+        let cancel = self.cancel.clone();
+        let state = self.state.clone();
+        let task = state.spawn_generation(self.run(Some(tx)));
+        OneShotStream {
+            deltas,
+            task,
+            cancel: Some(cancel),
+        }
+    }
+
+    /// Run the turn, forwarding deltas to `deltas`
+    #[tracing::instrument(name = "response", skip_all, fields(id = %self.id))]
+    async fn run(self, deltas: Option<DeltaSender>) -> Result<String, ApiError> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (state, message, image, cancel) =
+            (&self.state, &self.message, &self.image, &self.cancel);
+        let started = Instant::now();
+
+        let upstream = async move {
+            let mut client = state.checkout_session().await?;
+            if let Some(image) = image {
+                client.upload_attachment(image).await?;
+            }
+            let answer = client.start_conversation(message, Some(&tx), cancel).await;
+            // A failed session may be the cause, so it is not reused
+            if answer.is_ok() {
+                state.release_session(client);
+            }
+            Ok::<_, ApiError>(answer)
+        };
+
+        let forward = async {
+            let mut first = true;
+            while let Some(delta) = rx.recv().await {
+                if std::mem::take(&mut first) {
+                    state.metrics().observe_first_token(started.elapsed());
+                }
+                if let Some(deltas) = &deltas {
+                    let _ = deltas.send(delta);
+                }
+            }
+        };
+
+        let (result, ()) = tokio::join!(upstream, forward);
+        self.state.finish_response(&self.id).await;
+
+        let metrics = self.state.metrics();
+        match result? {
+            Ok(answer) => {
+                metrics.observe_generation("completed", started.elapsed());
+                Ok(answer)
+            }
+            Err(ChatGptError::Cancelled) => {
+                info!("Response {} cancelled", self.id);
+                metrics.observe_generation("cancelled", started.elapsed());
+                // Past the drain timeout the server cancelled it, not the caller
+                self.state.check_accepting()?;
+                Err(ApiError::from(ChatGptError::Cancelled))
+            }
+            Err(err) => {
+                error!(code = err.code(), "Conversation failed: {}", err);
+                metrics.observe_generation("failed", started.elapsed());
+                metrics.upstream_error(err.code());
+                Err(ApiError::from(err))
+            }
+        }
+    }
+}
+
+/// A [`OneShot`] turn, or a thread turn, running in the background
+pub struct OneShotStream {
+    /// Answer text as it arrives; closes when the turn is over
+    pub deltas: UnboundedReceiver<String>,
+    task: JoinHandle<Result<String, ApiError>>,
+    /// Unset for thread turns that outlive their caller
+    cancel: Option<CancellationToken>,
+}
+
+impl OneShotStream {
+    /// Run a thread turn in the background, handing out its deltas the same way
+    pub(super) fn from_generation<B: ChatBackend>(
+        state: &AppState<B>,
+        generation: Generation<B>,
+    ) -> Self {
+        let (tx, deltas) = mpsc::unbounded_channel();
+        let cancel = (!generation.is_detached()).then(|| generation.cancel.clone());
+        let run = generation.run(Some(tx));
+        let task =
+            state.spawn_generation(async move { run.await.map(|completed| completed.answer) });
+        Self {
+            deltas,
+            task,
+            cancel,
+        }
+    }
+
+    /// Stop the turn, e.g. because the client went away
+    pub fn cancel(&self) {
+        if let Some(cancel) = &self.cancel {
+            cancel.cancel();
+        }
+    }
+
+    /// Wait for the turn to end and return the full answer
+    pub async fn finish(self) -> Result<String, ApiError> {
+        self.task
+            .await
+            .map_err(|err| ApiError::internal_error(format!("Response task failed: {}", err)))?
+    }
+}
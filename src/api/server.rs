@@ -1,98 +1,384 @@
+use std::future::IntoFuture;
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 use axum::{
     Json, Router,
-    extract::State,
-    http::{Method, header::CONTENT_TYPE},
-    response::IntoResponse,
+    extract::{DefaultBodyLimit, MatchedPath, Request, State},
+    http::{HeaderMap, HeaderName, Method, Uri, header::{AUTHORIZATION, CONTENT_TYPE}},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{delete, get, post},
 };
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
-use tracing::info;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tracing::{Level, Span, error, info, info_span, warn};
 
+use crate::backend::ChatBackend;
+use crate::utils::redact::scrub;
 use crate::utils::{ChatGptError, Result as ChatGptResult};
-use super::{handlers, state::AppState};
+use super::{anthropic, chatgpt_import, config::GrpcMode, error::ApiError, export, handlers, metrics, ollama, state::AppState, ws};
 
-pub fn router(state: AppState) -> Router {
+/// Correlates a request with its log lines; set on every response
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// API key header of Messages API clients
+const X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+
+pub fn router<B: ChatBackend>(state: AppState<B>) -> Router {
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods([Method::POST, Method::GET, Method::OPTIONS, Method::DELETE])
-        .allow_headers([CONTENT_TYPE, axum::http::header::AUTHORIZATION]);
+        .allow_headers([
+            CONTENT_TYPE,
+            AUTHORIZATION,
+            HeaderName::from_static("idempotency-key"),
+            X_REQUEST_ID,
+            X_API_KEY,
+            HeaderName::from_static("anthropic-version"),
+        ])
+        .expose_headers([X_REQUEST_ID]);
+
+    // Every request gets an `x-request-id` (the caller's, or a new UUID),
+    // echoed on the response and recorded on the span its logs belong to
+    let tracing = ServiceBuilder::new()
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_span)
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        );
+
+    let max_body_bytes = state.limits().max_body_bytes;
+
+    let mut api = Router::new();
+    if state.grpc() != GrpcMode::Only {
+        api = api
+            // Threads endpoints
+            .route("/v1/threads", post(handlers::create_thread::<B>))
+            .route("/v1/threads", get(handlers::list_threads::<B>))
+            .route("/v1/threads/{thread_id}", get(handlers::get_thread::<B>))
+            .route("/v1/threads/{thread_id}", delete(handlers::delete_thread::<B>))
+            .route("/v1/threads/{thread_id}/export", get(export::export_thread::<B>))
+            .route("/v1/threads/import", post(export::import_thread::<B>))
+            .route("/v1/threads/import/chatgpt", post(chatgpt_import::import_chatgpt::<B>))
+            // Messages endpoints
+            .route("/v1/threads/{thread_id}/messages", post(handlers::add_message::<B>))
+            .route("/v1/threads/{thread_id}/messages", get(handlers::list_messages::<B>))
+            // Responses endpoint
+            .route("/v1/responses", post(handlers::create_response::<B>))
+            .route("/v1/responses/{response_id}/cancel", post(handlers::cancel_response::<B>))
+            .route("/v1/responses/{response_id}/webhook", get(handlers::get_webhook::<B>))
+            .route("/v1/responses/{response_id}/webhook/redeliver", post(handlers::redeliver_webhook::<B>))
+            .route("/v1/models", get(list_models::<B>))
+            .route(ws::PATH, get(ws::connect::<B>))
+            // Anthropic Messages API compatibility
+            .route(anthropic::PATH, post(anthropic::create_message::<B>))
+            // Ollama API compatibility
+            .route("/api/chat", post(ollama::chat::<B>))
+            .route("/api/generate", post(ollama::generate::<B>))
+            .route("/api/tags", get(ollama::tags::<B>))
+            .route("/api/version", get(ollama::version));
+    }
+    #[cfg(feature = "grpc")]
+    if state.grpc() != GrpcMode::Off {
+        api = api.merge(super::grpc::routes(state.clone()));
+    }
+    // A build without gRPC has no API routes left in `only` mode
+    if api.has_routes() {
+        api = api.route_layer(middleware::from_fn_with_state(state.clone(), require_api_key::<B>));
+    }
 
     Router::new()
-        // Threads endpoints
-        .route("/v1/threads", post(handlers::create_thread))
-        .route("/v1/threads", get(handlers::list_threads))
-        .route("/v1/threads/{thread_id}", get(handlers::get_thread))
-        .route("/v1/threads/{thread_id}", delete(handlers::delete_thread))
-        // Messages endpoints
-        .route("/v1/threads/{thread_id}/messages", post(handlers::add_message))
-        .route("/v1/threads/{thread_id}/messages", get(handlers::list_messages))
-        // Responses endpoint
-        .route("/v1/responses", post(handlers::create_response))
-        // Health and models
-        .route("/health", get(health_check))
-        .route("/v1/models", get(list_models))
+        .route("/health", get(health_check::<B>))
+        .route("/metrics", get(export_metrics::<B>))
+        .merge(api)
+        .route_layer(middleware::from_fn_with_state(state.clone(), record_request::<B>))
+        .fallback(fallback)
         .with_state(state)
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .layer(tracing)
         .layer(cors)
 }
 
+fn request_span(request: &Request) -> Span {
+    let request_id = request
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+    info_span!(
+        "request",
+        id = %request_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    )
+}
+
+/// Count and time every routed request by its route template
+async fn record_request<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    route: MatchedPath,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let started = Instant::now();
+    let response = next.run(request).await;
+
+    state.metrics().observe_request(
+        method.as_str(),
+        route.as_str(),
+        response.status().as_u16(),
+        started.elapsed(),
+    );
+    response
+}
+
+/// Reject API requests without one of the configured keys, given as a bearer
+/// token or in `x-api-key`
+async fn require_api_key<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let keys = state.api_keys();
+    if keys.is_empty() {
+        return next.run(request).await;
+    }
+
+    let err = match api_key(&request) {
+        Some(token) if keys.iter().any(|key| constant_time_eq(key.as_bytes(), token.as_bytes())) => {
+            return next.run(request).await;
+        }
+        Some(_) => ApiError::unauthorized("Incorrect API key provided"),
+        None => ApiError::unauthorized(
+            "Missing API key; send it as `Authorization: Bearer <key>` or `x-api-key: <key>`",
+        ),
+    };
+
+    // Clients of the compatibility APIs expect errors in their own format
+    match request.uri().path() {
+        #[cfg(feature = "grpc")]
+        path if path.starts_with(super::grpc::PREFIX) => tonic::Status::from(err).into_http(),
+        anthropic::PATH => anthropic::MessagesError(err).into_response(),
+        path if path.starts_with(ollama::PREFIX) => ollama::OllamaError(err).into_response(),
+        _ => err.into_response(),
+    }
+}
+
+fn api_key(request: &Request) -> Option<&str> {
+    let headers = request.headers();
+    bearer_token(headers)
+        .or_else(|| headers.get(X_API_KEY)?.to_str().ok().map(str::trim))
+        // Browsers cannot set headers on WebSocket requests
+        .or_else(|| {
+            if request.uri().path() != ws::PATH {
+                return None;
+            }
+            request.uri().query()?.split('&').find_map(|pair| pair.strip_prefix("api_key="))
+        })
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+/// Compare without returning early, so timing does not reveal how much of a
+/// key matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
 /// Health check endpoint
-async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
-    let threads = state.list_threads().await;
-    let proxy_info = state.get_default_proxy().unwrap_or("none");
+async fn health_check<B: ChatBackend>(State(state): State<AppState<B>>) -> impl IntoResponse {
+    let threads = state.thread_count().await;
+    let proxy_info = state.get_default_proxy().map_or("none".into(), scrub);
     
     Json(serde_json::json!({
         "status": "ok",
         "default_proxy": proxy_info,
-        "active_threads": threads.len(),
+        "active_threads": threads,
         "version": env!("CARGO_PKG_VERSION")
     }))
 }
 
+/// Prometheus metrics endpoint
+async fn export_metrics<B: ChatBackend>(State(state): State<AppState<B>>) -> impl IntoResponse {
+    let threads = state.thread_count().await;
+    ([(CONTENT_TYPE, metrics::CONTENT_TYPE)], state.metrics().render(threads))
+}
+
+/// Unknown routes answer with the same error format as everything else
+async fn fallback(uri: Uri) -> ApiError {
+    ApiError::not_found(format!("No route for {}", uri.path()))
+}
+
 /// List models endpoint (OpenAI compatibility)
-async fn list_models() -> impl IntoResponse {
+async fn list_models<B: ChatBackend>(State(state): State<AppState<B>>) -> impl IntoResponse {
+    let data: Vec<_> = state
+        .models()
+        .iter()
+        .map(|model| {
+            serde_json::json!({
+                "id": model.id,
+                "object": "model",
+                "created": model.created,
+                "owned_by": model.owned_by
+            })
+        })
+        .collect();
+
     Json(serde_json::json!({
         "object": "list",
-        "data": [
-            {
-                "id": "gpt-4",
-                "object": "model",
-                "created": 1677610602,
-                "owned_by": "openai"
-            },
-            {
-                "id": "gpt-4o",
-                "object": "model",
-                "created": 1715367049,
-                "owned_by": "openai"
-            }
-        ]
+        "data": data
     }))
 }
 
 /// Run the API server with the provided host and port.
 pub async fn run(host: &str, port: u16, default_proxy: Option<String>) -> ChatGptResult<()> {
+    serve(AppState::new(default_proxy), host, port).await
+}
+
+/// How long connections get to deliver their last events once every
+/// response has finished
+const CLOSE_GRACE: Duration = Duration::from_secs(5);
+
+/// Run the API server on top of an existing state, whatever its backend,
+/// until SIGINT or SIGTERM
+pub async fn serve<B: ChatBackend>(state: AppState<B>, host: &str, port: u16) -> ChatGptResult<()> {
     let addr: SocketAddr = format!("{}:{}", host, port)
         .parse()
         .map_err(|err| ChatGptError::configuration(format!("invalid address: {}", err)))?;
 
-    let state = AppState::new(default_proxy);
-    let app = router(state);
-
     let listener = tokio::net::TcpListener::bind(addr).await?;
+    serve_until(state, listener, shutdown_signal()).await
+}
+
+/// Serve on `listener` until `signal` resolves, then shut down gracefully.
+///
+/// Shutting down stops accepting connections and new responses, gives
+/// in-flight responses the state's drain timeout to finish (cancelling the
+/// rest), saves threads to storage and returns.
+pub async fn serve_until<B, F>(state: AppState<B>, listener: TcpListener, signal: F) -> ChatGptResult<()>
+where
+    B: ChatBackend,
+    F: Future<Output = ()> + Send,
+{
     let local_addr = listener.local_addr()?;
+    let app = router(state.clone());
 
     info!("🚀 API server listening on http://{}", local_addr);
     info!("📚 API Endpoints:");
     info!("  Health: GET /health");
-    info!("  Models: GET /v1/models");
-    info!("  Threads: POST /v1/threads, GET /v1/threads");
-    info!("  Thread: GET/DELETE /v1/threads/:thread_id");
-    info!("  Messages: POST/GET /v1/threads/:thread_id/messages");
-    info!("  Response: POST /v1/responses");
+    info!("  Metrics: GET /metrics");
+    if state.grpc() != GrpcMode::Only {
+        info!("  Models: GET /v1/models");
+        info!("  Threads: POST /v1/threads, GET /v1/threads");
+        info!("  Thread: GET/DELETE /v1/threads/:thread_id");
+        info!("  Export/Import: GET /v1/threads/:thread_id/export, POST /v1/threads/import, POST /v1/threads/import/chatgpt");
+        info!("  Messages: POST/GET /v1/threads/:thread_id/messages");
+        info!("  Response: POST /v1/responses");
+        info!("  Cancel: POST /v1/responses/:response_id/cancel");
+        info!("  Webhook: GET /v1/responses/:response_id/webhook, POST .../webhook/redeliver");
+        info!("  WebSocket: GET /v1/ws");
+        info!("  Messages (Anthropic): POST /v1/messages");
+        info!("  Ollama: POST /api/chat, POST /api/generate, GET /api/tags");
+    }
+    if state.grpc() != GrpcMode::Off {
+        info!("  gRPC: chatgpt.v1.ChatGpt (proto/chatgpt.proto)");
+    }
+
+    let flusher = state.store().map(|store| {
+        info!("💾 Saving threads to {}", store.path().display());
+        tokio::spawn(flush_periodically(state.clone(), store.flush_interval()))
+    });
+
+    let stop_accepting = CancellationToken::new();
+    let mut server = tokio::spawn(
+        axum::serve(listener, app)
+            .with_graceful_shutdown(stop_accepting.clone().cancelled_owned())
+            .into_future(),
+    );
+
+    let served = tokio::select! {
+        served = &mut server => Some(served),
+        _ = signal => None,
+    };
 
-    axum::serve(listener, app).await?;
+    let served = match served {
+        Some(served) => served,
+        None => {
+            info!("Shutting down, no longer accepting requests");
+            stop_accepting.cancel();
+            if state.drain().await {
+                info!("All in-flight responses finished");
+            }
+
+            // Streams end once their response is done; anything still open
+            // after that is an idle or stuck client
+            match tokio::time::timeout(CLOSE_GRACE, &mut server).await {
+                Ok(served) => served,
+                Err(_) => {
+                    warn!("Closing connections still open after shutdown");
+                    server.abort();
+                    Ok(Ok(()))
+                }
+            }
+        }
+    };
+
+    if let Some(flusher) = flusher {
+        flusher.abort();
+    }
+    let flushed = state.flush().await;
+
+    served.map_err(|err| ChatGptError::unknown(format!("Server task failed: {}", err)))??;
+    flushed
+}
+
+/// Resolve on Ctrl-C (SIGINT) or, on Unix, SIGTERM
+pub async fn shutdown_signal() {
+    let interrupt = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                error!("Cannot listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
 
-    Ok(())
+/// Write changed threads to storage every `interval`
+async fn flush_periodically<B: ChatBackend>(state: AppState<B>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = state.flush().await {
+            error!("Failed to save threads: {}", err);
+        }
+    }
 }
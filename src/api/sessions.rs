@@ -0,0 +1,59 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Idle sessions kept unless configured otherwise
+pub const DEFAULT_POOLED_SESSIONS: usize = 4;
+
+/// How long an idle session is reused unless configured otherwise
+pub const DEFAULT_SESSION_IDLE: Duration = Duration::from_secs(5 * 60);
+
+/// Connected backend sessions kept between stateless turns, so each turn
+/// does not pay for a full session bootstrap
+pub struct SessionPool<B> {
+    capacity: usize,
+    idle_timeout: Duration,
+    idle: Arc<Mutex<Vec<(B, Instant)>>>,
+}
+
+impl<B> Clone for SessionPool<B> {
+    fn clone(&self) -> Self {
+        Self {
+            capacity: self.capacity,
+            idle_timeout: self.idle_timeout,
+            idle: self.idle.clone(),
+        }
+    }
+}
+
+impl<B> Default for SessionPool<B> {
+    fn default() -> Self {
+        Self::new(DEFAULT_POOLED_SESSIONS, DEFAULT_SESSION_IDLE)
+    }
+}
+
+impl<B> SessionPool<B> {
+    /// Keep up to `capacity` sessions, each for `idle_timeout` after its last
+    /// turn. A capacity of 0 disables pooling.
+    pub fn new(capacity: usize, idle_timeout: Duration) -> Self {
+        Self {
+            capacity,
+            idle_timeout,
+            idle: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// The most recently used session that has not been idle too long
+    pub fn take(&self) -> Option<B> {
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|(_, since)| since.elapsed() < self.idle_timeout);
+        idle.pop().map(|(session, _)| session)
+    }
+
+    /// Keep `session` for a later turn, unless the pool is full
+    pub fn put(&self, session: B) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.capacity {
+            idle.push((session, Instant::now()));
+        }
+    }
+}
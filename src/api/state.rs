@@ -1,38 +1,130 @@
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{error, info};
+use std::time::{Duration, Instant};
+use axum::http::StatusCode;
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock, broadcast};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{Instrument, error, info, warn};
 
-use crate::client::ChatGptClient;
+use crate::backend::ChatBackend;
+use crate::client::{ChatGptClient, ClientConfig, ConversationPosition};
+use crate::utils::{ChatGptError, Result as ChatGptResult};
+use super::config::{
+    GrpcMode, LimitsConfig, ModelConfig, ServerConfig, StorageKind, WebhookConfig, default_models,
+};
+use super::chatgpt_import::{self, Branch};
 use super::error::ApiError;
-use super::types::ThreadMessage;
+use super::idempotency::IdempotencyStore;
+use super::metrics::Metrics;
+use super::sessions::SessionPool;
+use super::storage::{StoredThread, ThreadStore};
+use super::types::{ThreadEvent, ThreadMessage};
+use super::webhooks::Webhooks;
+
+/// What [`AppState::import_branches`] did
+pub struct BranchImport<B> {
+    pub created: Vec<(String, ThreadState<B>)>,
+    /// Threads of an earlier import that got the newer messages
+    pub updated: Vec<(String, ThreadState<B>)>,
+    /// Branches imported before and unchanged since
+    pub skipped: usize,
+}
 
 /// Thread state - manages conversation context
-#[derive(Clone)]
-pub struct ThreadState {
-    pub client: Arc<RwLock<ChatGptClient>>,
+pub struct ThreadState<B = ChatGptClient> {
+    /// Backend session. Threads restored from storage open theirs on first use.
+    pub client: Arc<RwLock<Option<B>>>,
     pub messages: Vec<ThreadMessage>,
     pub created_at: u64,
     pub metadata: Option<serde_json::Value>,
+    /// Proxy the thread was created with, if it brought its own
+    pub proxy: Option<String>,
+    /// Where the upstream conversation stood after the last turn
+    pub conversation: Option<ConversationPosition>,
+    /// Held for the duration of a response so only one runs per thread
+    run_lock: Arc<Mutex<()>>,
+}
+
+impl<B> Clone for ThreadState<B> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            messages: self.messages.clone(),
+            created_at: self.created_at,
+            metadata: self.metadata.clone(),
+            proxy: self.proxy.clone(),
+            conversation: self.conversation.clone(),
+            run_lock: self.run_lock.clone(),
+        }
+    }
 }
 
-impl ThreadState {
+impl<B> ThreadState<B> {
     pub fn new(
-        client: Arc<RwLock<ChatGptClient>>,
+        client: Option<B>,
+        proxy: Option<String>,
         metadata: Option<serde_json::Value>,
     ) -> Self {
         Self {
-            client,
+            client: Arc::new(RwLock::new(client)),
             messages: Vec::new(),
             created_at: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
                 .as_secs(),
             metadata,
+            proxy,
+            conversation: None,
+            run_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// A thread saved by an earlier run, without a backend session yet
+    pub fn restore(stored: StoredThread) -> Self {
+        Self {
+            messages: stored.messages,
+            created_at: stored.created_at,
+            conversation: stored.conversation,
+            ..Self::new(None, stored.proxy, stored.metadata)
         }
     }
 
+    /// Snapshot for storage
+    pub fn to_stored(&self, id: &str) -> StoredThread {
+        StoredThread {
+            id: id.to_string(),
+            created_at: self.created_at,
+            metadata: self.metadata.clone(),
+            proxy: self.proxy.clone(),
+            conversation: self.conversation.clone(),
+            messages: self.messages.clone(),
+        }
+    }
+
+    /// Claim the thread for a response, failing if one is already running
+    pub fn try_begin_run(&self, thread_id: &str) -> Result<OwnedMutexGuard<()>, ApiError> {
+        self.run_lock
+            .clone()
+            .try_lock_owned()
+            .map_err(|_| ApiError::thread_busy(thread_id))
+    }
+
     pub fn add_message(&mut self, role: String, content: String) {
+        let index = self.messages.len();
+        self.insert_message(index, role, content, None);
+    }
+
+    /// Insert a message at `index`, or at the end if the thread is shorter.
+    /// `status` marks answers that stopped early (e.g. "incomplete").
+    pub fn insert_message(
+        &mut self,
+        index: usize,
+        role: String,
+        content: String,
+        status: Option<String>,
+    ) {
         let message = ThreadMessage {
             role,
             content,
@@ -42,8 +134,10 @@ impl ThreadState {
                     .unwrap()
                     .as_secs(),
             ),
+            status,
+            attachments: Vec::new(),
         };
-        self.messages.push(message);
+        self.messages.insert(index.min(self.messages.len()), message);
     }
 
     pub fn is_new(&self) -> bool {
@@ -57,54 +151,420 @@ impl ThreadState {
 }
 
 /// App state for managing threads (conversations)
-#[derive(Clone)]
-pub struct AppState {
-    threads: Arc<RwLock<HashMap<String, ThreadState>>>,
+///
+/// Each thread gets its own session of backend `B`, opened from the shared
+/// backend config.
+pub struct AppState<B: ChatBackend = ChatGptClient> {
+    threads: Arc<RwLock<HashMap<String, ThreadState<B>>>>,
+    /// Cancellation handles for responses that are still generating
+    responses: Arc<RwLock<HashMap<String, CancellationToken>>>,
+    /// Parent of every response token, cancelled on server shutdown
+    shutdown: CancellationToken,
+    /// Response turns and webhook deliveries still running; closed once the
+    /// server starts draining
+    generations: TaskTracker,
+    /// How long draining waits for `generations` before cancelling them
+    drain_timeout: Duration,
+    idempotency: IdempotencyStore,
+    /// Sessions reused by turns that do not belong to a thread
+    sessions: SessionPool<B>,
+    backend_config: B::Config,
     default_proxy: Option<String>,
+    /// Bearer keys accepted by the API; empty means no authentication
+    api_keys: Arc<Vec<String>>,
+    limits: LimitsConfig,
+    models: Arc<Vec<ModelConfig>>,
+    /// Whether the router also serves, or only serves, gRPC
+    grpc: GrpcMode,
+    /// Where threads are saved, if anywhere
+    store: Option<Arc<ThreadStore>>,
+    metrics: Arc<Metrics>,
+    /// Thread changes, for WebSocket clients following a thread
+    events: broadcast::Sender<ThreadEvent>,
+    webhooks: Webhooks,
+}
+
+/// Thread events a slow subscriber may fall behind by before missing some
+const EVENT_BUFFER: usize = 256;
+
+impl<B: ChatBackend> Clone for AppState<B> {
+    fn clone(&self) -> Self {
+        Self {
+            threads: self.threads.clone(),
+            responses: self.responses.clone(),
+            shutdown: self.shutdown.clone(),
+            generations: self.generations.clone(),
+            drain_timeout: self.drain_timeout,
+            idempotency: self.idempotency.clone(),
+            sessions: self.sessions.clone(),
+            backend_config: self.backend_config.clone(),
+            default_proxy: self.default_proxy.clone(),
+            api_keys: self.api_keys.clone(),
+            limits: self.limits.clone(),
+            models: self.models.clone(),
+            grpc: self.grpc,
+            store: self.store.clone(),
+            metrics: self.metrics.clone(),
+            events: self.events.clone(),
+            webhooks: self.webhooks.clone(),
+        }
+    }
 }
 
 impl AppState {
+    /// State backed by ChatGPT with the default client settings
     pub fn new(default_proxy: Option<String>) -> Self {
+        Self::with_backend_config(ClientConfig::default(), default_proxy)
+    }
+}
+
+impl<B: ChatBackend> AppState<B> {
+    /// State whose threads open backend sessions from `backend_config`
+    pub fn with_backend_config(backend_config: B::Config, default_proxy: Option<String>) -> Self {
+        let shutdown = CancellationToken::new();
+        let generations = TaskTracker::new();
         Self {
             threads: Arc::new(RwLock::new(HashMap::new())),
+            responses: Arc::new(RwLock::new(HashMap::new())),
+            webhooks: Webhooks::default().tracked(generations.clone(), shutdown.clone()),
+            shutdown,
+            generations,
+            drain_timeout: Duration::from_secs(30),
+            idempotency: IdempotencyStore::default(),
+            sessions: SessionPool::default(),
+            backend_config,
             default_proxy,
+            api_keys: Arc::new(Vec::new()),
+            limits: LimitsConfig::default(),
+            models: Arc::new(default_models()),
+            grpc: GrpcMode::Off,
+            store: None,
+            metrics: Arc::new(Metrics::new()),
+            events: broadcast::channel(EVENT_BUFFER).0,
         }
     }
 
+    /// State set up from a validated server config, with threads restored
+    /// from storage if it is configured
+    pub fn from_config(backend_config: B::Config, config: &ServerConfig) -> ChatGptResult<Self> {
+        let mut state = Self::with_backend_config(backend_config, config.upstream.proxy.clone())
+            .with_api_keys(config.auth.api_keys.clone())
+            .with_limits(config.limits.clone())
+            .with_models(config.models.clone())
+            .with_drain_timeout(Duration::from_secs(config.server.drain_timeout_secs))
+            .with_grpc(config.server.grpc)
+            .with_webhooks(config.webhooks.clone())
+            .with_session_pool(
+                config.upstream.pooled_sessions,
+                Duration::from_secs(config.upstream.session_idle_secs),
+            );
+
+        if config.storage.kind == StorageKind::File
+            && let Some(path) = &config.storage.path
+        {
+            let interval = Duration::from_secs(config.storage.flush_interval_secs);
+            state = state.with_store(ThreadStore::new(path, interval))?;
+        }
+        Ok(state)
+    }
+
+    /// Set how long an `Idempotency-Key` keeps mapping to its response
+    pub fn with_idempotency_window(mut self, window: Duration) -> Self {
+        self.idempotency = IdempotencyStore::new(window);
+        self
+    }
+
+    /// Require one of `keys` as a bearer token on API requests
+    pub fn with_api_keys(mut self, keys: Vec<String>) -> Self {
+        self.api_keys = Arc::new(keys);
+        self
+    }
+
+    /// Apply request limits, including the idempotency window
+    pub fn with_limits(mut self, limits: LimitsConfig) -> Self {
+        self.idempotency = IdempotencyStore::new(Duration::from_secs(limits.idempotency_window_secs));
+        self.limits = limits;
+        self
+    }
+
+    /// Set the models served by `/v1/models` and accepted by responses
+    pub fn with_models(mut self, models: Vec<ModelConfig>) -> Self {
+        self.models = Arc::new(models);
+        self
+    }
+
+    /// Set how long shutdown lets in-flight responses finish
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Keep up to `capacity` idle sessions for turns outside threads, each
+    /// for `idle_timeout`. A capacity of 0 connects for every turn.
+    pub fn with_session_pool(mut self, capacity: usize, idle_timeout: Duration) -> Self {
+        self.sessions = SessionPool::new(capacity, idle_timeout);
+        self
+    }
+
+    /// Serve the gRPC service next to the HTTP API, or instead of it
+    pub fn with_grpc(mut self, mode: GrpcMode) -> Self {
+        self.grpc = mode;
+        self
+    }
+
+    /// Report finished responses to webhooks signed and retried as `config` says
+    pub fn with_webhooks(mut self, config: WebhookConfig) -> Self {
+        self.webhooks =
+            Webhooks::new(config).tracked(self.generations.clone(), self.shutdown.clone());
+        self
+    }
+
+    /// Save threads to `store`, starting from the threads it already holds
+    pub fn with_store(mut self, store: ThreadStore) -> ChatGptResult<Self> {
+        let threads = store
+            .load()?
+            .into_iter()
+            .map(|stored| (stored.id.clone(), ThreadState::restore(stored)))
+            .collect::<HashMap<_, _>>();
+        if !threads.is_empty() {
+            info!("Restored {} threads from {}", threads.len(), store.path().display());
+        }
+
+        self.threads = Arc::new(RwLock::new(threads));
+        self.store = Some(Arc::new(store));
+        Ok(self)
+    }
+
+    /// Store of `Idempotency-Key` headers seen on response creation
+    pub fn idempotency(&self) -> &IdempotencyStore {
+        &self.idempotency
+    }
+
+    pub fn api_keys(&self) -> &[String] {
+        &self.api_keys
+    }
+
+    pub fn limits(&self) -> &LimitsConfig {
+        &self.limits
+    }
+
+    pub fn models(&self) -> &[ModelConfig] {
+        &self.models
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn grpc(&self) -> GrpcMode {
+        self.grpc
+    }
+
+    pub fn webhooks(&self) -> &Webhooks {
+        &self.webhooks
+    }
+
+    pub fn store(&self) -> Option<&ThreadStore> {
+        self.store.as_deref()
+    }
+
+    /// Reject messages longer than the configured limit
+    pub fn check_message(&self, content: &str) -> Result<(), ApiError> {
+        match self.limits.max_message_chars {
+            Some(max) if content.chars().count() > max => Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "message_too_long",
+                format!("Message is longer than {} characters", max),
+            )
+            .with_param("content")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Open a backend session, through `proxy` or else the default proxy
+    pub async fn connect(&self, proxy: Option<&str>) -> Result<B, ApiError> {
+        let proxy_to_use = proxy.or(self.default_proxy.as_deref());
+
+        let started = Instant::now();
+        let client = B::connect(&self.backend_config, proxy_to_use).await.map_err(|err| {
+            error!("Failed to open backend session: {}", err);
+            self.metrics.upstream_error(err.code());
+            ApiError::from(err)
+        })?;
+        self.metrics.observe_client_init(started.elapsed());
+        Ok(client)
+    }
+
+    /// A session for a turn outside any thread: an idle one from the pool,
+    /// or a new one through the default proxy
+    pub async fn checkout_session(&self) -> Result<B, ApiError> {
+        match self.sessions.take() {
+            Some(session) => Ok(session),
+            None => self.connect(None).await,
+        }
+    }
+
+    /// Give back a session whose turn went through, for the next turn
+    pub fn release_session(&self, session: B) {
+        self.sessions.put(session);
+    }
+
     /// Create a new thread
     pub async fn create_thread(
         &self,
         initial_messages: Vec<ThreadMessage>,
         metadata: Option<serde_json::Value>,
         proxy: Option<&str>,
-    ) -> Result<(String, ThreadState), ApiError> {
-        // Use request-specific proxy if provided, otherwise use default
-        let proxy_to_use = proxy.or(self.default_proxy.as_deref());
-
-        let client = ChatGptClient::new(proxy_to_use).await.map_err(|err| {
-            error!("Failed to create ChatGPT client: {}", err);
-            ApiError::from(err)
-        })?;
+    ) -> Result<(String, ThreadState<B>), ApiError> {
+        for msg in &initial_messages {
+            self.check_message(&msg.content)?;
+        }
+        self.check_thread_limit(self.threads.read().await.len())?;
 
-        let client_arc = Arc::new(RwLock::new(client));
+        // Use request-specific proxy if provided, otherwise use default
+        let client = self.connect(proxy).await?;
         let thread_id = uuid::Uuid::new_v4().to_string();
 
-        let mut state = ThreadState::new(client_arc, metadata);
-        
+        let mut state = ThreadState::new(Some(client), proxy.map(str::to_string), metadata);
+
         // Add initial messages
         for msg in initial_messages {
             state.add_message(msg.role, msg.content);
         }
 
         let mut threads = self.threads.write().await;
+        self.check_thread_limit(threads.len())?;
         threads.insert(thread_id.clone(), state.clone());
+        self.mark_dirty();
 
         info!("Created new thread: {}", thread_id);
         Ok((thread_id, state))
     }
 
+    /// Add a thread brought in from elsewhere, keeping its messages and
+    /// timestamps as they are. It has no upstream conversation yet, so its
+    /// first response starts a new one.
+    pub async fn import_thread(
+        &self,
+        created_at: u64,
+        metadata: Option<serde_json::Value>,
+        messages: Vec<ThreadMessage>,
+    ) -> Result<(String, ThreadState<B>), ApiError> {
+        for msg in &messages {
+            self.check_message(&msg.content)?;
+        }
+
+        let thread_id = uuid::Uuid::new_v4().to_string();
+        let state = ThreadState::restore(StoredThread {
+            id: thread_id.clone(),
+            created_at,
+            metadata,
+            proxy: None,
+            conversation: None,
+            messages,
+        });
+
+        let mut threads = self.threads.write().await;
+        self.check_thread_limit(threads.len())?;
+        threads.insert(thread_id.clone(), state.clone());
+        self.mark_dirty();
+
+        info!("Imported thread {} with {} messages", thread_id, state.messages.len());
+        Ok((thread_id, state))
+    }
+
+    /// Import ChatGPT `branches`, either all of them or none: a message too
+    /// long or too many new threads rejects the whole import. Branches
+    /// imported before are skipped, and threads whose conversation went on
+    /// get the newer messages (see [`chatgpt_import::plan`]).
+    pub async fn import_branches(&self, branches: Vec<Branch>) -> Result<BranchImport<B>, ApiError> {
+        let mut threads = self.threads.write().await;
+        let plan = chatgpt_import::plan(
+            threads.iter().map(|(id, thread)| (id.clone(), thread.metadata.as_ref())),
+            branches,
+        );
+
+        let new_messages = plan.new.iter().flat_map(|export| &export.messages);
+        let added_messages = plan.extend.iter().flat_map(|extension| &extension.messages);
+        for msg in new_messages.chain(added_messages) {
+            self.check_message(&msg.content)?;
+        }
+        if let Some(max) = self.limits.max_threads
+            && !plan.new.is_empty()
+            && threads.len() + plan.new.len() > max
+        {
+            return Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "thread_limit_reached",
+                format!(
+                    "Importing {} threads would take the server past its limit of {}; delete some first",
+                    plan.new.len(),
+                    max
+                ),
+            ));
+        }
+
+        let mut import = BranchImport {
+            created: Vec::with_capacity(plan.new.len()),
+            updated: Vec::with_capacity(plan.extend.len()),
+            skipped: plan.skipped,
+        };
+        for extension in plan.extend {
+            let thread_id = extension.thread.clone();
+            let Some(thread) = threads.get_mut(&thread_id) else {
+                continue;
+            };
+            let from = thread.messages.len();
+            extension.apply(&mut thread.metadata, &mut thread.messages);
+            for index in from..thread.messages.len() {
+                self.publish(ThreadEvent::Message {
+                    thread_id: thread_id.clone(),
+                    index,
+                    message: thread.messages[index].clone(),
+                    shifted: false,
+                });
+            }
+            import.updated.push((thread_id, thread.clone()));
+        }
+        for export in plan.new {
+            let thread_id = uuid::Uuid::new_v4().to_string();
+            let state = ThreadState::restore(StoredThread {
+                id: thread_id.clone(),
+                created_at: export.created_at.unwrap_or_else(super::export::now),
+                metadata: export.metadata,
+                proxy: None,
+                conversation: None,
+                messages: export.messages,
+            });
+            threads.insert(thread_id.clone(), state.clone());
+            import.created.push((thread_id, state));
+        }
+        if !import.created.is_empty() || !import.updated.is_empty() {
+            self.mark_dirty();
+        }
+
+        info!(
+            "Imported {} threads, updated {}, skipped {} already present",
+            import.created.len(),
+            import.updated.len(),
+            import.skipped
+        );
+        Ok(import)
+    }
+
+    fn check_thread_limit(&self, threads: usize) -> Result<(), ApiError> {
+        match self.limits.max_threads {
+            Some(max) if threads >= max => Err(ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "thread_limit_reached",
+                format!("The server already holds {} threads; delete some first", max),
+            )),
+            _ => Ok(()),
+        }
+    }
+
     /// Get an existing thread
-    pub async fn get_thread(&self, thread_id: &str) -> Result<ThreadState, ApiError> {
+    pub async fn get_thread(&self, thread_id: &str) -> Result<ThreadState<B>, ApiError> {
         let threads = self.threads.read().await;
         threads
             .get(thread_id)
@@ -112,35 +572,73 @@ impl AppState {
             .ok_or_else(|| ApiError::not_found(format!("Thread {} not found", thread_id)))
     }
 
-    /// Update thread state
-    pub async fn update_thread(&self, thread_id: &str, state: ThreadState) -> Result<(), ApiError> {
+    /// Add a message to a thread, returning its index
+    pub async fn add_message_to_thread(
+        &self,
+        thread_id: &str,
+        role: String,
+        content: String,
+    ) -> Result<usize, ApiError> {
+        self.check_message(&content)?;
+
         let mut threads = self.threads.write().await;
-        if threads.contains_key(thread_id) {
-            threads.insert(thread_id.to_string(), state);
-            Ok(())
-        } else {
-            Err(ApiError::not_found(format!("Thread {} not found", thread_id)))
-        }
+        let thread = threads
+            .get_mut(thread_id)
+            .ok_or_else(|| ApiError::not_found(format!("Thread {} not found", thread_id)))?;
+
+        thread.add_message(role, content);
+        let index = thread.messages.len() - 1;
+        self.mark_dirty();
+        self.publish(ThreadEvent::Message {
+            thread_id: thread_id.to_string(),
+            index,
+            message: thread.messages[index].clone(),
+            shifted: false,
+        });
+        Ok(index)
     }
 
-    /// Add a message to a thread
-    pub async fn add_message_to_thread(
+    /// Record an assistant answer on a thread.
+    ///
+    /// The answer is inserted right after the first `position` messages (the ones the
+    /// response was generated from), so it comes before any message added while it
+    /// was generating. Those move down one index, which changes their positional
+    /// ids; the published event is marked `shifted` so subscribers can follow.
+    /// `conversation` is where the upstream conversation stands after the turn.
+    pub async fn record_answer(
         &self,
         thread_id: &str,
-        role: String,
+        position: usize,
         content: String,
+        status: Option<String>,
+        conversation: Option<ConversationPosition>,
     ) -> Result<(), ApiError> {
         let mut threads = self.threads.write().await;
         let thread = threads
             .get_mut(thread_id)
             .ok_or_else(|| ApiError::not_found(format!("Thread {} not found", thread_id)))?;
 
-        thread.add_message(role, content);
+        thread.insert_message(position, "assistant".to_string(), content, status);
+        if conversation.is_some() {
+            thread.conversation = conversation;
+        }
+        let index = position.min(thread.messages.len() - 1);
+        self.mark_dirty();
+        self.publish(ThreadEvent::Message {
+            thread_id: thread_id.to_string(),
+            index,
+            message: thread.messages[index].clone(),
+            shifted: index + 1 < thread.messages.len(),
+        });
         Ok(())
     }
 
     /// List all threads
-    pub async fn list_threads(&self) -> Vec<(String, ThreadState)> {
+    pub async fn thread_count(&self) -> usize {
+        self.threads.read().await.len()
+    }
+
+    pub async fn list_threads(&self) -> Vec<(String, ThreadState<B>)> {
         let threads = self.threads.read().await;
         threads
             .iter()
@@ -154,10 +652,145 @@ impl AppState {
         threads
             .remove(thread_id)
             .ok_or_else(|| ApiError::not_found(format!("Thread {} not found", thread_id)))?;
+        self.mark_dirty();
+        self.metrics.thread_removed("deleted");
+        self.publish(ThreadEvent::Deleted {
+            thread_id: thread_id.to_string(),
+        });
         info!("Deleted thread: {}", thread_id);
         Ok(())
     }
 
+    /// Follow changes to threads
+    pub fn subscribe(&self) -> broadcast::Receiver<ThreadEvent> {
+        self.events.subscribe()
+    }
+
+    fn publish(&self, event: ThreadEvent) {
+        // Nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    fn mark_dirty(&self) {
+        if let Some(store) = &self.store {
+            store.mark_dirty();
+        }
+    }
+
+    /// Write threads to storage if any changed since the last flush
+    pub async fn flush(&self) -> ChatGptResult<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        if !store.take_dirty() {
+            return Ok(());
+        }
+
+        let snapshot: Vec<StoredThread> = {
+            let threads = self.threads.read().await;
+            threads.iter().map(|(id, thread)| thread.to_stored(id)).collect()
+        };
+        let store = store.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let result = store.save(snapshot);
+            if result.is_err() {
+                // Try again on the next flush
+                store.mark_dirty();
+            }
+            result
+        })
+        .await;
+
+        match result {
+            Ok(result) => result,
+            Err(err) => Err(ChatGptError::unknown(format!("Flush task failed: {}", err))),
+        }
+    }
+
+    /// Register an in-flight response and return its cancellation token
+    pub async fn begin_response(&self, response_id: &str) -> CancellationToken {
+        let token = self.shutdown.child_token();
+        let mut responses = self.responses.write().await;
+        responses.insert(response_id.to_string(), token.clone());
+        token
+    }
+
+    /// Forget a response once it has finished generating
+    pub async fn finish_response(&self, response_id: &str) {
+        let mut responses = self.responses.write().await;
+        responses.remove(response_id);
+    }
+
+    /// Cancel an in-flight response
+    pub async fn cancel_response(&self, response_id: &str) -> Result<(), ApiError> {
+        let responses = self.responses.read().await;
+        let token = responses.get(response_id).ok_or_else(|| {
+            ApiError::not_found(format!("Response {} is not in progress", response_id))
+        })?;
+
+        token.cancel();
+        info!("Cancelled response: {}", response_id);
+        Ok(())
+    }
+
+    /// Cancel every in-flight response (used on server shutdown)
+    pub fn cancel_all_responses(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Run a response turn that shutdown waits for
+    pub fn spawn_generation<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.generations.spawn(task.in_current_span())
+    }
+
+    /// Whether the server is draining and takes no new responses
+    pub fn is_shutting_down(&self) -> bool {
+        self.generations.is_closed()
+    }
+
+    /// Refuse new responses if the server is shutting down
+    pub fn check_accepting(&self) -> Result<(), ApiError> {
+        if self.is_shutting_down() {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_shutting_down",
+                "Server is shutting down, retry on another instance",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Stop taking new responses and wait for running ones to finish.
+    ///
+    /// Turns still running after the drain timeout are cancelled; they keep
+    /// what they streamed so far as an incomplete answer. Webhook deliveries
+    /// are waited for too, but stop retrying at the timeout. Returns whether
+    /// every task finished on its own.
+    pub async fn drain(&self) -> bool {
+        self.generations.close();
+        if self.generations.is_empty() {
+            return true;
+        }
+
+        info!(
+            "Waiting up to {}s for {} in-flight responses and webhook deliveries",
+            self.drain_timeout.as_secs(),
+            self.generations.len()
+        );
+        if tokio::time::timeout(self.drain_timeout, self.generations.wait()).await.is_ok() {
+            return true;
+        }
+
+        warn!("Cancelling {} responses still running after the drain timeout", self.generations.len());
+        self.cancel_all_responses();
+        self.generations.wait().await;
+        false
+    }
+
     /// Get the default proxy setting
     pub fn get_default_proxy(&self) -> Option<&str> {
         self.default_proxy.as_deref()
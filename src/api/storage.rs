@@ -0,0 +1,98 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::types::ThreadMessage;
+use crate::client::ConversationPosition;
+use crate::utils::{ChatGptError, Result};
+
+/// A thread as written to disk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredThread {
+    pub id: String,
+    pub created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    /// Proxy the thread was created with, if it brought its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// Where the upstream conversation stands, so it can be continued
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation: Option<ConversationPosition>,
+    pub messages: Vec<ThreadMessage>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoreFile {
+    threads: Vec<StoredThread>,
+}
+
+/// Threads saved as one JSON file, rewritten whenever something changed
+#[derive(Debug)]
+pub struct ThreadStore {
+    path: PathBuf,
+    flush_interval: Duration,
+    dirty: AtomicBool,
+    /// Held while the file is rewritten so saves never interleave
+    writing: Mutex<()>,
+}
+
+impl ThreadStore {
+    pub fn new(path: impl Into<PathBuf>, flush_interval: Duration) -> Self {
+        Self {
+            path: path.into(),
+            flush_interval,
+            dirty: AtomicBool::new(false),
+            writing: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+
+    /// Threads saved by an earlier run; none if the file does not exist yet
+    pub fn load(&self) -> Result<Vec<StoredThread>> {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let file: StoreFile = serde_json::from_str(&text).map_err(|err| {
+            ChatGptError::configuration(format!("{}: {}", self.path.display(), err))
+        })?;
+        Ok(file.threads)
+    }
+
+    /// Replace the file with `threads`. The new contents are written next to
+    /// it first, so a crash mid-write leaves the previous file intact.
+    pub fn save(&self, threads: Vec<StoredThread>) -> Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&StoreFile { threads })?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// Note that threads changed since the last save
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Whether threads changed since the last call
+    pub fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Ordering::AcqRel)
+    }
+}
@@ -5,18 +5,24 @@ use serde::{Deserialize, Serialize};
 pub struct CreateResponseRequest {
     /// The thread ID to create a response for
     pub thread_id: String,
-    /// The model to use
-    #[serde(default = "default_model")]
-    pub model: String,
-    /// Optional instructions for the assistant
+    /// The model to use; the first configured model when left out
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Optional instructions for the assistant. Accepted for compatibility;
+    /// the web backend has no system prompt to put them in.
     #[serde(default)]
     pub instructions: Option<String>,
     /// Whether to stream the response
     #[serde(default)]
     pub stream: bool,
-    /// Optional proxy configuration
+    /// Optional proxy configuration. Accepted for compatibility; a thread
+    /// keeps the proxy it was created with.
     #[serde(default)]
     pub proxy: Option<String>,
+    /// Where to POST an event once the response has finished; the server's
+    /// configured webhook when left out
+    #[serde(default)]
+    pub webhook_url: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -40,7 +46,7 @@ pub struct AddMessageRequest {
     pub content: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct ThreadMessage {
     /// The role of the message sender (user or assistant)
     pub role: String,
@@ -49,6 +55,49 @@ pub struct ThreadMessage {
     /// When the message was created
     #[serde(skip_serializing_if = "Option::is_none")]
     pub created_at: Option<u64>,
+    /// Set to "incomplete" when generation stopped before the answer finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// Files that came with the message, by reference only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+}
+
+/// Reference to a file sent with a message; the file itself is not kept
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Attachment {
+    /// ID the file had where the message came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    /// Where the file can be fetched, if anywhere
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+/// Change to a thread, passed on to WebSocket clients that have it selected
+#[derive(Debug, Clone)]
+pub enum ThreadEvent {
+    /// A message was added at position `index`
+    Message {
+        thread_id: String,
+        index: usize,
+        message: ThreadMessage,
+        /// The message was inserted before others, which moved down one place
+        shifted: bool,
+    },
+    Deleted { thread_id: String },
+}
+
+impl ThreadEvent {
+    pub fn thread_id(&self) -> &str {
+        match self {
+            Self::Message { thread_id, .. } | Self::Deleted { thread_id } => thread_id,
+        }
+    }
 }
 
 // Response types
@@ -68,6 +117,8 @@ pub struct Message {
     pub thread_id: String,
     pub role: String,
     pub content: Vec<ContentPart>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -102,6 +153,9 @@ pub struct ResponseChunk {
     pub created_at: u64,
     pub thread_id: String,
     pub delta: Delta,
+    /// Final status ("completed" or "cancelled"), set on the last chunk only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -121,8 +175,16 @@ pub struct Usage {
 
 #[derive(Debug, Serialize)]
 pub struct ErrorResponse {
-    pub status: &'static str,
-    pub detail: String,
+    pub error: ErrorBody,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: &'static str,
+    pub code: &'static str,
+    pub param: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -138,7 +200,3 @@ pub struct ListMessagesResponse {
     pub data: Vec<Message>,
     pub has_more: bool,
 }
-
-fn default_model() -> String {
-    "gpt-4".to_string()
-}
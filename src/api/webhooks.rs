@@ -0,0 +1,381 @@
+//! Webhook callbacks for finished responses.
+//!
+//! When a response completes, fails or is cancelled, an event is POSTed to
+//! its webhook URL, signed with the configured secret:
+//!
+//! - `webhook-id`: the event ID, the same on every attempt
+//! - `webhook-timestamp`: Unix seconds of the attempt
+//! - `webhook-signature`: `v1=` and the hex HMAC-SHA256 of
+//!   `"{timestamp}.{body}"`, see [`sign`]
+//!
+//! Deliveries that fail or get a non-2xx answer are retried with exponential
+//! backoff. Every attempt is kept in a per-response delivery log, from which
+//! an event can also be sent again.
+//!
+//! A request may only name its own URL if the server config allows it, so the
+//! API cannot be used to make the server call arbitrary addresses.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::http::StatusCode;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::{Value, json};
+use sha2::Sha256;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{Instrument, info, warn};
+
+use super::config::WebhookConfig;
+use super::error::ApiError;
+use super::types::Response;
+use crate::utils::redact::scrub;
+
+/// Delivery logs kept before the oldest are dropped
+const MAX_LOGS: usize = 10_000;
+
+/// `webhook-signature` of an event `body` sent at `timestamp`
+pub fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("v1={}", digest)
+}
+
+/// The event for a finished response; `status` is its final status and
+/// names the event type, e.g. `response.completed`
+pub(super) fn response_event(response: Response, output_text: Option<&str>, error: Option<&ApiError>) -> Value {
+    let event_type = format!("response.{}", response.status);
+    let mut data = serde_json::to_value(response).expect("response serializes");
+    if let Some(text) = output_text {
+        data["output_text"] = json!(text);
+    }
+    if let Some(error) = error {
+        data["error"] = json!(error.body().error);
+    }
+
+    json!({
+        "id": format!("evt_{}", uuid::Uuid::new_v4().simple()),
+        "object": "event",
+        "type": event_type,
+        "created_at": now(),
+        "data": data,
+    })
+}
+
+/// Where a response's event stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Being sent, or waiting to be retried
+    Pending,
+    Delivered,
+    /// Every attempt failed; it can still be redelivered
+    Failed,
+}
+
+/// One try at sending an event
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryAttempt {
+    /// Counts across redeliveries, starting at 1
+    pub attempt: u32,
+    pub created_at: u64,
+    /// HTTP status the receiver answered with, if it answered
+    pub status_code: Option<u16>,
+    /// Why the attempt failed, if it did
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// Delivery log of one response, as served by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryLog {
+    pub object: &'static str,
+    pub response_id: String,
+    pub url: String,
+    pub event_id: String,
+    pub event_type: String,
+    pub status: DeliveryStatus,
+    pub deliveries: Vec<DeliveryAttempt>,
+    #[serde(skip)]
+    event: Value,
+}
+
+#[derive(Default)]
+struct Logs {
+    by_response: HashMap<String, DeliveryLog>,
+    /// Response IDs, oldest first
+    order: VecDeque<String>,
+}
+
+/// Sends response events and keeps their delivery logs
+#[derive(Clone)]
+pub struct Webhooks {
+    config: Arc<WebhookConfig>,
+    http: reqwest::Client,
+    logs: Arc<Mutex<Logs>>,
+    /// Deliveries in progress, so shutdown can wait for them
+    tasks: TaskTracker,
+    /// Cancelled when shutdown stops waiting; pending retries are then dropped
+    shutdown: CancellationToken,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Self::new(WebhookConfig::default())
+    }
+}
+
+impl Webhooks {
+    pub fn new(config: WebhookConfig) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs.max(1)))
+            .build()
+            .expect("webhook HTTP client");
+        Self {
+            config: Arc::new(config),
+            http,
+            logs: Arc::default(),
+            tasks: TaskTracker::new(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Run deliveries on `tasks` and give up retrying once `shutdown` is cancelled
+    pub fn tracked(mut self, tasks: TaskTracker, shutdown: CancellationToken) -> Self {
+        self.tasks = tasks;
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// The URL a response reports to: the one its request named, else the
+    /// configured one
+    pub fn resolve(&self, requested: Option<String>) -> Result<Option<String>, ApiError> {
+        let Some(url) = requested else {
+            return Ok(self.config.url.clone());
+        };
+        if self.config.secret.is_none() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "webhooks_disabled",
+                "Webhooks need a signing secret in the server config (webhooks.secret)",
+            )
+            .with_param("webhook_url"));
+        }
+        let parsed = match url::Url::parse(&url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => parsed,
+            _ => {
+                return Err(ApiError::bad_request(format!("{:?} is not an http(s) URL", url))
+                    .with_param("webhook_url"));
+            }
+        };
+        let allowed = self.config.url.as_deref() == Some(url.as_str())
+            || self
+                .config
+                .allowed_urls
+                .iter()
+                .any(|prefix| under_prefix(&parsed, prefix));
+        if !allowed {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "webhook_url_not_allowed",
+                "webhook_url is not allowed by the server config (webhooks.allowed_urls)",
+            )
+            .with_param("webhook_url"));
+        }
+        Ok(Some(url))
+    }
+
+    /// Send `event` about `response_id` to `url` in the background
+    pub fn deliver(&self, response_id: &str, url: String, event: Value) {
+        let log = DeliveryLog {
+            object: "response.webhook",
+            response_id: response_id.to_string(),
+            url,
+            event_id: event["id"].as_str().unwrap_or_default().to_string(),
+            event_type: event["type"].as_str().unwrap_or_default().to_string(),
+            status: DeliveryStatus::Pending,
+            deliveries: Vec::new(),
+            event,
+        };
+        {
+            let mut logs = self.logs.lock().unwrap();
+            if logs.by_response.insert(response_id.to_string(), log).is_none() {
+                logs.order.push_back(response_id.to_string());
+            }
+            while logs.order.len() > MAX_LOGS {
+                if let Some(oldest) = logs.order.pop_front() {
+                    logs.by_response.remove(&oldest);
+                }
+            }
+        }
+        self.spawn(response_id.to_string());
+    }
+
+    /// Send a response's event again, with a fresh round of attempts
+    pub fn redeliver(&self, response_id: &str) -> Result<DeliveryLog, ApiError> {
+        let log = {
+            let mut logs = self.logs.lock().unwrap();
+            let log = logs.by_response.get_mut(response_id).ok_or_else(|| {
+                ApiError::not_found(format!("Response {} has no webhook deliveries", response_id))
+            })?;
+            if log.status == DeliveryStatus::Pending {
+                return Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    "delivery_in_progress",
+                    format!("The webhook for response {} is still being delivered", response_id),
+                ));
+            }
+            log.status = DeliveryStatus::Pending;
+            log.clone()
+        };
+        self.spawn(response_id.to_string());
+        Ok(log)
+    }
+
+    /// Delivery log of a response that has a webhook
+    pub fn log(&self, response_id: &str) -> Option<DeliveryLog> {
+        self.logs.lock().unwrap().by_response.get(response_id).cloned()
+    }
+
+    fn spawn(&self, response_id: String) {
+        let webhooks = self.clone();
+        self.tasks
+            .spawn(async move { webhooks.send(&response_id).await }.in_current_span());
+    }
+
+    /// Try until the receiver accepts the event or the attempts run out
+    async fn send(&self, response_id: &str) {
+        let Some((url, event, first)) = self.logs.lock().unwrap().by_response.get(response_id).map(|log| {
+            (log.url.clone(), log.event.clone(), log.deliveries.len() as u32 + 1)
+        }) else {
+            return;
+        };
+        let secret = self.config.secret.as_ref().map(|s| s.expose().as_str()).unwrap_or_default();
+        let event_id = event["id"].as_str().unwrap_or_default().to_string();
+        let body = event.to_string();
+
+        let max_attempts = self.config.max_attempts.max(1);
+        for attempt in 0..max_attempts {
+            let timestamp = now();
+            let started = Instant::now();
+            let result = self
+                .http
+                .post(&url)
+                .header("content-type", "application/json")
+                .header("webhook-id", &event_id)
+                .header("webhook-timestamp", timestamp.to_string())
+                .header("webhook-signature", sign(secret, timestamp, &body))
+                .body(body.clone())
+                .send()
+                .await;
+
+            let (status_code, error) = match result {
+                Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+                Ok(response) => (
+                    Some(response.status().as_u16()),
+                    Some(format!("Receiver answered {}", response.status())),
+                ),
+                Err(err) => (None, Some(scrub(&err.to_string()).into_owned())),
+            };
+            let delivered = error.is_none();
+            if let Some(error) = &error {
+                warn!("Webhook for response {} to {} failed: {}", response_id, scrub(&url), error);
+            }
+
+            let last = attempt + 1 == max_attempts;
+            if !self.record(
+                response_id,
+                DeliveryAttempt {
+                    attempt: first + attempt,
+                    created_at: timestamp,
+                    status_code,
+                    error,
+                    duration_ms: started.elapsed().as_millis() as u64,
+                },
+                delivered,
+                last,
+            ) {
+                // The log was dropped to make room for newer ones
+                return;
+            }
+            if delivered {
+                info!("Webhook for response {} delivered", response_id);
+                return;
+            }
+            if !last {
+                tokio::select! {
+                    _ = tokio::time::sleep(self.backoff(attempt)) => {}
+                    _ = self.shutdown.cancelled() => {
+                        warn!("Dropping the webhook for response {}: server is shutting down", response_id);
+                        self.give_up(response_id);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Mark a delivery failed without further attempts
+    fn give_up(&self, response_id: &str) {
+        if let Some(log) = self.logs.lock().unwrap().by_response.get_mut(response_id) {
+            log.status = DeliveryStatus::Failed;
+        }
+    }
+
+    /// Add an attempt to the log; returns whether the log is still there
+    fn record(&self, response_id: &str, attempt: DeliveryAttempt, delivered: bool, last: bool) -> bool {
+        let mut logs = self.logs.lock().unwrap();
+        let Some(log) = logs.by_response.get_mut(response_id) else {
+            return false;
+        };
+        log.deliveries.push(attempt);
+        if delivered {
+            log.status = DeliveryStatus::Delivered;
+        } else if last {
+            log.status = DeliveryStatus::Failed;
+        }
+        true
+    }
+
+    /// Wait after the failed attempt `attempt` (0-based)
+    fn backoff(&self, attempt: u32) -> Duration {
+        let initial = Duration::from_millis(self.config.initial_backoff_ms);
+        let max = Duration::from_millis(self.config.max_backoff_ms);
+        initial.saturating_mul(2u32.saturating_pow(attempt)).min(max)
+    }
+}
+
+/// Whether `url` is on the scheme, host and port of `prefix`, under its path
+fn under_prefix(url: &url::Url, prefix: &str) -> bool {
+    let Ok(prefix) = url::Url::parse(prefix) else {
+        return false;
+    };
+    if url.scheme() != prefix.scheme()
+        || url.host_str() != prefix.host_str()
+        || url.port_or_known_default() != prefix.port_or_known_default()
+    {
+        return false;
+    }
+    let (path, base) = (url.path(), prefix.path());
+    // `/hooks` allows `/hooks/a` but not `/hooks-admin`
+    path == base
+        || path
+            .strip_prefix(base)
+            .is_some_and(|rest| base.ends_with('/') || rest.starts_with('/'))
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
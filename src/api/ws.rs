@@ -0,0 +1,362 @@
+//! WebSocket chat on `/v1/ws`.
+//!
+//! One connection can create and select threads, send messages, stream the
+//! answers and cancel them, all as JSON text frames. Turns go through the
+//! same generation path as `/v1/responses`, and changes to the selected
+//! thread (including ones made over REST) are pushed as thread events.
+
+use axum::extract::State;
+use axum::extract::ws::{Message as Frame, WebSocket, WebSocketUpgrade};
+use axum::response::Response;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, info, warn};
+
+use super::error::ApiError;
+use super::handlers::{
+    message_object, message_objects, prepare_generation, response_object, thread_object,
+};
+use super::state::AppState;
+use super::types::{
+    ErrorBody, Message, Response as ResponseObject, Thread, ThreadEvent, ThreadMessage,
+};
+use crate::backend::ChatBackend;
+
+/// Route of the WebSocket endpoint
+pub const PATH: &str = "/v1/ws";
+
+/// Frames from the client
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum Command {
+    /// Create a thread and select it
+    #[serde(rename = "thread.create")]
+    CreateThread {
+        #[serde(default)]
+        messages: Vec<ThreadMessage>,
+        #[serde(default)]
+        metadata: Option<serde_json::Value>,
+        #[serde(default)]
+        proxy: Option<String>,
+    },
+    /// Select a thread, receiving its messages and from then on its events
+    #[serde(rename = "thread.select")]
+    SelectThread { thread_id: String },
+    /// Add a user message to a thread (the selected one by default) and
+    /// generate the answer
+    #[serde(rename = "message.send")]
+    SendMessage {
+        content: String,
+        #[serde(default)]
+        thread_id: Option<String>,
+        #[serde(default)]
+        model: Option<String>,
+    },
+    /// Cancel a response, by default the last one started on this connection
+    #[serde(rename = "response.cancel")]
+    CancelResponse {
+        #[serde(default)]
+        response_id: Option<String>,
+    },
+}
+
+/// Frames to the client
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+enum Event {
+    #[serde(rename = "thread.created")]
+    ThreadCreated { thread: Thread },
+    #[serde(rename = "thread.selected")]
+    ThreadSelected {
+        thread: Thread,
+        messages: Vec<Message>,
+    },
+    #[serde(rename = "response.created")]
+    ResponseCreated {
+        response_id: String,
+        thread_id: String,
+    },
+    #[serde(rename = "response.delta")]
+    ResponseDelta { response_id: String, delta: String },
+    /// The turn is over; `response.status` is `completed` or `cancelled`
+    #[serde(rename = "response.completed")]
+    ResponseCompleted { response: ResponseObject },
+    /// A message was added to the selected thread, by anyone
+    #[serde(rename = "thread.message")]
+    ThreadMessage {
+        message: Message,
+        /// Messages from this one's index on moved down one place, so their
+        /// ids changed
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        shifted: bool,
+    },
+    #[serde(rename = "thread.deleted")]
+    ThreadDeleted { thread_id: String },
+    #[serde(rename = "error")]
+    Error {
+        /// Set when a response failed
+        #[serde(skip_serializing_if = "Option::is_none")]
+        response_id: Option<String>,
+        error: ErrorBody,
+    },
+}
+
+impl Event {
+    fn error(response_id: Option<String>, err: ApiError) -> Self {
+        Self::Error {
+            response_id,
+            error: err.body().error,
+        }
+    }
+}
+
+fn frame(event: &impl Serialize) -> Frame {
+    Frame::Text(serde_json::to_string(event).unwrap().into())
+}
+
+/// Upgrade to a WebSocket chat connection
+pub async fn connect<B: ChatBackend>(
+    State(state): State<AppState<B>>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| serve(state, socket).in_current_span())
+}
+
+async fn serve<B: ChatBackend>(state: AppState<B>, socket: WebSocket) {
+    let (mut sink, mut stream) = socket.split();
+    let (out, mut outgoing) = mpsc::channel::<Frame>(100);
+
+    // Frames from every response of the connection funnel through one writer
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = outgoing.recv().await {
+            if sink.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut events = state.subscribe();
+    let mut connection = Connection {
+        state,
+        out,
+        selected: None,
+        last_response: None,
+        closed: CancellationToken::new(),
+    };
+    // Responses still generating stop when the client goes away
+    let _closed = connection.closed.clone().drop_guard();
+
+    loop {
+        tokio::select! {
+            incoming = stream.next() => match incoming {
+                Some(Ok(Frame::Text(text))) => connection.handle(&text).await,
+                Some(Ok(Frame::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum; binary frames are not part of the protocol
+                Some(Ok(_)) => {}
+            },
+            event = events.recv() => match event {
+                Ok(event) if Some(event.thread_id()) == connection.selected.as_deref() => {
+                    connection.follow(event).await;
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("WebSocket client missed {} thread events", missed);
+                }
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+
+    writer.abort();
+}
+
+/// What one WebSocket connection is doing
+struct Connection<B: ChatBackend> {
+    state: AppState<B>,
+    out: mpsc::Sender<Frame>,
+    selected: Option<String>,
+    last_response: Option<String>,
+    /// Cancelled when the connection ends
+    closed: CancellationToken,
+}
+
+impl<B: ChatBackend> Connection<B> {
+    async fn send(&self, event: &impl Serialize) {
+        let _ = self.out.send(frame(event)).await;
+    }
+
+    /// Pass on a change to the selected thread
+    async fn follow(&mut self, event: ThreadEvent) {
+        let event = match event {
+            ThreadEvent::Message {
+                thread_id,
+                index,
+                message,
+                shifted,
+            } => {
+                let created_at = message.created_at.unwrap_or_default();
+                Event::ThreadMessage {
+                    message: message_object(&thread_id, index, &message, created_at),
+                    shifted,
+                }
+            }
+            ThreadEvent::Deleted { thread_id } => {
+                self.selected = None;
+                Event::ThreadDeleted { thread_id }
+            }
+        };
+        self.send(&event).await;
+    }
+
+    async fn handle(&mut self, text: &str) {
+        let result = match serde_json::from_str::<Command>(text) {
+            Ok(command) => self.run(command).await,
+            Err(err) => Err(ApiError::bad_request(format!("Invalid command: {}", err))),
+        };
+        if let Err(err) = result {
+            self.send(&Event::error(None, err)).await;
+        }
+    }
+
+    async fn run(&mut self, command: Command) -> Result<(), ApiError> {
+        match command {
+            Command::CreateThread {
+                messages,
+                metadata,
+                proxy,
+            } => {
+                let (thread_id, thread) = self
+                    .state
+                    .create_thread(messages, metadata, proxy.as_deref())
+                    .await?;
+                self.selected = Some(thread_id.clone());
+                self.send(&Event::ThreadCreated {
+                    thread: thread_object(thread_id, &thread),
+                })
+                .await;
+            }
+            Command::SelectThread { thread_id } => {
+                let thread = self.state.get_thread(&thread_id).await?;
+                self.selected = Some(thread_id.clone());
+                self.send(&Event::ThreadSelected {
+                    messages: message_objects(&thread_id, &thread),
+                    thread: thread_object(thread_id, &thread),
+                })
+                .await;
+            }
+            Command::SendMessage {
+                content,
+                thread_id,
+                model,
+            } => {
+                let thread_id = thread_id.or_else(|| self.selected.clone()).ok_or_else(|| {
+                    ApiError::bad_request(
+                        "No thread selected; send thread.create or thread.select first",
+                    )
+                    .with_param("thread_id")
+                })?;
+                self.send_message(thread_id, content, model)
+                    .await?;
+            }
+            Command::CancelResponse { response_id } => {
+                let response_id = response_id
+                    .or_else(|| self.last_response.clone())
+                    .ok_or_else(|| ApiError::not_found("No response started on this connection"))?;
+                self.state.cancel_response(&response_id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_message(
+        &mut self,
+        thread_id: String,
+        content: String,
+        model: Option<String>,
+    ) -> Result<(), ApiError> {
+        self.state.check_accepting()?;
+        if content.trim().is_empty() {
+            return Err(
+                ApiError::bad_request("Message content cannot be empty").with_param("content")
+            );
+        }
+
+        // Claim the thread before adding the message, so a busy thread is left as it was
+        let run_guard = self
+            .state
+            .get_thread(&thread_id)
+            .await?
+            .try_begin_run(&thread_id)?;
+        self.state
+            .add_message_to_thread(&thread_id, "user".to_string(), content)
+            .await?;
+        let generation = prepare_generation(
+            self.state.clone(),
+            thread_id.clone(),
+            run_guard,
+            model,
+            None,
+        )
+        .await?;
+
+        let response_id = generation.response_id.clone();
+        let cancel = generation.cancel.clone();
+        info!("Generating response {} over WebSocket", response_id);
+        self.last_response = Some(response_id.clone());
+        self.send(&Event::ResponseCreated {
+            response_id: response_id.clone(),
+            thread_id,
+        })
+        .await;
+
+        let state = self.state.clone();
+        let out = self.out.clone();
+        let closed = self.closed.clone();
+
+        // Relay deltas while the connection keeps taking other commands
+        tokio::spawn(
+            async move {
+                let (delta_tx, mut delta_rx) = mpsc::unbounded_channel();
+                let task = state.spawn_generation(generation.run(Some(delta_tx)));
+
+                loop {
+                    let delta = tokio::select! {
+                        delta = delta_rx.recv() => delta,
+                        _ = closed.cancelled() => {
+                            cancel.cancel();
+                            break;
+                        }
+                    };
+                    let Some(delta) = delta else { break };
+                    let bytes = delta.len();
+                    let event = Event::ResponseDelta {
+                        response_id: response_id.clone(),
+                        delta,
+                    };
+                    if out.send(frame(&event)).await.is_err() {
+                        cancel.cancel();
+                        break;
+                    }
+                    state.metrics().add_streamed_bytes(bytes);
+                }
+
+                let event = match task.await {
+                    Ok(Ok(completed)) => Event::ResponseCompleted {
+                        response: response_object(&completed),
+                    },
+                    Ok(Err(err)) => Event::error(Some(response_id), err),
+                    Err(err) => Event::error(
+                        Some(response_id),
+                        ApiError::internal_error(format!("Response task failed: {}", err)),
+                    ),
+                };
+                let _ = out.send(frame(&event)).await;
+            }
+            .in_current_span(),
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use super::{ChatBackend, DeltaSender};
+use crate::client::{ChatGptClient, ChatGptClientBuilder, ClientConfig, ConversationPosition};
+use crate::utils::{ChatGptError, Result};
+use tokio_util::sync::CancellationToken;
+
+impl ChatBackend for ChatGptClient {
+    type Config = ClientConfig;
+
+    async fn connect(config: &ClientConfig, proxy: Option<&str>) -> Result<Self> {
+        let mut builder = ChatGptClientBuilder::from_config(config.clone());
+        if proxy.is_some() {
+            builder = builder.proxy(proxy);
+        }
+        builder.build().await
+    }
+
+    async fn start_conversation(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        match self.pending_attachment.take() {
+            Some(image) => {
+                self.start_with_image_streaming(message, &image, deltas, cancel)
+                    .await
+            }
+            None => {
+                self.start_conversation_streaming(message, deltas, cancel)
+                    .await
+            }
+        }
+    }
+
+    async fn continue_conversation(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        // Upstream only takes images on the first message of a conversation.
+        // Starting over would quietly drop everything said so far.
+        if self.pending_attachment.take().is_some() {
+            return Err(ChatGptError::invalid_request(
+                "Images can only be sent with the first message of a conversation",
+            ));
+        }
+        self.hold_conversation_streaming(message, deltas, cancel)
+            .await
+    }
+
+    async fn upload_attachment(&mut self, data: &str) -> Result<()> {
+        // The upload happens as part of the image turn, so it is retried
+        // along with it
+        self.pending_attachment = Some(data.to_string());
+        Ok(())
+    }
+
+    fn position(&self) -> Option<ConversationPosition> {
+        ChatGptClient::position(self)
+    }
+
+    fn resume(&mut self, position: ConversationPosition) {
+        ChatGptClient::resume(self, position)
+    }
+}
@@ -0,0 +1,62 @@
+use super::{ChatBackend, DeltaSender, stream_chunks, word_chunks};
+use crate::utils::Result;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Offline backend that answers every message with the message itself,
+/// streamed word by word
+#[derive(Debug, Default)]
+pub struct EchoBackend {
+    attachments: usize,
+}
+
+impl EchoBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of attachments uploaded so far
+    pub fn attachments(&self) -> usize {
+        self.attachments
+    }
+
+    async fn echo(
+        &self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        stream_chunks(&word_chunks(message), Duration::ZERO, deltas, cancel).await
+    }
+}
+
+impl ChatBackend for EchoBackend {
+    type Config = ();
+
+    async fn connect(_config: &(), _proxy: Option<&str>) -> Result<Self> {
+        Ok(Self::new())
+    }
+
+    async fn start_conversation(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        self.echo(message, deltas, cancel).await
+    }
+
+    async fn continue_conversation(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        self.echo(message, deltas, cancel).await
+    }
+
+    async fn upload_attachment(&mut self, _data: &str) -> Result<()> {
+        self.attachments += 1;
+        Ok(())
+    }
+}
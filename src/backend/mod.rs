@@ -0,0 +1,98 @@
+//! Conversation backends the API server can run against.
+//!
+//! [`ChatGptClient`](crate::client::ChatGptClient) talks to the real upstream;
+//! [`EchoBackend`] and [`ScriptedBackend`] answer locally so the HTTP API can
+//! be exercised without network access.
+
+pub mod chatgpt;
+pub mod echo;
+pub mod scripted;
+
+pub use crate::client::chatgpt::DeltaSender;
+pub use echo::EchoBackend;
+pub use scripted::{Script, ScriptStep, ScriptedBackend};
+
+use crate::client::ConversationPosition;
+use crate::utils::{ChatGptError, Result};
+use std::future::Future;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// A conversation session, one per API thread
+pub trait ChatBackend: Sized + Send + Sync + 'static {
+    /// Settings shared by every session the server opens
+    type Config: Clone + Send + Sync + 'static;
+
+    /// Open a session. `proxy` is the thread's proxy, if one applies.
+    fn connect(
+        config: &Self::Config,
+        proxy: Option<&str>,
+    ) -> impl Future<Output = Result<Self>> + Send;
+
+    /// Send the first message of a conversation and return the full answer.
+    ///
+    /// Answer text is forwarded to `deltas` as it is produced; cancelling
+    /// `cancel` stops the turn with `ChatGptError::Cancelled`.
+    fn start_conversation(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> impl Future<Output = Result<String>> + Send;
+
+    /// Send a follow-up message in the current conversation
+    fn continue_conversation(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> impl Future<Output = Result<String>> + Send;
+
+    /// Attach base64 image data to the next message. Backends may refuse
+    /// images on follow-ups with `ChatGptError::InvalidRequest`.
+    fn upload_attachment(&mut self, data: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Where the current conversation stands upstream, for backends that can
+    /// pick a conversation up again in a later session
+    fn position(&self) -> Option<ConversationPosition> {
+        None
+    }
+
+    /// Continue from a position returned by [`position`](Self::position)
+    fn resume(&mut self, _position: ConversationPosition) {}
+}
+
+/// Forward `chunks` to `deltas` one by one, pausing `delay` between them,
+/// and return the joined answer
+pub(crate) async fn stream_chunks(
+    chunks: &[String],
+    delay: Duration,
+    deltas: Option<&DeltaSender>,
+    cancel: &CancellationToken,
+) -> Result<String> {
+    let mut answer = String::new();
+
+    for chunk in chunks {
+        if !delay.is_zero() {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = cancel.cancelled() => return Err(ChatGptError::Cancelled),
+            }
+        }
+        if cancel.is_cancelled() {
+            return Err(ChatGptError::Cancelled);
+        }
+
+        answer.push_str(chunk);
+        if let Some(deltas) = deltas {
+            let _ = deltas.send(chunk.clone());
+        }
+    }
+
+    Ok(answer)
+}
+
+/// Split text into word-sized chunks that join back to the original
+pub(crate) fn word_chunks(text: &str) -> Vec<String> {
+    text.split_inclusive(' ').map(str::to_string).collect()
+}
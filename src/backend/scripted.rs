@@ -0,0 +1,150 @@
+use super::{ChatBackend, DeltaSender, stream_chunks, word_chunks};
+use crate::utils::{ChatGptError, Result};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// One canned turn of a [`Script`]
+#[derive(Debug, Clone)]
+pub enum ScriptStep {
+    /// Stream `chunks` as the answer, waiting `delay` before each one
+    Reply {
+        chunks: Vec<String>,
+        delay: Duration,
+    },
+    /// Fail the turn as if upstream answered with `status`
+    Fail { status: u16, message: String },
+}
+
+/// Canned turns replayed in order by every [`ScriptedBackend`] session
+///
+/// ```
+/// # use std::time::Duration;
+/// # use chatgpt_rs::backend::Script;
+/// let script = Script::new()
+///     .reply("Hello there")
+///     .fail(503, "overloaded")
+///     .reply_slowly("Take your time", Duration::from_millis(50));
+/// assert_eq!(script.steps().len(), 3);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    steps: Vec<ScriptStep>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer with `text`, streamed word by word
+    pub fn reply(self, text: &str) -> Self {
+        self.reply_slowly(text, Duration::ZERO)
+    }
+
+    /// Answer with `text`, pausing `delay` before each word
+    pub fn reply_slowly(self, text: &str, delay: Duration) -> Self {
+        self.step(ScriptStep::Reply {
+            chunks: word_chunks(text),
+            delay,
+        })
+    }
+
+    /// Fail the turn with an upstream error
+    pub fn fail(self, status: u16, message: &str) -> Self {
+        self.step(ScriptStep::Fail {
+            status,
+            message: message.to_string(),
+        })
+    }
+
+    pub fn step(mut self, step: ScriptStep) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    pub fn steps(&self) -> &[ScriptStep] {
+        &self.steps
+    }
+}
+
+/// Offline backend that plays back a [`Script`], one step per turn, and
+/// remembers the messages it was sent
+#[derive(Debug, Default)]
+pub struct ScriptedBackend {
+    steps: VecDeque<ScriptStep>,
+    received: Vec<String>,
+    attachments: usize,
+}
+
+impl ScriptedBackend {
+    pub fn new(script: Script) -> Self {
+        Self {
+            steps: script.steps.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Messages sent to this session, oldest first
+    pub fn received(&self) -> &[String] {
+        &self.received
+    }
+
+    /// Number of attachments uploaded so far
+    pub fn attachments(&self) -> usize {
+        self.attachments
+    }
+
+    async fn play(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        self.received.push(message.to_string());
+
+        match self.steps.pop_front() {
+            Some(ScriptStep::Reply { chunks, delay }) => {
+                stream_chunks(&chunks, delay, deltas, cancel).await
+            }
+            Some(ScriptStep::Fail { status, message }) => Err(ChatGptError::Upstream {
+                status,
+                endpoint: "scripted".to_string(),
+                body_excerpt: message,
+                retry_after: None,
+            }),
+            None => Err(ChatGptError::unknown("Script has no turns left")),
+        }
+    }
+}
+
+impl ChatBackend for ScriptedBackend {
+    type Config = Script;
+
+    async fn connect(script: &Script, _proxy: Option<&str>) -> Result<Self> {
+        Ok(Self::new(script.clone()))
+    }
+
+    async fn start_conversation(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        self.play(message, deltas, cancel).await
+    }
+
+    async fn continue_conversation(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        self.play(message, deltas, cancel).await
+    }
+
+    async fn upload_attachment(&mut self, _data: &str) -> Result<()> {
+        self.attachments += 1;
+        Ok(())
+    }
+}
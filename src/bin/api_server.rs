@@ -1,118 +1,181 @@
-use chatgpt_rs::api::server;
+use chatgpt_rs::api::config::{BackendKind, ConfigOverrides, GrpcMode};
+use chatgpt_rs::api::{AppState, ServerConfig, server};
+use chatgpt_rs::backend::EchoBackend;
+use chatgpt_rs::client::ChatGptClient;
+use chatgpt_rs::utils::{LogFormat, logger};
 use chatgpt_rs::{log_error, log_info, log_success};
-use std::env;
-
-fn print_usage() {
-    println!("Usage: api_server [OPTIONS]");
-    println!();
-    println!("Options:");
-    println!("  --host <HOST>      Server host (default: 0.0.0.0)");
-    println!("  --port <PORT>      Server port (default: 6969)");
-    println!("  --proxy <PROXY>    Default proxy for ChatGPT client (default: http://127.0.0.1:1082)");
-    println!("  --no-proxy         Don't use any default proxy");
-    println!("  --help             Show this help message");
-    println!();
-    println!("Note: Proxy can also be overridden per-request in the API payload.");
-    println!();
-    println!("Examples:");
-    println!("  api_server");
-    println!("  api_server --port 8080");
-    println!("  api_server --proxy http://127.0.0.1:7890");
-    println!("  api_server --no-proxy");
-    println!("  api_server --host 127.0.0.1 --port 8080 --proxy http://localhost:7890");
+use clap::Parser;
+use std::path::PathBuf;
+
+/// OpenAI-style Responses API on top of ChatGPT
+#[derive(Debug, Parser)]
+#[command(
+    name = "api_server",
+    version,
+    after_help = "\
+Settings are taken from, highest first: flags, environment variables, the
+config file, built-in defaults. Storage, limits and the model catalog can
+only be set in the config file; see api_server.example.toml.
+
+Examples:
+  api_server --config api_server.toml
+  api_server --port 8080 --proxy http://127.0.0.1:7890
+  api_server --echo --port 8080
+  api_server --config api_server.toml --print-config"
+)]
+struct Cli {
+    /// TOML config file
+    #[arg(short, long, env = "API_SERVER_CONFIG", value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// IP address to listen on [default: 0.0.0.0]
+    #[arg(long, env = "API_HOST")]
+    host: Option<String>,
+
+    /// Port to listen on [default: 6969]
+    #[arg(long, env = "API_PORT")]
+    port: Option<u16>,
+
+    /// Default proxy for new threads (requests may still bring their own)
+    #[arg(long, env = "DEFAULT_PROXY")]
+    proxy: Option<String>,
+
+    /// Use no default proxy, even if the config file or DEFAULT_PROXY sets one
+    #[arg(long)]
+    no_proxy: bool,
+
+    /// Answer with the offline echo backend (no network)
+    #[arg(long)]
+    echo: bool,
+
+    /// Seconds a shutdown lets running responses finish [default: 30]
+    #[arg(long = "drain-timeout", env = "API_DRAIN_TIMEOUT", value_name = "SECS")]
+    drain_timeout_secs: Option<u64>,
+
+    /// Serve gRPC on the API port too, or only gRPC (needs the `grpc` feature) [default: off]
+    #[arg(long, env = "API_GRPC", value_enum)]
+    grpc: Option<GrpcMode>,
+
+    /// Accepted bearer key; repeat the flag or separate keys with commas
+    #[arg(
+        long = "api-key",
+        env = "API_KEYS",
+        value_name = "KEY",
+        value_delimiter = ','
+    )]
+    api_keys: Vec<String>,
+
+    /// Webhook for responses whose request names none
+    #[arg(long, env = "API_WEBHOOK_URL", value_name = "URL")]
+    webhook_url: Option<String>,
+
+    /// Key the webhook signatures are made with
+    #[arg(long, env = "API_WEBHOOK_SECRET", value_name = "SECRET", hide_env_values = true)]
+    webhook_secret: Option<String>,
+
+    /// Log filter, e.g. `info` or `chatgpt_rs=debug` [default: info]
+    #[arg(long, env = "RUST_LOG", value_name = "FILTER")]
+    log_level: Option<String>,
+
+    /// Log line format [default: text]
+    #[arg(long, env = "LOG_FORMAT", value_enum)]
+    log_format: Option<LogFormat>,
+
+    /// Print the effective config as TOML and exit
+    #[arg(long)]
+    print_config: bool,
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
-
-    let args: Vec<String> = env::args().collect();
-    
-    // Parse command line arguments
-    let mut host = "0.0.0.0".to_string();
-    let mut port = 6969u16;
-    let mut default_proxy = Some("http://127.0.0.1:1082".to_string());
-    
-    let mut i = 1;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--help" | "-h" => {
-                print_usage();
-                return;
-            }
-            "--host" => {
-                if i + 1 < args.len() {
-                    host = args[i + 1].clone();
-                    i += 2;
-                } else {
-                    log_error!("--host requires a value");
-                    std::process::exit(1);
-                }
-            }
-            "--port" => {
-                if i + 1 < args.len() {
-                    match args[i + 1].parse::<u16>() {
-                        Ok(p) => port = p,
-                        Err(_) => {
-                            log_error!("Invalid port number: {}", args[i + 1]);
-                            std::process::exit(1);
-                        }
-                    }
-                    i += 2;
-                } else {
-                    log_error!("--port requires a value");
-                    std::process::exit(1);
-                }
-            }
-            "--proxy" => {
-                if i + 1 < args.len() {
-                    default_proxy = Some(args[i + 1].clone());
-                    i += 2;
-                } else {
-                    log_error!("--proxy requires a value");
-                    std::process::exit(1);
-                }
-            }
-            "--no-proxy" => {
-                default_proxy = None;
-                i += 1;
-            }
-            _ => {
-                log_error!("Unknown option: {}", args[i]);
-                println!();
-                print_usage();
-                std::process::exit(1);
-            }
+impl Cli {
+    fn overrides(&self) -> ConfigOverrides {
+        ConfigOverrides {
+            host: self.host.clone(),
+            port: self.port,
+            proxy: self.proxy.clone(),
+            no_proxy: self.no_proxy,
+            backend: self.echo.then_some(BackendKind::Echo),
+            drain_timeout_secs: self.drain_timeout_secs,
+            grpc: self.grpc,
+            api_keys: self.api_keys.clone(),
+            log_level: self.log_level.clone(),
+            log_format: self.log_format,
+            webhook_url: self.webhook_url.clone(),
+            webhook_secret: self.webhook_secret.clone(),
         }
     }
+}
 
-    // Environment variables can override command line args
-    if let Ok(env_host) = env::var("API_HOST") {
-        host = env_host;
-    }
-    
-    if let Ok(env_port) = env::var("API_PORT") {
-        if let Ok(p) = env_port.parse::<u16>() {
-            port = p;
+fn load_config(cli: &Cli) -> chatgpt_rs::Result<ServerConfig> {
+    let mut config = match &cli.config {
+        Some(path) => ServerConfig::load(path)?,
+        None => ServerConfig::default(),
+    };
+    config.apply(cli.overrides());
+    config.validate()?;
+    Ok(config)
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    // Everything is checked before logging is set up or a port is bound
+    let config = match load_config(&cli) {
+        Ok(config) => config,
+        Err(err) => {
+            let _ = logger::init_console("error");
+            log_error!("{}", err);
+            std::process::exit(2);
         }
+    };
+
+    if cli.print_config {
+        print!("{}", config.to_toml());
+        return;
     }
-    
-    if let Ok(env_proxy) = env::var("DEFAULT_PROXY") {
-        default_proxy = Some(env_proxy);
+
+    if let Err(err) = logger::init(&config.logging.filter(), config.logging.format) {
+        eprintln!("{}", err);
+        std::process::exit(2);
     }
 
     log_info!("Starting ChatGPT-RS API Server");
     log_info!("================================");
-    log_info!("Host: {}", host);
-    log_info!("Port: {}", port);
-    
-    if let Some(ref proxy) = default_proxy {
-        log_info!("Default Proxy: {}", proxy);
-    } else {
-        log_info!("Default Proxy: None");
+    log_info!("Host: {}", config.server.host);
+    log_info!("Port: {}", config.server.port);
+    match config.server.grpc {
+        GrpcMode::Off => {}
+        GrpcMode::Alongside => log_info!("gRPC: on the API port"),
+        GrpcMode::Only => log_info!("gRPC: only (HTTP API disabled)"),
+    }
+
+    match config.server.backend {
+        BackendKind::Echo => log_info!("Backend: echo (offline)"),
+        BackendKind::Chatgpt => log_info!(
+            "Default Proxy: {}",
+            config.upstream.proxy.as_deref().unwrap_or("None")
+        ),
+    }
+    if !config.auth.api_keys.is_empty() {
+        log_info!("API keys: {} configured", config.auth.api_keys.len());
     }
-    
-    if let Err(err) = server::run(&host, port, default_proxy).await {
+
+    let host = &config.server.host;
+    let port = config.server.port;
+    let result = match config.server.backend {
+        BackendKind::Echo => match AppState::<EchoBackend>::from_config((), &config) {
+            Ok(state) => server::serve(state, host, port).await,
+            Err(err) => Err(err),
+        },
+        BackendKind::Chatgpt => {
+            match AppState::<ChatGptClient>::from_config(config.client_config(), &config) {
+                Ok(state) => server::serve(state, host, port).await,
+                Err(err) => Err(err),
+            }
+        }
+    };
+
+    if let Err(err) = result {
         log_error!("API server failed: {}", err);
         std::process::exit(1);
     }
@@ -0,0 +1,528 @@
+use base64::{Engine as _, engine::general_purpose};
+use chatgpt_rs::api::chatgpt_import::{self, Branches};
+use chatgpt_rs::api::storage::ThreadStore;
+use chatgpt_rs::backend::{ChatBackend, EchoBackend};
+use chatgpt_rs::cli::batch::{BatchRequest, read_requests};
+use chatgpt_rs::cli::{
+    BatchOptions, BatchOutcome, BatchSummary, Command, HELP, Input, InputBuffer, MarkdownRenderer,
+    Session, render, run_batch,
+};
+use chatgpt_rs::client::{ChatGptClient, ClientConfig};
+use chatgpt_rs::utils::logger;
+use chatgpt_rs::{ChatGptError, log_error, log_info, log_success, log_warning};
+use clap::Parser;
+use colored::*;
+use std::env;
+use std::io::{IsTerminal, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Chat with ChatGPT in the terminal
+#[derive(Debug, Parser)]
+#[command(
+    name = "chatgpt",
+    version,
+    args_conflicts_with_subcommands = true,
+    after_help = format!("Commands inside the chat:\n{}", HELP)
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Subcommands>,
+
+    /// Proxy for the ChatGPT client
+    #[arg(long, env = "DEFAULT_PROXY")]
+    proxy: Option<String>,
+
+    /// Load the conversation from PATH if it exists and save every turn to it
+    #[arg(long, value_name = "PATH")]
+    session: Option<PathBuf>,
+
+    /// Print plain text
+    #[arg(long, global = true)]
+    no_color: bool,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Subcommands {
+    /// Run every prompt of a JSONL file
+    #[command(after_help = r#"Each line of INPUT looks like
+  {"custom_id": "q1", "prompt": "...", "image": "cat.png", "conversation": "c1"}
+where image and conversation are optional. Prompts sharing a conversation
+key run in order in one conversation.
+
+Prompts whose custom_id is already in the output are skipped, so an
+interrupted run picks up where it stopped when started again."#)]
+    Batch(BatchArgs),
+
+    /// Add a ChatGPT data export to the thread file of api_server
+    #[command(after_help = "\
+The thread file is the [storage] path of api_server with kind = \"file\".
+Stop the server first, as it rewrites the file with the threads it holds.
+
+Branches already imported into the file are skipped and threads whose
+conversation went on get the newer messages, so a newer export can be
+imported over an older one.")]
+    Import(ImportArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct BatchArgs {
+    /// JSONL file with one prompt per line
+    input: PathBuf,
+
+    /// Results file, appended to [default: INPUT with .results.jsonl]
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Conversations to run at once [default: 4]
+    #[arg(long, value_name = "N")]
+    concurrency: Option<NonZeroUsize>,
+
+    /// Run prompts again whose earlier results failed
+    #[arg(long)]
+    retry_failed: bool,
+
+    /// Proxy for the ChatGPT client
+    #[arg(long, env = "DEFAULT_PROXY")]
+    proxy: Option<String>,
+
+    /// Answer locally with each prompt instead of calling ChatGPT
+    #[arg(long)]
+    echo: bool,
+}
+
+#[derive(Debug, clap::Args)]
+struct ImportArgs {
+    /// conversations.json from a ChatGPT data export
+    #[arg(value_name = "CONVERSATIONS.JSON")]
+    input: PathBuf,
+
+    /// Thread file to add the conversations to
+    #[arg(long, value_name = "PATH")]
+    store: PathBuf,
+
+    /// One thread per branch instead of only the active one
+    #[arg(long)]
+    all_branches: bool,
+}
+
+async fn batch(args: BatchArgs) {
+    let input = args.input;
+    let output = args
+        .output
+        .unwrap_or_else(|| input.with_extension("results.jsonl"));
+    if output == input {
+        log_error!("The output file must differ from the input file");
+        std::process::exit(1);
+    }
+    let defaults = BatchOptions::default();
+    let options = BatchOptions {
+        concurrency: args
+            .concurrency
+            .map_or(defaults.concurrency, NonZeroUsize::get),
+        retry_failed: args.retry_failed,
+        proxy: args.proxy,
+    };
+
+    let requests = match read_requests(&input) {
+        Ok(requests) => requests,
+        Err(err) => {
+            log_error!("Failed to read {}: {}", input.display(), err);
+            std::process::exit(1);
+        }
+    };
+
+    let summary = if args.echo {
+        run_batch_with::<EchoBackend>(&(), requests, &output, &options).await
+    } else {
+        run_batch_with::<ChatGptClient>(&ClientConfig::default(), requests, &output, &options).await
+    };
+
+    match summary {
+        Ok(summary) => {
+            log_success!(
+                "{} completed, {} failed, {} skipped (already in {})",
+                summary.completed,
+                summary.failed,
+                summary.skipped,
+                output.display()
+            );
+            if summary.remaining > 0 {
+                log_warning!(
+                    "Stopped with {} prompts left; run the same command again to resume",
+                    summary.remaining
+                );
+            }
+            if summary.failed > 0 || summary.remaining > 0 {
+                std::process::exit(1);
+            }
+        }
+        Err(err) => {
+            log_error!("Batch failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn import(args: ImportArgs) {
+    let input = args.input;
+    let branches = if args.all_branches {
+        Branches::All
+    } else {
+        Branches::Active
+    };
+
+    let conversations = match std::fs::read_to_string(&input)
+        .map_err(|err| err.to_string())
+        .and_then(|text| chatgpt_import::parse(&text).map_err(|err| err.to_string()))
+    {
+        Ok(conversations) => conversations,
+        Err(err) => {
+            log_error!("Failed to read {}: {}", input.display(), err);
+            std::process::exit(1);
+        }
+    };
+    let branches = chatgpt_import::convert(&conversations, branches);
+    let messages: usize = branches
+        .iter()
+        .map(|branch| branch.thread.messages.len())
+        .sum();
+
+    let store = ThreadStore::new(&args.store, Duration::ZERO);
+    match chatgpt_import::import_to_store(&store, branches) {
+        Ok(summary) => log_success!(
+            "{} threads imported from {} conversations ({} messages), {} updated, {} already in {}",
+            summary.imported,
+            conversations.len(),
+            messages,
+            summary.updated,
+            summary.skipped,
+            store.path().display()
+        ),
+        Err(err) => {
+            log_error!("Import failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run the batch, logging each result as it lands; Ctrl-C stops it
+async fn run_batch_with<B: ChatBackend>(
+    config: &B::Config,
+    requests: Vec<BatchRequest>,
+    output: &Path,
+    options: &BatchOptions,
+) -> chatgpt_rs::Result<BatchSummary> {
+    let total = requests.len();
+    let cancel = CancellationToken::new();
+    let watcher = tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                log_warning!("Stopping after the running prompts are cancelled...");
+                cancel.cancel();
+            }
+        }
+    });
+
+    log_info!(
+        "Running {} prompts, {} conversations at a time",
+        total,
+        options.concurrency
+    );
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let run = async {
+        let tx = tx;
+        run_batch::<B>(config, requests, output, options, Some(&tx), &cancel).await
+    };
+    let report = async {
+        while let Some(result) = rx.recv().await {
+            match &result.outcome {
+                BatchOutcome::Completed { .. } => log_success!("{}", result.custom_id),
+                BatchOutcome::Failed { error } => {
+                    log_error!("{}: {}", result.custom_id, error.message)
+                }
+            }
+        }
+    };
+
+    let (summary, ()) = tokio::join!(run, report);
+    watcher.abort();
+    summary
+}
+
+struct Repl {
+    client: ChatGptClient,
+    session: Session,
+    session_path: Option<PathBuf>,
+    pending_image: Option<PathBuf>,
+}
+
+impl Repl {
+    /// Run one command; returns false when the REPL should exit
+    fn run_command(&mut self, command: Command) -> bool {
+        match command {
+            Command::New => {
+                self.client.reset_conversation();
+                self.session = Session::new();
+                self.session_path = None;
+                self.pending_image = None;
+                log_info!("Started a new conversation");
+            }
+            Command::Image(path) => {
+                if path.is_file() {
+                    log_info!(
+                        "{} will be sent with your next message (image turns start a new upstream conversation)",
+                        path.display()
+                    );
+                    self.pending_image = Some(path);
+                } else {
+                    log_error!("No such file: {}", path.display());
+                }
+            }
+            Command::Save(path) => {
+                let path = path
+                    .or_else(|| self.session_path.clone())
+                    .unwrap_or_else(|| self.session.default_file_name());
+                match self.session.save(&path) {
+                    Ok(()) => {
+                        log_success!("Saved to {}", path.display());
+                        self.session_path = Some(path);
+                    }
+                    Err(err) => log_error!("Failed to save {}: {}", path.display(), err),
+                }
+            }
+            Command::Load(path) => match Session::load(&path) {
+                Ok(session) => {
+                    self.restore(session);
+                    log_success!(
+                        "Loaded {} messages from {}",
+                        self.session.messages.len(),
+                        path.display()
+                    );
+                    self.session_path = Some(path);
+                }
+                Err(err) => log_error!("Failed to load {}: {}", path.display(), err),
+            },
+            Command::History => self.print_history(),
+            Command::Help => println!("{}", HELP),
+            Command::Quit => return false,
+        }
+        true
+    }
+
+    fn restore(&mut self, session: Session) {
+        match session.position.clone() {
+            Some(position) => self.client.resume(position),
+            None => self.client.reset_conversation(),
+        }
+        self.session = session;
+        self.pending_image = None;
+    }
+
+    fn print_history(&self) {
+        if self.session.messages.is_empty() {
+            log_info!("No messages yet");
+            return;
+        }
+        for message in &self.session.messages {
+            if message.role == "user" {
+                println!("{} {}", "you ›".green().bold(), message.content);
+                if let Some(image) = &message.image {
+                    println!("      {}", format!("[image: {}]", image.display()).dimmed());
+                }
+            } else {
+                println!("{}", "assistant ›".blue().bold());
+                print!("{}", render(&message.content));
+            }
+            println!();
+        }
+    }
+
+    /// Send a message and print the answer as it streams in. Ctrl-C stops
+    /// the answer; whatever arrived is kept.
+    async fn send(&mut self, message: &str) {
+        let cancel = CancellationToken::new();
+        let watcher = tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    cancel.cancel();
+                }
+            }
+        });
+
+        let image = self.pending_image.take();
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let client = &mut self.client;
+        let turn = async {
+            let tx = tx;
+            match &image {
+                Some(path) => {
+                    let data = general_purpose::STANDARD.encode(std::fs::read(path)?);
+                    client
+                        .start_with_image_streaming(message, &data, Some(&tx), &cancel)
+                        .await
+                }
+                None if client.position().is_none() => {
+                    client
+                        .start_conversation_streaming(message, Some(&tx), &cancel)
+                        .await
+                }
+                None => {
+                    client
+                        .hold_conversation_streaming(message, Some(&tx), &cancel)
+                        .await
+                }
+            }
+        };
+
+        let print = async {
+            let mut renderer = MarkdownRenderer::new();
+            let mut received = String::new();
+            println!("{}", "assistant ›".blue().bold());
+            while let Some(delta) = rx.recv().await {
+                received.push_str(&delta);
+                print!("{}", renderer.push(&delta));
+                let _ = std::io::stdout().flush();
+            }
+            print!("{}", renderer.finish());
+            received
+        };
+
+        let (result, received): (chatgpt_rs::Result<String>, String) = tokio::join!(turn, print);
+        watcher.abort();
+        println!();
+
+        let answer = match result {
+            Ok(answer) => answer,
+            Err(ChatGptError::Cancelled) => {
+                log_warning!("Stopped");
+                received
+            }
+            Err(err) => {
+                log_error!("{}", err);
+                return;
+            }
+        };
+
+        self.session.push("user", message, image);
+        if !answer.is_empty() {
+            self.session.push("assistant", &answer, None);
+        }
+        self.session.position = self.client.position();
+
+        if let Some(path) = &self.session_path
+            && let Err(err) = self.session.save(path)
+        {
+            log_error!("Failed to save {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// This tool's own messages plus library warnings; `RUST_LOG` replaces it
+const DEFAULT_LOG_FILTER: &str = "chatgpt=info,chatgpt_rs=warn";
+
+#[tokio::main]
+async fn main() {
+    let filter = env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LOG_FILTER.to_string());
+    if let Err(err) = logger::init_console(&filter) {
+        eprintln!("{}", err);
+        std::process::exit(2);
+    }
+
+    let cli = Cli::parse();
+    if cli.no_color {
+        colored::control::set_override(false);
+    }
+
+    match cli.command {
+        Some(Subcommands::Batch(args)) => return batch(args).await,
+        Some(Subcommands::Import(args)) => return import(args),
+        None => {}
+    }
+
+    if !std::io::stdout().is_terminal() {
+        colored::control::set_override(false);
+    }
+    let session_path = cli.session;
+
+    log_info!("Connecting to ChatGPT...");
+    let client = match ChatGptClient::new(cli.proxy.as_deref()).await {
+        Ok(client) => client,
+        Err(err) => {
+            log_error!("Failed to connect: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let mut repl = Repl {
+        client,
+        session: Session::new(),
+        session_path: session_path.clone(),
+        pending_image: None,
+    };
+
+    if let Some(path) = session_path.filter(|path| path.exists()) {
+        match Session::load(&path) {
+            Ok(session) => {
+                repl.restore(session);
+                log_success!(
+                    "Resumed {} messages from {}",
+                    repl.session.messages.len(),
+                    path.display()
+                );
+            }
+            Err(err) => {
+                log_error!("Failed to load {}: {}", path.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    log_success!("Connected. Type /help for commands.");
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut buffer = InputBuffer::new();
+
+    loop {
+        let prompt = if buffer.is_continuing() {
+            "... "
+        } else {
+            "› "
+        };
+        print!("{}", prompt.green().bold());
+        let _ = std::io::stdout().flush();
+
+        let line = tokio::select! {
+            line = lines.next_line() => line,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        let Ok(Some(line)) = line else {
+            break;
+        };
+
+        let Some(text) = buffer.push(&line) else {
+            continue;
+        };
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        match Input::parse(&text) {
+            Ok(Input::Command(command)) => {
+                if !repl.run_command(command) {
+                    break;
+                }
+            }
+            Ok(Input::Message(message)) => repl.send(&message).await,
+            Err(err) => log_error!("{}", err),
+        }
+    }
+
+    println!();
+}
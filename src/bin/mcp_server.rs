@@ -0,0 +1,85 @@
+use chatgpt_rs::backend::{ChatBackend, EchoBackend};
+use chatgpt_rs::client::{ChatGptClient, ClientConfig};
+use chatgpt_rs::mcp::{ConversationStore, McpServer};
+use chatgpt_rs::utils::logger;
+use chatgpt_rs::{log_error, log_info};
+use clap::Parser;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use tokio::io::BufReader;
+
+/// Model Context Protocol server exposing ChatGPT as tools, over stdio
+#[derive(Debug, Parser)]
+#[command(
+    name = "mcp_server",
+    version,
+    after_help = "\
+Reads JSON-RPC messages on stdin and answers on stdout; logs go to stderr.
+
+Example MCP client entry:
+  {\"command\": \"mcp_server\", \"args\": [\"--state\", \"/home/me/.chatgpt-mcp.json\"]}"
+)]
+struct Cli {
+    /// Keep conversations in this JSON file so they survive restarts
+    /// [default: in memory only]
+    #[arg(long, env = "CHATGPT_MCP_STATE", value_name = "PATH")]
+    state: Option<PathBuf>,
+
+    /// Proxy for the ChatGPT client
+    #[arg(long, env = "DEFAULT_PROXY")]
+    proxy: Option<String>,
+
+    /// Answer with the offline echo backend (no network)
+    #[arg(long)]
+    echo: bool,
+
+    /// Log filter, e.g. `info` or `chatgpt_rs=debug`
+    #[arg(long, env = "RUST_LOG", value_name = "FILTER", default_value = "warn")]
+    log_level: String,
+}
+
+async fn serve<B: ChatBackend>(
+    config: B::Config,
+    cli: Cli,
+    store: ConversationStore,
+) -> chatgpt_rs::Result<()> {
+    let server = McpServer::<B>::new(config, cli.proxy, store);
+    server
+        .serve(BufReader::new(tokio::io::stdin()), tokio::io::stdout())
+        .await
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+
+    colored::control::set_override(std::io::stderr().is_terminal());
+    if let Err(err) = logger::init_stderr(&cli.log_level) {
+        eprintln!("{}", err);
+        std::process::exit(2);
+    }
+
+    let store = match &cli.state {
+        Some(path) => match ConversationStore::open(path) {
+            Ok(store) => {
+                log_info!("{} conversations in {}", store.len(), path.display());
+                store
+            }
+            Err(err) => {
+                log_error!("Failed to load {}: {}", path.display(), err);
+                std::process::exit(2);
+            }
+        },
+        None => ConversationStore::in_memory(),
+    };
+
+    let result = if cli.echo {
+        serve::<EchoBackend>((), cli, store).await
+    } else {
+        serve::<ChatGptClient>(ClientConfig::default(), cli, store).await
+    };
+    if let Err(err) = result {
+        log_error!("MCP server failed: {}", err);
+        std::process::exit(1);
+    }
+}
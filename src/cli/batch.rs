@@ -0,0 +1,434 @@
+use crate::backend::ChatBackend;
+use crate::client::ConversationPosition;
+use crate::utils::{ChatGptError, Result};
+use base64::{Engine as _, engine::general_purpose};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// One line of a batch input file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchRequest {
+    /// Caller-chosen id, echoed in the result and used to resume a run
+    pub custom_id: String,
+    pub prompt: String,
+    /// Image to send along with the prompt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<PathBuf>,
+    /// Requests sharing a key run in input order in one conversation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation: Option<String>,
+}
+
+/// One line of a batch output file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub custom_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation: Option<String>,
+    #[serde(flatten)]
+    pub outcome: BatchOutcome,
+    /// Where the conversation stood after this turn, for backends that can
+    /// pick it up again; a resumed run continues from here
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<ConversationPosition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BatchOutcome {
+    Completed { response: String },
+    Failed { error: BatchError },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchError {
+    pub code: String,
+    pub message: String,
+}
+
+impl From<&ChatGptError> for BatchError {
+    fn from(err: &ChatGptError) -> Self {
+        Self {
+            code: err.code().to_string(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl BatchResult {
+    pub fn is_completed(&self) -> bool {
+        matches!(self.outcome, BatchOutcome::Completed { .. })
+    }
+}
+
+/// Receives every result as soon as it is written
+pub type ResultSender = mpsc::UnboundedSender<BatchResult>;
+
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    /// Conversations run at the same time
+    pub concurrency: usize,
+    /// Run requests again whose only results in the output are failures
+    pub retry_failed: bool,
+    /// Proxy passed to every backend session
+    pub proxy: Option<String>,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            retry_failed: false,
+            proxy: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub total: usize,
+    /// Already in the output from an earlier run
+    pub skipped: usize,
+    pub completed: usize,
+    pub failed: usize,
+    /// Not run because the batch was cancelled
+    pub remaining: usize,
+}
+
+/// Read batch requests from a JSONL file. Blank lines are ignored; a
+/// malformed line or a repeated `custom_id` rejects the whole file.
+pub fn read_requests(path: &Path) -> Result<Vec<BatchRequest>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut seen = HashSet::new();
+    let mut requests = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: BatchRequest = serde_json::from_str(line).map_err(|err| {
+            ChatGptError::configuration(format!("{}:{}: {}", path.display(), index + 1, err))
+        })?;
+        if request.custom_id.is_empty() {
+            return Err(ChatGptError::configuration(format!(
+                "{}:{}: custom_id must not be empty",
+                path.display(),
+                index + 1
+            )));
+        }
+        if !seen.insert(request.custom_id.clone()) {
+            return Err(ChatGptError::configuration(format!(
+                "{}:{}: duplicate custom_id {:?}",
+                path.display(),
+                index + 1,
+                request.custom_id
+            )));
+        }
+        requests.push(request);
+    }
+
+    Ok(requests)
+}
+
+/// Read the results written so far. A missing file has none; lines that do
+/// not parse (a write cut short by a crash) are ignored.
+pub fn read_results(path: &Path) -> Result<Vec<BatchResult>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(text
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Ids in `output` that a resumed run should skip
+pub fn finished_ids(output: &Path, retry_failed: bool) -> Result<HashSet<String>> {
+    Ok(read_results(output)?
+        .into_iter()
+        .filter(|result| !retry_failed || result.is_completed())
+        .map(|result| result.custom_id)
+        .collect())
+}
+
+/// Run `requests` against fresh `B` sessions and append a result line per
+/// request to `output`.
+///
+/// Requests without a conversation key each get their own session; those
+/// sharing a key run one after another in a single session. Up to
+/// `options.concurrency` sessions run at once, so results are written in
+/// completion order, not input order. Ids already in `output` are skipped,
+/// which makes an interrupted run resumable by running it again: a
+/// conversation cut short continues upstream from its last completed turn,
+/// or runs again from the start if the backend cannot pick it up.
+///
+/// Cancelling `cancel` stops the run; turns cut short are not recorded and
+/// run again on the next resume.
+pub async fn run_batch<B: ChatBackend>(
+    config: &B::Config,
+    requests: Vec<BatchRequest>,
+    output: &Path,
+    options: &BatchOptions,
+    progress: Option<&ResultSender>,
+    cancel: &CancellationToken,
+) -> Result<BatchSummary> {
+    let finished = finished_ids(output, options.retry_failed)?;
+    let completed: HashMap<String, Option<ConversationPosition>> = read_results(output)?
+        .into_iter()
+        .filter(BatchResult::is_completed)
+        .map(|result| (result.custom_id, result.position))
+        .collect();
+    let mut summary = BatchSummary {
+        total: requests.len(),
+        ..BatchSummary::default()
+    };
+
+    let jobs: Vec<Job> = group(requests)
+        .into_iter()
+        .filter_map(|requests| Job::remaining(requests, &finished, &completed))
+        .collect();
+    summary.skipped = summary.total - jobs.iter().map(|job| job.requests.len()).sum::<usize>();
+
+    let writer = ResultWriter::open(output, progress)?;
+    let outcomes: Vec<Result<JobSummary>> = futures::stream::iter(jobs)
+        .map(|job| run_job::<B>(config, job, options.proxy.as_deref(), &writer, cancel))
+        .buffer_unordered(options.concurrency.max(1))
+        .collect()
+        .await;
+
+    for job in outcomes {
+        let job = job?;
+        summary.completed += job.completed;
+        summary.failed += job.failed;
+        summary.remaining += job.remaining;
+    }
+    Ok(summary)
+}
+
+/// Split requests into jobs: one per conversation key, in order of first
+/// appearance, and one per request without a key
+fn group(requests: Vec<BatchRequest>) -> Vec<Vec<BatchRequest>> {
+    let mut jobs: Vec<Vec<BatchRequest>> = Vec::new();
+    let mut by_key: HashMap<String, usize> = HashMap::new();
+
+    for request in requests {
+        match &request.conversation {
+            Some(key) => match by_key.get(key) {
+                Some(&index) => jobs[index].push(request),
+                None => {
+                    by_key.insert(key.clone(), jobs.len());
+                    jobs.push(vec![request]);
+                }
+            },
+            None => jobs.push(vec![request]),
+        }
+    }
+
+    jobs
+}
+
+/// Requests run one after another in one session
+struct Job {
+    requests: Vec<BatchRequest>,
+    /// Conversation of an earlier run to continue instead of starting one
+    resume: Option<ConversationPosition>,
+}
+
+impl Job {
+    /// What an earlier run left of `requests`: nothing, the ones from the
+    /// first unfinished request on, continuing after the last completed
+    /// turn before it, or all of them if that turn left no position
+    fn remaining(
+        requests: Vec<BatchRequest>,
+        finished: &HashSet<String>,
+        completed: &HashMap<String, Option<ConversationPosition>>,
+    ) -> Option<Self> {
+        let first = requests
+            .iter()
+            .position(|request| !finished.contains(&request.custom_id))?;
+        let last_completed = requests[..first]
+            .iter()
+            .rev()
+            .find_map(|request| completed.get(&request.custom_id));
+
+        let resume = match last_completed {
+            Some(None) => {
+                return Some(Self {
+                    requests,
+                    resume: None,
+                });
+            }
+            Some(Some(position)) => Some(position.clone()),
+            None => None,
+        };
+        let requests = requests
+            .into_iter()
+            .skip(first)
+            .filter(|request| !finished.contains(&request.custom_id))
+            .collect();
+        Some(Self { requests, resume })
+    }
+}
+
+#[derive(Default)]
+struct JobSummary {
+    completed: usize,
+    failed: usize,
+    remaining: usize,
+}
+
+async fn run_job<B: ChatBackend>(
+    config: &B::Config,
+    job: Job,
+    proxy: Option<&str>,
+    writer: &ResultWriter<'_>,
+    cancel: &CancellationToken,
+) -> Result<JobSummary> {
+    let Job {
+        requests: job,
+        mut resume,
+    } = job;
+    let mut summary = JobSummary::default();
+    let mut session: Option<B> = None;
+    let mut started = false;
+
+    for (index, request) in job.iter().enumerate() {
+        if cancel.is_cancelled() {
+            summary.remaining = job.len() - index;
+            break;
+        }
+
+        let result = match &mut session {
+            Some(backend) => run_request(backend, request, started, cancel).await,
+            None => match tokio::select! {
+                backend = B::connect(config, proxy) => backend,
+                _ = cancel.cancelled() => Err(ChatGptError::Cancelled),
+            } {
+                Ok(backend) => {
+                    let backend = session.insert(backend);
+                    if let Some(position) = resume.take() {
+                        backend.resume(position);
+                        started = true;
+                    }
+                    run_request(backend, request, started, cancel).await
+                }
+                Err(err) => Err(err),
+            },
+        };
+
+        let mut position = None;
+        let outcome = match result {
+            Ok(response) => {
+                started = true;
+                summary.completed += 1;
+                if request.conversation.is_some() {
+                    position = session.as_ref().and_then(B::position);
+                }
+                BatchOutcome::Completed { response }
+            }
+            Err(ChatGptError::Cancelled) => {
+                summary.remaining = job.len() - index;
+                break;
+            }
+            Err(err) => {
+                summary.failed += 1;
+                BatchOutcome::Failed {
+                    error: BatchError::from(&err),
+                }
+            }
+        };
+
+        writer.write(BatchResult {
+            custom_id: request.custom_id.clone(),
+            conversation: request.conversation.clone(),
+            outcome,
+            position,
+        })?;
+    }
+
+    Ok(summary)
+}
+
+async fn run_request<B: ChatBackend>(
+    backend: &mut B,
+    request: &BatchRequest,
+    started: bool,
+    cancel: &CancellationToken,
+) -> Result<String> {
+    if let Some(path) = &request.image {
+        let data = std::fs::read(path).map_err(|err| {
+            ChatGptError::configuration(format!("Failed to read {}: {}", path.display(), err))
+        })?;
+        backend
+            .upload_attachment(&general_purpose::STANDARD.encode(data))
+            .await?;
+    }
+
+    if started {
+        backend
+            .continue_conversation(&request.prompt, None, cancel)
+            .await
+    } else {
+        backend
+            .start_conversation(&request.prompt, None, cancel)
+            .await
+    }
+}
+
+/// Appends result lines to the output file, flushing each one so a crash
+/// loses at most the line being written
+struct ResultWriter<'a> {
+    file: Mutex<File>,
+    progress: Option<&'a ResultSender>,
+}
+
+impl<'a> ResultWriter<'a> {
+    fn open(path: &Path, progress: Option<&'a ResultSender>) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        // Start on a fresh line if the last run died mid-write
+        if file.metadata()?.len() > 0 {
+            let mut last = [0u8; 1];
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                file.write_all(b"\n")?;
+            }
+        }
+
+        Ok(Self {
+            file: Mutex::new(file),
+            progress,
+        })
+    }
+
+    fn write(&self, result: BatchResult) -> Result<()> {
+        let mut line = serde_json::to_string(&result)?;
+        line.push('\n');
+        {
+            let mut file = self.file.lock().unwrap();
+            file.write_all(line.as_bytes())?;
+            file.flush()?;
+        }
+        if let Some(progress) = self.progress {
+            let _ = progress.send(result);
+        }
+        Ok(())
+    }
+}
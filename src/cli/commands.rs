@@ -0,0 +1,127 @@
+use std::path::PathBuf;
+
+/// Slash commands understood by the chat REPL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Start a new conversation
+    New,
+    /// Attach an image to the next message
+    Image(PathBuf),
+    /// Save the conversation, to the given file or the current session file
+    Save(Option<PathBuf>),
+    /// Load a saved conversation and continue it
+    Load(PathBuf),
+    /// Print the conversation so far
+    History,
+    Help,
+    Quit,
+}
+
+/// What the user entered at the prompt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    Message(String),
+    Command(Command),
+}
+
+pub const HELP: &str = "\
+/new            start a new conversation
+/image <path>   attach an image to the next message
+/save [path]    save the conversation (default: the current session file)
+/load <path>    load a saved conversation and continue it
+/history        show the conversation so far
+/help           show this help
+/quit           exit
+
+End a line with \\ to continue on the next one, or wrap a block in \"\"\".
+Start a message with // to send a literal leading /.
+Ctrl-C stops the answer being generated.";
+
+impl Input {
+    /// Interpret a complete input. Unknown commands and missing arguments are
+    /// reported as errors rather than sent as messages.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let trimmed = text.trim();
+
+        if let Some(escaped) = trimmed.strip_prefix("//") {
+            return Ok(Self::Message(format!("/{}", escaped)));
+        }
+
+        let Some(command) = trimmed.strip_prefix('/') else {
+            return Ok(Self::Message(text.to_string()));
+        };
+
+        let (name, arg) = match command.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, Some(arg.trim()).filter(|arg| !arg.is_empty())),
+            None => (command, None),
+        };
+
+        let command = match (name, arg) {
+            ("new", None) => Command::New,
+            ("image", Some(path)) => Command::Image(PathBuf::from(path)),
+            ("image", None) => return Err("usage: /image <path>".to_string()),
+            ("save", path) => Command::Save(path.map(PathBuf::from)),
+            ("load", Some(path)) => Command::Load(PathBuf::from(path)),
+            ("load", None) => return Err("usage: /load <path>".to_string()),
+            ("history", None) => Command::History,
+            ("help", None) => Command::Help,
+            ("quit" | "exit", None) => Command::Quit,
+            ("new" | "history" | "help" | "quit" | "exit", Some(_)) => {
+                return Err(format!("/{} takes no arguments", name));
+            }
+            _ => return Err(format!("unknown command /{} (try /help)", name)),
+        };
+
+        Ok(Self::Command(command))
+    }
+}
+
+/// Joins prompt lines into one input.
+///
+/// A line ending in `\` continues on the next line; a line of `"""` opens or
+/// closes a block that is taken verbatim.
+#[derive(Debug, Default)]
+pub struct InputBuffer {
+    lines: Vec<String>,
+    in_block: bool,
+}
+
+impl InputBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a line, returning the full input once it is complete
+    pub fn push(&mut self, line: &str) -> Option<String> {
+        if line.trim() == "\"\"\"" {
+            if self.in_block {
+                self.in_block = false;
+                return Some(self.take());
+            }
+            self.in_block = true;
+            return None;
+        }
+
+        if self.in_block {
+            self.lines.push(line.to_string());
+            return None;
+        }
+
+        if let Some(continued) = line.strip_suffix('\\') {
+            self.lines.push(continued.to_string());
+            return None;
+        }
+
+        self.lines.push(line.to_string());
+        Some(self.take())
+    }
+
+    /// Whether the next line continues an unfinished input
+    pub fn is_continuing(&self) -> bool {
+        self.in_block || !self.lines.is_empty()
+    }
+
+    fn take(&mut self) -> String {
+        std::mem::take(&mut self.lines).join("\n")
+    }
+}
@@ -0,0 +1,177 @@
+use colored::*;
+
+/// Renders Markdown for the terminal, line by line.
+///
+/// Streamed text is fed in with [`push`](Self::push); each line is rendered
+/// once it is complete, so formatting that spans a line (headings, lists,
+/// code fences) is known before anything is printed.
+#[derive(Debug, Default)]
+pub struct MarkdownRenderer {
+    in_code_block: bool,
+    pending: String,
+}
+
+impl MarkdownRenderer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed streamed text and get back the lines it completed, rendered
+    pub fn push(&mut self, text: &str) -> String {
+        self.pending.push_str(text);
+
+        let mut out = String::new();
+        while let Some(pos) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=pos).collect();
+            out.push_str(&self.render_line(line.trim_end_matches(['\n', '\r'])));
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Render whatever is left of the last line
+    pub fn finish(&mut self) -> String {
+        if self.pending.is_empty() {
+            return String::new();
+        }
+        let line = std::mem::take(&mut self.pending);
+        let mut out = self.render_line(&line);
+        out.push('\n');
+        self.in_code_block = false;
+        out
+    }
+
+    /// Render one complete line, tracking code fences across calls
+    pub fn render_line(&mut self, line: &str) -> String {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+
+        if trimmed.starts_with("```") {
+            self.in_code_block = !self.in_code_block;
+            return line.dimmed().to_string();
+        }
+
+        if self.in_code_block {
+            return format!("{} {}", "│".dimmed(), line.yellow());
+        }
+
+        if let Some((level, text)) = heading(trimmed) {
+            let text = text.bold();
+            return match level {
+                1 => text.cyan().underline().to_string(),
+                2 => text.cyan().to_string(),
+                _ => text.to_string(),
+            };
+        }
+
+        if matches!(trimmed, "---" | "***" | "___") {
+            return "─".repeat(40).dimmed().to_string();
+        }
+
+        if let Some(quote) = trimmed.strip_prefix('>') {
+            return format!(
+                "{}{} {}",
+                indent,
+                "│".dimmed(),
+                inline(quote.trim_start()).dimmed()
+            );
+        }
+
+        if let Some(item) = ["- ", "* ", "+ "]
+            .iter()
+            .find_map(|marker| trimmed.strip_prefix(marker))
+        {
+            return format!("{}{} {}", indent, "•".cyan(), inline(item));
+        }
+
+        if let Some((number, item)) = numbered(trimmed) {
+            return format!("{}{} {}", indent, number.cyan(), inline(item));
+        }
+
+        inline(line)
+    }
+}
+
+/// Render a complete Markdown document
+pub fn render(markdown: &str) -> String {
+    let mut renderer = MarkdownRenderer::new();
+    let mut out = renderer.push(markdown);
+    out.push_str(&renderer.finish());
+    out
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    line[level..].strip_prefix(' ').map(|text| (level, text))
+}
+
+fn numbered(line: &str) -> Option<(&str, &str)> {
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    if digits == 0 {
+        return None;
+    }
+    let rest = line[digits..].strip_prefix(". ")?;
+    Some((&line[..digits + 1], rest))
+}
+
+/// Inline code, bold, italics and links
+fn inline(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    let mut prev: Option<char> = None;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '`'
+            && let Some(end) = rest[1..].find('`')
+        {
+            out.push_str(&rest[1..1 + end].yellow().to_string());
+            rest = &rest[end + 2..];
+            prev = Some('`');
+            continue;
+        }
+
+        if rest.starts_with("**") || rest.starts_with("__") {
+            let marker = &rest[..2];
+            if let Some(end) = rest[2..].find(marker).filter(|&end| end > 0) {
+                out.push_str(&rest[2..2 + end].bold().to_string());
+                rest = &rest[end + 4..];
+                prev = Some(c);
+                continue;
+            }
+        }
+
+        // Single markers only count at a word start, so snake_case and 2 * 3 survive
+        if (c == '*' || c == '_')
+            && !prev.is_some_and(char::is_alphanumeric)
+            && !rest[1..].starts_with([' ', c])
+            && let Some(end) = rest[1..].find(c).filter(|&end| end > 0)
+        {
+            out.push_str(&rest[1..1 + end].italic().to_string());
+            rest = &rest[end + 2..];
+            prev = Some(c);
+            continue;
+        }
+
+        if c == '['
+            && let Some(close) = rest.find("](")
+            && let Some(end) = rest[close + 2..].find(')')
+        {
+            let label = &rest[1..close];
+            let url = &rest[close + 2..close + 2 + end];
+            out.push_str(&label.underline().to_string());
+            out.push_str(&format!(" ({})", url).dimmed().to_string());
+            rest = &rest[close + 3 + end..];
+            prev = Some(')');
+            continue;
+        }
+
+        out.push(c);
+        prev = Some(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    out
+}
@@ -0,0 +1,15 @@
+//! Building blocks of the `chatgpt` terminal client: input handling, Markdown
+//! rendering, saved sessions and batch runs.
+
+pub mod batch;
+pub mod commands;
+pub mod markdown;
+pub mod session;
+
+pub use batch::{
+    BatchError, BatchOptions, BatchOutcome, BatchRequest, BatchResult, BatchSummary, ResultSender,
+    run_batch,
+};
+pub use commands::{Command, HELP, Input, InputBuffer};
+pub use markdown::{MarkdownRenderer, render};
+pub use session::{Session, SessionMessage};
@@ -0,0 +1,70 @@
+use crate::client::ConversationPosition;
+use crate::utils::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A saved terminal conversation: the transcript plus where the upstream
+/// conversation stands, so it can be continued after a restart
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Session {
+    pub created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<ConversationPosition>,
+    pub messages: Vec<SessionMessage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionMessage {
+    pub role: String,
+    pub content: String,
+    pub created_at: u64,
+    /// Image sent along with the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<PathBuf>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            created_at: now(),
+            ..Self::default()
+        }
+    }
+
+    pub fn push(&mut self, role: &str, content: &str, image: Option<PathBuf>) {
+        self.messages.push(SessionMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+            created_at: now(),
+            image,
+        });
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// File name for a session saved without an explicit path
+    pub fn default_file_name(&self) -> PathBuf {
+        let created =
+            chrono::DateTime::from_timestamp(self.created_at as i64, 0).unwrap_or_default();
+        PathBuf::from(format!("chatgpt-{}.json", created.format("%Y%m%d-%H%M%S")))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,209 @@
+use crate::client::{ChatGptClient, RetryPolicy};
+use crate::network::IpInfo;
+use crate::network::cassette::{CassetteMode, ReplayMatching};
+use crate::utils::{ChatGptError, Result, Utils};
+use reqwest::{Client, Proxy};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Default upstream the client talks to
+pub const DEFAULT_BASE_URL: &str = "https://chatgpt.com";
+
+/// User agent sent with every request (must match the browser fingerprint)
+pub(crate) const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/140.0.0.0 Safari/537.36";
+
+/// Connection settings for [`ChatGptClient`]
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Scheme and host of the upstream, without a trailing path
+    pub base_url: String,
+    /// HTTP/HTTPS proxy, in any format accepted by `Utils::format_proxy`
+    pub proxy: Option<String>,
+    /// Time allowed to establish a connection
+    pub connect_timeout: Option<Duration>,
+    /// Longest silence allowed while reading a response, including between
+    /// chunks of a streamed answer
+    pub read_timeout: Option<Duration>,
+    /// Total time allowed for a single non-streaming request
+    pub request_timeout: Option<Duration>,
+    /// Total time allowed for one conversation turn, from the first token
+    /// request to the end of the answer
+    pub deadline: Option<Duration>,
+    /// Look up the public IP and timezone on startup. When off the client
+    /// presents itself as UTC, which is what local test servers want.
+    pub ip_lookup: bool,
+    /// Retries for transient upstream failures
+    pub retry: RetryPolicy,
+    /// Record upstream traffic to a cassette file, or replay it from one
+    pub cassette: Option<CassetteMode>,
+    /// What replay checks besides the method and path of each request
+    pub replay_matching: ReplayMatching,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            proxy: None,
+            connect_timeout: Some(Duration::from_secs(15)),
+            read_timeout: Some(Duration::from_secs(60)),
+            request_timeout: Some(Duration::from_secs(30)),
+            deadline: None,
+            ip_lookup: true,
+            retry: RetryPolicy::default(),
+            cassette: None,
+            replay_matching: ReplayMatching::default(),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Build the HTTP client described by this config
+    pub fn http_client(&self) -> Result<Client> {
+        let mut client_builder = Client::builder().user_agent(USER_AGENT).cookie_store(true);
+
+        if let Some(proxy_url) = self.proxy.as_deref() {
+            let formatted_proxy = Utils::format_proxy(proxy_url)?;
+            let proxy = Proxy::all(&formatted_proxy).map_err(|e| {
+                ChatGptError::invalid_proxy(format!("Failed to create proxy: {}", e))
+            })?;
+            client_builder = client_builder.proxy(proxy);
+        }
+
+        if let Some(timeout) = self.connect_timeout {
+            client_builder = client_builder.connect_timeout(timeout);
+        }
+
+        if let Some(timeout) = self.read_timeout {
+            client_builder = client_builder.read_timeout(timeout);
+        }
+
+        Ok(client_builder.build()?)
+    }
+}
+
+/// Builder for [`ChatGptClient`]
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use chatgpt_rs::client::ChatGptClient;
+/// # async fn demo() -> chatgpt_rs::Result<()> {
+/// let client = ChatGptClient::builder()
+///     .base_url("http://127.0.0.1:8080")
+///     .read_timeout(Duration::from_secs(20))
+///     .deadline(Duration::from_secs(120))
+///     .ip_lookup(false)
+///     .build()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct ChatGptClientBuilder {
+    config: ClientConfig,
+    http_client: Option<Client>,
+}
+
+impl ChatGptClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from an existing config
+    pub fn from_config(config: ClientConfig) -> Self {
+        Self {
+            config,
+            http_client: None,
+        }
+    }
+
+    /// The settings the client will be built with
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.config.base_url = base_url.into();
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Option<&str>) -> Self {
+        self.config.proxy = proxy.map(str::to_string);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.config.request_timeout = Some(timeout);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.config.deadline = Some(deadline);
+        self
+    }
+
+    pub fn ip_lookup(mut self, enabled: bool) -> Self {
+        self.config.ip_lookup = enabled;
+        self
+    }
+
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.config.retry = policy;
+        self
+    }
+
+    /// Record every upstream exchange, with secrets redacted, to `path`
+    pub fn record_to(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.cassette = Some(CassetteMode::Record(path.into()));
+        self
+    }
+
+    /// Answer every upstream request from the cassette at `path`, offline.
+    /// The IP lookup is skipped.
+    pub fn replay_from(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.cassette = Some(CassetteMode::Replay(path.into()));
+        self
+    }
+
+    /// Also check request bodies or headers against the cassette on replay
+    pub fn replay_matching(mut self, matching: ReplayMatching) -> Self {
+        self.config.replay_matching = matching;
+        self
+    }
+
+    /// Use a preconfigured HTTP client instead of building one.
+    ///
+    /// The proxy, connect and read timeouts of the config are then ignored;
+    /// the client must have its cookie store enabled.
+    pub fn http_client(mut self, client: Client) -> Self {
+        self.http_client = Some(client);
+        self
+    }
+
+    /// Connect to the upstream and fetch the initial session
+    pub async fn build(self) -> Result<ChatGptClient> {
+        let client = match self.http_client {
+            Some(client) => client,
+            None => self.config.http_client()?,
+        };
+
+        let replaying = matches!(self.config.cassette, Some(CassetteMode::Replay(_)));
+        let ip_info = if self.config.ip_lookup && !replaying {
+            IpInfo::fetch(&client).await?
+        } else {
+            IpInfo::default()
+        };
+
+        ChatGptClient::connect(self.config, client, ip_info).await
+    }
+}
@@ -1,15 +1,23 @@
+use crate::client::builder::{ChatGptClientBuilder, ClientConfig};
 use crate::crypto::Challenges;
-use crate::network::{Headers, IpInfo};
-use crate::utils::{ChatGptError, Result, Utils};
+use crate::network::cassette::Transport;
+use crate::network::upstream::upstream_error;
+use crate::network::{Headers, IpInfo, ensure_success};
+use crate::utils::{ChatGptError, Result, Secret, Utils};
 use crate::vm::VM;
 use base64::{Engine as _, engine::general_purpose};
 use chrono::prelude::*;
+use futures::StreamExt;
 use image::ImageReader;
 use rand::Rng;
-use reqwest::{Client, Proxy};
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 use std::io::Cursor;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, instrument, warn};
 use uuid::Uuid;
 
 /// Browser window keys for environment simulation
@@ -270,17 +278,39 @@ const WINDOW_KEYS: &[&str] = &[
     "_oaiHandleSessionExpired",
 ];
 
+/// Sender for answer text deltas as they stream in from upstream
+pub type DeltaSender = mpsc::UnboundedSender<String>;
+
+/// Run a future unless the token is cancelled first
+async fn until_cancelled<T>(
+    cancel: &CancellationToken,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::select! {
+        result = future => result,
+        _ = cancel.cancelled() => Err(ChatGptError::Cancelled),
+    }
+}
+
+/// Where an upstream conversation stands, enough to continue it later
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationPosition {
+    pub conversation_id: String,
+    pub parent_message_id: String,
+}
+
 /// ChatGPT configuration data
 #[derive(Debug, Clone)]
 pub struct ChatGptData {
     pub prod: String,
-    pub device_id: String,
-    pub token: String,
-    pub conversation_id: Option<String>,
-    pub parent_message_id: Option<String>,
+    pub device_id: Secret<String>,
+    /// Sentinel chat-requirements token of the current turn
+    pub token: Secret<String>,
+    pub conversation_id: Option<Secret<String>>,
+    pub parent_message_id: Option<Secret<String>>,
     pub proofofwork: Option<Value>,
     pub bytecode: Option<String>,
-    pub vm_token: Option<String>,
+    pub vm_token: Option<Secret<String>>,
     pub file_id: Option<String>,
     pub file_name: Option<String>,
     pub file_size: Option<usize>,
@@ -293,8 +323,8 @@ impl Default for ChatGptData {
     fn default() -> Self {
         Self {
             prod: String::new(),
-            device_id: String::new(),
-            token: String::new(),
+            device_id: Secret::default(),
+            token: Secret::default(),
             conversation_id: None,
             parent_message_id: None,
             proofofwork: None,
@@ -313,6 +343,8 @@ impl Default for ChatGptData {
 /// Main ChatGPT client
 pub struct ChatGptClient {
     client: Client,
+    transport: Transport,
+    config: ClientConfig,
     data: ChatGptData,
     ip_info: IpInfo,
     timezone_offset: i32,
@@ -320,26 +352,32 @@ pub struct ChatGptClient {
     sid: String,
     window_keys: Vec<String>,
     reacts: Vec<String>,
+    /// Set once upstream accepted the current turn's message; from then on
+    /// the turn is never retried
+    turn_accepted: bool,
+    /// Image staged through [`ChatBackend::upload_attachment`](crate::backend::ChatBackend::upload_attachment)
+    /// for the next message
+    pub(crate) pending_attachment: Option<String>,
 }
 
 impl ChatGptClient {
     /// Create new ChatGPT client
     pub async fn new(proxy: Option<&str>) -> Result<Self> {
-        let mut client_builder = Client::builder()
-            .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/140.0.0.0 Safari/537.36")
-            .cookie_store(true);
-
-        if let Some(proxy_url) = proxy {
-            let formatted_proxy = Utils::format_proxy(proxy_url)?;
-            let proxy = Proxy::all(&formatted_proxy).map_err(|e| {
-                ChatGptError::invalid_proxy(format!("Failed to create proxy: {}", e))
-            })?;
-            client_builder = client_builder.proxy(proxy);
-        }
+        Self::builder().proxy(proxy).build().await
+    }
 
-        let client = client_builder.build()?;
-        let ip_info = IpInfo::fetch(&client).await?;
+    /// Start configuring a client (base URL, timeouts, custom HTTP client)
+    pub fn builder() -> ChatGptClientBuilder {
+        ChatGptClientBuilder::new()
+    }
 
+    /// Set up session state on top of a ready HTTP client
+    #[instrument(name = "chatgpt_session", skip_all)]
+    pub(crate) async fn connect(
+        config: ClientConfig,
+        client: Client,
+        ip_info: IpInfo,
+    ) -> Result<Self> {
         // Calculate timezone offset
         let timezone_offset = match ip_info.timezone.parse::<chrono_tz::Tz>() {
             Ok(tz) => {
@@ -365,8 +403,12 @@ impl ChatGptClient {
 
         let window_keys: Vec<String> = WINDOW_KEYS.iter().map(|s| s.to_string()).collect();
 
+        let transport = Transport::new(config.cassette.as_ref(), &config.replay_matching)?;
+
         let mut instance = Self {
             client,
+            transport,
+            config,
             data: ChatGptData::default(),
             ip_info,
             timezone_offset,
@@ -374,15 +416,46 @@ impl ChatGptClient {
             sid,
             window_keys,
             reacts,
+            turn_accepted: false,
+            pending_attachment: None,
         };
 
-        instance.fetch_cookies().await?;
+        let mut attempt = 1;
+        loop {
+            match instance.fetch_cookies().await {
+                Err(err)
+                    if instance
+                        .should_retry("session setup", attempt, &err, None)
+                        .await =>
+                {
+                    attempt += 1
+                }
+                result => break result?,
+            }
+        }
         Ok(instance)
     }
 
+    /// Absolute URL of an upstream path
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.config.base_url.trim_end_matches('/'), path)
+    }
+
+    /// Apply the per-request timeout to a non-streaming request
+    fn with_request_timeout(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.config.request_timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        }
+    }
+
     /// Fetch initial cookies and configuration
     async fn fetch_cookies(&mut self) -> Result<()> {
-        let response = self.client.get("https://chatgpt.com").send().await?;
+        let response = self
+            .transport
+            .send(self.with_request_timeout(self.client.get(self.url("/"))))
+            .await?;
+        let response = ensure_success(response, "/").await?;
 
         let html = response.text().await?;
 
@@ -393,7 +466,7 @@ impl ChatGptClient {
 
         // Extract device ID from cookies
         if let Some(device_id) = self.get_cookie_value("oai-did") {
-            self.data.device_id = device_id;
+            self.data.device_id = Secret::new(device_id);
         }
 
         self.initialize_config()?;
@@ -475,7 +548,10 @@ impl ChatGptClient {
     async fn get_tokens(&mut self) -> Result<()> {
         let mut headers = Headers::requirements();
         headers.insert("oai-client-version".to_string(), self.data.prod.clone());
-        headers.insert("oai-device-id".to_string(), self.data.device_id.clone());
+        headers.insert(
+            "oai-device-id".to_string(),
+            self.data.device_id.expose().clone(),
+        );
 
         // Update config with new timing
         let process_time = {
@@ -485,7 +561,7 @@ impl ChatGptClient {
         self.update_config_timing(process_time)?;
 
         let p_value = Challenges::generate_token(self.data.config.clone())?;
-        self.data.vm_token = Some(p_value.clone());
+        self.data.vm_token = Some(Secret::new(p_value.clone()));
 
         let payload = json!({
             "p": p_value
@@ -493,7 +569,7 @@ impl ChatGptClient {
 
         let mut request = self
             .client
-            .post("https://chatgpt.com/backend-anon/sentinel/chat-requirements")
+            .post(self.url("/backend-anon/sentinel/chat-requirements"))
             .json(&payload);
 
         // Apply headers
@@ -501,26 +577,24 @@ impl ChatGptClient {
             request = request.header(key, value);
         }
 
-        let response = request.send().await?;
+        let response = self
+            .transport
+            .send(self.with_request_timeout(request))
+            .await?;
+        let response = ensure_success(response, "/backend-anon/sentinel/chat-requirements").await?;
 
-        if response.status().is_success() {
-            let json: Value = response.json().await?;
+        let json: Value = response.json().await?;
 
-            if let Some(token) = json.get("token").and_then(|v| v.as_str()) {
-                self.data.token = token.to_string();
-            }
+        if let Some(token) = json.get("token").and_then(|v| v.as_str()) {
+            self.data.token = Secret::new(token.to_string());
+        }
 
-            self.data.proofofwork = json.get("proofofwork").cloned();
+        self.data.proofofwork = json.get("proofofwork").cloned();
 
-            if let Some(turnstile) = json.get("turnstile")
-                && let Some(dx) = turnstile.get("dx").and_then(|v| v.as_str())
-            {
-                self.data.bytecode = Some(dx.to_string());
-            }
-        } else {
-            return Err(ChatGptError::authentication(
-                "Failed to get chat requirements",
-            ));
+        if let Some(turnstile) = json.get("turnstile")
+            && let Some(dx) = turnstile.get("dx").and_then(|v| v.as_str())
+        {
+            self.data.bytecode = Some(dx.to_string());
         }
 
         Ok(())
@@ -552,7 +626,10 @@ impl ChatGptClient {
     async fn get_conduit(&self, next: bool) -> Result<String> {
         let mut headers = Headers::conduit();
         headers.insert("oai-client-version".to_string(), self.data.prod.clone());
-        headers.insert("oai-device-id".to_string(), self.data.device_id.clone());
+        headers.insert(
+            "oai-device-id".to_string(),
+            self.data.device_id.expose().clone(),
+        );
 
         let payload = if next {
             json!({
@@ -591,7 +668,7 @@ impl ChatGptClient {
 
         let mut request = self
             .client
-            .post("https://chatgpt.com/backend-anon/f/conversation/prepare")
+            .post(self.url("/backend-anon/f/conversation/prepare"))
             .json(&payload);
 
         // Apply headers
@@ -599,7 +676,11 @@ impl ChatGptClient {
             request = request.header(key, value);
         }
 
-        let response = request.send().await?;
+        let response = self
+            .transport
+            .send(self.with_request_timeout(request))
+            .await?;
+        let response = ensure_success(response, "/backend-anon/f/conversation/prepare").await?;
 
         let json: Value = response.json().await?;
 
@@ -612,8 +693,42 @@ impl ChatGptClient {
 
     /// Start a conversation
     pub async fn start_conversation(&mut self, message: &str) -> Result<String> {
-        self.get_tokens().await?;
-        let conduit_token = self.get_conduit(false).await?;
+        self.start_conversation_streaming(message, None, &CancellationToken::new())
+            .await
+    }
+
+    /// Start a conversation, forwarding answer deltas as they arrive
+    ///
+    /// Cancelling `cancel` drops the in-flight upstream request and returns
+    /// `ChatGptError::Cancelled`.
+    #[instrument(name = "chatgpt_turn", skip_all, fields(new = true))]
+    pub async fn start_conversation_streaming(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        let (turn, timer) = self.start_turn(cancel);
+        let mut attempt = 1;
+        let result = loop {
+            self.turn_accepted = false;
+            let result = self.start_conversation_turn(message, deltas, &turn).await;
+            match &result {
+                Err(err) if self.retry_turn(attempt, err, &turn).await => attempt += 1,
+                _ => break result,
+            }
+        };
+        self.finish_turn(result, cancel, timer)
+    }
+
+    async fn start_conversation_turn(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        until_cancelled(cancel, self.get_tokens()).await?;
+        let conduit_token = until_cancelled(cancel, self.get_conduit(false)).await?;
 
         let (echo_logs, time_since_loaded) = {
             let mut rng = rand::rng();
@@ -635,9 +750,13 @@ impl ChatGptClient {
         };
 
         // Get turnstile token
-        let turnstile_token = if let (Some(bytecode), Some(vm_token)) =
-            (self.data.bytecode.as_deref(), self.data.vm_token.as_deref())
-        {
+        let turnstile_token = if let (Some(bytecode), Some(vm_token)) = (
+            self.data.bytecode.as_deref(),
+            self.data
+                .vm_token
+                .as_ref()
+                .map(|token| token.expose().as_str()),
+        ) {
             VM::get_turnstile(bytecode, vm_token, &self.ip_info.without_timezone())?
         } else {
             return Err(ChatGptError::authentication("Missing bytecode or VM token"));
@@ -646,11 +765,14 @@ impl ChatGptClient {
         // Prepare conversation headers
         let mut headers = Headers::conversation();
         headers.insert("oai-client-version".to_string(), self.data.prod.clone());
-        headers.insert("oai-device-id".to_string(), self.data.device_id.clone());
+        headers.insert(
+            "oai-device-id".to_string(),
+            self.data.device_id.expose().clone(),
+        );
         headers.insert("oai-echo-logs".to_string(), echo_logs.clone());
         headers.insert(
             "openai-sentinel-chat-requirements-token".to_string(),
-            self.data.token.clone(),
+            self.data.token.expose().clone(),
         );
         headers.insert("openai-sentinel-proof-token".to_string(), proof_token);
         headers.insert(
@@ -706,7 +828,7 @@ impl ChatGptClient {
 
         let mut request = self
             .client
-            .post("https://chatgpt.com/backend-anon/f/conversation")
+            .post(self.url("/backend-anon/f/conversation"))
             .json(&conversation_payload);
 
         // Apply headers
@@ -714,36 +836,17 @@ impl ChatGptClient {
             request = request.header(key, value);
         }
 
-        let response = request.send().await?;
-
-        let response_text = response.text().await?;
-
-        if response_text.contains("Unusual activity") {
-            return Err(ChatGptError::IpFlagged);
-        }
-
-        // Extract conversation data
-        if let Some(conversation_id) =
-            Utils::between(&response_text, r#""conversation_id": ""#, r#"""#)
-        {
-            self.data.conversation_id = Some(conversation_id);
-        }
-
-        if let Some(parent_message_id) =
-            Utils::between(&response_text, r#""message_id": ""#, r#"""#)
-        {
-            self.data.parent_message_id = Some(parent_message_id);
-        }
-
-        let parsed_response = Utils::parse_event_stream(&response_text);
-        Ok(parsed_response)
+        self.send_conversation(request, deltas, cancel).await
     }
 
     /// Upload an image for multimodal conversation
     pub async fn upload_image(&mut self, image_data: &str) -> Result<()> {
         let mut headers = Headers::requirements();
         headers.insert("oai-client-version".to_string(), self.data.prod.clone());
-        headers.insert("oai-device-id".to_string(), self.data.device_id.clone());
+        headers.insert(
+            "oai-device-id".to_string(),
+            self.data.device_id.expose().clone(),
+        );
 
         // Generate file name
         let file_name = Uuid::new_v4().to_string();
@@ -777,7 +880,7 @@ impl ChatGptClient {
 
         let mut request = self
             .client
-            .post("https://chatgpt.com/backend-anon/files")
+            .post(self.url("/backend-anon/files"))
             .json(&image_payload);
 
         // Apply headers
@@ -785,7 +888,11 @@ impl ChatGptClient {
             request = request.header(key, value);
         }
 
-        let response = request.send().await?;
+        let response = self
+            .transport
+            .send(self.with_request_timeout(request))
+            .await?;
+        let response = ensure_success(response, "/backend-anon/files").await?;
 
         let upload_response: Value = response.json().await?;
 
@@ -795,16 +902,19 @@ impl ChatGptClient {
 
         if let Some(upload_url) = upload_response.get("upload_url").and_then(|v| v.as_str()) {
             // Upload the file
-            let upload_response = self.client.put(upload_url).body(image_bytes).send().await?;
-
-            if !upload_response.status().is_success() {
-                return Err(ChatGptError::authentication("Failed to upload image"));
-            }
+            let upload_response = self
+                .transport
+                .send(self.with_request_timeout(self.client.put(upload_url).body(image_bytes)))
+                .await?;
+            ensure_success(upload_response, "file upload").await?;
 
             // Process the uploaded file
             let mut process_headers = Headers::requirements();
             process_headers.insert("oai-client-version".to_string(), self.data.prod.clone());
-            process_headers.insert("oai-device-id".to_string(), self.data.device_id.clone());
+            process_headers.insert(
+                "oai-device-id".to_string(),
+                self.data.device_id.expose().clone(),
+            );
 
             let process_payload = json!({
                 "file_id": self.data.file_id.as_ref().unwrap(),
@@ -815,7 +925,7 @@ impl ChatGptClient {
 
             let mut process_request = self
                 .client
-                .post("https://chatgpt.com/backend-anon/files/process_upload_stream")
+                .post(self.url("/backend-anon/files/process_upload_stream"))
                 .json(&process_payload);
 
             // Apply headers
@@ -823,7 +933,15 @@ impl ChatGptClient {
                 process_request = process_request.header(key, value);
             }
 
-            let process_response = process_request.send().await?;
+            let process_response = self
+                .transport
+                .send(self.with_request_timeout(process_request))
+                .await?;
+            let process_response = ensure_success(
+                process_response,
+                "/backend-anon/files/process_upload_stream",
+            )
+            .await?;
 
             let process_text = process_response.text().await?;
             if !process_text.contains("Succeeded processing") {
@@ -840,9 +958,45 @@ impl ChatGptClient {
 
     /// Start a conversation with an image
     pub async fn start_with_image(&mut self, message: &str, image_data: &str) -> Result<String> {
-        self.get_tokens().await?;
-        let conduit_token = self.get_conduit(false).await?;
-        self.upload_image(image_data).await?;
+        self.start_with_image_streaming(message, image_data, None, &CancellationToken::new())
+            .await
+    }
+
+    /// Start a conversation with an image, forwarding answer text to `deltas`
+    /// as it arrives; cancelling `cancel` stops the turn
+    #[instrument(name = "chatgpt_turn", skip_all, fields(new = true, image = true))]
+    pub async fn start_with_image_streaming(
+        &mut self,
+        message: &str,
+        image_data: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        let (turn, timer) = self.start_turn(cancel);
+        let mut attempt = 1;
+        let result = loop {
+            self.turn_accepted = false;
+            let result = self
+                .start_with_image_turn(message, image_data, deltas, &turn)
+                .await;
+            match &result {
+                Err(err) if self.retry_turn(attempt, err, &turn).await => attempt += 1,
+                _ => break result,
+            }
+        };
+        self.finish_turn(result, cancel, timer)
+    }
+
+    async fn start_with_image_turn(
+        &mut self,
+        message: &str,
+        image_data: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        until_cancelled(cancel, self.get_tokens()).await?;
+        let conduit_token = until_cancelled(cancel, self.get_conduit(false)).await?;
+        until_cancelled(cancel, self.upload_image(image_data)).await?;
 
         let (echo_logs, time_since_loaded) = {
             let mut rng = rand::rng();
@@ -864,9 +1018,13 @@ impl ChatGptClient {
         };
 
         // Get turnstile token
-        let turnstile_token = if let (Some(bytecode), Some(vm_token)) =
-            (self.data.bytecode.as_deref(), self.data.vm_token.as_deref())
-        {
+        let turnstile_token = if let (Some(bytecode), Some(vm_token)) = (
+            self.data.bytecode.as_deref(),
+            self.data
+                .vm_token
+                .as_ref()
+                .map(|token| token.expose().as_str()),
+        ) {
             VM::get_turnstile(bytecode, vm_token, &self.ip_info.without_timezone())?
         } else {
             return Err(ChatGptError::authentication("Missing bytecode or VM token"));
@@ -875,11 +1033,14 @@ impl ChatGptClient {
         // Prepare conversation headers
         let mut headers = Headers::conversation();
         headers.insert("oai-client-version".to_string(), self.data.prod.clone());
-        headers.insert("oai-device-id".to_string(), self.data.device_id.clone());
+        headers.insert(
+            "oai-device-id".to_string(),
+            self.data.device_id.expose().clone(),
+        );
         headers.insert("oai-echo-logs".to_string(), echo_logs.clone());
         headers.insert(
             "openai-sentinel-chat-requirements-token".to_string(),
-            self.data.token.clone(),
+            self.data.token.expose().clone(),
         );
         headers.insert("openai-sentinel-proof-token".to_string(), proof_token);
         headers.insert(
@@ -953,7 +1114,7 @@ impl ChatGptClient {
 
         let mut request = self
             .client
-            .post("https://chatgpt.com/backend-anon/f/conversation")
+            .post(self.url("/backend-anon/f/conversation"))
             .json(&conversation_payload);
 
         // Apply headers
@@ -961,29 +1122,27 @@ impl ChatGptClient {
             request = request.header(key, value);
         }
 
-        let response = request.send().await?;
-
-        let response_text = response.text().await?;
-
-        if response_text.contains("Unusual activity") {
-            return Err(ChatGptError::IpFlagged);
-        }
+        self.send_conversation(request, deltas, cancel).await
+    }
 
-        // Extract conversation data
-        if let Some(conversation_id) =
-            Utils::between(&response_text, r#""conversation_id": ""#, r#"""#)
-        {
-            self.data.conversation_id = Some(conversation_id);
-        }
+    /// Current conversation position, if a conversation has been started
+    pub fn position(&self) -> Option<ConversationPosition> {
+        Some(ConversationPosition {
+            conversation_id: self.data.conversation_id.clone()?.into_inner(),
+            parent_message_id: self.data.parent_message_id.clone()?.into_inner(),
+        })
+    }
 
-        if let Some(parent_message_id) =
-            Utils::between(&response_text, r#""message_id": ""#, r#"""#)
-        {
-            self.data.parent_message_id = Some(parent_message_id);
-        }
+    /// Continue from a saved position on the next `hold_conversation*` call
+    pub fn resume(&mut self, position: ConversationPosition) {
+        self.data.conversation_id = Some(Secret::new(position.conversation_id));
+        self.data.parent_message_id = Some(Secret::new(position.parent_message_id));
+    }
 
-        let parsed_response = Utils::parse_event_stream(&response_text);
-        Ok(parsed_response)
+    /// Forget the current conversation so the next turn starts a new one
+    pub fn reset_conversation(&mut self) {
+        self.data.conversation_id = None;
+        self.data.parent_message_id = None;
     }
 
     /// Send a question and get response
@@ -1002,17 +1161,51 @@ impl ChatGptClient {
 
     /// Hold a conversation with ability to continue chatting
     pub async fn hold_conversation(&mut self, message: &str, new: bool) -> Result<String> {
-        let mut index = 2000;
-
         if new {
             self.start_conversation(message).await?;
         }
 
+        self.hold_conversation_streaming(message, None, &CancellationToken::new())
+            .await
+    }
+
+    /// Continue the current conversation, forwarding answer deltas as they arrive
+    ///
+    /// Cancelling `cancel` drops the in-flight upstream request and returns
+    /// `ChatGptError::Cancelled`.
+    #[instrument(name = "chatgpt_turn", skip_all, fields(new = false))]
+    pub async fn hold_conversation_streaming(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        let (turn, timer) = self.start_turn(cancel);
+        let mut attempt = 1;
+        let result = loop {
+            self.turn_accepted = false;
+            let result = self.hold_conversation_turn(message, deltas, &turn).await;
+            match &result {
+                Err(err) if self.retry_turn(attempt, err, &turn).await => attempt += 1,
+                _ => break result,
+            }
+        };
+        self.finish_turn(result, cancel, timer)
+    }
+
+    async fn hold_conversation_turn(
+        &mut self,
+        message: &str,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        let mut index = 2000;
+
         // Get conduit token
-        let conduit_token = self.get_conduit(true).await?;
+        let conduit_token = until_cancelled(cancel, self.get_conduit(true)).await?;
 
         // Get tokens
-        self.get_tokens().await?;
+        until_cancelled(cancel, self.get_tokens()).await?;
         index += 3000;
 
         let echo_logs = {
@@ -1040,9 +1233,13 @@ impl ChatGptClient {
         };
 
         // Get turnstile token
-        let turnstile_token = if let (Some(bytecode), Some(vm_token)) =
-            (self.data.bytecode.as_deref(), self.data.vm_token.as_deref())
-        {
+        let turnstile_token = if let (Some(bytecode), Some(vm_token)) = (
+            self.data.bytecode.as_deref(),
+            self.data
+                .vm_token
+                .as_ref()
+                .map(|token| token.expose().as_str()),
+        ) {
             VM::get_turnstile(bytecode, vm_token, &self.ip_info.ip)?
         } else {
             return Err(ChatGptError::invalid_response(
@@ -1053,11 +1250,14 @@ impl ChatGptClient {
         // Prepare headers
         let mut headers = Headers::conversation();
         headers.insert("oai-client-version".to_string(), self.data.prod.clone());
-        headers.insert("oai-device-id".to_string(), self.data.device_id.clone());
+        headers.insert(
+            "oai-device-id".to_string(),
+            self.data.device_id.expose().clone(),
+        );
         headers.insert("oai-echo-logs".to_string(), echo_logs);
         headers.insert(
             "openai-sentinel-chat-requirements-token".to_string(),
-            self.data.token.clone(),
+            self.data.token.expose().clone(),
         );
         headers.insert("openai-sentinel-proof-token".to_string(), proof_token);
         headers.insert(
@@ -1066,14 +1266,6 @@ impl ChatGptClient {
         );
         headers.insert("x-conduit-token".to_string(), conduit_token);
 
-        let new_message = if new {
-            // In interactive mode, would prompt for input
-            // For now, use the provided message
-            message.to_string()
-        } else {
-            message.to_string()
-        };
-
         let conversation_data = json!({
             "action": "next",
             "messages": [{
@@ -1084,7 +1276,7 @@ impl ChatGptClient {
                 "create_time": (SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64()),
                 "content": {
                     "content_type": "text",
-                    "parts": [new_message]
+                    "parts": [message]
                 },
                 "metadata": {
                     "selected_github_repos": [],
@@ -1122,7 +1314,7 @@ impl ChatGptClient {
 
         let mut request = self
             .client
-            .post("https://chatgpt.com/backend-anon/f/conversation")
+            .post(self.url("/backend-anon/f/conversation"))
             .json(&conversation_data);
 
         // Apply headers
@@ -1130,26 +1322,208 @@ impl ChatGptClient {
             request = request.header(&key, &value);
         }
 
-        let response = request.send().await?;
-        let response_text = response.text().await?;
+        self.send_conversation(request, deltas, cancel).await
+    }
+
+    /// Derive the cancellation token for one conversation turn.
+    ///
+    /// If an overall deadline is configured, a timer cancels the turn when it
+    /// passes, which goes through the same path as a caller cancel and keeps the
+    /// conversation position.
+    fn start_turn(
+        &self,
+        cancel: &CancellationToken,
+    ) -> (CancellationToken, Option<tokio::task::JoinHandle<()>>) {
+        let turn = cancel.child_token();
+        let timer = self.config.deadline.map(|deadline| {
+            let turn = turn.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(deadline).await;
+                turn.cancel();
+            })
+        });
+        (turn, timer)
+    }
+
+    /// Whether a failed turn attempt should run again, after waiting out the
+    /// backoff. Turns upstream already accepted are never retried, so partial
+    /// output is not repeated and the message is not sent twice.
+    async fn retry_turn(
+        &self,
+        attempt: u32,
+        error: &ChatGptError,
+        cancel: &CancellationToken,
+    ) -> bool {
+        !self.turn_accepted
+            && self
+                .should_retry("conversation turn", attempt, error, Some(cancel))
+                .await
+    }
+
+    /// Apply the retry policy to a failed attempt, sleeping out the backoff if
+    /// it allows another one. Returns false early if `cancel` fires meanwhile.
+    async fn should_retry(
+        &self,
+        operation: &str,
+        attempt: u32,
+        error: &ChatGptError,
+        cancel: Option<&CancellationToken>,
+    ) -> bool {
+        let policy = &self.config.retry;
+        let Some(delay) = policy.backoff(attempt, error) else {
+            if attempt > 1 {
+                warn!(
+                    "{} failed after {} attempt(s): {}",
+                    operation, attempt, error
+                );
+            }
+            return false;
+        };
+
+        warn!(
+            "{} failed (attempt {}/{}, {}): {}; retrying in {:?}",
+            operation,
+            attempt,
+            policy.max_attempts,
+            error.code(),
+            error,
+            delay
+        );
+
+        match cancel {
+            Some(cancel) => tokio::select! {
+                _ = tokio::time::sleep(delay) => true,
+                _ = cancel.cancelled() => false,
+            },
+            None => {
+                tokio::time::sleep(delay).await;
+                true
+            }
+        }
+    }
+
+    /// Stop the deadline timer and report a deadline cancel as a timeout
+    fn finish_turn(
+        &self,
+        result: Result<String>,
+        cancel: &CancellationToken,
+        timer: Option<tokio::task::JoinHandle<()>>,
+    ) -> Result<String> {
+        if let Some(timer) = timer {
+            timer.abort();
+        }
+
+        match result {
+            Err(ChatGptError::Cancelled) if !cancel.is_cancelled() => {
+                Err(ChatGptError::timeout(format!(
+                    "conversation turn exceeded {:?}",
+                    self.config.deadline.unwrap_or_default()
+                )))
+            }
+            result => result,
+        }
+    }
+
+    /// Send a conversation request and read the event stream as it arrives
+    ///
+    /// Answer text is forwarded to `deltas` line by line. If `cancel` fires the
+    /// upstream response is dropped, the conversation position seen so far is
+    /// kept and `ChatGptError::Cancelled` is returned.
+    async fn send_conversation(
+        &mut self,
+        request: RequestBuilder,
+        deltas: Option<&DeltaSender>,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        let response = until_cancelled(cancel, self.transport.send(request)).await?;
+
+        if !response.status().is_success() {
+            let error = upstream_error(response, "/backend-anon/f/conversation").await;
+            return match error {
+                ChatGptError::Upstream { body_excerpt, .. }
+                    if body_excerpt.contains("Unusual activity") =>
+                {
+                    Err(ChatGptError::IpFlagged)
+                }
+                error => Err(error),
+            };
+        }
+        self.turn_accepted = true;
+        debug!("Upstream accepted the turn");
+
+        let mut stream = response.bytes_stream();
+
+        let mut response_text = String::new();
+        let mut answer = String::new();
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut done = false;
+        let mut cancelled = false;
+
+        let mut handle_line = |line: &str, done: &mut bool| {
+            response_text.push_str(line);
+            if *done {
+                return;
+            }
+            match Utils::parse_event_line(line.trim_end()) {
+                Some(parts) => {
+                    for part in parts {
+                        answer.push_str(&part);
+                        if let Some(deltas) = deltas {
+                            // The receiver going away is the caller's business
+                            let _ = deltas.send(part);
+                        }
+                    }
+                }
+                None => *done = true,
+            }
+        };
+
+        loop {
+            let chunk = tokio::select! {
+                chunk = stream.next() => chunk,
+                _ = cancel.cancelled() => {
+                    cancelled = true;
+                    break;
+                }
+            };
+
+            let Some(chunk) = chunk else {
+                break;
+            };
+            buffer.extend_from_slice(&chunk?);
+
+            while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=pos).collect();
+                handle_line(&String::from_utf8_lossy(&line), &mut done);
+            }
+        }
+
+        if !buffer.is_empty() {
+            handle_line(&String::from_utf8_lossy(&buffer), &mut done);
+        }
 
         if response_text.contains("Unusual activity") {
-            return Err(ChatGptError::invalid_response(
-                "Your IP got flagged by chatgpt, retry with a new IP".to_string(),
-            ));
+            return Err(ChatGptError::IpFlagged);
         }
 
-        // Update conversation state
+        // Extract conversation data
         if let Some(conversation_id) =
-            Utils::between(&response_text, "\"conversation_id\": \"", "\"")
+            Utils::between(&response_text, r#""conversation_id": ""#, r#"""#)
         {
-            self.data.conversation_id = Some(conversation_id);
+            self.data.conversation_id = Some(Secret::new(conversation_id));
         }
-        if let Some(parent_message_id) = Utils::between(&response_text, "\"message_id\": \"", "\"")
+
+        if let Some(parent_message_id) =
+            Utils::between(&response_text, r#""message_id": ""#, r#"""#)
         {
-            self.data.parent_message_id = Some(parent_message_id);
+            self.data.parent_message_id = Some(Secret::new(parent_message_id));
+        }
+
+        if cancelled {
+            return Err(ChatGptError::Cancelled);
         }
 
-        Ok(Utils::parse_event_stream(&response_text))
+        debug!(chars = answer.chars().count(), "Upstream turn finished");
+        Ok(answer)
     }
 }
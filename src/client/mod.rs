@@ -1,3 +1,7 @@
+pub mod builder;
 pub mod chatgpt;
+pub mod retry;
 
-pub use chatgpt::{ChatGptClient, ChatGptData};
+pub use builder::{ChatGptClientBuilder, ClientConfig};
+pub use chatgpt::{ChatGptClient, ChatGptData, ConversationPosition};
+pub use retry::RetryPolicy;
@@ -0,0 +1,190 @@
+use crate::utils::ChatGptError;
+use rand::Rng;
+use std::time::Duration;
+
+/// When and how often [`ChatGptClient`](crate::client::ChatGptClient) retries
+/// failed upstream calls.
+///
+/// A conversation turn is retried as a whole (tokens, conduit, conversation
+/// POST) and only until upstream accepts the message, so a retry never
+/// repeats answer text the caller has already seen.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one; 1 disables retries
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub initial_backoff: Duration,
+    /// Upper bound for any single delay
+    pub max_backoff: Duration,
+    /// Factor the delay grows by after each attempt
+    pub multiplier: f64,
+    /// Fraction (0.0 to 1.0) of each delay that is randomized away, so that
+    /// clients failing together don't retry in lockstep
+    pub jitter: f64,
+    /// Error codes (see `ChatGptError::code`) worth retrying. `None` falls
+    /// back to `ChatGptError::is_retryable`.
+    pub retry_on: Option<Vec<String>>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(8),
+            multiplier: 2.0,
+            jitter: 0.5,
+            retry_on: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Whether `error` is a kind this policy retries
+    pub fn is_retryable(&self, error: &ChatGptError) -> bool {
+        match &self.retry_on {
+            Some(codes) => codes.iter().any(|code| code == error.code()),
+            None => error.is_retryable(),
+        }
+    }
+
+    /// Delay before the attempt following `attempt` (1-based), or `None` if the
+    /// error should be returned to the caller instead.
+    ///
+    /// An upstream `Retry-After` is honoured; if it asks for longer than
+    /// `max_backoff` the error is not retried.
+    pub fn backoff(&self, attempt: u32, error: &ChatGptError) -> Option<Duration> {
+        if attempt >= self.max_attempts || !self.is_retryable(error) {
+            return None;
+        }
+
+        if let ChatGptError::Upstream {
+            retry_after: Some(seconds),
+            ..
+        } = error
+        {
+            let requested = Duration::from_secs(*seconds);
+            return (requested <= self.max_backoff).then_some(requested);
+        }
+
+        let exponent = attempt.saturating_sub(1) as i32;
+        let base = self
+            .initial_backoff
+            .mul_f64(self.multiplier.max(1.0).powi(exponent))
+            .min(self.max_backoff);
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 - jitter * rand::rng().random::<f64>();
+        Some(base.mul_f64(factor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unavailable(retry_after: Option<u64>) -> ChatGptError {
+        ChatGptError::Upstream {
+            status: 503,
+            endpoint: "conversation".to_string(),
+            body_excerpt: String::new(),
+            retry_after,
+        }
+    }
+
+    fn without_jitter() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.0,
+            retry_on: None,
+        }
+    }
+
+    #[test]
+    fn grows_exponentially_up_to_the_cap() {
+        let policy = without_jitter();
+        let delays: Vec<_> = (1..=6)
+            .map(|attempt| policy.backoff(attempt, &unavailable(None)).unwrap())
+            .collect();
+        let millis: Vec<_> = delays.iter().map(Duration::as_millis).collect();
+        assert_eq!(millis, [100, 200, 400, 800, 1000, 1000]);
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..without_jitter()
+        };
+        for _ in 0..200 {
+            let delay = policy.backoff(3, &unavailable(None)).unwrap();
+            assert!(delay <= Duration::from_millis(400), "{:?}", delay);
+            assert!(delay >= Duration::from_millis(200), "{:?}", delay);
+        }
+
+        // Out-of-range jitter is clamped, so delays never go negative or grow
+        let wild = RetryPolicy {
+            jitter: 7.0,
+            ..without_jitter()
+        };
+        for _ in 0..200 {
+            assert!(wild.backoff(1, &unavailable(None)).unwrap() <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn honours_retry_after_within_the_cap() {
+        let policy = RetryPolicy {
+            max_backoff: Duration::from_secs(5),
+            jitter: 0.5,
+            ..without_jitter()
+        };
+        assert_eq!(
+            policy.backoff(1, &unavailable(Some(3))),
+            Some(Duration::from_secs(3))
+        );
+        // Waiting longer than the cap allows is left to the caller
+        assert_eq!(policy.backoff(1, &unavailable(Some(6))), None);
+    }
+
+    #[test]
+    fn gives_up_after_the_last_attempt_or_on_other_errors() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            ..without_jitter()
+        };
+        assert!(policy.backoff(2, &unavailable(None)).is_some());
+        assert_eq!(policy.backoff(3, &unavailable(None)), None);
+        assert_eq!(RetryPolicy::none().backoff(1, &unavailable(None)), None);
+
+        let rejected = ChatGptError::Upstream {
+            status: 403,
+            endpoint: "conversation".to_string(),
+            body_excerpt: String::new(),
+            retry_after: None,
+        };
+        assert_eq!(policy.backoff(1, &rejected), None);
+
+        let only_timeouts = RetryPolicy {
+            retry_on: Some(vec!["upstream_timeout".to_string()]),
+            ..without_jitter()
+        };
+        assert_eq!(only_timeouts.backoff(1, &unavailable(None)), None);
+        assert!(
+            only_timeouts
+                .backoff(1, &ChatGptError::timeout("read"))
+                .is_some()
+        );
+    }
+}
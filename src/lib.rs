@@ -1,9 +1,15 @@
 pub mod api;
+pub mod backend;
+pub mod cli;
 pub mod client;
 pub mod crypto;
+pub mod mcp;
 pub mod network;
+#[cfg(feature = "test-support")]
+pub mod test_support;
 pub mod utils;
 pub mod vm;
 
+#[allow(deprecated)]
 pub use utils::logger::*;
 pub use utils::{ChatGptError, Result};
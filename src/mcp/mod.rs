@@ -0,0 +1,13 @@
+//! Model Context Protocol server, so agent frameworks can ask ChatGPT for a
+//! second opinion: JSON-RPC over stdio with `ask`, `continue_conversation`,
+//! `ask_with_image` and `list_conversations` tools. Conversations are kept
+//! in a [`ConversationStore`] between calls and across restarts.
+
+pub mod jsonrpc;
+pub mod server;
+pub mod store;
+pub mod tools;
+
+pub use server::{McpServer, PROTOCOL_VERSIONS};
+pub use store::{Conversation, ConversationStore};
+pub use tools::ToolOutput;
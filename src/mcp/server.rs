@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use base64::{Engine as _, engine::general_purpose};
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Mutex as AsyncMutex, mpsc};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info};
+
+use super::jsonrpc::{Incoming, RpcError, response};
+use super::store::{Conversation, ConversationStore};
+use super::tools::{self, DEFAULT_LIST_LIMIT, ToolOutput};
+use crate::backend::ChatBackend;
+use crate::cli::Session;
+use crate::utils::{ChatGptError, Result};
+
+/// Protocol versions the server speaks, newest first
+pub const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+/// Longest conversation title taken from a prompt
+const TITLE_CHARS: usize = 60;
+
+/// Image sent with a prompt
+struct Image {
+    data: String,
+    /// File it was read from, kept in the transcript
+    path: Option<PathBuf>,
+}
+
+/// MCP server answering tool calls with backend `B`.
+///
+/// Each conversation keeps its backend session open between calls; after a
+/// restart it is resumed from the position saved in the store.
+pub struct McpServer<B: ChatBackend> {
+    inner: Arc<Inner<B>>,
+}
+
+struct Inner<B: ChatBackend> {
+    config: B::Config,
+    proxy: Option<String>,
+    store: Mutex<ConversationStore>,
+    /// Open sessions by conversation; turns on one conversation run one at a time
+    sessions: Mutex<HashMap<String, Arc<AsyncMutex<Option<B>>>>>,
+}
+
+impl<B: ChatBackend> Clone for McpServer<B> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<B: ChatBackend> McpServer<B> {
+    pub fn new(config: B::Config, proxy: Option<String>, store: ConversationStore) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                config,
+                proxy,
+                store: Mutex::new(store),
+                sessions: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Answer newline-delimited JSON-RPC messages from `reader` on `writer`
+    /// until `reader` ends. Calls run side by side, so a long answer does
+    /// not hold up `ping` or a cancellation.
+    pub async fn serve<R, W>(&self, reader: R, mut writer: W) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (out, mut outgoing) = mpsc::unbounded_channel::<Value>();
+        let writer = tokio::spawn(async move {
+            while let Some(message) = outgoing.recv().await {
+                let mut line = message.to_string();
+                line.push('\n');
+                writer.write_all(line.as_bytes()).await?;
+                writer.flush().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        });
+
+        let calls = TaskTracker::new();
+        let running = Arc::new(Mutex::new(HashMap::<String, CancellationToken>::new()));
+        let closed = CancellationToken::new();
+
+        let mut lines = reader.lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let message: Incoming = match serde_json::from_str(&line) {
+                Ok(message) => message,
+                Err(err) => {
+                    let _ = out.send(response(
+                        Value::Null,
+                        Err(RpcError::parse_error(err.to_string())),
+                    ));
+                    continue;
+                }
+            };
+            let Some(method) = message.method else {
+                continue;
+            };
+            let Some(id) = message.id else {
+                notified(&method, &message.params, &running);
+                continue;
+            };
+
+            let key = id.to_string();
+            let cancel = closed.child_token();
+            running.lock().unwrap().insert(key.clone(), cancel.clone());
+            let (server, out, running) = (self.clone(), out.clone(), running.clone());
+            calls.spawn(async move {
+                let result = server.handle(&method, message.params, &cancel).await;
+                running.lock().unwrap().remove(&key);
+                // Cancelled requests get no response
+                if !cancel.is_cancelled() {
+                    let _ = out.send(response(id, result));
+                }
+            });
+        }
+
+        // The client is gone, so nobody is waiting for the calls still running
+        closed.cancel();
+        calls.close();
+        calls.wait().await;
+        drop(out);
+        writer
+            .await
+            .map_err(|err| ChatGptError::unknown(format!("Writer task failed: {}", err)))??;
+        Ok(())
+    }
+
+    async fn handle(
+        &self,
+        method: &str,
+        params: Value,
+        cancel: &CancellationToken,
+    ) -> std::result::Result<Value, RpcError> {
+        match method {
+            "initialize" => Ok(initialize(&params)),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": tools::definitions() })),
+            "tools/call" => {
+                let name = params["name"]
+                    .as_str()
+                    .ok_or_else(|| RpcError::invalid_params("tools/call needs the tool `name`"))?;
+                let arguments = match params.get("arguments") {
+                    Some(Value::Null) | None => json!({}),
+                    Some(arguments) => arguments.clone(),
+                };
+                Ok(self.call(name, arguments, cancel).await?.to_json())
+            }
+            _ => Err(RpcError::method_not_found(method)),
+        }
+    }
+
+    async fn call(
+        &self,
+        name: &str,
+        arguments: Value,
+        cancel: &CancellationToken,
+    ) -> std::result::Result<ToolOutput, RpcError> {
+        info!("Tool call: {}", name);
+        let result = match name {
+            "ask" => {
+                let args: tools::Ask = parse(name, arguments)?;
+                let title = args.title.unwrap_or_else(|| title_of(&args.prompt));
+                self.start(title, args.prompt, None, cancel).await
+            }
+            "continue_conversation" => {
+                let args: tools::Continue = parse(name, arguments)?;
+                self.follow_up(args.conversation_id, args.prompt, cancel)
+                    .await
+            }
+            "ask_with_image" => {
+                let args: tools::AskWithImage = parse(name, arguments)?;
+                let image = match read_image(args.image_path, args.image_base64) {
+                    Ok(image) => image,
+                    Err(message) => return Ok(ToolOutput::error(message)),
+                };
+                let title = args.title.unwrap_or_else(|| title_of(&args.prompt));
+                self.start(title, args.prompt, Some(image), cancel).await
+            }
+            "list_conversations" => {
+                let args: tools::List = parse(name, arguments)?;
+                Ok(self.list(args.limit.unwrap_or(DEFAULT_LIST_LIMIT)))
+            }
+            _ => return Err(RpcError::invalid_params(format!("Unknown tool: {}", name))),
+        };
+
+        Ok(result.unwrap_or_else(|err| match err {
+            ChatGptError::Cancelled => ToolOutput::error("Cancelled"),
+            err => {
+                error!(code = err.code(), "Tool {} failed: {}", name, err);
+                ToolOutput::error(format!("ChatGPT request failed: {}", err))
+            }
+        }))
+    }
+
+    /// Start a conversation with `prompt`
+    async fn start(
+        &self,
+        title: String,
+        prompt: String,
+        image: Option<Image>,
+        cancel: &CancellationToken,
+    ) -> Result<ToolOutput> {
+        let id = format!("conv_{}", &uuid::Uuid::new_v4().simple().to_string()[..12]);
+        let mut backend = B::connect(&self.inner.config, self.inner.proxy.as_deref()).await?;
+        if let Some(image) = &image {
+            backend.upload_attachment(&image.data).await?;
+        }
+        let answer = backend.start_conversation(&prompt, None, cancel).await?;
+
+        let mut conversation = Conversation {
+            title,
+            session: Session::new(),
+        };
+        conversation
+            .session
+            .push("user", &prompt, image.and_then(|image| image.path));
+        let output = self.record(&id, conversation, &answer, backend.position());
+        self.inner
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(id, Arc::new(AsyncMutex::new(Some(backend))));
+        Ok(output)
+    }
+
+    /// Send `prompt` in conversation `id`
+    async fn follow_up(
+        &self,
+        id: String,
+        prompt: String,
+        cancel: &CancellationToken,
+    ) -> Result<ToolOutput> {
+        if self.inner.store.lock().unwrap().get(&id).is_none() {
+            return Ok(ToolOutput::error(format!(
+                "No conversation {}; list_conversations shows the known ones",
+                id
+            )));
+        }
+        let session = self
+            .inner
+            .sessions
+            .lock()
+            .unwrap()
+            .entry(id.clone())
+            .or_default()
+            .clone();
+        let mut session = session.lock().await;
+
+        // Read the conversation once the session is ours, so it is current
+        let mut conversation = self
+            .inner
+            .store
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .expect("checked above");
+        let mut start = false;
+        if session.is_none() {
+            let mut backend = B::connect(&self.inner.config, self.inner.proxy.as_deref()).await?;
+            match conversation.session.position.clone() {
+                Some(position) => backend.resume(position),
+                // Nothing upstream to continue, so the conversation starts over
+                None => start = true,
+            }
+            *session = Some(backend);
+        }
+        let backend = session.as_mut().expect("session was just opened");
+
+        let answer = if start {
+            backend.start_conversation(&prompt, None, cancel).await?
+        } else {
+            backend.continue_conversation(&prompt, None, cancel).await?
+        };
+
+        conversation.session.push("user", &prompt, None);
+        Ok(self.record(&id, conversation, &answer, backend.position()))
+    }
+
+    /// Save a finished turn whose prompt is already on `conversation`
+    fn record(
+        &self,
+        id: &str,
+        mut conversation: Conversation,
+        answer: &str,
+        position: Option<crate::client::ConversationPosition>,
+    ) -> ToolOutput {
+        conversation.session.push("assistant", answer, None);
+        conversation.session.position = position;
+        if let Err(err) = self
+            .inner
+            .store
+            .lock()
+            .unwrap()
+            .put(id.to_string(), conversation)
+        {
+            error!("Failed to save conversations: {}", err);
+        }
+
+        ToolOutput {
+            text: vec![answer.to_string(), format!("conversation_id: {}", id)],
+            is_error: false,
+        }
+    }
+
+    fn list(&self, limit: usize) -> ToolOutput {
+        let store = self.inner.store.lock().unwrap();
+        if store.is_empty() {
+            return ToolOutput::text("No conversations yet");
+        }
+
+        let recent = store.recent();
+        let mut lines: Vec<_> = recent
+            .iter()
+            .take(limit)
+            .map(|(id, conversation)| {
+                let updated = chrono::DateTime::from_timestamp(conversation.updated_at() as i64, 0)
+                    .unwrap_or_default()
+                    .format("%Y-%m-%d %H:%M UTC");
+                format!(
+                    "{}  {}  ({} messages, updated {})",
+                    id,
+                    conversation.title,
+                    conversation.session.messages.len(),
+                    updated
+                )
+            })
+            .collect();
+        if recent.len() > limit {
+            lines.push(format!("... and {} more", recent.len() - limit));
+        }
+        ToolOutput::text(lines.join("\n"))
+    }
+}
+
+/// Handle a notification; only cancellations need anything done
+fn notified(method: &str, params: &Value, running: &Mutex<HashMap<String, CancellationToken>>) {
+    if method == "notifications/cancelled"
+        && let Some(request_id) = params.get("requestId")
+        && let Some(cancel) = running.lock().unwrap().get(&request_id.to_string())
+    {
+        info!("Request {} cancelled by the client", request_id);
+        cancel.cancel();
+    }
+}
+
+fn initialize(params: &Value) -> Value {
+    // Answer with the client's version when we speak it, else our newest
+    let requested = params["protocolVersion"].as_str();
+    let version = PROTOCOL_VERSIONS
+        .iter()
+        .find(|version| Some(**version) == requested)
+        .unwrap_or(&PROTOCOL_VERSIONS[0]);
+
+    json!({
+        "protocolVersion": version,
+        "capabilities": { "tools": {} },
+        "serverInfo": { "name": "chatgpt-rs", "version": env!("CARGO_PKG_VERSION") },
+        "instructions": "Ask ChatGPT for a second opinion. Use continue_conversation with the returned conversation_id to follow up."
+    })
+}
+
+fn parse<T: DeserializeOwned>(tool: &str, arguments: Value) -> std::result::Result<T, RpcError> {
+    serde_json::from_value(arguments)
+        .map_err(|err| RpcError::invalid_params(format!("Invalid arguments for {}: {}", tool, err)))
+}
+
+/// The first line of `prompt`, shortened to a title
+fn title_of(prompt: &str) -> String {
+    let line = prompt.trim().lines().next().unwrap_or_default();
+    if line.chars().count() <= TITLE_CHARS {
+        return line.to_string();
+    }
+    let mut title: String = line.chars().take(TITLE_CHARS - 1).collect();
+    title.push('…');
+    title
+}
+
+/// The image of an `ask_with_image` call, or the message to answer with
+fn read_image(path: Option<String>, data: Option<String>) -> std::result::Result<Image, String> {
+    match (path, data) {
+        (Some(path), None) => {
+            let path = PathBuf::from(path);
+            let bytes = std::fs::read(&path)
+                .map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+            Ok(Image {
+                data: general_purpose::STANDARD.encode(bytes),
+                path: Some(path),
+            })
+        }
+        (None, Some(data)) => Ok(Image { data, path: None }),
+        _ => Err("Give the image as exactly one of image_path and image_base64".to_string()),
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cli::Session;
+use crate::utils::{ChatGptError, Result};
+
+/// A conversation started through the MCP tools
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conversation {
+    pub title: String,
+    /// Transcript and upstream position, as saved by the terminal client
+    #[serde(flatten)]
+    pub session: Session,
+}
+
+impl Conversation {
+    /// When the last message was added
+    pub fn updated_at(&self) -> u64 {
+        self.session
+            .messages
+            .last()
+            .map_or(self.session.created_at, |message| message.created_at)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoreFile<C> {
+    conversations: C,
+}
+
+/// Conversations by ID, rewritten to a JSON file after every change when the
+/// store has one
+#[derive(Debug, Default)]
+pub struct ConversationStore {
+    path: Option<PathBuf>,
+    conversations: BTreeMap<String, Conversation>,
+}
+
+impl ConversationStore {
+    /// A store that forgets everything when the server exits
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// A store saved to `path`, starting from the conversations already in
+    /// it; empty if the file does not exist yet
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let conversations = match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                let file: StoreFile<BTreeMap<String, Conversation>> = serde_json::from_str(&text)
+                    .map_err(|err| {
+                    ChatGptError::configuration(format!("{}: {}", path.display(), err))
+                })?;
+                file.conversations
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            conversations,
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Conversation> {
+        self.conversations.get(id)
+    }
+
+    /// Conversations, most recently updated first
+    pub fn recent(&self) -> Vec<(&str, &Conversation)> {
+        let mut conversations: Vec<_> = self
+            .conversations
+            .iter()
+            .map(|(id, conversation)| (id.as_str(), conversation))
+            .collect();
+        conversations.sort_by_key(|(_, conversation)| std::cmp::Reverse(conversation.updated_at()));
+        conversations
+    }
+
+    pub fn len(&self) -> usize {
+        self.conversations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conversations.is_empty()
+    }
+
+    /// Add or replace a conversation and save the store
+    pub fn put(&mut self, id: String, conversation: Conversation) -> Result<()> {
+        self.conversations.insert(id, conversation);
+        self.save()
+    }
+
+    /// Write the file next to the old one first, so a crash mid-write leaves
+    /// the previous contents intact
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        let file = StoreFile {
+            conversations: &self.conversations,
+        };
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&file)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! The tools the MCP server offers, with their argument types
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+/// Arguments of `ask`
+#[derive(Debug, Deserialize)]
+pub struct Ask {
+    pub prompt: String,
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// Arguments of `continue_conversation`
+#[derive(Debug, Deserialize)]
+pub struct Continue {
+    pub conversation_id: String,
+    pub prompt: String,
+}
+
+/// Arguments of `ask_with_image`; exactly one of the image fields is set
+#[derive(Debug, Deserialize)]
+pub struct AskWithImage {
+    pub prompt: String,
+    #[serde(default)]
+    pub image_path: Option<String>,
+    #[serde(default)]
+    pub image_base64: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// Arguments of `list_conversations`
+#[derive(Debug, Default, Deserialize)]
+pub struct List {
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// Conversations `list_conversations` shows unless asked for more
+pub const DEFAULT_LIST_LIMIT: usize = 20;
+
+/// Result of a tool call: text blocks, flagged when the call failed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolOutput {
+    pub text: Vec<String>,
+    pub is_error: bool,
+}
+
+impl ToolOutput {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self {
+            text: vec![text.into()],
+            is_error: false,
+        }
+    }
+
+    /// A failure the calling model should see, such as an unknown
+    /// conversation or an upstream error
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            text: vec![message.into()],
+            is_error: true,
+        }
+    }
+
+    /// The `tools/call` result
+    pub fn to_json(&self) -> Value {
+        let content: Vec<_> = self
+            .text
+            .iter()
+            .map(|text| json!({ "type": "text", "text": text }))
+            .collect();
+        json!({ "content": content, "isError": self.is_error })
+    }
+}
+
+/// The `tools/list` entries
+pub fn definitions() -> Value {
+    json!([
+        {
+            "name": "ask",
+            "description": "Ask ChatGPT a question in a new conversation. Returns the answer and a conversation_id for follow-ups.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "prompt": { "type": "string", "description": "The question, with any context ChatGPT needs" },
+                    "title": { "type": "string", "description": "Name for the conversation in list_conversations (default: start of the prompt)" }
+                },
+                "required": ["prompt"]
+            }
+        },
+        {
+            "name": "continue_conversation",
+            "description": "Send a follow-up message in a conversation started by ask or ask_with_image, which ChatGPT remembers.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "conversation_id": { "type": "string" },
+                    "prompt": { "type": "string" }
+                },
+                "required": ["conversation_id", "prompt"]
+            }
+        },
+        {
+            "name": "ask_with_image",
+            "description": "Ask ChatGPT about an image in a new conversation. Give the image as a file path or as base64 data.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "prompt": { "type": "string" },
+                    "image_path": { "type": "string", "description": "Image file readable by the server" },
+                    "image_base64": { "type": "string", "description": "Base64 image data, instead of image_path" },
+                    "title": { "type": "string" }
+                },
+                "required": ["prompt"]
+            }
+        },
+        {
+            "name": "list_conversations",
+            "description": "List earlier conversations, most recent first, with their conversation_id.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "limit": { "type": "integer", "minimum": 1, "description": "At most this many (default: 20)" }
+                }
+            }
+        }
+    ])
+}
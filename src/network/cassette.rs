@@ -0,0 +1,457 @@
+use crate::utils::{ChatGptError, Result};
+use async_stream::stream;
+use base64::{Engine as _, engine::general_purpose};
+use bytes::Bytes;
+use futures::StreamExt;
+use reqwest::{Client, Request, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+pub use crate::utils::redact::REDACTED;
+use crate::utils::redact::{SECRET_FIELDS, SECRET_HEADERS};
+
+/// Where the client's upstream traffic goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Talk to the upstream and write every exchange to the cassette file
+    /// once the client is dropped
+    Record(PathBuf),
+    /// Answer every request from the cassette file without touching the network
+    Replay(PathBuf),
+}
+
+/// What replay checks besides the method and path of each request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayMatching {
+    /// Compare request bodies, redacted as when recording. Fields that
+    /// change on every run, such as message ids and timings, are ignored.
+    pub body: bool,
+    /// Headers whose values must match the recording
+    pub headers: Vec<String>,
+}
+
+/// JSON fields that differ between runs of the same session
+const VOLATILE_FIELDS: &[&str] = &["id", "create_time", "time_since_loaded", "file_name"];
+
+/// A recorded session: the exchanges with the upstream, in request order
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// One request and the response it got
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// Body as it arrived, chunk by chunk, so replay keeps the original framing.
+    /// A character split between two chunks is kept whole in the first one.
+    #[serde(default)]
+    pub chunks: Vec<String>,
+    /// Base64 of a body that is not UTF-8 text, in place of `chunks`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_base64: Option<String>,
+}
+
+impl RecordedRequest {
+    fn capture(request: &Request) -> Self {
+        let body = request.body().map(|body| match body.as_bytes() {
+            Some(bytes) => match std::str::from_utf8(bytes) {
+                Ok(text) => redact_body(text),
+                Err(_) => format!("<binary, {} bytes>", bytes.len()),
+            },
+            None => "<stream>".to_string(),
+        });
+
+        Self {
+            method: request.method().to_string(),
+            url: redact_url(request.url().as_str()),
+            headers: redact_headers(request.headers()),
+            body,
+        }
+    }
+
+    fn path(&self) -> String {
+        url::Url::parse(&self.url)
+            .map(|url| url.path().to_string())
+            .unwrap_or_else(|_| self.url.clone())
+    }
+}
+
+/// Sends the client's requests live, while recording, or from a cassette
+pub(crate) enum Transport {
+    Live,
+    Record(Arc<Recorder>),
+    Replay {
+        interactions: Mutex<VecDeque<Interaction>>,
+        matching: ReplayMatching,
+    },
+}
+
+impl Transport {
+    pub(crate) fn new(mode: Option<&CassetteMode>, matching: &ReplayMatching) -> Result<Self> {
+        Ok(match mode {
+            None => Self::Live,
+            Some(CassetteMode::Record(path)) => Self::Record(Arc::new(Recorder {
+                path: path.clone(),
+                interactions: Mutex::new(Vec::new()),
+            })),
+            Some(CassetteMode::Replay(path)) => {
+                let cassette = Cassette::load(path).map_err(|err| {
+                    ChatGptError::configuration(format!(
+                        "Failed to load cassette {}: {}",
+                        path.display(),
+                        err
+                    ))
+                })?;
+                Self::Replay {
+                    interactions: Mutex::new(cassette.interactions.into()),
+                    matching: matching.clone(),
+                }
+            }
+        })
+    }
+
+    pub(crate) async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        match self {
+            Self::Live => Ok(request.send().await?),
+            Self::Record(recorder) => {
+                let (client, request) = request.build_split();
+                recorder.clone().send(client, request?).await
+            }
+            Self::Replay {
+                interactions,
+                matching,
+            } => {
+                let request = request.build()?;
+                let interaction = interactions.lock().unwrap().pop_front();
+                replay(&request, interaction, matching)
+            }
+        }
+    }
+}
+
+/// Collects the exchanges of a session and writes them out when the last
+/// handle, the client or a response still being read, goes away
+pub(crate) struct Recorder {
+    path: PathBuf,
+    interactions: Mutex<Vec<Interaction>>,
+}
+
+impl Recorder {
+    /// Send the request and hand back its response, teeing the body into the
+    /// cassette. The exchange is written once the body is read to the end or
+    /// dropped, so sessions that fail midway are kept too.
+    async fn send(self: Arc<Self>, client: Client, request: Request) -> Result<Response> {
+        let recorded = RecordedRequest::capture(&request);
+        let response = client.execute(request).await?;
+
+        let mut builder = http::Response::builder().status(response.status());
+        for (name, value) in response.headers() {
+            builder = builder.header(name, value);
+        }
+
+        let pending = PendingInteraction {
+            recorder: self,
+            interaction: Some(Interaction {
+                request: recorded,
+                response: RecordedResponse {
+                    status: response.status().as_u16(),
+                    headers: redact_headers(response.headers()),
+                    chunks: Vec::new(),
+                    body_base64: None,
+                },
+            }),
+            body: Vec::new(),
+        };
+
+        let mut upstream = response.bytes_stream();
+        let body = stream! {
+            let mut pending = pending;
+            while let Some(chunk) = upstream.next().await {
+                if let Ok(bytes) = &chunk {
+                    pending.push(bytes);
+                }
+                yield chunk;
+            }
+        };
+
+        let response = builder
+            .body(reqwest::Body::wrap_stream(body))
+            .map_err(|err| ChatGptError::unknown(format!("Failed to rebuild response: {}", err)))?;
+        Ok(Response::from(response))
+    }
+
+    fn store(&self, interaction: Interaction) {
+        self.interactions.lock().unwrap().push(interaction);
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let cassette = Cassette {
+            interactions: std::mem::take(self.interactions.get_mut().unwrap()),
+        };
+        if let Err(err) = cassette.save(&self.path) {
+            warn!("Failed to write cassette {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// An exchange whose response body is still being read
+struct PendingInteraction {
+    recorder: Arc<Recorder>,
+    interaction: Option<Interaction>,
+    /// Body chunks as they arrived
+    body: Vec<Bytes>,
+}
+
+impl PendingInteraction {
+    fn push(&mut self, bytes: &Bytes) {
+        self.body.push(bytes.clone());
+    }
+}
+
+impl Drop for PendingInteraction {
+    fn drop(&mut self) {
+        if let Some(mut interaction) = self.interaction.take() {
+            match text_chunks(&self.body) {
+                Some(chunks) => {
+                    // Whole JSON documents get their token fields redacted
+                    let body = chunks.concat();
+                    let redacted = redact_body(&body);
+                    interaction.response.chunks = if redacted != body {
+                        vec![redacted]
+                    } else {
+                        chunks
+                    };
+                }
+                None => {
+                    let body = self.body.concat();
+                    interaction.response.body_base64 = Some(general_purpose::STANDARD.encode(body));
+                }
+            }
+            self.recorder.store(interaction);
+        }
+    }
+}
+
+/// `body` as text chunks, each ending on a character boundary, or `None` if
+/// it is not UTF-8
+fn text_chunks(body: &[Bytes]) -> Option<Vec<String>> {
+    let mut chunks = Vec::with_capacity(body.len());
+    let mut pending = Vec::new();
+    for bytes in body {
+        pending.extend_from_slice(bytes);
+        let complete = match std::str::from_utf8(&pending) {
+            Ok(_) => pending.len(),
+            // The rest of the last character is in the next chunk
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
+            Err(_) => return None,
+        };
+        let rest = pending.split_off(complete);
+        let chunk = std::mem::replace(&mut pending, rest);
+        if !chunk.is_empty() {
+            chunks.push(String::from_utf8(chunk).ok()?);
+        }
+    }
+    pending.is_empty().then_some(chunks)
+}
+
+/// Answer a request with the next recorded exchange
+fn replay(
+    request: &Request,
+    interaction: Option<Interaction>,
+    matching: &ReplayMatching,
+) -> Result<Response> {
+    let method = request.method().as_str();
+    let path = request.url().path();
+
+    let interaction = interaction.ok_or_else(|| {
+        ChatGptError::invalid_response(format!(
+            "Cassette has no interaction left for {} {}",
+            method, path
+        ))
+    })?;
+
+    if interaction.request.method != method || interaction.request.path() != path {
+        return Err(ChatGptError::invalid_response(format!(
+            "Cassette expected {} {}, got {} {}",
+            interaction.request.method,
+            interaction.request.path(),
+            method,
+            path
+        )));
+    }
+
+    let live = RecordedRequest::capture(request);
+    if matching.body
+        && live.body.as_deref().map(stable_body)
+            != interaction.request.body.as_deref().map(stable_body)
+    {
+        return Err(ChatGptError::invalid_response(format!(
+            "Cassette body for {} {} does not match the request",
+            method, path
+        )));
+    }
+    for name in &matching.headers {
+        let name = name.to_ascii_lowercase();
+        let expected = header_value(&interaction.request.headers, &name);
+        if header_value(&live.headers, &name) != expected {
+            return Err(ChatGptError::invalid_response(format!(
+                "Cassette header {} for {} {} does not match the request",
+                name, method, path
+            )));
+        }
+    }
+
+    let mut builder = http::Response::builder().status(interaction.response.status);
+    for (name, value) in &interaction.response.headers {
+        builder = builder.header(name, value);
+    }
+
+    let chunks: Vec<Bytes> = match &interaction.response.body_base64 {
+        Some(body) => vec![Bytes::from(
+            general_purpose::STANDARD.decode(body).map_err(|err| {
+                ChatGptError::invalid_response(format!("Bad cassette body: {}", err))
+            })?,
+        )],
+        None => interaction
+            .response
+            .chunks
+            .into_iter()
+            .map(Bytes::from)
+            .collect(),
+    };
+    let chunks = chunks.into_iter().map(Ok::<_, Infallible>);
+    let response = builder
+        .body(reqwest::Body::wrap_stream(futures::stream::iter(chunks)))
+        .map_err(|err| ChatGptError::invalid_response(format!("Bad cassette response: {}", err)))?;
+
+    Ok(Response::from(response))
+}
+
+fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// A recorded body with the fields that change between runs blanked out
+fn stable_body(body: &str) -> String {
+    let Ok(mut value) = serde_json::from_str::<Value>(body) else {
+        return body.to_string();
+    };
+    blank_volatile(&mut value);
+    value.to_string()
+}
+
+fn blank_volatile(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if VOLATILE_FIELDS.contains(&key.as_str()) {
+                    *value = Value::Null;
+                } else {
+                    blank_volatile(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(blank_volatile),
+        _ => {}
+    }
+}
+
+fn redact_headers(headers: &reqwest::header::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if SECRET_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// Drop the query string, which for signed upload URLs is the credential
+fn redact_url(url: &str) -> String {
+    match url.split_once('?') {
+        Some((base, _)) => format!("{}?{}", base, REDACTED),
+        None => url.to_string(),
+    }
+}
+
+/// Redact token fields if `body` is a JSON document; other bodies are kept
+fn redact_body(body: &str) -> String {
+    let Ok(mut value) = serde_json::from_str::<Value>(body) else {
+        return body.to_string();
+    };
+    if redact_value(&mut value) {
+        value.to_string()
+    } else {
+        body.to_string()
+    }
+}
+
+/// Redact secrets in place, returning whether anything changed
+fn redact_value(value: &mut Value) -> bool {
+    match value {
+        Value::Object(map) => {
+            let mut changed = false;
+            for (key, value) in map.iter_mut() {
+                if SECRET_FIELDS.contains(&key.as_str()) && value.is_string() {
+                    *value = Value::String(REDACTED.to_string());
+                    changed = true;
+                } else if let Some(url) = value
+                    .as_str()
+                    .filter(|url| key.ends_with("_url") && url.contains('?'))
+                {
+                    *value = Value::String(redact_url(url));
+                    changed = true;
+                } else {
+                    changed |= redact_value(value);
+                }
+            }
+            changed
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .fold(false, |changed, item| redact_value(item) | changed),
+        _ => false,
+    }
+}
@@ -1,3 +1,4 @@
+use crate::network::ensure_success;
 use crate::utils::{Result, Utils};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
@@ -18,6 +19,7 @@ impl IpInfo {
     pub async fn fetch(client: &Client) -> Result<Self> {
         // First request to iplocation.com
         let response1 = client.get("https://iplocation.com/").send().await?;
+        let response1 = ensure_success(response1, "iplocation.com").await?;
 
         let html1 = response1.text().await?;
 
@@ -33,6 +35,7 @@ impl IpInfo {
             .get("https://ipaddresslocation.net/ip-to-timezone")
             .send()
             .await?;
+        let response2 = ensure_success(response2, "ipaddresslocation.net").await?;
 
         let html2 = response2.text().await?;
         let timezone = Utils::between(&html2, "Time Zone:</strong> ", " ").unwrap_or_default();
@@ -1,5 +1,8 @@
+pub mod cassette;
 pub mod headers;
 pub mod ip_info;
+pub mod upstream;
 
 pub use headers::Headers;
 pub use ip_info::IpInfo;
+pub use upstream::ensure_success;
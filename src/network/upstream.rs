@@ -0,0 +1,44 @@
+use crate::utils::{ChatGptError, Result};
+use reqwest::Response;
+use reqwest::header::RETRY_AFTER;
+
+/// Maximum number of characters of an error body kept in `ChatGptError::Upstream`
+const BODY_EXCERPT_LEN: usize = 300;
+
+/// Pass successful responses through and turn everything else into an upstream error
+pub async fn ensure_success(response: Response, endpoint: &str) -> Result<Response> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(upstream_error(response, endpoint).await)
+    }
+}
+
+/// Build `ChatGptError::Upstream` from a failed response, consuming its body
+pub async fn upstream_error(response: Response, endpoint: &str) -> ChatGptError {
+    let status = response.status().as_u16();
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    let body = response.text().await.unwrap_or_default();
+
+    ChatGptError::Upstream {
+        status,
+        endpoint: endpoint.to_string(),
+        body_excerpt: body_excerpt(&body),
+        retry_after,
+    }
+}
+
+/// Collapse whitespace and cut the body down to something that fits in a log line
+fn body_excerpt(body: &str) -> String {
+    let collapsed = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.chars().count() > BODY_EXCERPT_LEN {
+        let cut: String = collapsed.chars().take(BODY_EXCERPT_LEN).collect();
+        format!("{}…", cut)
+    } else {
+        collapsed
+    }
+}
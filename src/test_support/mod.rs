@@ -0,0 +1,57 @@
+//! Helpers for running the client and the API server offline in tests.
+//!
+//! Enabled with the `test-support` feature. [`MockUpstream`] stands in for
+//! the ChatGPT web backend; [`TestServer`] serves the API router on a local
+//! port so requests go through the real HTTP stack.
+
+pub mod upstream;
+
+pub use upstream::{MockUpstream, RecordedRequest, conversation_stream, fixtures, paths};
+
+use crate::api::{AppState, server};
+use crate::backend::ChatBackend;
+use crate::utils::{ChatGptError, Result};
+use std::net::SocketAddr;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// The API server on a free local port until dropped or shut down
+pub struct TestServer {
+    addr: SocketAddr,
+    stop: CancellationToken,
+    task: JoinHandle<Result<()>>,
+}
+
+impl TestServer {
+    pub async fn start<B: ChatBackend>(state: AppState<B>) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind test server");
+        let addr = listener.local_addr().expect("test server address");
+        let stop = CancellationToken::new();
+
+        let signal = stop.clone().cancelled_owned();
+        let task = tokio::spawn(server::serve_until(state, listener, signal));
+
+        Self { addr, stop, task }
+    }
+
+    /// Shut down as on SIGTERM and wait until the server has stopped
+    pub async fn shutdown(mut self) -> Result<()> {
+        self.stop.cancel();
+        (&mut self.task)
+            .await
+            .map_err(|err| ChatGptError::unknown(format!("Server task failed: {}", err)))?
+    }
+
+    /// Absolute URL of an API path
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,385 @@
+use crate::client::{ChatGptClient, ChatGptClientBuilder, ClientConfig, RetryPolicy};
+use crate::utils::Result;
+use async_stream::stream;
+use axum::{
+    Json, Router,
+    body::{Body, Bytes, to_bytes},
+    extract::{Path, Request, State},
+    http::{HeaderMap, Method, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+};
+use serde_json::{Value, json};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Recorded upstream responses served by default
+pub mod fixtures {
+    /// Home page carrying the build id in `data-build`
+    pub const HOME_HTML: &str = include_str!("../../tests/fixtures/upstream/home.html");
+    /// Sentinel requirements with a trivially solvable proof of work
+    pub const CHAT_REQUIREMENTS: &str =
+        include_str!("../../tests/fixtures/upstream/chat-requirements.json");
+    /// Conversation prepare response with a conduit token
+    pub const PREPARE: &str = include_str!("../../tests/fixtures/upstream/prepare.json");
+    /// Conversation event stream in the `v1` delta encoding
+    pub const CONVERSATION: &str = include_str!("../../tests/fixtures/upstream/conversation.sse");
+    /// Answer text contained in [`CONVERSATION`]
+    pub const CONVERSATION_ANSWER: &str = "Hello! How can I help you today?";
+    /// Conversation id contained in [`CONVERSATION`]
+    pub const CONVERSATION_ID: &str = "68f1a2b3-c4d5-8006-9e7f-a1b2c3d4e5f6";
+    /// Assistant message id contained in [`CONVERSATION`]
+    pub const MESSAGE_ID: &str = "5f0e2c1a-9b7d-4e3f-8a6c-1d2b3c4d5e6f";
+}
+
+/// Upstream paths the client calls
+pub mod paths {
+    pub const HOME: &str = "/";
+    pub const CHAT_REQUIREMENTS: &str = "/backend-anon/sentinel/chat-requirements";
+    pub const PREPARE: &str = "/backend-anon/f/conversation/prepare";
+    pub const CONVERSATION: &str = "/backend-anon/f/conversation";
+    pub const FILES: &str = "/backend-anon/files";
+    pub const PROCESS_UPLOAD: &str = "/backend-anon/files/process_upload_stream";
+}
+
+/// A request the mock received
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl RecordedRequest {
+    /// The body parsed as JSON, or `Value::Null` if it isn't JSON
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap_or(Value::Null)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+struct Failure {
+    status: StatusCode,
+    body: String,
+    retry_after: Option<u64>,
+}
+
+#[derive(Default)]
+struct MockState {
+    base_url: String,
+    requests: Mutex<Vec<RecordedRequest>>,
+    failures: Mutex<HashMap<String, VecDeque<Failure>>>,
+    conversations: Mutex<VecDeque<String>>,
+    chunk_delay: Mutex<Duration>,
+    chunk_bytes: Mutex<Option<usize>>,
+    turns: Mutex<usize>,
+}
+
+/// Local stand-in for the ChatGPT web backend.
+///
+/// Serves the recorded fixtures on `127.0.0.1` so the client and the API
+/// server can be tested without network access. Conversation replies, failures
+/// and streaming speed can be scripted per test; every request is recorded.
+/// The server stops when the value is dropped.
+pub struct MockUpstream {
+    state: Arc<MockState>,
+    task: JoinHandle<()>,
+}
+
+impl MockUpstream {
+    /// Bind to a free local port and start serving
+    pub async fn start() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind mock upstream");
+        let addr = listener.local_addr().expect("mock upstream address");
+
+        let state = Arc::new(MockState {
+            base_url: format!("http://{}", addr),
+            ..MockState::default()
+        });
+
+        let app = Router::new()
+            .route(paths::HOME, get(home))
+            .route(paths::CHAT_REQUIREMENTS, post(chat_requirements))
+            .route(paths::PREPARE, post(prepare))
+            .route(paths::CONVERSATION, post(conversation))
+            .route(paths::FILES, post(files))
+            .route("/upload/{file_id}", put(upload))
+            .route(paths::PROCESS_UPLOAD, post(process_upload))
+            .layer(middleware::from_fn_with_state(state.clone(), record))
+            .with_state(state.clone());
+
+        let task = tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+
+        Self { state, task }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.state.base_url
+    }
+
+    /// Client settings pointing at this mock, with IP lookup off and quick retries
+    pub fn client_config(&self) -> ClientConfig {
+        ClientConfig {
+            base_url: self.base_url().to_string(),
+            ip_lookup: false,
+            retry: RetryPolicy {
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(50),
+                ..RetryPolicy::default()
+            },
+            ..ClientConfig::default()
+        }
+    }
+
+    /// Builder preloaded with [`client_config`](Self::client_config)
+    pub fn client_builder(&self) -> ChatGptClientBuilder {
+        ChatGptClientBuilder::from_config(self.client_config())
+    }
+
+    /// Connect a client to this mock
+    pub async fn client(&self) -> Result<ChatGptClient> {
+        self.client_builder().build().await
+    }
+
+    /// Queue an answer for the next conversation turn, streamed word by word.
+    ///
+    /// Unqueued turns get the recorded [`fixtures::CONVERSATION`].
+    pub fn reply_with(&self, text: &str) {
+        let turn = {
+            let mut turns = self.state.turns.lock().unwrap();
+            *turns += 1;
+            *turns
+        };
+        self.push_conversation(conversation_stream(
+            &format!("mock-conversation-{}", turn),
+            &format!("mock-message-{}", turn),
+            text,
+        ));
+    }
+
+    /// Queue a raw event stream body for the next conversation turn
+    pub fn push_conversation(&self, body: impl Into<String>) {
+        self.state
+            .conversations
+            .lock()
+            .unwrap()
+            .push_back(body.into());
+    }
+
+    /// Answer the next `times` requests to `path` with `status`
+    pub fn fail_next(&self, path: &str, status: u16, times: usize) {
+        self.fail_next_with(path, status, "mock upstream failure", None, times);
+    }
+
+    /// Answer the next `times` requests to `path` with `status`, `body` and an
+    /// optional `Retry-After`
+    pub fn fail_next_with(
+        &self,
+        path: &str,
+        status: u16,
+        body: &str,
+        retry_after: Option<u64>,
+        times: usize,
+    ) {
+        let status = StatusCode::from_u16(status).expect("valid status code");
+        let mut failures = self.state.failures.lock().unwrap();
+        let queue = failures.entry(path.to_string()).or_default();
+        for _ in 0..times {
+            queue.push_back(Failure {
+                status,
+                body: body.to_string(),
+                retry_after,
+            });
+        }
+    }
+
+    /// Pause between event stream lines, to leave room for cancellation
+    pub fn set_chunk_delay(&self, delay: Duration) {
+        *self.state.chunk_delay.lock().unwrap() = delay;
+    }
+
+    /// Send event streams in pieces of `size` bytes instead of line by line,
+    /// splitting lines and multi-byte characters
+    pub fn set_chunk_bytes(&self, size: usize) {
+        *self.state.chunk_bytes.lock().unwrap() = Some(size);
+    }
+
+    /// Every request received so far, oldest first
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// Requests received for `path`
+    pub fn requests_to(&self, path: &str) -> Vec<RecordedRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.path == path)
+            .collect()
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Build a `v1` event stream that answers with `text`, one word per delta
+pub fn conversation_stream(conversation_id: &str, message_id: &str, text: &str) -> String {
+    let mut events = vec![
+        "event: delta_encoding\ndata: \"v1\"\n".to_string(),
+        format!(
+            "event: delta\ndata: {}\n",
+            json!({
+                "p": "",
+                "o": "add",
+                "v": {
+                    "message": {
+                        "id": message_id,
+                        "author": {"role": "assistant"},
+                        "content": {"content_type": "text", "parts": [""]},
+                        "status": "in_progress"
+                    },
+                    "conversation_id": conversation_id
+                },
+                "c": 0
+            })
+        ),
+    ];
+
+    for word in text.split_inclusive(' ') {
+        events.push(format!(
+            "event: delta\ndata: {}\n",
+            json!({"p": "/message/content/parts/0", "o": "append", "v": word})
+        ));
+    }
+
+    events.push(format!(
+        "data: {{\"type\": \"message_stream_complete\", \"conversation_id\": \"{}\"}}\n",
+        conversation_id
+    ));
+    events.push(format!(
+        "data: {{\"type\": \"server_ste_metadata\", \"metadata\": {{\"message_id\": \"{}\"}}}}\n",
+        message_id
+    ));
+    events.push("data: [DONE]\n".to_string());
+
+    events.join("\n")
+}
+
+/// Record every request and serve any failure queued for its path
+async fn record(State(state): State<Arc<MockState>>, request: Request, next: Next) -> Response {
+    let (parts, body) = request.into_parts();
+    let body = to_bytes(body, usize::MAX).await.unwrap_or_default();
+
+    state.requests.lock().unwrap().push(RecordedRequest {
+        method: parts.method.clone(),
+        path: parts.uri.path().to_string(),
+        headers: parts.headers.clone(),
+        body: body.clone(),
+    });
+
+    let failure = state
+        .failures
+        .lock()
+        .unwrap()
+        .get_mut(parts.uri.path())
+        .and_then(VecDeque::pop_front);
+
+    if let Some(failure) = failure {
+        let mut response = (failure.status, failure.body).into_response();
+        if let Some(seconds) = failure.retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, seconds.into());
+        }
+        return response;
+    }
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+async fn home() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (
+                header::SET_COOKIE,
+                "oai-did=0d6c3f4e-8a1b-4c2d-9e0f-mockdevice01; Path=/",
+            ),
+        ],
+        fixtures::HOME_HTML,
+    )
+}
+
+async fn chat_requirements() -> impl IntoResponse {
+    json_fixture(fixtures::CHAT_REQUIREMENTS)
+}
+
+async fn prepare() -> impl IntoResponse {
+    json_fixture(fixtures::PREPARE)
+}
+
+async fn conversation(State(state): State<Arc<MockState>>) -> impl IntoResponse {
+    let body = state
+        .conversations
+        .lock()
+        .unwrap()
+        .pop_front()
+        .unwrap_or_else(|| fixtures::CONVERSATION.to_string());
+    let delay = *state.chunk_delay.lock().unwrap();
+
+    let pieces: Vec<Vec<u8>> = match *state.chunk_bytes.lock().unwrap() {
+        Some(size) => body.as_bytes().chunks(size).map(<[u8]>::to_vec).collect(),
+        None => body
+            .split_inclusive('\n')
+            .map(|line| line.as_bytes().to_vec())
+            .collect(),
+    };
+    let body = Body::from_stream(stream! {
+        for piece in pieces {
+            if !delay.is_zero() && piece.starts_with(b"data:") {
+                tokio::time::sleep(delay).await;
+            }
+            yield Ok::<_, Infallible>(piece);
+        }
+    });
+
+    ([(header::CONTENT_TYPE, "text/event-stream")], body)
+}
+
+async fn files(State(state): State<Arc<MockState>>) -> impl IntoResponse {
+    let file_id = format!("file-{}", uuid::Uuid::new_v4().simple());
+    Json(json!({
+        "status": "success",
+        "upload_url": format!("{}/upload/{}", state.base_url, file_id),
+        "file_id": file_id,
+    }))
+}
+
+async fn upload(Path(_file_id): Path<String>) -> impl IntoResponse {
+    StatusCode::CREATED
+}
+
+async fn process_upload() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        "{\"status\": \"indexing\"}\n{\"status\": \"success\", \"event\": \"file.processing.completed\", \"message\": \"Succeeded processing multimodal file\"}\n",
+    )
+}
+
+fn json_fixture(body: &'static str) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], body)
+}
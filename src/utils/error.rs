@@ -36,9 +36,27 @@ pub enum ChatGptError {
     #[error("Invalid response format: {0}")]
     InvalidResponse(String),
 
+    #[error("Upstream {endpoint} returned HTTP {status}: {body_excerpt}")]
+    Upstream {
+        status: u16,
+        endpoint: String,
+        body_excerpt: String,
+        /// Seconds to wait before retrying, from the `Retry-After` header
+        retry_after: Option<u64>,
+    },
+
     #[error("Configuration error: {0}")]
     Configuration(String),
 
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Request cancelled")]
+    Cancelled,
+
+    #[error("Timed out: {0}")]
+    Timeout(String),
+
     #[error("Unknown error: {0}")]
     Unknown(String),
 }
@@ -47,6 +65,40 @@ pub enum ChatGptError {
 pub type Result<T> = std::result::Result<T, ChatGptError>;
 
 impl ChatGptError {
+    /// Stable, machine-readable code for this error.
+    ///
+    /// These are part of the public API (they end up in HTTP error bodies), so
+    /// existing codes must not change.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Network(err) if err.is_timeout() => "upstream_timeout",
+            Self::Timeout(_) => "upstream_timeout",
+            Self::Network(_) => "upstream_unavailable",
+            Self::Json(_) | Self::InvalidResponse(_) => "upstream_invalid_response",
+            Self::Upstream { status: 429, .. } => "rate_limited",
+            Self::Upstream { status, .. } if *status >= 500 => "upstream_unavailable",
+            Self::Upstream { .. } => "upstream_rejected",
+            Self::Base64Decode(_) | Self::Image(_) => "invalid_image",
+            Self::Io(_) | Self::Unknown(_) => "internal_error",
+            Self::InvalidProxy(_) => "invalid_proxy",
+            Self::ChallengeSolve(_) | Self::VmExecution(_) => "upstream_challenge_failed",
+            Self::IpFlagged | Self::Authentication(_) => "upstream_rejected",
+            Self::Configuration(_) => "configuration_error",
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    /// Whether the same request may succeed if simply tried again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Network(err) => !err.is_builder(),
+            Self::Upstream { status, .. } => matches!(status, 408 | 429) || *status >= 500,
+            Self::ChallengeSolve(_) | Self::Timeout(_) => true,
+            _ => false,
+        }
+    }
+
     pub fn invalid_proxy(msg: impl Into<String>) -> Self {
         Self::InvalidProxy(msg.into())
     }
@@ -71,6 +123,14 @@ impl ChatGptError {
         Self::Configuration(msg.into())
     }
 
+    pub fn invalid_request(msg: impl Into<String>) -> Self {
+        Self::InvalidRequest(msg.into())
+    }
+
+    pub fn timeout(msg: impl Into<String>) -> Self {
+        Self::Timeout(msg.into())
+    }
+
     pub fn unknown(msg: impl Into<String>) -> Self {
         Self::Unknown(msg.into())
     }
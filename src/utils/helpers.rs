@@ -98,45 +98,43 @@ impl Utils {
         let mut result = Vec::new();
 
         for line in stream_data.lines() {
-            if let Some(stripped) = line.strip_prefix("data:") {
-                let data_str = stripped.trim();
+            match Self::parse_event_line(line) {
+                Some(parts) => result.extend(parts),
+                None => break,
+            }
+        }
 
-                if data_str == "[DONE]" {
-                    break;
-                }
+        result.join("")
+    }
 
-                if let Ok(data) = serde_json::from_str::<serde_json::Value>(data_str) {
-                    // Handle direct append operations
-                    if let (Some("append"), Some("/message/content/parts/0"), Some(value)) = (
-                        data.get("o").and_then(|v| v.as_str()),
-                        data.get("p").and_then(|v| v.as_str()),
-                        data.get("v").and_then(|v| v.as_str()),
-                    ) {
-                        result.push(value.to_string());
-                    }
-                    // Handle patch operations with list of operations
-                    else if let (Some(op), Some(operations)) = (
-                        data.get("o").and_then(|v| v.as_str()),
-                        data.get("v").and_then(|v| v.as_array()),
-                    ) {
-                        if op == "patch" {
-                            for operation in operations {
-                                if let (
-                                    Some("append"),
-                                    Some("/message/content/parts/0"),
-                                    Some(value),
-                                ) = (
-                                    operation.get("o").and_then(|v| v.as_str()),
-                                    operation.get("p").and_then(|v| v.as_str()),
-                                    operation.get("v").and_then(|v| v.as_str()),
-                                ) {
-                                    result.push(value.to_string());
-                                }
-                            }
-                        }
-                    }
-                    // Handle 'v' field containing list of operations
-                    else if let Some(operations) = data.get("v").and_then(|v| v.as_array()) {
+    /// Parse a single event stream line into the text it appends to the answer.
+    ///
+    /// Returns `None` once the `[DONE]` marker is reached.
+    pub fn parse_event_line(line: &str) -> Option<Vec<String>> {
+        let mut result = Vec::new();
+
+        if let Some(stripped) = line.strip_prefix("data:") {
+            let data_str = stripped.trim();
+
+            if data_str == "[DONE]" {
+                return None;
+            }
+
+            if let Ok(data) = serde_json::from_str::<serde_json::Value>(data_str) {
+                // Handle direct append operations
+                if let (Some("append"), Some("/message/content/parts/0"), Some(value)) = (
+                    data.get("o").and_then(|v| v.as_str()),
+                    data.get("p").and_then(|v| v.as_str()),
+                    data.get("v").and_then(|v| v.as_str()),
+                ) {
+                    result.push(value.to_string());
+                }
+                // Handle patch operations with list of operations
+                else if let (Some(op), Some(operations)) = (
+                    data.get("o").and_then(|v| v.as_str()),
+                    data.get("v").and_then(|v| v.as_array()),
+                ) {
+                    if op == "patch" {
                         for operation in operations {
                             if let (Some("append"), Some("/message/content/parts/0"), Some(value)) = (
                                 operation.get("o").and_then(|v| v.as_str()),
@@ -148,9 +146,21 @@ impl Utils {
                         }
                     }
                 }
+                // Handle 'v' field containing list of operations
+                else if let Some(operations) = data.get("v").and_then(|v| v.as_array()) {
+                    for operation in operations {
+                        if let (Some("append"), Some("/message/content/parts/0"), Some(value)) = (
+                            operation.get("o").and_then(|v| v.as_str()),
+                            operation.get("p").and_then(|v| v.as_str()),
+                            operation.get("v").and_then(|v| v.as_str()),
+                        ) {
+                            result.push(value.to_string());
+                        }
+                    }
+                }
             }
         }
 
-        result.join("")
+        Some(result)
     }
 }
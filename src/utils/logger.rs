@@ -1,90 +1,257 @@
+//! Log output on top of `tracing`.
+//!
+//! The `log_*!` macros are `tracing` events, so they share levels, filters
+//! and request spans with the rest of the crate. Terminal tools install
+//! [`init_console`] for the familiar `[HH:MM:SS] [+] message` lines; the API
+//! server uses [`init`] for plain or JSON output. Either way every line is
+//! [`scrub`]bed of credentials before it is written.
+
+use std::fmt;
+use std::io;
+
 use chrono::Local;
 use colored::*;
-use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
 
-/// Logger with colored output similar to the Python version
-pub struct Logger {
-    lock: Mutex<()>,
+use super::redact::scrub;
+use super::{ChatGptError, Result};
+
+// For the macros below, so callers need no `tracing` dependency of their own
+#[doc(hidden)]
+pub use tracing;
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines with span context
+    #[default]
+    Text,
+    /// One JSON object per line, including the current spans
+    Json,
 }
 
-impl Logger {
-    pub fn new() -> Self {
-        Self {
-            lock: Mutex::new(()),
+/// Send logs matching `filter` (an `EnvFilter` directive list such as
+/// `info,chatgpt_rs::client=debug`) to stdout in `format`
+pub fn init(filter: &str, format: LogFormat) -> Result<()> {
+    let filter = parse_filter(filter)?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(Scrubbed::new(io::stdout));
+    let installed = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+    installed.map_err(|err| ChatGptError::configuration(format!("logging: {}", err)))
+}
+
+/// Send logs matching `filter` to stdout as colored console lines
+pub fn init_console(filter: &str) -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(parse_filter(filter)?)
+        .with_writer(Scrubbed::new(io::stdout))
+        .event_format(ConsoleFormat)
+        .try_init()
+        .map_err(|err| ChatGptError::configuration(format!("logging: {}", err)))
+}
+
+/// Send logs matching `filter` to stderr as console lines, for tools whose
+/// stdout carries a protocol
+pub fn init_stderr(filter: &str) -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(parse_filter(filter)?)
+        .with_writer(Scrubbed::new(io::stderr))
+        .event_format(ConsoleFormat)
+        .try_init()
+        .map_err(|err| ChatGptError::configuration(format!("logging: {}", err)))
+}
+
+fn parse_filter(filter: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(filter)
+        .map_err(|err| ChatGptError::configuration(format!("log filter {:?}: {}", filter, err)))
+}
+
+/// Log writer that [`scrub`]s each formatted line before passing it on
+#[derive(Debug, Clone)]
+pub struct Scrubbed<M>(M);
+
+impl<M> Scrubbed<M> {
+    pub fn new(make_writer: M) -> Self {
+        Self(make_writer)
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for Scrubbed<M> {
+    type Writer = ScrubbedWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        ScrubbedWriter(self.0.make_writer())
+    }
+}
+
+/// Writer made by [`Scrubbed`]. The formatter writes an event in one call,
+/// so a secret is never split across two writes.
+pub struct ScrubbedWriter<W>(W);
+
+impl<W: io::Write> io::Write for ScrubbedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match std::str::from_utf8(buf) {
+            Ok(text) => self.0.write_all(scrub(text).as_bytes())?,
+            Err(_) => self.0.write_all(buf)?,
         }
+        Ok(buf.len())
     }
 
-    fn get_timestamp() -> String {
-        Local::now().format("%H:%M:%S").to_string()
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
     }
+}
 
-    fn log(&self, _level: &str, prefix: &str, message: &str, color: Color) {
-        let _guard = self.lock.lock().unwrap();
+/// Event format of the terminal tools: timestamp, a colored marker for the
+/// level (`[+]` for `log_success!`) and the message
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConsoleFormat;
 
-        let timestamp = Self::get_timestamp();
-        let timestamp_formatted = format!("[{}]", timestamp.magenta());
-        let prefix_colored = prefix.color(color).bold();
+impl<S, N> FormatEvent<S, N> for ConsoleFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = ConsoleFields::default();
+        event.record(&mut fields);
 
-        println!(
-            "{} {} {}",
-            timestamp_formatted.bright_black(),
-            prefix_colored,
-            message
+        let (prefix, color) = match *event.metadata().level() {
+            Level::ERROR => ("[!]", Color::Red),
+            Level::WARN => ("[!]", Color::Yellow),
+            Level::INFO if fields.success => ("[+]", Color::Green),
+            Level::INFO => ("[*]", Color::White),
+            _ => ("[-]", Color::BrightBlack),
+        };
+        let timestamp = format!(
+            "[{}]",
+            Local::now().format("%H:%M:%S").to_string().magenta()
         );
+
+        writeln!(
+            writer,
+            "{} {} {}{}",
+            timestamp.bright_black(),
+            prefix.color(color).bold(),
+            fields.message,
+            fields.rest
+        )
+    }
+}
+
+/// The message of an event, whether it is a success, and its other fields
+/// as ` key=value` pairs
+#[derive(Default)]
+struct ConsoleFields {
+    message: String,
+    success: bool,
+    rest: String,
+}
+
+impl Visit for ConsoleFields {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == "success" {
+            self.success = value;
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.rest.push_str(
+                &format!(" {}={:?}", field.name(), value)
+                    .dimmed()
+                    .to_string(),
+            );
+        }
+    }
+}
+
+/// The logger from before the switch to `tracing`. Its methods emit the same
+/// events as the `log_*!` macros, so they need a subscriber to show up.
+#[deprecated(note = "use the `log_*!` macros or `tracing` directly")]
+#[derive(Debug, Default)]
+pub struct Logger {
+    _private: (),
+}
+
+#[allow(deprecated)]
+impl Logger {
+    pub const fn new() -> Self {
+        Self { _private: () }
     }
 
     pub fn success(&self, message: &str) {
-        self.log("SUCCESS", "[+]", message, Color::Green);
+        tracing::info!(success = true, "{}", message);
     }
 
     pub fn error(&self, message: &str) {
-        self.log("ERROR", "[!]", message, Color::Red);
+        tracing::error!("{}", message);
     }
 
     pub fn info(&self, message: &str) {
-        self.log("INFO", "[*]", message, Color::White);
+        tracing::info!("{}", message);
     }
 
     pub fn warning(&self, message: &str) {
-        self.log("WARNING", "[!]", message, Color::Yellow);
+        tracing::warn!("{}", message);
     }
 }
 
-impl Default for Logger {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-lazy_static::lazy_static! {
-    pub static ref LOG: Logger = Logger::new();
-}
+/// Shared [`Logger`], kept for code written against the old API
+#[deprecated(note = "use the `log_*!` macros or `tracing` directly")]
+#[allow(deprecated)]
+pub static LOG: Logger = Logger::new();
 
 /// Convenience macros for logging
 #[macro_export]
 macro_rules! log_success {
     ($($arg:tt)*) => {
-        $crate::utils::logger::LOG.success(&format!($($arg)*))
+        $crate::utils::logger::tracing::info!(success = true, $($arg)*)
     };
 }
 
 #[macro_export]
 macro_rules! log_error {
     ($($arg:tt)*) => {
-        $crate::utils::logger::LOG.error(&format!($($arg)*))
+        $crate::utils::logger::tracing::error!($($arg)*)
     };
 }
 
 #[macro_export]
 macro_rules! log_info {
     ($($arg:tt)*) => {
-        $crate::utils::logger::LOG.info(&format!($($arg)*))
+        $crate::utils::logger::tracing::info!($($arg)*)
     };
 }
 
 #[macro_export]
 macro_rules! log_warning {
     ($($arg:tt)*) => {
-        $crate::utils::logger::LOG.warning(&format!($($arg)*))
+        $crate::utils::logger::tracing::warn!($($arg)*)
     };
 }
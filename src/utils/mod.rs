@@ -1,7 +1,10 @@
 pub mod error;
 pub mod helpers;
 pub mod logger;
+pub mod redact;
 
 pub use error::{ChatGptError, Result};
 pub use helpers::Utils;
-pub use logger::Logger;
+#[allow(deprecated)]
+pub use logger::{LogFormat, Logger};
+pub use redact::Secret;
@@ -0,0 +1,1325 @@
+use chatgpt_rs::ChatGptError;
+use chatgpt_rs::api::storage::ThreadStore;
+use chatgpt_rs::api::{AppState, ServerConfig};
+use chatgpt_rs::backend::{EchoBackend, Script, ScriptedBackend};
+use chatgpt_rs::client::ChatGptClient;
+use chatgpt_rs::test_support::{MockUpstream, TestServer, fixtures, paths};
+use serde_json::{Value, json};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+async fn create_thread(server: &TestServer, content: &str) -> String {
+    let thread: Value = reqwest::Client::new()
+        .post(server.url("/v1/threads"))
+        .json(&json!({"messages": [{"role": "user", "content": content}]}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    thread["id"].as_str().unwrap().to_string()
+}
+
+async fn messages(server: &TestServer, thread_id: &str) -> Vec<Value> {
+    let list: Value = reqwest::get(server.url(&format!("/v1/threads/{}/messages", thread_id)))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    list["data"].as_array().unwrap().clone()
+}
+
+#[tokio::test]
+async fn answers_through_mock_upstream() {
+    let upstream = MockUpstream::start().await;
+    let state = AppState::<ChatGptClient>::with_backend_config(upstream.client_config(), None);
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "Hi").await;
+
+    let response = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "completed");
+
+    let messages = messages(&server, &thread_id).await;
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[1]["role"], "assistant");
+    assert_eq!(
+        messages[1]["content"][0]["text"]["value"],
+        fixtures::CONVERSATION_ANSWER
+    );
+}
+
+#[tokio::test]
+async fn streams_through_mock_upstream() {
+    let upstream = MockUpstream::start().await;
+    upstream.reply_with("streamed answer");
+    let state = AppState::<ChatGptClient>::with_backend_config(upstream.client_config(), None);
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "Hi").await;
+
+    let body = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id, "stream": true}))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    let chunks: Vec<Value> = body
+        .lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect();
+    let text: String = chunks
+        .iter()
+        .filter_map(|chunk| chunk["delta"]["content"].as_str())
+        .collect();
+    assert_eq!(text, "streamed answer");
+    assert_eq!(chunks.last().unwrap()["status"], "completed");
+}
+
+#[tokio::test]
+async fn reports_upstream_outage() {
+    let upstream = MockUpstream::start().await;
+    let state = AppState::<ChatGptClient>::with_backend_config(upstream.client_config(), None);
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "Hi").await;
+    upstream.fail_next(paths::CHAT_REQUIREMENTS, 503, 3);
+
+    let response = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 503);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "upstream_unavailable");
+    assert_eq!(messages(&server, &thread_id).await.len(), 1);
+}
+
+#[test]
+fn error_codes_are_stable() {
+    let upstream = |status| ChatGptError::Upstream {
+        status,
+        endpoint: "conversation".to_string(),
+        body_excerpt: String::new(),
+        retry_after: None,
+    };
+    assert_eq!(upstream(429).code(), "rate_limited");
+    assert_eq!(upstream(500).code(), "upstream_unavailable");
+    assert_eq!(upstream(401).code(), "upstream_rejected");
+    assert_eq!(ChatGptError::timeout("read").code(), "upstream_timeout");
+    assert_eq!(ChatGptError::invalid_proxy("x").code(), "invalid_proxy");
+    assert_eq!(
+        ChatGptError::challenge_solve("x").code(),
+        "upstream_challenge_failed"
+    );
+    assert_eq!(ChatGptError::Cancelled.code(), "cancelled");
+    assert_eq!(ChatGptError::unknown("x").code(), "internal_error");
+}
+
+#[tokio::test]
+async fn upstream_errors_keep_their_status_and_retry_after() {
+    let upstream = MockUpstream::start().await;
+    let state = AppState::<ChatGptClient>::with_backend_config(upstream.client_config(), None);
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "Hi").await;
+    let create = || {
+        reqwest::Client::new()
+            .post(server.url("/v1/responses"))
+            .json(&json!({"thread_id": thread_id}))
+            .send()
+    };
+
+    // Retry-After is longer than the client waits, so it is passed on
+    upstream.fail_next_with(paths::CONVERSATION, 429, "slow down", Some(120), 1);
+    let response = create().await.unwrap();
+    assert_eq!(response.status(), 429);
+    assert_eq!(response.headers()["retry-after"], "120");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "rate_limited");
+    assert_eq!(body["error"]["type"], "rate_limit_error");
+
+    upstream.fail_next(paths::CONVERSATION, 403, 1);
+    let response = create().await.unwrap();
+    assert_eq!(response.status(), 502);
+    assert!(response.headers().get("retry-after").is_none());
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "upstream_rejected");
+    assert_eq!(body["error"]["type"], "upstream_error");
+
+    upstream.fail_next(paths::CONVERSATION, 504, 3);
+    let response = create().await.unwrap();
+    assert_eq!(response.status(), 504);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "upstream_unavailable");
+}
+
+#[tokio::test]
+async fn echo_backend_needs_no_upstream() {
+    let state = AppState::<EchoBackend>::with_backend_config((), None);
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "ping pong").await;
+
+    reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap();
+
+    let messages = messages(&server, &thread_id).await;
+    assert_eq!(messages[1]["content"][0]["text"]["value"], "ping pong");
+}
+
+#[tokio::test]
+async fn scripted_backend_sees_the_last_user_message() {
+    let script = Script::new().reply("Bonjour");
+    let state = AppState::<ScriptedBackend>::with_backend_config(script, None);
+    let server = TestServer::start(state.clone()).await;
+    let thread_id = create_thread(&server, "Hello").await;
+
+    let response = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({
+            "thread_id": thread_id,
+            "instructions": "Answer in French",
+            "proxy": "http://127.0.0.1:9"
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let thread = state.get_thread(&thread_id).await.unwrap();
+    let backend = thread.client.read().await;
+    assert_eq!(backend.as_ref().unwrap().received(), ["Hello"]);
+}
+
+#[tokio::test]
+async fn requires_a_configured_api_key() {
+    let state = AppState::<EchoBackend>::with_backend_config((), None)
+        .with_api_keys(vec!["sk-test".to_string()]);
+    let server = TestServer::start(state).await;
+    let client = reqwest::Client::new();
+
+    let missing = client.get(server.url("/v1/threads")).send().await.unwrap();
+    assert_eq!(missing.status(), 401);
+    let body: Value = missing.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "invalid_api_key");
+
+    let wrong = client
+        .get(server.url("/v1/threads"))
+        .bearer_auth("sk-nope")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(wrong.status(), 401);
+
+    let right = client
+        .get(server.url("/v1/threads"))
+        .bearer_auth("sk-test")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(right.status(), 200);
+
+    let health = client.get(server.url("/health")).send().await.unwrap();
+    assert_eq!(health.status(), 200);
+}
+
+#[tokio::test]
+async fn serves_and_enforces_the_model_catalog() {
+    let config = ServerConfig::from_toml("[[models]]\nid = \"local-echo\"\n").unwrap();
+    let state = AppState::<EchoBackend>::from_config((), &config).unwrap();
+    let server = TestServer::start(state).await;
+    let client = reqwest::Client::new();
+
+    let models: Value = client
+        .get(server.url("/v1/models"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(models["data"][0]["id"], "local-echo");
+    assert_eq!(models["data"].as_array().unwrap().len(), 1);
+
+    let thread_id = create_thread(&server, "hi").await;
+    let unknown = client
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id, "model": "gpt-4"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(unknown.status(), 404);
+    let body: Value = unknown.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "model_not_found");
+    assert_eq!(body["error"]["param"], "model");
+
+    let response: Value = client
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["model"], "local-echo");
+}
+
+#[tokio::test]
+async fn enforces_thread_and_message_limits() {
+    let config =
+        ServerConfig::from_toml("[limits]\nmax_threads = 1\nmax_message_chars = 5\n").unwrap();
+    let state = AppState::<EchoBackend>::from_config((), &config).unwrap();
+    let server = TestServer::start(state).await;
+    let client = reqwest::Client::new();
+
+    let too_long = client
+        .post(server.url("/v1/threads"))
+        .json(&json!({"messages": [{"role": "user", "content": "far too long"}]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(too_long.status(), 400);
+    let body: Value = too_long.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "message_too_long");
+
+    create_thread(&server, "hi").await;
+    let second = client
+        .post(server.url("/v1/threads"))
+        .json(&json!({"messages": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(second.status(), 429);
+    let body: Value = second.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "thread_limit_reached");
+}
+
+#[tokio::test]
+async fn restored_threads_continue_their_conversation() {
+    let path = std::env::temp_dir().join(format!("threads-{}.json", uuid::Uuid::new_v4()));
+    let config = ServerConfig::from_toml(&format!(
+        "[storage]\nkind = \"file\"\npath = {:?}\n",
+        path.to_str().unwrap()
+    ))
+    .unwrap();
+    let upstream = MockUpstream::start().await;
+    let client = reqwest::Client::new();
+
+    let first = AppState::<ChatGptClient>::from_config(upstream.client_config(), &config).unwrap();
+    let server = TestServer::start(first.clone()).await;
+    let thread_id = create_thread(&server, "Hi").await;
+    client
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap();
+    first.flush().await.unwrap();
+    drop(server);
+
+    // A new server process picks the thread up from the file
+    let second = AppState::<ChatGptClient>::from_config(upstream.client_config(), &config).unwrap();
+    let server = TestServer::start(second).await;
+    assert_eq!(messages(&server, &thread_id).await.len(), 2);
+
+    upstream.reply_with("welcome back");
+    client
+        .post(server.url(&format!("/v1/threads/{}/messages", thread_id)))
+        .json(&json!({"role": "user", "content": "Still there?"}))
+        .send()
+        .await
+        .unwrap();
+    let response: Value = client
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["status"], "completed");
+
+    let follow_up = upstream.requests_to(paths::PREPARE).last().unwrap().json();
+    assert_eq!(follow_up["conversation_id"], fixtures::CONVERSATION_ID);
+    assert_eq!(follow_up["parent_message_id"], fixtures::MESSAGE_ID);
+    std::fs::remove_file(path).unwrap();
+}
+
+fn storage_config(path: &std::path::Path, drain_timeout_secs: u64) -> ServerConfig {
+    ServerConfig::from_toml(&format!(
+        "[server]\ndrain_timeout_secs = {}\n[storage]\nkind = \"file\"\npath = {:?}\nflush_interval_secs = 3600\n",
+        drain_timeout_secs,
+        path.to_str().unwrap()
+    ))
+    .unwrap()
+}
+
+fn stream_chunks(body: &str) -> Vec<Value> {
+    body.lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn shutdown_lets_streams_finish_and_saves_threads() {
+    let path = std::env::temp_dir().join(format!("threads-{}.json", uuid::Uuid::new_v4()));
+    let script = Script::new().reply_slowly("one two three four", Duration::from_millis(100));
+    let state =
+        AppState::<ScriptedBackend>::from_config(script, &storage_config(&path, 30)).unwrap();
+    let server = TestServer::start(state.clone()).await;
+    let thread_id = create_thread(&server, "Count").await;
+
+    let mut stream = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id, "stream": true}))
+        .send()
+        .await
+        .unwrap();
+    let first = stream.chunk().await.unwrap().unwrap();
+
+    // SIGTERM arrives mid-answer
+    let shutdown = tokio::spawn(server.shutdown());
+    let rest = stream.text().await.unwrap();
+    shutdown.await.unwrap().unwrap();
+
+    let body = format!("{}{}", String::from_utf8_lossy(&first), rest);
+    let chunks = stream_chunks(&body);
+    let text: String = chunks
+        .iter()
+        .filter_map(|chunk| chunk["delta"]["content"].as_str())
+        .collect();
+    assert_eq!(text, "one two three four");
+    assert_eq!(chunks.last().unwrap()["status"], "completed");
+    assert_eq!(
+        state.check_accepting().unwrap_err().code,
+        "server_shutting_down"
+    );
+
+    // The answer reached storage without waiting for the next periodic flush
+    let saved = ThreadStore::new(&path, Duration::from_secs(1))
+        .load()
+        .unwrap();
+    assert_eq!(saved[0].messages[1].content, "one two three four");
+    assert_eq!(saved[0].messages[1].status, None);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_cancels_responses_past_the_drain_timeout() {
+    let path = std::env::temp_dir().join(format!("threads-{}.json", uuid::Uuid::new_v4()));
+    let script = Script::new().reply_slowly("a b c d e f g h i j", Duration::from_millis(200));
+    let state =
+        AppState::<ScriptedBackend>::from_config(script, &storage_config(&path, 0)).unwrap();
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "Take your time").await;
+
+    let mut stream = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id, "stream": true}))
+        .send()
+        .await
+        .unwrap();
+    let first = stream.chunk().await.unwrap().unwrap();
+
+    let started = std::time::Instant::now();
+    let shutdown = tokio::spawn(server.shutdown());
+    let rest = stream.text().await.unwrap();
+    shutdown.await.unwrap().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    let body = format!("{}{}", String::from_utf8_lossy(&first), rest);
+    assert_eq!(stream_chunks(&body).last().unwrap()["status"], "cancelled");
+
+    let saved = ThreadStore::new(&path, Duration::from_secs(1))
+        .load()
+        .unwrap();
+    let answer = &saved[0].messages[1];
+    assert!(answer.content.starts_with('a'), "{:?}", answer.content);
+    assert_eq!(answer.status.as_deref(), Some("incomplete"));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn one_response_per_thread_at_a_time() {
+    let script = Script::new()
+        .reply_slowly("one two three", Duration::from_millis(100))
+        .reply("four");
+    let state = AppState::<ScriptedBackend>::with_backend_config(script, None);
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "Count").await;
+    let client = reqwest::Client::new();
+
+    let mut stream = client
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id, "stream": true}))
+        .send()
+        .await
+        .unwrap();
+    let first = stream.chunk().await.unwrap().unwrap();
+
+    let busy = client
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(busy.status(), 409);
+    let body: Value = busy.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "thread_busy");
+
+    // A message added meanwhile stays after the answer it did not take part in
+    let added = client
+        .post(server.url(&format!("/v1/threads/{}/messages", thread_id)))
+        .json(&json!({"role": "user", "content": "And then?"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(added.status(), 200);
+
+    let body = format!(
+        "{}{}",
+        String::from_utf8_lossy(&first),
+        stream.text().await.unwrap()
+    );
+    assert_eq!(stream_chunks(&body).last().unwrap()["status"], "completed");
+
+    let texts: Vec<_> = messages(&server, &thread_id)
+        .await
+        .iter()
+        .map(|m| (m["role"].clone(), m["content"][0]["text"]["value"].clone()))
+        .collect();
+    assert_eq!(
+        texts,
+        [
+            (json!("user"), json!("Count")),
+            (json!("assistant"), json!("one two three")),
+            (json!("user"), json!("And then?")),
+        ]
+    );
+
+    // Once the first answer is in, the thread takes the next turn
+    let response = client
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(messages(&server, &thread_id).await.len(), 4);
+}
+
+async fn create_response_with_key(
+    server: &TestServer,
+    key: &str,
+    body: &Value,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .header("idempotency-key", key)
+        .json(body)
+        .send()
+        .await
+        .unwrap()
+}
+
+fn replayed(response: &reqwest::Response) -> bool {
+    response.headers().get("idempotent-replayed").is_some()
+}
+
+#[tokio::test]
+async fn idempotency_key_replays_the_completed_response() {
+    // A second generation would fail, as the script has only one answer
+    let state = AppState::<ScriptedBackend>::with_backend_config(Script::new().reply("Once"), None);
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "Hi").await;
+    let request = json!({"thread_id": thread_id});
+
+    let first = create_response_with_key(&server, "key-1", &request).await;
+    assert_eq!(first.status(), 200);
+    assert!(!replayed(&first));
+    let first: Value = first.json().await.unwrap();
+
+    let retry = create_response_with_key(&server, "key-1", &request).await;
+    assert_eq!(retry.status(), 200);
+    assert_eq!(retry.headers()["idempotent-replayed"], "true");
+    let retry: Value = retry.json().await.unwrap();
+    assert_eq!(retry["id"], first["id"]);
+
+    // Streamed retries replay the answer as chunks
+    let streamed = create_response_with_key(
+        &server,
+        "key-1",
+        &json!({"thread_id": thread_id, "stream": true}),
+    )
+    .await;
+    assert!(replayed(&streamed));
+    let chunks = stream_chunks(&streamed.text().await.unwrap());
+    assert_eq!(chunks[0]["id"], first["id"]);
+    assert_eq!(chunks[0]["delta"]["content"], "Once");
+    assert_eq!(messages(&server, &thread_id).await.len(), 2);
+
+    // The same key with another request body is refused
+    let other = create_response_with_key(
+        &server,
+        "key-1",
+        &json!({"thread_id": thread_id, "model": "gpt-4o"}),
+    )
+    .await;
+    assert_eq!(other.status(), 422);
+    let body: Value = other.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "idempotency_key_reused");
+}
+
+#[tokio::test]
+async fn idempotency_key_duplicates_wait_for_the_first_request() {
+    let script = Script::new().reply_slowly("one two three", Duration::from_millis(100));
+    let state = AppState::<ScriptedBackend>::with_backend_config(script, None);
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "Count").await;
+    let request = json!({"thread_id": thread_id});
+
+    let (first, second) = tokio::join!(
+        create_response_with_key(&server, "key-2", &request),
+        async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            create_response_with_key(&server, "key-2", &request).await
+        }
+    );
+    assert_eq!(first.status(), 200);
+    assert_eq!(second.status(), 200);
+    assert!(!replayed(&first));
+    assert!(replayed(&second));
+    let first: Value = first.json().await.unwrap();
+    let second: Value = second.json().await.unwrap();
+    assert_eq!(first["id"], second["id"]);
+    assert_eq!(messages(&server, &thread_id).await.len(), 2);
+}
+
+#[tokio::test]
+async fn idempotency_key_is_released_when_the_request_fails() {
+    let script = Script::new().fail(503, "overloaded").reply("Second try");
+    let state = AppState::<ScriptedBackend>::with_backend_config(script, None);
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "Hi").await;
+    let request = json!({"thread_id": thread_id});
+
+    let failed = create_response_with_key(&server, "key-3", &request).await;
+    assert_eq!(failed.status(), 503);
+
+    let retry = create_response_with_key(&server, "key-3", &request).await;
+    assert_eq!(retry.status(), 200);
+    assert!(!replayed(&retry));
+    let messages = messages(&server, &thread_id).await;
+    assert_eq!(messages[1]["content"][0]["text"]["value"], "Second try");
+}
+
+/// Wait for the thread's answer to be recorded as incomplete
+async fn incomplete_answer(server: &TestServer, thread_id: &str) -> Value {
+    for _ in 0..100 {
+        let messages = messages(server, thread_id).await;
+        if messages.len() == 2 {
+            return messages[1].clone();
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("no answer was recorded");
+}
+
+#[tokio::test]
+async fn cancel_endpoint_stops_a_streaming_response() {
+    let script = Script::new().reply_slowly("a b c d e f g h i j", Duration::from_millis(100));
+    let state = AppState::<ScriptedBackend>::with_backend_config(script, None);
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "Take your time").await;
+    let client = reqwest::Client::new();
+
+    let mut stream = client
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id, "stream": true}))
+        .send()
+        .await
+        .unwrap();
+    let first = stream.chunk().await.unwrap().unwrap();
+    let first = String::from_utf8_lossy(&first).to_string();
+    let response_id = stream_chunks(&first)[0]["id"].as_str().unwrap().to_string();
+
+    let cancel: Value = client
+        .post(server.url(&format!("/v1/responses/{}/cancel", response_id)))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(cancel["status"], "cancelling");
+
+    let body = format!("{}{}", first, stream.text().await.unwrap());
+    assert_eq!(stream_chunks(&body).last().unwrap()["status"], "cancelled");
+
+    let answer = incomplete_answer(&server, &thread_id).await;
+    assert_eq!(answer["status"], "incomplete");
+    let text = answer["content"][0]["text"]["value"].as_str().unwrap();
+    assert!(text.starts_with('a') && !text.ends_with('j'), "{:?}", text);
+
+    // Finished responses can no longer be cancelled
+    let response = client
+        .post(server.url(&format!("/v1/responses/{}/cancel", response_id)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn client_disconnect_cancels_the_response() {
+    let script = Script::new().reply_slowly("a b c d e f g h i j", Duration::from_millis(100));
+    let state = AppState::<ScriptedBackend>::with_backend_config(script, None);
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "Take your time").await;
+
+    // The caller gives up while the answer is still coming in
+    let result = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .timeout(Duration::from_millis(350))
+        .send()
+        .await;
+    assert!(result.unwrap_err().is_timeout());
+
+    let answer = incomplete_answer(&server, &thread_id).await;
+    assert_eq!(answer["status"], "incomplete");
+    let text = answer["content"][0]["text"]["value"].as_str().unwrap();
+    assert!(text.starts_with('a') && !text.ends_with('j'), "{:?}", text);
+}
+
+#[tokio::test]
+async fn stream_disconnect_before_the_first_delta_cancels_the_response() {
+    let script = Script::new().reply_slowly("a b c", Duration::from_secs(2));
+    let state = AppState::<ScriptedBackend>::with_backend_config(script, None);
+    let server = TestServer::start(state).await;
+    let thread_id = create_thread(&server, "Take your time").await;
+
+    // The caller gives up while upstream has not produced anything yet
+    let stream = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id, "stream": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(stream.status(), 200);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    drop(stream);
+
+    let cancelled = r#"chatgpt_generation_duration_seconds_count{status="cancelled"} 1"#;
+    let mut metrics = String::new();
+    for _ in 0..50 {
+        metrics = reqwest::get(server.url("/metrics"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        if metrics.contains(cancelled) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(metrics.contains(cancelled), "{}", metrics);
+    // Nothing was generated, so no answer is recorded
+    assert_eq!(messages(&server, &thread_id).await.len(), 1);
+}
+
+#[tokio::test]
+async fn exports_prometheus_metrics() {
+    let script = Script::new().reply("Hello there").fail(503, "overloaded");
+    let state = AppState::<ScriptedBackend>::with_backend_config(script, None);
+    let server = TestServer::start(state).await;
+    let client = reqwest::Client::new();
+    let thread_id = create_thread(&server, "Hi").await;
+
+    client
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id, "stream": true}))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    client
+        .post(server.url(&format!("/v1/threads/{}/messages", thread_id)))
+        .json(&json!({"role": "user", "content": "Again"}))
+        .send()
+        .await
+        .unwrap();
+    let failed = client
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(failed.status(), 503);
+    client
+        .delete(server.url(&format!("/v1/threads/{}", thread_id)))
+        .send()
+        .await
+        .unwrap();
+
+    let response = reqwest::get(server.url("/metrics")).await.unwrap();
+    assert!(
+        response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("application/openmetrics-text")
+    );
+    let text = response.text().await.unwrap();
+    for line in [
+        r#"chatgpt_http_requests_total{method="POST",route="/v1/responses",status="200"} 1"#,
+        r#"chatgpt_http_requests_total{method="POST",route="/v1/responses",status="503"} 1"#,
+        r#"chatgpt_http_requests_total{method="DELETE",route="/v1/threads/{thread_id}",status="200"} 1"#,
+        r#"chatgpt_generation_duration_seconds_count{status="completed"} 1"#,
+        r#"chatgpt_generation_duration_seconds_count{status="failed"} 1"#,
+        r#"chatgpt_upstream_errors_total{kind="upstream_unavailable"} 1"#,
+        "chatgpt_time_to_first_token_seconds_count 1",
+        "chatgpt_client_init_duration_seconds_count 1",
+        "chatgpt_streamed_bytes_total 11",
+        "chatgpt_threads_active 0",
+        r#"chatgpt_threads_removed_total{reason="deleted"} 1"#,
+    ] {
+        assert!(text.contains(line), "missing {}\n{}", line, text);
+    }
+}
+
+/// Log output collected in memory
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn request_ids_tie_client_logs_to_the_request() {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_span_list(true)
+        .with_env_filter("chatgpt_rs=debug")
+        .with_writer(move || writer.clone())
+        .finish();
+    // Tests run on a current-thread runtime, so spawned tasks log here too
+    let _default = tracing::subscriber::set_default(subscriber);
+
+    let upstream = MockUpstream::start().await;
+    let state = AppState::<ChatGptClient>::with_backend_config(upstream.client_config(), None);
+    let server = TestServer::start(state).await;
+    let client = reqwest::Client::new();
+    let thread_id = create_thread(&server, "Hi").await;
+
+    let generated = client.get(server.url("/health")).send().await.unwrap();
+    let id = generated.headers()["x-request-id"].to_str().unwrap();
+    assert!(uuid::Uuid::parse_str(id).is_ok(), "{}", id);
+
+    let response = client
+        .post(server.url("/v1/responses"))
+        .header("x-request-id", "req-123")
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-request-id"], "req-123");
+    assert_eq!(response.status(), 200);
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let accepted: Value = logs
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .find(|line| line["fields"]["message"] == "Upstream accepted the turn")
+        .expect("client logged the turn");
+    let spans = accepted["spans"].as_array().unwrap();
+    let names: Vec<&str> = spans.iter().map(|s| s["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["request", "response", "chatgpt_turn"]);
+    assert_eq!(spans[0]["id"], "req-123");
+    assert_eq!(spans[1]["thread_id"], thread_id.as_str());
+}
+
+fn compat_state<B: chatgpt_rs::backend::ChatBackend>(config: B::Config) -> AppState<B> {
+    let catalog = ServerConfig::from_toml("[[models]]\nid = \"claude-sonnet-4\"\n").unwrap();
+    AppState::<B>::from_config(config, &catalog)
+        .unwrap()
+        .with_api_keys(vec!["sk-test".to_string()])
+}
+
+#[tokio::test]
+async fn messages_api_folds_the_conversation_into_one_turn() {
+    let server = TestServer::start(compat_state::<EchoBackend>(())).await;
+
+    let response = reqwest::Client::new()
+        .post(server.url("/v1/messages"))
+        .header("x-api-key", "sk-test")
+        .header("anthropic-version", "2023-06-01")
+        .json(&json!({
+            "model": "claude-sonnet-4",
+            "max_tokens": 1024,
+            "system": [{"type": "text", "text": "Be brief."}],
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": [{"type": "text", "text": "Hello!"}]},
+                {"role": "user", "content": [
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo="}},
+                    {"type": "text", "text": "What is this?"}
+                ]}
+            ]
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert!(body["id"].as_str().unwrap().starts_with("msg_"));
+    assert_eq!(body["type"], "message");
+    assert_eq!(body["role"], "assistant");
+    assert_eq!(body["model"], "claude-sonnet-4");
+    assert_eq!(body["stop_reason"], "end_turn");
+    assert_eq!(body["content"][0]["type"], "text");
+    assert_eq!(
+        body["content"][0]["text"],
+        "Be brief.\n\nConversation so far:\n\nUser: Hi\n\nAssistant: Hello!\n\nWhat is this?"
+    );
+    assert_eq!(body["usage"]["input_tokens"], 0);
+}
+
+#[tokio::test]
+async fn messages_api_streams_message_events() {
+    let script = Script::new().reply("Hello there");
+    let server = TestServer::start(compat_state::<ScriptedBackend>(script)).await;
+    let request = json!({
+        "model": "claude-sonnet-4",
+        "max_tokens": 64,
+        "stream": true,
+        "messages": [{"role": "user", "content": "Hi"}]
+    });
+
+    let body = reqwest::Client::new()
+        .post(server.url("/v1/messages"))
+        .header("x-api-key", "sk-test")
+        .json(&request)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let names: Vec<_> = body
+        .lines()
+        .filter_map(|line| line.strip_prefix("event: "))
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "message_start",
+            "content_block_start",
+            "content_block_delta",
+            "content_block_delta",
+            "content_block_stop",
+            "message_delta",
+            "message_stop"
+        ]
+    );
+    let events = stream_chunks(&body);
+    assert_eq!(events[0]["message"]["model"], "claude-sonnet-4");
+    let text: String = events
+        .iter()
+        .filter(|event| event["type"] == "content_block_delta")
+        .map(|event| event["delta"]["text"].as_str().unwrap())
+        .collect();
+    assert_eq!(text, "Hello there");
+    assert_eq!(events[5]["delta"]["stop_reason"], "end_turn");
+
+    // Failures after the stream has started arrive as an `error` event
+    let failing = Script::new().fail(503, "overloaded");
+    let server = TestServer::start(compat_state::<ScriptedBackend>(failing)).await;
+    let body = reqwest::Client::new()
+        .post(server.url("/v1/messages"))
+        .bearer_auth("sk-test")
+        .json(&request)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(body.contains("event: error"), "{}", body);
+    let error = stream_chunks(&body).pop().unwrap();
+    assert_eq!(error["error"]["type"], "overloaded_error");
+}
+
+#[tokio::test]
+async fn stateless_turns_reuse_sessions_that_worked() {
+    // Every new session plays the script from the start, so the answers show
+    // which session a turn ran on
+    let script = Script::new().reply("First").fail(503, "overloaded");
+    let server = TestServer::start(compat_state::<ScriptedBackend>(script.clone())).await;
+    let client = reqwest::Client::new();
+    let ask = |server: &TestServer| {
+        client
+            .post(server.url("/v1/messages"))
+            .header("x-api-key", "sk-test")
+            .json(&json!({
+                "model": "claude-sonnet-4",
+                "max_tokens": 64,
+                "messages": [{"role": "user", "content": "Hi"}]
+            }))
+            .send()
+    };
+
+    assert_eq!(ask(&server).await.unwrap().status(), 200);
+    // Reused, so this is the session's second turn
+    assert_eq!(ask(&server).await.unwrap().status(), 503);
+    // The failed session was dropped rather than reused
+    let body: Value = ask(&server).await.unwrap().json().await.unwrap();
+    assert_eq!(body["content"][0]["text"], "First");
+
+    let unpooled = compat_state::<ScriptedBackend>(script).with_session_pool(0, Duration::ZERO);
+    let server = TestServer::start(unpooled).await;
+    for _ in 0..2 {
+        assert_eq!(ask(&server).await.unwrap().status(), 200);
+    }
+}
+
+#[tokio::test]
+async fn messages_api_reports_errors_in_its_own_format() {
+    let server = TestServer::start(compat_state::<EchoBackend>(())).await;
+    let client = reqwest::Client::new();
+    let send = |key: &'static str, request: Value| {
+        client
+            .post(server.url("/v1/messages"))
+            .header("x-api-key", key)
+            .json(&request)
+            .send()
+    };
+
+    let unauthorized = send("sk-nope", json!({})).await.unwrap();
+    assert_eq!(unauthorized.status(), 401);
+    let body: Value = unauthorized.json().await.unwrap();
+    assert_eq!(body["type"], "error");
+    assert_eq!(body["error"]["type"], "authentication_error");
+
+    let prefill = send(
+        "sk-test",
+        json!({
+            "model": "claude-sonnet-4",
+            "max_tokens": 64,
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hel"}
+            ]
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(prefill.status(), 400);
+    let body: Value = prefill.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "invalid_request_error");
+    assert!(body["error"]["message"].as_str().unwrap().contains("user"));
+
+    let tools = send(
+        "sk-test",
+        json!({
+            "model": "claude-sonnet-4",
+            "max_tokens": 64,
+            "messages": [{"role": "user", "content": [{"type": "tool_result", "tool_use_id": "x"}]}]
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(tools.status(), 400);
+
+    let unknown_model = send(
+        "sk-test",
+        json!({
+            "model": "claude-opus",
+            "max_tokens": 64,
+            "messages": [{"role": "user", "content": "Hi"}]
+        }),
+    )
+    .await
+    .unwrap();
+    assert_eq!(unknown_model.status(), 404);
+    let body: Value = unknown_model.json().await.unwrap();
+    assert_eq!(body["error"]["type"], "not_found_error");
+}
+
+#[tokio::test]
+async fn ollama_chat_and_tags() {
+    let server = TestServer::start(compat_state::<EchoBackend>(())).await;
+    let client = reqwest::Client::new();
+
+    let tags: Value = client
+        .get(server.url("/api/tags"))
+        .bearer_auth("sk-test")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(tags["models"][0]["name"], "claude-sonnet-4");
+
+    let chat: Value = client
+        .post(server.url("/api/chat"))
+        .bearer_auth("sk-test")
+        .json(&json!({
+            "model": "claude-sonnet-4:latest",
+            "stream": false,
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello!"},
+                {"role": "user", "content": "What is this?", "images": ["iVBORw0KGgo="]}
+            ]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(chat["model"], "claude-sonnet-4");
+    assert_eq!(chat["done"], true);
+    assert_eq!(chat["done_reason"], "stop");
+    assert_eq!(chat["message"]["role"], "assistant");
+    assert_eq!(
+        chat["message"]["content"],
+        "Be brief.\n\nConversation so far:\n\nUser: Hi\n\nAssistant: Hello!\n\nWhat is this?"
+    );
+
+    // No messages only loads the model
+    let load: Value = client
+        .post(server.url("/api/chat"))
+        .bearer_auth("sk-test")
+        .json(&json!({"model": "claude-sonnet-4", "messages": [], "stream": false}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(load["done_reason"], "load");
+}
+
+#[tokio::test]
+async fn ollama_generate_streams_ndjson() {
+    let script = Script::new().reply("Hello there");
+    let server = TestServer::start(compat_state::<ScriptedBackend>(script)).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(server.url("/api/generate"))
+        .bearer_auth("sk-test")
+        .json(&json!({"model": "claude-sonnet-4", "prompt": "Hi"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+    let body = response.text().await.unwrap();
+    let lines: Vec<Value> = body
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let (last, deltas) = lines.split_last().unwrap();
+    let text: String = deltas
+        .iter()
+        .map(|line| {
+            assert_eq!(line["done"], false);
+            line["response"].as_str().unwrap()
+        })
+        .collect();
+    assert_eq!(text, "Hello there");
+    assert_eq!(last["done"], true);
+    assert_eq!(last["done_reason"], "stop");
+    assert!(last.get("thread_id").is_none());
+
+    // Chats stream the same way from their thread
+    let body = client
+        .post(server.url("/api/chat"))
+        .bearer_auth("sk-test")
+        .json(&json!({"model": "claude-sonnet-4", "messages": [{"role": "user", "content": "Hi"}]}))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let lines: Vec<Value> = body
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let (last, deltas) = lines.split_last().unwrap();
+    let text: String = deltas
+        .iter()
+        .map(|line| line["message"]["content"].as_str().unwrap())
+        .collect();
+    assert_eq!(text, "Hello there");
+    assert!(deltas.iter().all(|line| line.get("thread_id").is_none()));
+    assert!(last["thread_id"].is_string());
+
+    let unknown = client
+        .post(server.url("/api/generate"))
+        .bearer_auth("sk-test")
+        .json(&json!({"model": "llama3", "prompt": "Hi"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(unknown.status(), 404);
+    let body: Value = unknown.json().await.unwrap();
+    assert!(body["error"].as_str().unwrap().contains("llama3"));
+
+    let unauthorized = client.get(server.url("/api/tags")).send().await.unwrap();
+    assert_eq!(unauthorized.status(), 401);
+    let body: Value = unauthorized.json().await.unwrap();
+    assert!(body["error"].is_string());
+}
+
+#[tokio::test]
+async fn ollama_chats_continue_their_thread() {
+    // Each new session plays the script from the start, so the answers show
+    // which session a turn ran on
+    let script = Script::new()
+        .reply("Hello!")
+        .reply("In Lisbon.")
+        .reply("Then Porto.");
+    let server = TestServer::start(compat_state::<ScriptedBackend>(script)).await;
+    let client = reqwest::Client::new();
+    let chat = |messages: Value| {
+        client
+            .post(server.url("/api/chat"))
+            .bearer_auth("sk-test")
+            .json(&json!({"model": "claude-sonnet-4", "stream": false, "messages": messages}))
+            .send()
+    };
+
+    let first: Value = chat(json!([
+        {"role": "system", "content": "Be brief."},
+        {"role": "user", "content": "Hi"}
+    ]))
+    .await
+    .unwrap()
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(first["message"]["content"], "Hello!");
+    let thread_id = first["thread_id"].as_str().unwrap().to_string();
+
+    let history = json!([
+        {"role": "system", "content": "Be brief."},
+        {"role": "user", "content": "Hi"},
+        {"role": "assistant", "content": "Hello!"},
+        {"role": "user", "content": "Where?"}
+    ]);
+    let second: Value = chat(history.clone()).await.unwrap().json().await.unwrap();
+    assert_eq!(second["message"]["content"], "In Lisbon.");
+    assert_eq!(second["thread_id"], thread_id.as_str());
+
+    let messages: Value = client
+        .get(server.url(&format!("/v1/threads/{}/messages", thread_id)))
+        .bearer_auth("sk-test")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let texts: Vec<_> = messages["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["content"][0]["text"]["value"].as_str().unwrap())
+        .collect();
+    assert_eq!(texts, ["Be brief.\n\nHi", "Hello!", "Where?", "In Lisbon."]);
+
+    // A history that went differently gets a conversation of its own
+    let mut edited = history;
+    edited[2]["content"] = json!("Hey!");
+    let other: Value = chat(edited).await.unwrap().json().await.unwrap();
+    assert_eq!(other["message"]["content"], "Hello!");
+    assert_ne!(other["thread_id"], thread_id.as_str());
+
+    // Prompts can name the thread to continue
+    let generated: Value = client
+        .post(server.url("/api/generate"))
+        .bearer_auth("sk-test")
+        .json(&json!({
+            "model": "claude-sonnet-4",
+            "prompt": "And then?",
+            "stream": false,
+            "thread_id": thread_id
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(generated["response"], "Then Porto.");
+
+    let image = client
+        .post(server.url("/api/generate"))
+        .bearer_auth("sk-test")
+        .json(&json!({
+            "model": "claude-sonnet-4",
+            "prompt": "What is this?",
+            "images": ["iVBORw0KGgo="],
+            "thread_id": thread_id
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(image.status(), 400);
+}
+
+#[tokio::test]
+async fn ollama_errors_pass_on_retry_after() {
+    let upstream = MockUpstream::start().await;
+    let server = TestServer::start(compat_state::<ChatGptClient>(upstream.client_config())).await;
+
+    upstream.fail_next_with(paths::CONVERSATION, 429, "slow down", Some(120), 1);
+    let response = reqwest::Client::new()
+        .post(server.url("/api/generate"))
+        .bearer_auth("sk-test")
+        .json(&json!({"model": "claude-sonnet-4", "prompt": "Hi", "stream": false}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 429);
+    assert_eq!(response.headers()["retry-after"], "120");
+    let body: Value = response.json().await.unwrap();
+    assert!(body["error"].is_string());
+}
@@ -0,0 +1,283 @@
+use chatgpt_rs::backend::{EchoBackend, Script, ScriptedBackend};
+use chatgpt_rs::cli::batch::{read_requests, read_results};
+use chatgpt_rs::cli::{BatchOptions, BatchOutcome, BatchRequest, BatchSummary, run_batch};
+use chatgpt_rs::client::ChatGptClient;
+use chatgpt_rs::test_support::{MockUpstream, fixtures, paths};
+use std::io::Cursor;
+use std::path::PathBuf;
+use tokio_util::sync::CancellationToken;
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("batch-{}-{}", uuid::Uuid::new_v4(), name))
+}
+
+fn request(custom_id: &str, prompt: &str, conversation: Option<&str>) -> BatchRequest {
+    BatchRequest {
+        custom_id: custom_id.to_string(),
+        prompt: prompt.to_string(),
+        image: None,
+        conversation: conversation.map(str::to_string),
+    }
+}
+
+fn response(outcome: &BatchOutcome) -> &str {
+    match outcome {
+        BatchOutcome::Completed { response } => response,
+        BatchOutcome::Failed { error } => panic!("unexpected failure: {:?}", error),
+    }
+}
+
+#[tokio::test]
+async fn runs_shared_conversations_in_one_session() {
+    let output = temp_path("results.jsonl");
+    let script = Script::new().reply("first answer").reply("second answer");
+    let requests = vec![
+        request("a", "one", Some("chat")),
+        request("b", "alone", None),
+        request("c", "two", Some("chat")),
+    ];
+
+    let summary = run_batch::<ScriptedBackend>(
+        &script,
+        requests,
+        &output,
+        &BatchOptions::default(),
+        None,
+        &CancellationToken::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(summary.completed, 3);
+
+    let mut results = read_results(&output).unwrap();
+    results.sort_by(|a, b| a.custom_id.cmp(&b.custom_id));
+    let answers: Vec<_> = results.iter().map(|r| response(&r.outcome)).collect();
+    // Each session replays the script from the start, so only the second turn
+    // of the shared conversation gets the second answer
+    assert_eq!(answers, ["first answer", "first answer", "second answer"]);
+    assert_eq!(results[2].conversation.as_deref(), Some("chat"));
+    std::fs::remove_file(output).unwrap();
+}
+
+#[tokio::test]
+async fn resumes_by_skipping_finished_ids() {
+    let output = temp_path("results.jsonl");
+    // A finished line plus one cut short by a crash
+    std::fs::write(
+        &output,
+        "{\"custom_id\":\"a\",\"status\":\"completed\",\"response\":\"old\"}\n{\"custom_id\":\"b\",\"sta",
+    )
+    .unwrap();
+
+    let summary = run_batch::<EchoBackend>(
+        &(),
+        vec![request("a", "hello", None), request("b", "world", None)],
+        &output,
+        &BatchOptions::default(),
+        None,
+        &CancellationToken::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        summary,
+        BatchSummary {
+            total: 2,
+            skipped: 1,
+            completed: 1,
+            ..BatchSummary::default()
+        }
+    );
+
+    let results = read_results(&output).unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(response(&results[0].outcome), "old");
+    assert_eq!(results[1].custom_id, "b");
+    assert_eq!(response(&results[1].outcome), "world");
+    std::fs::remove_file(output).unwrap();
+}
+
+#[tokio::test]
+async fn records_failures_and_retries_them_on_request() {
+    let output = temp_path("results.jsonl");
+    let requests = vec![request("a", "hello", None)];
+    let cancel = CancellationToken::new();
+
+    let summary = run_batch::<ScriptedBackend>(
+        &Script::new().fail(503, "overloaded"),
+        requests.clone(),
+        &output,
+        &BatchOptions::default(),
+        None,
+        &cancel,
+    )
+    .await
+    .unwrap();
+    assert_eq!(summary.failed, 1);
+    match &read_results(&output).unwrap()[0].outcome {
+        BatchOutcome::Failed { error } => assert_eq!(error.code, "upstream_unavailable"),
+        other => panic!("expected a failure, got {:?}", other),
+    }
+
+    let skipped = run_batch::<EchoBackend>(
+        &(),
+        requests.clone(),
+        &output,
+        &BatchOptions::default(),
+        None,
+        &cancel,
+    )
+    .await
+    .unwrap();
+    assert_eq!(skipped.skipped, 1);
+
+    let options = BatchOptions {
+        retry_failed: true,
+        ..BatchOptions::default()
+    };
+    let retried = run_batch::<EchoBackend>(&(), requests, &output, &options, None, &cancel)
+        .await
+        .unwrap();
+    assert_eq!(retried.completed, 1);
+    assert!(read_results(&output).unwrap()[1].is_completed());
+    std::fs::remove_file(output).unwrap();
+}
+
+#[tokio::test]
+async fn resumed_conversations_continue_upstream() {
+    let upstream = MockUpstream::start().await;
+    upstream.reply_with("first answer");
+    upstream.reply_with("second answer");
+    let output = temp_path("results.jsonl");
+    let config = upstream.client_config();
+    let options = BatchOptions::default();
+    let cancel = CancellationToken::new();
+    let run =
+        |requests| run_batch::<ChatGptClient>(&config, requests, &output, &options, None, &cancel);
+
+    // The first run stops after the first turn of the conversation
+    run(vec![request("a", "one", Some("chat"))]).await.unwrap();
+    let summary = run(vec![
+        request("a", "one", Some("chat")),
+        request("b", "two", Some("chat")),
+    ])
+    .await
+    .unwrap();
+    assert_eq!((summary.skipped, summary.completed), (1, 1));
+
+    let results = read_results(&output).unwrap();
+    let position = results[0].position.clone().unwrap();
+    assert_eq!(position.conversation_id, "mock-conversation-1");
+    assert_eq!(response(&results[1].outcome), "second answer");
+
+    // The follow-up went to the conversation the first run started
+    let turns = upstream.requests_to(paths::CONVERSATION);
+    assert_eq!(turns.len(), 2);
+    assert!(turns[0].json()["conversation_id"].is_null());
+    assert_eq!(turns[1].json()["conversation_id"], position.conversation_id);
+    assert_eq!(
+        turns[1].json()["parent_message_id"],
+        position.parent_message_id
+    );
+    std::fs::remove_file(output).unwrap();
+}
+
+#[tokio::test]
+async fn reruns_conversations_that_cannot_be_resumed() {
+    let output = temp_path("results.jsonl");
+    std::fs::write(
+        &output,
+        "{\"custom_id\":\"a\",\"conversation\":\"chat\",\"status\":\"completed\",\"response\":\"old\"}\n",
+    )
+    .unwrap();
+
+    // The echo backend has no upstream conversation to pick up
+    let summary = run_batch::<EchoBackend>(
+        &(),
+        vec![
+            request("a", "hello", Some("chat")),
+            request("b", "world", Some("chat")),
+        ],
+        &output,
+        &BatchOptions::default(),
+        None,
+        &CancellationToken::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!((summary.skipped, summary.completed), (0, 2));
+
+    let ids: Vec<_> = read_results(&output)
+        .unwrap()
+        .into_iter()
+        .map(|result| result.custom_id)
+        .collect();
+    assert_eq!(ids, ["a", "a", "b"]);
+    std::fs::remove_file(output).unwrap();
+}
+
+#[tokio::test]
+async fn sends_images_through_the_upstream() {
+    let upstream = MockUpstream::start().await;
+    let image = temp_path("image.png");
+    let mut png = Vec::new();
+    image::RgbImage::new(3, 2)
+        .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    std::fs::write(&image, png).unwrap();
+
+    let output = temp_path("results.jsonl");
+    let requests = vec![BatchRequest {
+        image: Some(image.clone()),
+        ..request("pic", "What is this?", None)
+    }];
+    let summary = run_batch::<ChatGptClient>(
+        &upstream.client_config(),
+        requests,
+        &output,
+        &BatchOptions::default(),
+        None,
+        &CancellationToken::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(summary.completed, 1);
+
+    let results = read_results(&output).unwrap();
+    assert_eq!(response(&results[0].outcome), fixtures::CONVERSATION_ANSWER);
+    let conversation = upstream.requests_to(paths::CONVERSATION)[0].json();
+    assert_eq!(
+        conversation["messages"][0]["content"]["parts"][0]["width"],
+        3
+    );
+    assert_eq!(upstream.requests_to(paths::PROCESS_UPLOAD).len(), 1);
+    std::fs::remove_file(image).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn rejects_malformed_input() {
+    let input = temp_path("input.jsonl");
+
+    std::fs::write(
+        &input,
+        "{\"custom_id\":\"a\",\"prompt\":\"x\"}\n\n{\"custom_id\":\"a\",\"prompt\":\"y\"}\n",
+    )
+    .unwrap();
+    let err = read_requests(&input).unwrap_err().to_string();
+    assert!(err.contains(":3: duplicate custom_id \"a\""), "{}", err);
+
+    std::fs::write(&input, "{\"prompt\":\"no id\"}\n").unwrap();
+    assert!(read_requests(&input).is_err());
+
+    std::fs::write(
+        &input,
+        "{\"custom_id\":\"a\",\"prompt\":\"x\",\"conversation\":\"c\"}\n",
+    )
+    .unwrap();
+    assert_eq!(
+        read_requests(&input).unwrap(),
+        [request("a", "x", Some("c"))]
+    );
+    std::fs::remove_file(input).unwrap();
+}
@@ -0,0 +1,213 @@
+use chatgpt_rs::client::ChatGptClient;
+use chatgpt_rs::network::cassette::{Cassette, REDACTED, ReplayMatching};
+use chatgpt_rs::test_support::{MockUpstream, fixtures, paths};
+use std::path::PathBuf;
+
+fn temp_cassette(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}-{}.json", name, uuid::Uuid::new_v4()))
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+#[tokio::test]
+async fn replays_a_recorded_session_offline() {
+    let path = temp_cassette("session");
+
+    let upstream = MockUpstream::start().await;
+    upstream.reply_with("recorded answer");
+    let mut client = upstream
+        .client_builder()
+        .record_to(&path)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(
+        client.start_conversation("Hi").await.unwrap(),
+        "recorded answer"
+    );
+    drop(client);
+    drop(upstream);
+
+    let mut client = ChatGptClient::builder()
+        .base_url("http://127.0.0.1:9")
+        .replay_from(&path)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(
+        client.start_conversation("Hi").await.unwrap(),
+        "recorded answer"
+    );
+
+    // The cassette is used up, so another turn can't silently go elsewhere
+    assert!(client.start_conversation("again").await.is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn replay_can_match_bodies_and_headers() {
+    let path = temp_cassette("matching");
+
+    let upstream = MockUpstream::start().await;
+    let mut client = upstream
+        .client_builder()
+        .record_to(&path)
+        .build()
+        .await
+        .unwrap();
+    client.start_conversation("Hi").await.unwrap();
+    drop(client);
+    drop(upstream);
+
+    let replay = |matching: ReplayMatching| {
+        ChatGptClient::builder()
+            .base_url("http://127.0.0.1:9")
+            .replay_from(&path)
+            .replay_matching(matching)
+            .build()
+    };
+    let bodies = ReplayMatching {
+        body: true,
+        ..Default::default()
+    };
+
+    // Message ids and timestamps differ between runs and are not compared
+    let mut client = replay(bodies.clone()).await.unwrap();
+    assert_eq!(
+        client.start_conversation("Hi").await.unwrap(),
+        fixtures::CONVERSATION_ANSWER
+    );
+
+    let mut client = replay(bodies).await.unwrap();
+    let err = client.start_conversation("Bye").await.unwrap_err();
+    assert!(err.to_string().contains("body"), "{}", err);
+
+    // Path-only matching does not notice the different prompt
+    let mut client = replay(ReplayMatching::default()).await.unwrap();
+    assert!(client.start_conversation("Bye").await.is_ok());
+
+    let mut client = replay(ReplayMatching {
+        headers: vec!["User-Agent".to_string(), "x-missing".to_string()],
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    assert!(client.start_conversation("Hi").await.is_ok());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn redacts_secrets_when_recording() {
+    let path = temp_cassette("redacted");
+
+    let upstream = MockUpstream::start().await;
+    let mut client = upstream
+        .client_builder()
+        .record_to(&path)
+        .build()
+        .await
+        .unwrap();
+    client.start_conversation("Hi").await.unwrap();
+    assert!(!path.exists());
+    drop(client);
+
+    let cassette = Cassette::load(&path).unwrap();
+    let recorded: Vec<_> = cassette
+        .interactions
+        .iter()
+        .map(|i| i.request.url.trim_start_matches(upstream.base_url()))
+        .collect();
+    assert_eq!(
+        recorded,
+        [
+            paths::HOME,
+            paths::CHAT_REQUIREMENTS,
+            paths::PREPARE,
+            paths::CONVERSATION
+        ]
+    );
+
+    let home = &cassette.interactions[0].response;
+    assert_eq!(header(&home.headers, "set-cookie"), Some(REDACTED));
+
+    let requirements = &cassette.interactions[1];
+    assert!(
+        requirements
+            .request
+            .body
+            .as_ref()
+            .unwrap()
+            .contains(REDACTED)
+    );
+    assert!(
+        !requirements
+            .response
+            .chunks
+            .concat()
+            .contains("fixture-chat-requirements-token")
+    );
+
+    let conversation = &cassette.interactions[3].request;
+    assert_eq!(
+        header(&conversation.headers, "x-conduit-token"),
+        Some(REDACTED)
+    );
+    assert_eq!(
+        header(&conversation.headers, "openai-sentinel-proof-token"),
+        Some(REDACTED)
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn keeps_characters_split_between_chunks() {
+    let path = temp_cassette("split");
+    let text = "Grüße 👋👋👋 aus Köln";
+
+    // Three-byte pieces cut through the emoji and umlauts
+    let upstream = MockUpstream::start().await;
+    upstream.reply_with(text);
+    upstream.set_chunk_bytes(3);
+    let mut client = upstream
+        .client_builder()
+        .record_to(&path)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(client.start_conversation("Hi").await.unwrap(), text);
+    drop(client);
+    drop(upstream);
+
+    let cassette = Cassette::load(&path).unwrap();
+    let conversation = &cassette.interactions[3].response;
+    assert!(conversation.chunks.len() > 1);
+    let body = conversation.chunks.concat();
+    assert!(!body.contains('\u{FFFD}'));
+    assert!(body.contains("👋"), "{}", body);
+
+    let mut client = ChatGptClient::builder()
+        .base_url("http://127.0.0.1:9")
+        .replay_from(&path)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(client.start_conversation("Hi").await.unwrap(), text);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn replays_committed_cassette() {
+    let mut client = ChatGptClient::builder()
+        .replay_from("tests/fixtures/cassettes/fixture_conversation.json")
+        .build()
+        .await
+        .unwrap();
+
+    let answer = client.start_conversation("Hi").await.unwrap();
+    assert_eq!(answer, fixtures::CONVERSATION_ANSWER);
+}
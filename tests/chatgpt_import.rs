@@ -0,0 +1,390 @@
+use chatgpt_rs::api::chatgpt_import::{Branches, convert, import_to_store, parse};
+use chatgpt_rs::api::storage::ThreadStore;
+use chatgpt_rs::api::{AppState, ServerConfig};
+use chatgpt_rs::backend::EchoBackend;
+use chatgpt_rs::test_support::TestServer;
+use serde_json::{Value, json};
+use std::time::Duration;
+
+fn node(id: &str, parent: Option<&str>, children: &[&str], message: Value) -> (String, Value) {
+    (
+        id.to_string(),
+        json!({"id": id, "message": message, "parent": parent, "children": children}),
+    )
+}
+
+fn text(role: &str, time: f64, text: &str) -> Value {
+    json!({
+        "author": {"role": role},
+        "create_time": time,
+        "content": {"content_type": "text", "parts": [text]},
+        "recipient": "all",
+        "metadata": {}
+    })
+}
+
+/// A conversation whose first prompt was edited, so it has two branches;
+/// the second one is active and went through a tool call
+fn export() -> Value {
+    let mapping: serde_json::Map<String, Value> = [
+        node("root", None, &["system"], Value::Null),
+        node(
+            "system",
+            Some("root"),
+            &["q1", "q2"],
+            json!({
+                "author": {"role": "system"},
+                "content": {"content_type": "text", "parts": [""]},
+                "metadata": {"is_visually_hidden_from_conversation": true}
+            }),
+        ),
+        node("q1", Some("system"), &["a1"], text("user", 1700000010.5, "Plan a trip")),
+        node("a1", Some("q1"), &[], text("assistant", 1700000020.0, "Where to?")),
+        node(
+            "q2",
+            Some("system"),
+            &["call"],
+            json!({
+                "author": {"role": "user"},
+                "create_time": 1700000100.9,
+                "content": {
+                    "content_type": "multimodal_text",
+                    "parts": [
+                        {"content_type": "image_asset_pointer", "asset_pointer": "file-service://file-img"},
+                        "Plan a trip to this place"
+                    ]
+                },
+                "metadata": {
+                    "attachments": [{"id": "file-doc", "name": "notes.pdf", "mimeType": "application/pdf"}]
+                }
+            }),
+        ),
+        node(
+            "call",
+            Some("q2"),
+            &["tool"],
+            json!({
+                "author": {"role": "assistant"},
+                "create_time": 1700000110.0,
+                "content": {"content_type": "code", "text": "search(\"Lisbon\")", "language": "unknown"},
+                "recipient": "browser",
+                "metadata": {}
+            }),
+        ),
+        node(
+            "tool",
+            Some("call"),
+            &["a2"],
+            json!({
+                "author": {"role": "tool"},
+                "content": {"content_type": "tether_browsing_display", "result": "..."},
+                "metadata": {}
+            }),
+        ),
+        node("a2", Some("tool"), &["a3"], text("assistant", 1700000120.0, "That is Lisbon.")),
+        node(
+            "a3",
+            Some("a2"),
+            &[],
+            json!({
+                "author": {"role": "assistant"},
+                "create_time": 1700000121.0,
+                "content": {"content_type": "code", "text": "print(1)", "language": "python"},
+                "recipient": "all",
+                "metadata": {}
+            }),
+        ),
+    ]
+    .into_iter()
+    .collect();
+
+    json!([
+        {
+            "id": "conv-1",
+            "title": "Trip",
+            "create_time": 1700000000.25,
+            "update_time": 1700000121.0,
+            "current_node": "a3",
+            "mapping": mapping
+        },
+        {"id": "conv-empty", "title": "Nothing", "mapping": {}}
+    ])
+}
+
+#[test]
+fn active_branch() {
+    let conversations = parse(&export().to_string()).unwrap();
+    let threads = convert(&conversations, Branches::Active);
+    assert_eq!(threads.len(), 1);
+
+    let thread = &threads[0].thread;
+    assert_eq!(thread.created_at, Some(1700000000));
+    assert_eq!(
+        thread.metadata,
+        Some(json!({
+            "source": "chatgpt",
+            "title": "Trip",
+            "chatgpt_conversation_id": "conv-1",
+            "chatgpt_leaf_id": "a3"
+        }))
+    );
+
+    let messages = &thread.messages;
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].role, "user");
+    assert_eq!(messages[0].content, "Plan a trip to this place");
+    assert_eq!(messages[0].created_at, Some(1700000100));
+    let ids: Vec<_> = messages[0]
+        .attachments
+        .iter()
+        .map(|a| a.id.as_deref().unwrap())
+        .collect();
+    assert_eq!(ids, ["file-doc", "file-img"]);
+    assert_eq!(
+        messages[0].attachments[0].mime_type.as_deref(),
+        Some("application/pdf")
+    );
+
+    // The tool call and its output are dropped; the answer around them is one turn
+    assert_eq!(messages[1].role, "assistant");
+    assert_eq!(
+        messages[1].content,
+        "That is Lisbon.\n\n```python\nprint(1)\n```"
+    );
+    assert_eq!(messages[1].created_at, Some(1700000120));
+}
+
+#[test]
+fn all_branches() {
+    let conversations = parse(&export().to_string()).unwrap();
+    let threads = convert(&conversations, Branches::All);
+    assert_eq!(threads.len(), 2);
+
+    let (first, second) = (&threads[0].thread, &threads[1].thread);
+    assert_eq!(first.messages[0].content, "Plan a trip");
+    assert_eq!(first.messages[1].content, "Where to?");
+    assert_eq!(first.metadata.as_ref().unwrap()["branch"], 1);
+    assert_eq!(first.metadata.as_ref().unwrap()["chatgpt_leaf_id"], "a1");
+    assert_eq!(second.messages[0].content, "Plan a trip to this place");
+    assert_eq!(second.metadata.as_ref().unwrap()["branch"], 2);
+    assert_eq!(second.metadata.as_ref().unwrap()["branches"], 2);
+}
+
+/// [`export`] after the active branch went on for another turn
+fn grown_export() -> Value {
+    let mut export = export();
+    let conversation = &mut export[0];
+    conversation["current_node"] = json!("a4");
+    conversation["mapping"]["a3"]["children"] = json!(["q3"]);
+    for (id, node) in [
+        node(
+            "q3",
+            Some("a3"),
+            &["a4"],
+            text("user", 1700000200.0, "How long should I stay?"),
+        ),
+        node(
+            "a4",
+            Some("q3"),
+            &[],
+            text("assistant", 1700000210.0, "Three days."),
+        ),
+    ] {
+        conversation["mapping"][id] = node;
+    }
+    export
+}
+
+#[test]
+fn store_import_extends_conversations_that_went_on() {
+    let path = std::env::temp_dir().join(format!("chatgpt-import-{}.json", uuid::Uuid::new_v4()));
+    let store = ThreadStore::new(&path, Duration::ZERO);
+    let conversations = parse(&export().to_string()).unwrap();
+    import_to_store(&store, convert(&conversations, Branches::Active)).unwrap();
+    let before = store.load().unwrap();
+
+    let conversations = parse(&grown_export().to_string()).unwrap();
+    let summary = import_to_store(&store, convert(&conversations, Branches::Active)).unwrap();
+    assert_eq!(
+        (summary.imported, summary.updated, summary.skipped),
+        (0, 1, 0)
+    );
+
+    let threads = store.load().unwrap();
+    assert_eq!(threads.len(), 1);
+    assert_eq!(threads[0].id, before[0].id);
+    let contents: Vec<_> = threads[0]
+        .messages
+        .iter()
+        .map(|m| m.content.as_str())
+        .collect();
+    assert_eq!(
+        contents[..],
+        [
+            "Plan a trip to this place",
+            "That is Lisbon.\n\n```python\nprint(1)\n```",
+            "How long should I stay?",
+            "Three days."
+        ]
+    );
+    assert_eq!(
+        threads[0].metadata.as_ref().unwrap()["chatgpt_leaf_id"],
+        "a4"
+    );
+
+    // Importing every branch now adds only the edited first prompt's branch
+    let summary = import_to_store(&store, convert(&conversations, Branches::All)).unwrap();
+    assert_eq!(
+        (summary.imported, summary.updated, summary.skipped),
+        (1, 0, 1)
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn store_import_skips_known_branches() {
+    let path = std::env::temp_dir().join(format!("chatgpt-import-{}.json", uuid::Uuid::new_v4()));
+    let store = ThreadStore::new(&path, Duration::ZERO);
+    let conversations = parse(&export().to_string()).unwrap();
+
+    let summary = import_to_store(&store, convert(&conversations, Branches::Active)).unwrap();
+    assert_eq!((summary.imported, summary.skipped), (1, 0));
+
+    // The active branch is already there, only the other one is new
+    let summary = import_to_store(&store, convert(&conversations, Branches::All)).unwrap();
+    assert_eq!((summary.imported, summary.skipped), (1, 1));
+    let summary = import_to_store(&store, convert(&conversations, Branches::All)).unwrap();
+    assert_eq!((summary.imported, summary.skipped), (0, 2));
+
+    let threads = store.load().unwrap();
+    assert_eq!(threads.len(), 2);
+    assert!(threads.iter().all(|t| t.conversation.is_none()));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn import_endpoint() {
+    let server = TestServer::start(AppState::<EchoBackend>::with_backend_config((), None)).await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(server.url("/v1/threads/import/chatgpt?branches=all"))
+        .json(&export())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let list: Value = response.json().await.unwrap();
+    assert_eq!(list["object"], "list");
+    assert_eq!(list["data"].as_array().unwrap().len(), 2);
+    assert_eq!(list["data"][1]["metadata"]["title"], "Trip");
+
+    // Imported threads carry on like any other
+    let thread_id = list["data"][1]["id"].as_str().unwrap();
+    client
+        .post(server.url(&format!("/v1/threads/{}/messages", thread_id)))
+        .json(&json!({"role": "user", "content": "How long should I stay?"}))
+        .send()
+        .await
+        .unwrap();
+    let response: Value = client
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["status"], "completed");
+
+    let response = client
+        .post(server.url("/v1/threads/import/chatgpt?branches=some"))
+        .json(&export())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["param"], "branches");
+}
+
+async fn import(server: &TestServer, branches: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(server.url(&format!("/v1/threads/import/chatgpt?branches={}", branches)))
+        .json(&export())
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn thread_count(server: &TestServer) -> usize {
+    let list: Value = reqwest::get(server.url("/v1/threads"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    list["data"].as_array().unwrap().len()
+}
+
+#[tokio::test]
+async fn import_endpoint_skips_known_branches() {
+    let server = TestServer::start(AppState::<EchoBackend>::with_backend_config((), None)).await;
+
+    let list: Value = import(&server, "all").await.json().await.unwrap();
+    assert_eq!(list["data"].as_array().unwrap().len(), 2);
+    let list: Value = import(&server, "all").await.json().await.unwrap();
+    assert_eq!(list["data"], json!([]));
+
+    // The active branch is one of those
+    let list: Value = import(&server, "active").await.json().await.unwrap();
+    assert_eq!(list["data"], json!([]));
+    assert_eq!(thread_count(&server).await, 2);
+
+    // A newer export of the same conversation extends the thread in place
+    let list: Value = reqwest::Client::new()
+        .post(server.url("/v1/threads/import/chatgpt"))
+        .json(&grown_export())
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(list["data"].as_array().unwrap().len(), 1);
+    let thread_id = list["data"][0]["id"].as_str().unwrap();
+    let messages: Value = reqwest::get(server.url(&format!("/v1/threads/{}/messages", thread_id)))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let messages = messages["data"].as_array().unwrap();
+    assert_eq!(messages.len(), 4);
+    assert_eq!(messages[3]["content"][0]["text"]["value"], "Three days.");
+    assert_eq!(thread_count(&server).await, 2);
+}
+
+#[tokio::test]
+async fn import_endpoint_is_all_or_nothing() {
+    let config = ServerConfig::from_toml("[limits]\nmax_threads = 1\n").unwrap();
+    let server =
+        TestServer::start(AppState::<EchoBackend>::from_config((), &config).unwrap()).await;
+    let response = import(&server, "all").await;
+    assert_eq!(response.status(), 429);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "thread_limit_reached");
+    assert_eq!(thread_count(&server).await, 0);
+    assert_eq!(import(&server, "active").await.status(), 200);
+    assert_eq!(thread_count(&server).await, 1);
+
+    let config = ServerConfig::from_toml("[limits]\nmax_message_chars = 20\n").unwrap();
+    let server =
+        TestServer::start(AppState::<EchoBackend>::from_config((), &config).unwrap()).await;
+    let response = import(&server, "all").await;
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "message_too_long");
+    assert_eq!(thread_count(&server).await, 0);
+}
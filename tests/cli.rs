@@ -0,0 +1,104 @@
+use chatgpt_rs::cli::{Command, Input, InputBuffer, MarkdownRenderer, Session, render};
+use chatgpt_rs::client::ConversationPosition;
+use std::path::PathBuf;
+
+fn plain() {
+    colored::control::set_override(false);
+}
+
+#[test]
+fn renders_markdown_structure() {
+    plain();
+    let rendered = render(
+        "# Title\n- one\n* two\n3. three\n> quoted\n```rust\nlet x = 1;\n```\nUse `cargo` and [docs](https://docs.rs)",
+    );
+
+    assert_eq!(
+        rendered.lines().collect::<Vec<_>>(),
+        [
+            "Title",
+            "• one",
+            "• two",
+            "3. three",
+            "│ quoted",
+            "```rust",
+            "│ let x = 1;",
+            "```",
+            "Use cargo and docs (https://docs.rs)",
+        ]
+    );
+}
+
+#[test]
+fn keeps_snake_case_and_arithmetic() {
+    plain();
+    assert_eq!(
+        render("call my_func with 2 * 3 * 4"),
+        "call my_func with 2 * 3 * 4\n"
+    );
+    assert_eq!(render("**bold** and *italic*"), "bold and italic\n");
+}
+
+#[test]
+fn renders_streamed_text_by_complete_lines() {
+    plain();
+    let mut renderer = MarkdownRenderer::new();
+
+    assert_eq!(renderer.push("- fir"), "");
+    assert_eq!(renderer.push("st\n```\nco"), "• first\n```\n");
+    assert_eq!(renderer.push("de\n"), "│ code\n");
+    assert_eq!(renderer.push("```\ntail"), "```\n");
+    assert_eq!(renderer.finish(), "tail\n");
+}
+
+#[test]
+fn parses_commands() {
+    assert_eq!(Input::parse("/new"), Ok(Input::Command(Command::New)));
+    assert_eq!(
+        Input::parse("/image  cat.png "),
+        Ok(Input::Command(Command::Image(PathBuf::from("cat.png"))))
+    );
+    assert_eq!(
+        Input::parse("/save"),
+        Ok(Input::Command(Command::Save(None)))
+    );
+    assert_eq!(
+        Input::parse("//etc/hosts is a file"),
+        Ok(Input::Message("/etc/hosts is a file".to_string()))
+    );
+    assert!(Input::parse("/image").is_err());
+    assert!(Input::parse("/nope").is_err());
+}
+
+#[test]
+fn joins_multi_line_input() {
+    let mut buffer = InputBuffer::new();
+    assert_eq!(buffer.push("first \\"), None);
+    assert!(buffer.is_continuing());
+    assert_eq!(buffer.push("second").as_deref(), Some("first \nsecond"));
+
+    assert_eq!(buffer.push("\"\"\""), None);
+    assert_eq!(buffer.push("line with \\"), None);
+    assert_eq!(buffer.push(""), None);
+    assert_eq!(buffer.push("\"\"\"").as_deref(), Some("line with \\\n"));
+    assert!(!buffer.is_continuing());
+}
+
+#[test]
+fn saves_and_loads_sessions() {
+    let path = std::env::temp_dir().join(format!("session-{}.json", uuid::Uuid::new_v4()));
+
+    let mut session = Session::new();
+    session.push("user", "Hi", Some(PathBuf::from("cat.png")));
+    session.push("assistant", "Hello!", None);
+    session.position = Some(ConversationPosition {
+        conversation_id: "conversation".to_string(),
+        parent_message_id: "message".to_string(),
+    });
+    session.save(&path).unwrap();
+
+    let loaded = Session::load(&path).unwrap();
+    assert_eq!(loaded.messages, session.messages);
+    assert_eq!(loaded.position, session.position);
+    std::fs::remove_file(path).unwrap();
+}
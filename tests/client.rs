@@ -0,0 +1,244 @@
+use base64::{Engine as _, engine::general_purpose};
+use chatgpt_rs::ChatGptError;
+use chatgpt_rs::backend::ChatBackend;
+use chatgpt_rs::client::builder::DEFAULT_BASE_URL;
+use chatgpt_rs::client::{ChatGptClient, ChatGptClientBuilder, ClientConfig, RetryPolicy};
+use chatgpt_rs::network::cassette::CassetteMode;
+use chatgpt_rs::test_support::{MockUpstream, fixtures, paths};
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test]
+async fn answers_from_recorded_conversation() {
+    let upstream = MockUpstream::start().await;
+    let mut client = upstream.client().await.unwrap();
+
+    let answer = client.start_conversation("Hi").await.unwrap();
+    assert_eq!(answer, fixtures::CONVERSATION_ANSWER);
+
+    let requests = upstream.requests_to(paths::CONVERSATION);
+    assert_eq!(requests.len(), 1);
+    let request = &requests[0];
+    assert_eq!(request.json()["messages"][0]["content"]["parts"][0], "Hi");
+    assert_eq!(
+        request.header("oai-client-version"),
+        Some("prod-3f1c9d0e2b7a4c65a8e1f0d9b2c7e4a1d6f3b8c0")
+    );
+    assert!(request.header("openai-sentinel-proof-token").is_some());
+    assert!(request.header("x-conduit-token").is_some());
+}
+
+#[tokio::test]
+async fn follow_up_continues_the_conversation() {
+    let upstream = MockUpstream::start().await;
+    let mut client = upstream.client().await.unwrap();
+
+    client.start_conversation("first").await.unwrap();
+    upstream.reply_with("second answer");
+    let answer = client
+        .hold_conversation_streaming("second", None, &CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(answer, "second answer");
+
+    let prepare = upstream.requests_to(paths::PREPARE);
+    let follow_up = prepare.last().unwrap().json();
+    assert_eq!(follow_up["conversation_id"], fixtures::CONVERSATION_ID);
+    assert_eq!(follow_up["parent_message_id"], fixtures::MESSAGE_ID);
+}
+
+#[tokio::test]
+async fn streams_deltas_in_order() {
+    let upstream = MockUpstream::start().await;
+    let mut client = upstream.client().await.unwrap();
+    upstream.reply_with("one two three");
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let answer = client
+        .start_conversation_streaming("count", Some(&tx), &CancellationToken::new())
+        .await
+        .unwrap();
+    drop(tx);
+
+    let mut deltas = Vec::new();
+    while let Some(delta) = rx.recv().await {
+        deltas.push(delta);
+    }
+    assert_eq!(deltas, ["one ", "two ", "three"]);
+    assert_eq!(answer, "one two three");
+}
+
+#[tokio::test]
+async fn retries_transient_failures() {
+    let upstream = MockUpstream::start().await;
+    let mut client = upstream.client().await.unwrap();
+    upstream.fail_next(paths::CHAT_REQUIREMENTS, 503, 1);
+    upstream.fail_next(paths::CONVERSATION, 502, 1);
+
+    let answer = client.start_conversation("Hi").await.unwrap();
+    assert_eq!(answer, fixtures::CONVERSATION_ANSWER);
+    assert_eq!(upstream.requests_to(paths::CONVERSATION).len(), 2);
+}
+
+#[tokio::test]
+async fn does_not_retry_rejected_requests() {
+    let upstream = MockUpstream::start().await;
+    let mut client = upstream.client().await.unwrap();
+    upstream.fail_next(paths::CONVERSATION, 403, 1);
+
+    let err = client.start_conversation("Hi").await.unwrap_err();
+    assert!(matches!(err, ChatGptError::Upstream { status: 403, .. }));
+    assert_eq!(err.code(), "upstream_rejected");
+    assert_eq!(upstream.requests_to(paths::CONVERSATION).len(), 1);
+}
+
+#[tokio::test]
+async fn uploads_images_before_asking() {
+    let upstream = MockUpstream::start().await;
+    let mut client = upstream.client().await.unwrap();
+
+    let mut png = Vec::new();
+    image::RgbImage::new(3, 2)
+        .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    let image_data = general_purpose::STANDARD.encode(&png);
+
+    let answer = client
+        .start_with_image("What is this?", &image_data)
+        .await
+        .unwrap();
+    assert_eq!(answer, fixtures::CONVERSATION_ANSWER);
+
+    let conversation = upstream.requests_to(paths::CONVERSATION)[0].json();
+    let image = &conversation["messages"][0]["content"]["parts"][0];
+    assert_eq!(image["width"], 3);
+    assert_eq!(image["height"], 2);
+    assert_eq!(upstream.requests_to(paths::PROCESS_UPLOAD).len(), 1);
+}
+
+#[tokio::test]
+async fn refuses_images_on_follow_ups() {
+    let upstream = MockUpstream::start().await;
+    let mut client = upstream.client().await.unwrap();
+    client.start_conversation("first").await.unwrap();
+
+    ChatBackend::upload_attachment(&mut client, "aW1hZ2U=")
+        .await
+        .unwrap();
+    let err =
+        ChatBackend::continue_conversation(&mut client, "second", None, &CancellationToken::new())
+            .await
+            .unwrap_err();
+    assert_eq!(err.code(), "invalid_request");
+    assert_eq!(upstream.requests_to(paths::CONVERSATION).len(), 1);
+
+    // The refused image is not carried over to the next turn
+    let answer =
+        ChatBackend::continue_conversation(&mut client, "second", None, &CancellationToken::new())
+            .await
+            .unwrap();
+    assert_eq!(answer, fixtures::CONVERSATION_ANSWER);
+}
+
+#[test]
+fn builder_defaults() {
+    let builder = ChatGptClient::builder();
+    let config = builder.config();
+    assert_eq!(config.base_url, DEFAULT_BASE_URL);
+    assert_eq!(config.proxy, None);
+    assert_eq!(config.connect_timeout, Some(Duration::from_secs(15)));
+    assert_eq!(config.read_timeout, Some(Duration::from_secs(60)));
+    assert_eq!(config.request_timeout, Some(Duration::from_secs(30)));
+    assert_eq!(config.deadline, None);
+    assert!(config.ip_lookup);
+    assert_eq!(config.retry.max_attempts, 3);
+    assert_eq!(config.cassette, None);
+}
+
+#[test]
+fn builder_overrides() {
+    let builder = ChatGptClientBuilder::from_config(ClientConfig {
+        ip_lookup: false,
+        ..ClientConfig::default()
+    })
+    .base_url("http://127.0.0.1:8080")
+    .proxy(Some("127.0.0.1:7890"))
+    .connect_timeout(Duration::from_secs(1))
+    .read_timeout(Duration::from_secs(2))
+    .request_timeout(Duration::from_secs(3))
+    .deadline(Duration::from_secs(4))
+    .retry_policy(RetryPolicy::none())
+    .record_to("session.json");
+
+    let config = builder.config();
+    assert_eq!(config.base_url, "http://127.0.0.1:8080");
+    assert_eq!(config.proxy.as_deref(), Some("127.0.0.1:7890"));
+    assert_eq!(config.connect_timeout, Some(Duration::from_secs(1)));
+    assert_eq!(config.read_timeout, Some(Duration::from_secs(2)));
+    assert_eq!(config.request_timeout, Some(Duration::from_secs(3)));
+    assert_eq!(config.deadline, Some(Duration::from_secs(4)));
+    assert!(!config.ip_lookup);
+    assert_eq!(config.retry.max_attempts, 1);
+    assert_eq!(
+        config.cassette,
+        Some(CassetteMode::Record(PathBuf::from("session.json")))
+    );
+
+    let builder = builder.replay_from("session.json");
+    assert_eq!(
+        builder.config().cassette,
+        Some(CassetteMode::Replay(PathBuf::from("session.json")))
+    );
+}
+
+#[tokio::test]
+async fn builder_settings_reach_the_client() {
+    let upstream = MockUpstream::start().await;
+    let mut client = ChatGptClient::builder()
+        .base_url(upstream.base_url())
+        .ip_lookup(false)
+        .retry_policy(RetryPolicy::none())
+        .build()
+        .await
+        .unwrap();
+    upstream.fail_next(paths::CONVERSATION, 503, 1);
+
+    let err = client.start_conversation("Hi").await.unwrap_err();
+    assert_eq!(err.code(), "upstream_unavailable");
+    assert_eq!(upstream.requests_to(paths::CONVERSATION).len(), 1);
+}
+
+#[tokio::test]
+async fn replaying_skips_the_network_bootstrap() {
+    let path = std::env::temp_dir().join(format!("bootstrap-{}.json", uuid::Uuid::new_v4()));
+    let upstream = MockUpstream::start().await;
+    let mut client = upstream
+        .client_builder()
+        .record_to(&path)
+        .build()
+        .await
+        .unwrap();
+    client.start_conversation("Hi").await.unwrap();
+    drop(client);
+    drop(upstream);
+
+    // The IP lookup is on and every connection would go to a dead proxy, so
+    // building only works if nothing touches the network
+    let mut client = ChatGptClient::builder()
+        .base_url("http://127.0.0.1:9")
+        .proxy(Some("http://127.0.0.1:9"))
+        .ip_lookup(true)
+        .retry_policy(RetryPolicy::none())
+        .replay_from(&path)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(
+        client.start_conversation("Hi").await.unwrap(),
+        fixtures::CONVERSATION_ANSWER
+    );
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,127 @@
+use chatgpt_rs::api::ServerConfig;
+use chatgpt_rs::api::config::{BackendKind, ConfigOverrides, StorageKind};
+use chatgpt_rs::utils::{LogFormat, Secret};
+use std::path::Path;
+
+#[test]
+fn example_file_matches_the_defaults() {
+    let config = ServerConfig::load(Path::new("api_server.example.toml")).unwrap();
+    assert_eq!(config, ServerConfig::default());
+    config.validate().unwrap();
+}
+
+#[test]
+fn flags_and_environment_override_the_file() {
+    let mut config = ServerConfig::from_toml(
+        r#"
+        [server]
+        port = 7000
+        [upstream]
+        proxy = "http://127.0.0.1:7890"
+        [auth]
+        api_keys = ["from-file"]
+        [storage]
+        kind = "file"
+        path = "threads.json"
+        [logging]
+        level = "warn"
+        [logging.modules]
+        "chatgpt_rs::client" = "debug"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.server.host, "0.0.0.0");
+    assert_eq!(config.storage.kind, StorageKind::File);
+
+    config.apply(ConfigOverrides {
+        port: Some(8080),
+        no_proxy: true,
+        backend: Some(BackendKind::Echo),
+        api_keys: vec!["from-flag".to_string()],
+        log_format: Some(LogFormat::Json),
+        ..ConfigOverrides::default()
+    });
+    assert_eq!(config.server.port, 8080);
+    assert_eq!(config.server.backend, BackendKind::Echo);
+    assert_eq!(config.upstream.proxy, None);
+    assert_eq!(config.auth.api_keys, ["from-flag"]);
+    assert_eq!(config.logging.format, LogFormat::Json);
+    assert_eq!(config.logging.filter(), "warn,chatgpt_rs::client=debug");
+
+    // Nothing given leaves the file's values alone
+    config.apply(ConfigOverrides::default());
+    assert_eq!(config.server.port, 8080);
+    assert_eq!(config.auth.api_keys, ["from-flag"]);
+}
+
+#[test]
+fn reports_every_problem_at_once() {
+    let config = ServerConfig::from_toml(
+        r#"
+        models = []
+        [server]
+        host = "localhost"
+        [auth]
+        api_keys = ["same", "same"]
+        [storage]
+        kind = "file"
+        [limits]
+        max_threads = 0
+        [logging.modules]
+        "chatgpt_rs::client" = "loud"
+        [webhooks]
+        url = "ftp://example.com/hook"
+        allowed_urls = ["file:///etc"]
+        "#,
+    )
+    .unwrap();
+
+    let err = config.validate().unwrap_err().to_string();
+    for field in [
+        "server.host",
+        "auth.api_keys",
+        "storage.path",
+        "limits.max_threads",
+        "logging.modules.chatgpt_rs::client",
+        "webhooks.url",
+        "webhooks.secret",
+        "webhooks.allowed_urls",
+        "models",
+    ] {
+        assert!(err.contains(&format!("- {}:", field)), "{}", err);
+    }
+}
+
+#[test]
+fn grpc_needs_the_feature() {
+    let config = ServerConfig::from_toml("[server]\ngrpc = \"alongside\"\n").unwrap();
+    let result = config.validate();
+    if cfg!(feature = "grpc") {
+        result.unwrap();
+    } else {
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("- server.grpc:"), "{}", err);
+    }
+}
+
+#[test]
+fn rejects_unknown_keys() {
+    let err = ServerConfig::from_toml("[server]\nprot = 1\n").unwrap_err();
+    assert!(err.to_string().contains("unknown field `prot`"), "{}", err);
+}
+
+#[test]
+fn printed_config_masks_keys_and_reads_back() {
+    let mut config = ServerConfig::default();
+    config.auth.api_keys = vec!["sk-secret".to_string()];
+    config.webhooks.secret = Some(Secret::new("whsec-secret".to_string()));
+
+    let printed = config.to_toml();
+    assert!(!printed.contains("sk-secret"));
+    assert!(!printed.contains("whsec-secret"));
+
+    let mut read_back = ServerConfig::from_toml(&printed).unwrap();
+    read_back.auth.api_keys = config.auth.api_keys.clone();
+    read_back.webhooks.secret = config.webhooks.secret.clone();
+    assert_eq!(read_back, config);
+}
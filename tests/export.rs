@@ -0,0 +1,209 @@
+use chatgpt_rs::api::AppState;
+use chatgpt_rs::backend::EchoBackend;
+use chatgpt_rs::test_support::TestServer;
+use serde_json::{Value, json};
+
+async fn start() -> TestServer {
+    TestServer::start(AppState::<EchoBackend>::with_backend_config((), None)).await
+}
+
+async fn export(server: &TestServer, thread_id: &str, format: &str) -> reqwest::Response {
+    reqwest::get(server.url(&format!(
+        "/v1/threads/{}/export?format={}",
+        thread_id, format
+    )))
+    .await
+    .unwrap()
+}
+
+async fn import(server: &TestServer, body: &Value) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(server.url("/v1/threads/import"))
+        .json(body)
+        .send()
+        .await
+        .unwrap()
+}
+
+fn archived() -> Value {
+    json!({
+        "object": "thread.export",
+        "version": 1,
+        "id": "thread_old",
+        "created_at": 1700000000,
+        "metadata": {"title": "Trip <planning>", "tags": ["travel"]},
+        "messages": [
+            {
+                "role": "user",
+                "content": "Where should I go? <b>Europe</b> & more",
+                "created_at": 1700000010,
+                "attachments": [
+                    {"id": "file-1", "name": "map.png", "mime_type": "image/png"}
+                ]
+            },
+            {
+                "role": "assistant",
+                "content": "Try Lisbon.",
+                "created_at": 1700000020,
+                "status": "incomplete"
+            }
+        ]
+    })
+}
+
+#[tokio::test]
+async fn json_export_round_trips() {
+    let server = start().await;
+
+    let response = import(&server, &archived()).await;
+    assert_eq!(response.status(), 200);
+    let thread: Value = response.json().await.unwrap();
+    let thread_id = thread["id"].as_str().unwrap();
+    assert_ne!(thread_id, "thread_old");
+    assert_eq!(thread["created_at"], 1700000000);
+    assert_eq!(thread["metadata"]["title"], "Trip <planning>");
+
+    let response = export(&server, thread_id, "json").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    assert_eq!(
+        response.headers()["content-disposition"],
+        format!("attachment; filename=\"thread-{}.json\"", thread_id).as_str()
+    );
+    let exported: Value = response.json().await.unwrap();
+    assert_eq!(exported["id"], thread_id);
+    for field in ["object", "version", "created_at", "metadata", "messages"] {
+        assert_eq!(exported[field], archived()[field], "{}", field);
+    }
+
+    // Importing the export again gives the same thread under a new ID
+    let copy: Value = import(&server, &exported).await.json().await.unwrap();
+    let copy_id = copy["id"].as_str().unwrap();
+    let again: Value = export(&server, copy_id, "json").await.json().await.unwrap();
+    assert_eq!(again["messages"], exported["messages"]);
+
+    let messages: Value = reqwest::get(server.url(&format!("/v1/threads/{}/messages", copy_id)))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(messages["data"][0]["created_at"], 1700000010);
+    assert_eq!(messages["data"][1]["status"], "incomplete");
+}
+
+#[tokio::test]
+async fn imported_threads_continue() {
+    let server = start().await;
+    let thread: Value = import(&server, &archived()).await.json().await.unwrap();
+    let thread_id = thread["id"].as_str().unwrap();
+
+    let client = reqwest::Client::new();
+    client
+        .post(server.url(&format!("/v1/threads/{}/messages", thread_id)))
+        .json(&json!({"role": "user", "content": "And after that?"}))
+        .send()
+        .await
+        .unwrap();
+    let response: Value = client
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["status"], "completed");
+
+    let exported: Value = export(&server, thread_id, "json")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let messages = exported["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 4);
+    assert_eq!(messages[3]["content"], "And after that?");
+}
+
+#[tokio::test]
+async fn markdown_and_html() {
+    let server = start().await;
+    let thread: Value = import(&server, &archived()).await.json().await.unwrap();
+    let thread_id = thread["id"].as_str().unwrap();
+
+    let response = export(&server, thread_id, "markdown").await;
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/markdown; charset=utf-8"
+    );
+    let markdown = response.text().await.unwrap();
+    assert!(markdown.starts_with("# Trip <planning>\n"), "{}", markdown);
+    assert!(markdown.contains("### User · 2023-11-14 22:13:30 UTC\n\nWhere should I go?"));
+    assert!(markdown.contains("- map.png (image/png)"));
+    assert!(markdown.contains("### Assistant · 2023-11-14 22:13:40 UTC (incomplete)"));
+
+    let response = export(&server, thread_id, "html").await;
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/html; charset=utf-8"
+    );
+    let html = response.text().await.unwrap();
+    assert!(html.contains("<title>Trip &lt;planning&gt;</title>"));
+    assert!(html.contains("Where should I go? &lt;b&gt;Europe&lt;/b&gt; &amp; more"));
+    assert!(!html.contains("<b>Europe</b>"));
+    assert!(html.contains("<article class=\"message assistant\">"));
+}
+
+#[tokio::test]
+async fn html_only_links_web_urls() {
+    let server = start().await;
+    let mut archive = archived();
+    archive["messages"][0]["attachments"] = json!([
+        {"name": "map.png", "url": "https://example.com/map.png"},
+        {"name": "inline.png", "url": "data:image/png;base64,AAAA"},
+        {"name": "evil", "url": "javascript:alert(1)"},
+        {"name": "sneaky", "url": " \tJavaScript:alert(2)"},
+        {"name": "local", "url": "file:///etc/passwd"}
+    ]);
+    let thread: Value = import(&server, &archive).await.json().await.unwrap();
+    let thread_id = thread["id"].as_str().unwrap();
+
+    let html = export(&server, thread_id, "html")
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains("<a href=\"https://example.com/map.png\">map.png</a>"));
+    assert!(html.contains("<a href=\"data:image/png;base64,AAAA\">inline.png</a>"));
+    assert!(html.contains("evil <code>javascript:alert(1)</code>"));
+    assert!(html.contains("local <code>file:///etc/passwd</code>"));
+    assert_eq!(html.matches("<a href=").count(), 2, "{}", html);
+}
+
+#[tokio::test]
+async fn rejects_bad_requests() {
+    let server = start().await;
+    let thread: Value = import(&server, &archived()).await.json().await.unwrap();
+    let thread_id = thread["id"].as_str().unwrap();
+
+    let response = export(&server, thread_id, "pdf").await;
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["param"], "format");
+
+    assert_eq!(export(&server, "missing", "json").await.status(), 404);
+
+    let mut wrong = archived();
+    wrong["object"] = json!("thread");
+    let body: Value = import(&server, &wrong).await.json().await.unwrap();
+    assert_eq!(body["error"]["param"], "object");
+
+    let mut newer = archived();
+    newer["version"] = json!(2);
+    let body: Value = import(&server, &newer).await.json().await.unwrap();
+    assert_eq!(body["error"]["param"], "version");
+
+    let response = import(&server, &json!({"object": "thread.export"})).await;
+    assert_eq!(response.status(), 422);
+}
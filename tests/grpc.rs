@@ -0,0 +1,136 @@
+#![cfg(feature = "grpc")]
+
+use chatgpt_rs::api::AppState;
+use chatgpt_rs::api::config::GrpcMode;
+use chatgpt_rs::api::grpc::proto::chat_gpt_client::ChatGptClient;
+use chatgpt_rs::api::grpc::proto::generate_event::Event;
+use chatgpt_rs::api::grpc::proto::{
+    CreateThreadRequest, GenerateRequest, GetThreadRequest, ListMessagesRequest,
+    ListThreadsRequest, NewMessage,
+};
+use chatgpt_rs::backend::EchoBackend;
+use chatgpt_rs::test_support::TestServer;
+use tonic::transport::Channel;
+use tonic::{Code, Request};
+
+async fn client(server: &TestServer) -> ChatGptClient<Channel> {
+    ChatGptClient::connect(server.url("")).await.unwrap()
+}
+
+fn authorized<T>(message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    request
+        .metadata_mut()
+        .insert("authorization", "Bearer sk-test".parse().unwrap());
+    request
+}
+
+#[tokio::test]
+async fn threads_and_generate_over_grpc() {
+    let state = AppState::<EchoBackend>::with_backend_config((), None)
+        .with_api_keys(vec!["sk-test".to_string()])
+        .with_grpc(GrpcMode::Alongside);
+    let server = TestServer::start(state).await;
+    let mut grpc = client(&server).await;
+
+    let err = grpc.list_threads(ListThreadsRequest {}).await.unwrap_err();
+    assert_eq!(err.code(), Code::Unauthenticated);
+
+    let thread = grpc
+        .create_thread(authorized(CreateThreadRequest {
+            messages: vec![NewMessage {
+                role: "user".to_string(),
+                content: "hello there".to_string(),
+            }],
+            metadata_json: r#"{"source":"grpc"}"#.to_string(),
+            proxy: None,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(thread.metadata_json, r#"{"source":"grpc"}"#);
+
+    let mut events = grpc
+        .generate(authorized(GenerateRequest {
+            thread_id: thread.id.clone(),
+            content: Some("ping pong".to_string()),
+            model: None,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+
+    let mut created = None;
+    let mut text = String::new();
+    let mut completed = None;
+    while let Some(event) = events.message().await.unwrap() {
+        match event.event.unwrap() {
+            Event::Created(response) => created = Some(response),
+            Event::Delta(delta) => text.push_str(&delta),
+            Event::Completed(response) => completed = Some(response),
+        }
+    }
+    let (created, completed) = (created.unwrap(), completed.unwrap());
+    assert_eq!(created.status, "in_progress");
+    assert_eq!(created.thread_id, thread.id);
+    assert_eq!(completed.id, created.id);
+    assert_eq!(completed.status, "completed");
+    assert_eq!(text, "ping pong");
+
+    // Threads are shared with the HTTP API
+    let messages = grpc
+        .list_messages(authorized(ListMessagesRequest {
+            thread_id: thread.id.clone(),
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+        .messages;
+    let roles: Vec<_> = messages.iter().map(|m| m.role.as_str()).collect();
+    assert_eq!(roles, ["user", "user", "assistant"]);
+    assert_eq!(messages[2].content, "ping pong");
+    assert_eq!(messages[2].id, format!("msg_{}_2", thread.id));
+
+    let listed: serde_json::Value = reqwest::Client::new()
+        .get(server.url(&format!("/v1/threads/{}/messages", thread.id)))
+        .bearer_auth("sk-test")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(listed["data"].as_array().unwrap().len(), 3);
+
+    let err = grpc
+        .get_thread(authorized(GetThreadRequest {
+            thread_id: "nope".to_string(),
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+    assert_eq!(err.metadata().get("error-code").unwrap(), "not_found");
+}
+
+#[tokio::test]
+async fn grpc_only_leaves_out_the_http_api() {
+    let state = AppState::<EchoBackend>::with_backend_config((), None).with_grpc(GrpcMode::Only);
+    let server = TestServer::start(state).await;
+
+    let status = reqwest::get(server.url("/v1/threads"))
+        .await
+        .unwrap()
+        .status();
+    assert_eq!(status, 404);
+    let health = reqwest::get(server.url("/health")).await.unwrap().status();
+    assert_eq!(health, 200);
+
+    let threads = client(&server)
+        .await
+        .list_threads(ListThreadsRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .threads;
+    assert!(threads.is_empty());
+}
@@ -0,0 +1,252 @@
+use chatgpt_rs::backend::EchoBackend;
+use chatgpt_rs::mcp::{ConversationStore, McpServer};
+use serde_json::{Value, json};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, Lines};
+use tokio::task::JoinHandle;
+
+/// A client talking to an MCP server over in-memory pipes
+struct Client {
+    input: DuplexStream,
+    output: Lines<BufReader<DuplexStream>>,
+    server: JoinHandle<chatgpt_rs::Result<()>>,
+    next_id: u64,
+}
+
+impl Client {
+    fn start(store: ConversationStore) -> Self {
+        let (input, server_input) = tokio::io::duplex(64 * 1024);
+        let (server_output, output) = tokio::io::duplex(64 * 1024);
+        let server = McpServer::<EchoBackend>::new((), None, store);
+        let server = tokio::spawn(async move {
+            server
+                .serve(BufReader::new(server_input), server_output)
+                .await
+        });
+        Self {
+            input,
+            output: BufReader::new(output).lines(),
+            server,
+            next_id: 1,
+        }
+    }
+
+    async fn send(&mut self, message: Value) {
+        let mut line = message.to_string();
+        line.push('\n');
+        self.input.write_all(line.as_bytes()).await.unwrap();
+    }
+
+    async fn request(&mut self, method: &str, params: Value) -> Value {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .await;
+        let line = tokio::time::timeout(Duration::from_secs(5), self.output.next_line())
+            .await
+            .expect("no response within 5s")
+            .unwrap()
+            .expect("server closed its output");
+        let response: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(response["id"], id);
+        response
+    }
+
+    /// `tools/call` result text blocks, and whether it is an error
+    async fn call(&mut self, tool: &str, arguments: Value) -> (Vec<String>, bool) {
+        let response = self
+            .request(
+                "tools/call",
+                json!({ "name": tool, "arguments": arguments }),
+            )
+            .await;
+        let result = &response["result"];
+        let text = result["content"]
+            .as_array()
+            .expect("tool result has content")
+            .iter()
+            .map(|block| block["text"].as_str().unwrap().to_string())
+            .collect();
+        (text, result["isError"].as_bool().unwrap())
+    }
+
+    async fn close(self) {
+        drop(self.input);
+        self.server.await.unwrap().unwrap();
+    }
+}
+
+fn conversation_id(text: &[String]) -> String {
+    text.iter()
+        .find_map(|block| block.strip_prefix("conversation_id: "))
+        .expect("answer names its conversation")
+        .to_string()
+}
+
+fn state_path() -> PathBuf {
+    std::env::temp_dir().join(format!("mcp-{}.json", uuid::Uuid::new_v4()))
+}
+
+#[tokio::test]
+async fn initialize_and_list_tools() {
+    let mut client = Client::start(ConversationStore::in_memory());
+
+    let response = client
+        .request(
+            "initialize",
+            json!({ "protocolVersion": "2025-03-26", "capabilities": {}, "clientInfo": { "name": "test", "version": "0" } }),
+        )
+        .await;
+    assert_eq!(response["result"]["protocolVersion"], "2025-03-26");
+    assert_eq!(response["result"]["serverInfo"]["name"], "chatgpt-rs");
+    client
+        .send(json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }))
+        .await;
+
+    let response = client.request("tools/list", json!({})).await;
+    let names: Vec<_> = response["result"]["tools"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|tool| tool["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "ask",
+            "continue_conversation",
+            "ask_with_image",
+            "list_conversations"
+        ]
+    );
+
+    let response = client.request("resources/list", json!({})).await;
+    assert_eq!(response["error"]["code"], -32601);
+    let response = client
+        .request("tools/call", json!({ "name": "nope" }))
+        .await;
+    assert_eq!(response["error"]["code"], -32602);
+    let response = client
+        .request("tools/call", json!({ "name": "ask", "arguments": {} }))
+        .await;
+    assert_eq!(response["error"]["code"], -32602);
+
+    client.close().await;
+}
+
+#[tokio::test]
+async fn ask_continue_and_list() {
+    let mut client = Client::start(ConversationStore::in_memory());
+
+    let (text, is_error) = client.call("list_conversations", json!({})).await;
+    assert!(!is_error);
+    assert_eq!(text, ["No conversations yet"]);
+
+    let (text, is_error) = client
+        .call(
+            "ask",
+            json!({ "prompt": "Is this a good idea?\nMore context" }),
+        )
+        .await;
+    assert!(!is_error);
+    assert_eq!(text[0], "Is this a good idea?\nMore context");
+    let id = conversation_id(&text);
+
+    let (text, is_error) = client
+        .call(
+            "continue_conversation",
+            json!({ "conversation_id": id, "prompt": "Why?" }),
+        )
+        .await;
+    assert!(!is_error);
+    assert_eq!(text[0], "Why?");
+    assert_eq!(conversation_id(&text), id);
+
+    let (text, _) = client
+        .call(
+            "ask_with_image",
+            json!({ "prompt": "What is this?", "image_base64": "aGk=", "title": "Picture" }),
+        )
+        .await;
+    let image_id = conversation_id(&text);
+
+    let (text, _) = client.call("list_conversations", json!({})).await;
+    let lines: Vec<_> = text[0].lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.starts_with(&format!("{}  Is this a good idea?  (4 messages", id)))
+    );
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.starts_with(&format!("{}  Picture  (2 messages", image_id)))
+    );
+
+    let (text, _) = client
+        .call("list_conversations", json!({ "limit": 1 }))
+        .await;
+    assert!(text[0].ends_with("... and 1 more"));
+
+    let (text, is_error) = client
+        .call(
+            "continue_conversation",
+            json!({ "conversation_id": "conv_missing", "prompt": "Hi" }),
+        )
+        .await;
+    assert!(is_error);
+    assert!(text[0].contains("conv_missing"));
+
+    let (_, is_error) = client
+        .call("ask_with_image", json!({ "prompt": "What?" }))
+        .await;
+    assert!(is_error);
+
+    client.close().await;
+}
+
+#[tokio::test]
+async fn conversations_survive_a_restart() {
+    let path = state_path();
+
+    let mut client = Client::start(ConversationStore::open(&path).unwrap());
+    let (text, _) = client.call("ask", json!({ "prompt": "First" })).await;
+    let id = conversation_id(&text);
+    client.close().await;
+
+    let store = ConversationStore::open(&path).unwrap();
+    assert_eq!(store.len(), 1);
+    let mut client = Client::start(store);
+    let (text, is_error) = client
+        .call(
+            "continue_conversation",
+            json!({ "conversation_id": id, "prompt": "Second" }),
+        )
+        .await;
+    assert!(!is_error);
+    assert_eq!(text[0], "Second");
+    client.close().await;
+
+    let store = ConversationStore::open(&path).unwrap();
+    let conversation = store.get(&id).unwrap();
+    assert_eq!(conversation.title, "First");
+    let contents: Vec<_> = conversation
+        .session
+        .messages
+        .iter()
+        .map(|message| (message.role.as_str(), message.content.as_str()))
+        .collect();
+    assert_eq!(
+        contents,
+        [
+            ("user", "First"),
+            ("assistant", "First"),
+            ("user", "Second"),
+            ("assistant", "Second")
+        ]
+    );
+
+    std::fs::remove_file(path).unwrap();
+}
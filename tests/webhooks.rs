@@ -0,0 +1,351 @@
+use axum::Router;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use chatgpt_rs::api::AppState;
+use chatgpt_rs::api::config::WebhookConfig;
+use chatgpt_rs::api::webhooks::{DeliveryStatus, sign};
+use chatgpt_rs::backend::{ChatBackend, EchoBackend, Script, ScriptedBackend};
+use chatgpt_rs::test_support::TestServer;
+use chatgpt_rs::utils::Secret;
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const SECRET: &str = "whsec-test";
+
+/// An event as the receiver got it
+struct Received {
+    headers: HeaderMap,
+    body: String,
+}
+
+impl Received {
+    fn header(&self, name: &str) -> &str {
+        self.headers[name].to_str().unwrap()
+    }
+
+    fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+#[derive(Clone, Default)]
+struct Receiver {
+    received: Arc<Mutex<Vec<Received>>>,
+    /// Statuses to answer with, in order; 200 once they run out
+    statuses: Arc<Mutex<VecDeque<u16>>>,
+}
+
+impl Receiver {
+    /// Listen on a free local port and return the webhook URL
+    async fn start(&self) -> String {
+        async fn hook(
+            State(receiver): State<Receiver>,
+            headers: HeaderMap,
+            body: String,
+        ) -> StatusCode {
+            receiver
+                .received
+                .lock()
+                .unwrap()
+                .push(Received { headers, body });
+            let status = receiver.statuses.lock().unwrap().pop_front().unwrap_or(200);
+            StatusCode::from_u16(status).unwrap()
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/hook", post(hook))
+            .with_state(self.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    fn fail_next(&self, statuses: &[u16]) {
+        self.statuses.lock().unwrap().extend(statuses);
+    }
+
+    /// Wait until `count` events arrived
+    async fn wait_for(&self, count: usize) -> Vec<Received> {
+        for _ in 0..100 {
+            if self.received.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let received = std::mem::take(&mut *self.received.lock().unwrap());
+        assert_eq!(received.len(), count, "webhook events received");
+        received
+    }
+}
+
+fn config(url: Option<String>) -> WebhookConfig {
+    WebhookConfig {
+        url,
+        secret: Some(Secret::new(SECRET.to_string())),
+        initial_backoff_ms: 10,
+        max_attempts: 3,
+        ..WebhookConfig::default()
+    }
+}
+
+async fn start<B: ChatBackend>(state: AppState<B>) -> (TestServer, String) {
+    let server = TestServer::start(state).await;
+    let thread: Value = reqwest::Client::new()
+        .post(server.url("/v1/threads"))
+        .json(&json!({"messages": [{"role": "user", "content": "Hi there"}]}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let thread_id = thread["id"].as_str().unwrap().to_string();
+    (server, thread_id)
+}
+
+async fn delivery_log(server: &TestServer, response_id: &str) -> Value {
+    // The log is updated right after the receiver answers
+    for _ in 0..50 {
+        let log: Value =
+            reqwest::get(server.url(&format!("/v1/responses/{}/webhook", response_id)))
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+        if log["status"] != "pending" {
+            return log;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("webhook delivery still pending");
+}
+
+#[tokio::test]
+async fn signed_event_on_completion() {
+    let receiver = Receiver::default();
+    let url = receiver.start().await;
+    let webhooks = WebhookConfig {
+        allowed_urls: vec![url.clone()],
+        ..config(None)
+    };
+    let state = AppState::<EchoBackend>::with_backend_config((), None).with_webhooks(webhooks);
+    let (server, thread_id) = start(state).await;
+
+    let response: Value = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id, "webhook_url": url}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let response_id = response["id"].as_str().unwrap();
+
+    let received = receiver.wait_for(1).await;
+    let event = &received[0];
+    let timestamp: u64 = event.header("webhook-timestamp").parse().unwrap();
+    assert_eq!(
+        event.header("webhook-signature"),
+        sign(SECRET, timestamp, &event.body)
+    );
+    assert_ne!(
+        event.header("webhook-signature"),
+        sign("other", timestamp, &event.body)
+    );
+
+    let body = event.json();
+    assert_eq!(event.header("webhook-id"), body["id"]);
+    assert_eq!(body["type"], "response.completed");
+    assert_eq!(body["data"]["id"], response_id);
+    assert_eq!(body["data"]["thread_id"], thread_id.as_str());
+    assert_eq!(body["data"]["output_text"], "Hi there");
+
+    let log = delivery_log(&server, response_id).await;
+    assert_eq!(log["status"], "delivered");
+    assert_eq!(log["event_type"], "response.completed");
+    assert_eq!(log["deliveries"].as_array().unwrap().len(), 1);
+    assert_eq!(log["deliveries"][0]["status_code"], 200);
+}
+
+#[tokio::test]
+async fn retries_and_redelivers() {
+    let receiver = Receiver::default();
+    let url = receiver.start().await;
+    receiver.fail_next(&[500, 503, 500]);
+    // The configured webhook applies to every response
+    let script = Script::new().fail(503, "overloaded");
+    let state = AppState::<ScriptedBackend>::with_backend_config(script, None)
+        .with_webhooks(config(Some(url)));
+    let (server, thread_id) = start(state).await;
+
+    let response = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 503);
+
+    let received = receiver.wait_for(3).await;
+    let event = received[0].json();
+    assert_eq!(event["type"], "response.failed");
+    assert_eq!(event["data"]["status"], "failed");
+    assert_eq!(event["data"]["error"]["code"], "upstream_unavailable");
+    assert!(received.iter().all(|r| r.json()["id"] == event["id"]));
+    let response_id = event["data"]["id"].as_str().unwrap();
+
+    let log = delivery_log(&server, response_id).await;
+    assert_eq!(log["status"], "failed");
+    let codes: Vec<_> = log["deliveries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|attempt| attempt["status_code"].as_u64().unwrap())
+        .collect();
+    assert_eq!(codes, [500, 503, 500]);
+
+    let redeliver = server.url(&format!("/v1/responses/{}/webhook/redeliver", response_id));
+    let response = reqwest::Client::new()
+        .post(&redeliver)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 202);
+    let received = receiver.wait_for(1).await;
+    assert_eq!(received[0].json()["id"], event["id"]);
+
+    let log = delivery_log(&server, response_id).await;
+    assert_eq!(log["status"], "delivered");
+    assert_eq!(log["deliveries"][3]["attempt"], 4);
+    assert_eq!(log["deliveries"][3]["status_code"], 200);
+
+    let response = reqwest::Client::new()
+        .post(server.url("/v1/responses/missing/webhook/redeliver"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+#[tokio::test]
+async fn webhook_url_needs_a_secret() {
+    let state = AppState::<EchoBackend>::with_backend_config((), None);
+    let (server, thread_id) = start(state).await;
+
+    let response = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id, "webhook_url": "http://127.0.0.1:9/hook"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "webhooks_disabled");
+    assert_eq!(body["error"]["param"], "webhook_url");
+}
+
+#[tokio::test]
+async fn webhook_url_must_be_allowed() {
+    let webhooks = WebhookConfig {
+        allowed_urls: vec!["http://127.0.0.1:9/hooks".to_string()],
+        ..config(Some("https://example.com/configured".to_string()))
+    };
+    let state = AppState::<EchoBackend>::with_backend_config((), None).with_webhooks(webhooks);
+    let (server, thread_id) = start(state).await;
+    let create = |url: &str| {
+        reqwest::Client::new()
+            .post(server.url("/v1/responses"))
+            .json(&json!({"thread_id": thread_id, "webhook_url": url}))
+            .send()
+    };
+
+    for url in [
+        "http://127.0.0.1:9/hooks",
+        "http://127.0.0.1:9/hooks/a?b=c",
+        "https://example.com/configured",
+    ] {
+        assert_eq!(create(url).await.unwrap().status(), 200, "{}", url);
+    }
+    for url in [
+        "http://127.0.0.1:9/hooks-admin",
+        "http://127.0.0.1:9/hooks/../admin",
+        "http://127.0.0.1:10/hooks",
+        "https://127.0.0.1:9/hooks",
+        "http://169.254.169.254/latest/meta-data",
+        "http://127.0.0.1:9@169.254.169.254/hooks",
+        "https://example.com/configured/other",
+    ] {
+        let response = create(url).await.unwrap();
+        assert_eq!(response.status(), 400, "{}", url);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "webhook_url_not_allowed");
+        assert_eq!(body["error"]["param"], "webhook_url");
+    }
+}
+
+#[tokio::test]
+async fn shutdown_waits_for_pending_deliveries() {
+    let receiver = Receiver::default();
+    let url = receiver.start().await;
+    receiver.fail_next(&[503]);
+    let webhooks = WebhookConfig {
+        initial_backoff_ms: 200,
+        ..config(Some(url))
+    };
+    let state = AppState::<EchoBackend>::with_backend_config((), None).with_webhooks(webhooks);
+    let (server, thread_id) = start(state.clone()).await;
+
+    let response = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    // The retry after the first failed attempt finishes before the drain does
+    assert!(state.drain().await);
+    assert_eq!(receiver.received.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn shutdown_stops_retrying_at_the_drain_timeout() {
+    let receiver = Receiver::default();
+    let url = receiver.start().await;
+    receiver.fail_next(&[503]);
+    let webhooks = WebhookConfig {
+        initial_backoff_ms: 60_000,
+        ..config(Some(url))
+    };
+    let state = AppState::<EchoBackend>::with_backend_config((), None)
+        .with_webhooks(webhooks)
+        .with_drain_timeout(Duration::from_millis(100));
+    let (server, thread_id) = start(state.clone()).await;
+
+    let response: Value = reqwest::Client::new()
+        .post(server.url("/v1/responses"))
+        .json(&json!({"thread_id": thread_id}))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let response_id = response["id"].as_str().unwrap();
+    receiver.wait_for(1).await;
+
+    let drained = tokio::time::timeout(Duration::from_secs(5), state.drain())
+        .await
+        .expect("drain gave up on the retry");
+    assert!(!drained);
+    assert_eq!(
+        state.webhooks().log(response_id).unwrap().status,
+        DeliveryStatus::Failed
+    );
+}
@@ -0,0 +1,195 @@
+use chatgpt_rs::api::AppState;
+use chatgpt_rs::backend::{EchoBackend, Script, ScriptedBackend};
+use chatgpt_rs::test_support::TestServer;
+use futures::{SinkExt, StreamExt};
+use serde_json::{Value, json};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn connect(server: &TestServer, query: &str) -> Socket {
+    let url = server
+        .url(&format!("/v1/ws{}", query))
+        .replace("http", "ws");
+    tokio_tungstenite::connect_async(url).await.unwrap().0
+}
+
+async fn send(socket: &mut Socket, command: Value) {
+    socket
+        .send(Message::text(command.to_string()))
+        .await
+        .unwrap();
+}
+
+async fn next_event(socket: &mut Socket) -> Value {
+    loop {
+        let frame = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("no event within 5s")
+            .unwrap()
+            .unwrap();
+        if let Message::Text(text) = frame {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+/// Events up to and including the first one of type `last`
+async fn events_until(socket: &mut Socket, last: &str) -> Vec<Value> {
+    let mut events = Vec::new();
+    loop {
+        let event = next_event(socket).await;
+        let done = event["type"] == last;
+        events.push(event);
+        if done {
+            return events;
+        }
+    }
+}
+
+#[tokio::test]
+async fn chats_over_a_websocket() {
+    let state = AppState::<EchoBackend>::with_backend_config((), None)
+        .with_api_keys(vec!["sk-test".to_string()]);
+    let server = TestServer::start(state).await;
+    let mut socket = connect(&server, "?api_key=sk-test").await;
+
+    send(&mut socket, json!({"type": "thread.create"})).await;
+    let created = next_event(&mut socket).await;
+    assert_eq!(created["type"], "thread.created");
+    let thread_id = created["thread"]["id"].as_str().unwrap().to_string();
+
+    send(
+        &mut socket,
+        json!({"type": "message.send", "content": "ping pong"}),
+    )
+    .await;
+    let events = events_until(&mut socket, "response.completed").await;
+    let types: Vec<_> = events.iter().map(|e| e["type"].as_str().unwrap()).collect();
+    assert_eq!(types[..2], ["response.created", "thread.message"]);
+    assert_eq!(events[1]["message"]["role"], "user");
+    let text: String = events
+        .iter()
+        .filter(|e| e["type"] == "response.delta")
+        .map(|e| e["delta"].as_str().unwrap())
+        .collect();
+    assert_eq!(text, "ping pong");
+    let completed = events.last().unwrap();
+    assert_eq!(completed["response"]["status"], "completed");
+    assert_eq!(completed["response"]["thread_id"], thread_id);
+
+    // The answer also arrives as a thread event, as does a message added over REST
+    let answer = events
+        .iter()
+        .find(|e| e["type"] == "thread.message" && e["message"]["role"] == "assistant")
+        .cloned();
+    let answer = match answer {
+        Some(answer) => answer,
+        None => next_event(&mut socket).await,
+    };
+    assert_eq!(
+        answer["message"]["content"][0]["text"]["value"],
+        "ping pong"
+    );
+
+    reqwest::Client::new()
+        .post(server.url(&format!("/v1/threads/{}/messages", thread_id)))
+        .bearer_auth("sk-test")
+        .json(&json!({"role": "user", "content": "from REST"}))
+        .send()
+        .await
+        .unwrap();
+    let event = next_event(&mut socket).await;
+    assert_eq!(event["type"], "thread.message");
+    assert_eq!(event["message"]["id"], format!("msg_{}_2", thread_id));
+
+    send(
+        &mut socket,
+        json!({"type": "thread.select", "thread_id": "nope"}),
+    )
+    .await;
+    let error = next_event(&mut socket).await;
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["error"]["code"], "not_found");
+}
+
+#[tokio::test]
+async fn cancels_a_response_from_the_same_connection() {
+    let script = Script::new().reply_slowly("one two three four five", Duration::from_millis(200));
+    let state = AppState::<ScriptedBackend>::with_backend_config(script, None);
+    let server = TestServer::start(state).await;
+    let mut socket = connect(&server, "").await;
+
+    send(&mut socket, json!({"type": "thread.create"})).await;
+    next_event(&mut socket).await;
+    send(
+        &mut socket,
+        json!({"type": "message.send", "content": "Count"}),
+    )
+    .await;
+    events_until(&mut socket, "response.delta").await;
+
+    send(&mut socket, json!({"type": "response.cancel"})).await;
+    let events = events_until(&mut socket, "response.completed").await;
+    assert_eq!(events.last().unwrap()["response"]["status"], "cancelled");
+
+    // The thread is free again for the next message on the same connection
+    send(
+        &mut socket,
+        json!({"type": "message.send", "content": "Again"}),
+    )
+    .await;
+    let event = events_until(&mut socket, "response.created").await;
+    assert!(event.iter().all(|e| e["type"] != "error"), "{:?}", event);
+}
+
+#[tokio::test]
+async fn flags_answers_that_shift_later_messages() {
+    let script = Script::new().reply_slowly("one two three", Duration::from_millis(100));
+    let state = AppState::<ScriptedBackend>::with_backend_config(script, None);
+    let server = TestServer::start(state).await;
+    let mut socket = connect(&server, "").await;
+
+    send(&mut socket, json!({"type": "thread.create"})).await;
+    let thread_id = next_event(&mut socket).await["thread"]["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    send(
+        &mut socket,
+        json!({"type": "message.send", "content": "Count"}),
+    )
+    .await;
+    events_until(&mut socket, "response.delta").await;
+
+    // Added while the answer is still coming in, so the answer goes before it
+    reqwest::Client::new()
+        .post(server.url(&format!("/v1/threads/{}/messages", thread_id)))
+        .json(&json!({"role": "user", "content": "And then?"}))
+        .send()
+        .await
+        .unwrap();
+    let mut events = events_until(&mut socket, "response.completed").await;
+    // The answer's thread event may come after the response has completed
+    if events
+        .iter()
+        .filter(|e| e["type"] == "thread.message")
+        .count()
+        < 2
+    {
+        events.push(next_event(&mut socket).await);
+    }
+    let added: Vec<_> = events
+        .iter()
+        .filter(|e| e["type"] == "thread.message")
+        .collect();
+    assert_eq!(added.len(), 2, "{:?}", events);
+    assert_eq!(added[0]["message"]["id"], format!("msg_{}_1", thread_id));
+    assert_eq!(added[0].get("shifted"), None);
+    assert_eq!(added[1]["message"]["role"], "assistant");
+    assert_eq!(added[1]["message"]["id"], format!("msg_{}_1", thread_id));
+    assert_eq!(added[1]["shifted"], true);
+}